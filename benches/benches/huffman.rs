@@ -0,0 +1,80 @@
+// Huffman decoding: HuffmanDecoder::decode on a stream of symbols of the standard luminance AC table, into a new
+// and into a reused buffer, and the entropy decoding stage of whole images as DecodeStats times it, which leaves
+// out the IDCT and the color conversion.
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rsjpeg::{decode_image_with_stats, DHTType, DecodeOptions, HuffmanDecoder, HuffmanTable};
+use rsjpeg_benches::generated_images;
+
+// SYMBOLS is the number of symbols in the coded stream
+const SYMBOLS: usize = 64 * 1024;
+
+// coded_symbols writes codes of pseudo random symbols with table until the stream fills whole bytes, so it
+// decodes without padding bits
+fn coded_symbols(table: &HuffmanTable) -> Vec<u8> {
+    let codes = table.make_code_table();
+    let symbols = &table.values;
+    let (mut output, mut bits, mut count) = (Vec::new(), 0u64, 0u32);
+    let mut state = 0x1234_5678u32;
+    let mut written = 0;
+    while written < SYMBOLS || count % 8 != 0 {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let (code, length) = codes[usize::from(symbols[(state >> 16) as usize % symbols.len()])];
+        bits = bits << length | u64::from(code);
+        count += u32::from(length);
+        while count >= 8 {
+            output.push((bits >> (count - 8)) as u8);
+            count -= 8;
+        }
+        written += 1;
+    }
+    output
+}
+
+fn huffman(c: &mut Criterion) {
+    let table = HuffmanTable::standard(DHTType::LuminanceAC);
+    let stream = coded_symbols(&table);
+    let mut group = c.benchmark_group("huffman");
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    let decoder = HuffmanDecoder::new(&table);
+    group.bench_function("decode", |b| {
+        b.iter(|| decoder.decode(black_box(&stream)).unwrap())
+    });
+    // decode_into reuses the output buffer, which leaves only the bit handling and table lookups per symbol
+    let mut output = Vec::with_capacity(decoder.decode(&stream).unwrap().len());
+    group.bench_function("decode_into", |b| {
+        b.iter(|| {
+            output.clear();
+            decoder
+                .decode_into(black_box(&stream), &mut output)
+                .unwrap()
+        })
+    });
+    group.bench_function("new", |b| b.iter(|| HuffmanDecoder::new(black_box(&table))));
+
+    let options = DecodeOptions::default();
+    for fixture in generated_images() {
+        group.throughput(Throughput::Bytes(fixture.file.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("entropy_decoding", fixture.name),
+            &fixture.file,
+            |b, file| {
+                b.iter_custom(|iterations| {
+                    (0..iterations)
+                        .map(|_| {
+                            decode_image_with_stats(file, &options)
+                                .unwrap()
+                                .1
+                                .entropy_decoding
+                        })
+                        .sum::<Duration>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, huffman);
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+// The inverse DCT of every IdctMethod, timed as the reconstruction stage of DecodeStats: dequantizing and
+// transforming every block of the generated images into sample planes.
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rsjpeg::{decode_image_with_stats, DecodeOptions, IdctMethod};
+use rsjpeg_benches::{generated_images, HEIGHT, WIDTH};
+
+fn idct(c: &mut Criterion) {
+    let mut group = c.benchmark_group("idct");
+    group.throughput(Throughput::Elements((WIDTH * HEIGHT) as u64));
+    for fixture in generated_images() {
+        for &(name, idct) in &[
+            ("integer", IdctMethod::Integer),
+            ("float", IdctMethod::Float),
+            ("reference", IdctMethod::Reference),
+        ] {
+            let options = DecodeOptions {
+                idct,
+                ..DecodeOptions::default()
+            };
+            group.bench_with_input(
+                BenchmarkId::new(name, fixture.name),
+                &fixture.file,
+                |b, file| {
+                    b.iter_custom(|iterations| {
+                        (0..iterations)
+                            .map(|_| {
+                                decode_image_with_stats(file, &options)
+                                    .unwrap()
+                                    .1
+                                    .reconstruction
+                            })
+                            .sum::<Duration>()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, idct);
+criterion_main!(benches);
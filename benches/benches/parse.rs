@@ -0,0 +1,43 @@
+// Marker parsing: decode parses every segment of a file, including the scans, while read_info and MarkerIter
+// stop at the frame header without looking at the entropy coded data.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rsjpeg::{decode, read_info, Marker, MarkerIter, ParseOptions};
+use rsjpeg_benches::{generated_images, sample_images, Fixture};
+
+fn parse(c: &mut Criterion) {
+    let fixtures: Vec<Fixture> = sample_images()
+        .into_iter()
+        .chain(generated_images())
+        .collect();
+    let mut group = c.benchmark_group("parse");
+    for fixture in &fixtures {
+        group.throughput(Throughput::Bytes(fixture.file.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("decode", fixture.name),
+            &fixture.file,
+            |b, file| b.iter(|| decode(black_box(file)).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("read_info", fixture.name),
+            &fixture.file,
+            |b, file| b.iter(|| read_info(black_box(file)).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("frame_header", fixture.name),
+            &fixture.file,
+            |b, file| {
+                b.iter(|| {
+                    MarkerIter::new(black_box(file), &ParseOptions::default())
+                        .unwrap()
+                        .map(Result::unwrap)
+                        .find(|spanned| matches!(spanned.marker, Marker::SOF(_)))
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
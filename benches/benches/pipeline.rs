@@ -0,0 +1,45 @@
+// Full decodes from file to pixels: decode_image on every fixture, decode_image_with_scratch reusing its buffers
+// as video decoding does, and the streaming Decoder reading from an io::Read.
+use std::io::Cursor;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rsjpeg::{decode_image, decode_image_with_scratch, DecodeOptions, Decoder, DecoderScratch};
+use rsjpeg_benches::{generated_images, sample_images, Fixture};
+
+fn pipeline(c: &mut Criterion) {
+    let fixtures: Vec<Fixture> = sample_images()
+        .into_iter()
+        .chain(generated_images())
+        .collect();
+    let mut group = c.benchmark_group("pipeline");
+    for fixture in &fixtures {
+        group.throughput(Throughput::Bytes(fixture.file.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("decode_image", fixture.name),
+            &fixture.file,
+            |b, file| b.iter(|| decode_image(black_box(file)).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("with_scratch", fixture.name),
+            &fixture.file,
+            |b, file| {
+                let options = DecodeOptions::default();
+                let mut scratch = DecoderScratch::default();
+                b.iter(|| {
+                    let image =
+                        decode_image_with_scratch(black_box(file), &options, &mut scratch).unwrap();
+                    scratch.recycle(image);
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("stream", fixture.name),
+            &fixture.file,
+            |b, file| b.iter(|| Decoder::new(Cursor::new(black_box(file))).decode().unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+// Fixtures for the benchmarks: the sample images of the test suite and larger images that are encoded when a
+// benchmark starts. The encoder settings and pixels are fixed, so a revision always measures the same files and
+// results of runs on different machines compare.
+use rsjpeg::{encode_image_with_options, ChromaSubsampling, ColorType, EncodeOptions};
+
+pub struct Fixture {
+    pub name: &'static str,
+    pub file: Vec<u8>,
+}
+
+// sample_images returns the images in tests/images, which are small enough to show the fixed cost of a decode
+pub fn sample_images() -> Vec<Fixture> {
+    let fixture = |name, file: &[u8]| Fixture {
+        name,
+        file: file.to_vec(),
+    };
+    vec![
+        fixture(
+            "gradient_420",
+            include_bytes!("../../tests/images/gradient_420.jpg"),
+        ),
+        fixture(
+            "gradient_422",
+            include_bytes!("../../tests/images/gradient_422.jpg"),
+        ),
+        fixture(
+            "gradient_444",
+            include_bytes!("../../tests/images/gradient_444.jpg"),
+        ),
+        fixture("gray", include_bytes!("../../tests/images/gray.jpg")),
+        fixture(
+            "progressive_420",
+            include_bytes!("../../tests/images/progressive_420.jpg"),
+        ),
+        fixture(
+            "restart_420",
+            include_bytes!("../../tests/images/restart_420.jpg"),
+        ),
+    ]
+}
+
+// WIDTH and HEIGHT are the dimensions of the generated images
+pub const WIDTH: usize = 1024;
+pub const HEIGHT: usize = 768;
+
+// generated_images encodes photo sized images with the common layouts: baseline 4:2:0 and 4:4:4, progressive,
+// restart intervals and grayscale
+pub fn generated_images() -> Vec<Fixture> {
+    let pixels = photo_pixels(WIDTH, HEIGHT);
+    let encode = |name, options: EncodeOptions| {
+        let color_type = if options.subsampling == ChromaSubsampling::Gray {
+            ColorType::Gray
+        } else {
+            ColorType::Rgb
+        };
+        let pixels: Vec<u8> = match color_type {
+            ColorType::Gray => pixels.chunks(3).map(|pixel| pixel[1]).collect(),
+            ColorType::Rgb => pixels.clone(),
+        };
+        let file =
+            encode_image_with_options(&pixels, WIDTH as u64, HEIGHT as u64, color_type, &options)
+                .unwrap();
+        Fixture { name, file }
+    };
+    vec![
+        encode("photo_420", EncodeOptions::default()),
+        encode(
+            "photo_444",
+            EncodeOptions {
+                subsampling: ChromaSubsampling::Chroma444,
+                ..EncodeOptions::default()
+            },
+        ),
+        encode(
+            "photo_progressive",
+            EncodeOptions {
+                progressive: true,
+                ..EncodeOptions::default()
+            },
+        ),
+        encode(
+            "photo_restart",
+            EncodeOptions {
+                restart_interval: 16,
+                ..EncodeOptions::default()
+            },
+        ),
+        encode(
+            "photo_gray",
+            EncodeOptions {
+                subsampling: ChromaSubsampling::Gray,
+                ..EncodeOptions::default()
+            },
+        ),
+    ]
+}
+
+// photo_pixels returns RGB pixels of smooth gradients overlaid with noise, which code into about as many bits
+// per pixel as photos do at the default quality. The noise comes from a fixed linear congruential generator.
+pub fn photo_pixels(width: usize, height: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491u32;
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let noise = (state >> 24) as i32 / 8 - 16;
+            for base in [
+                x * 255 / width,
+                y * 255 / height,
+                (x + y) * 255 / (width + height),
+            ] {
+                pixels.push((base as i32 + noise).clamp(0, 255) as u8);
+            }
+        }
+    }
+    pixels
+}
@@ -0,0 +1,9 @@
+// decode feeds raw bytes through the whole decoder
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rsjpeg_fuzz::decode_options;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = rsjpeg::decode_image_with_options(data, &decode_options());
+});
@@ -0,0 +1,26 @@
+// huffman builds a table from the first bytes and decodes the remaining bytes with it. The first byte selects
+// the class, the next 16 are the code counts per length followed by the symbols.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rsjpeg::{DHTType, HuffmanTable};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 17 {
+        return;
+    }
+    let class = DHTType::from_selector(data[0] & 1, data[0] >> 1 & 1).unwrap();
+    let mut counts = [0u8; 16];
+    let mut remaining = &data[17..];
+    for (count, &wanted) in counts.iter_mut().zip(&data[1..17]) {
+        *count = wanted.min(remaining.len().min(255) as u8);
+        remaining = &remaining[usize::from(*count)..];
+    }
+    let values = data[17..data.len() - remaining.len()].to_vec();
+    let table = HuffmanTable {
+        class,
+        counts,
+        values,
+    };
+    let _ = table.huffman_decode(remaining);
+});
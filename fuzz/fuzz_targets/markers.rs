@@ -0,0 +1,14 @@
+// markers parses raw bytes into markers and checks that writing them back yields the same markers
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rsjpeg::{decode, encode_markers, read_info};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = read_info(data);
+    if let Ok(markers) = decode(data) {
+        if let Ok(written) = encode_markers(&markers) {
+            assert_eq!(decode(&written).unwrap(), markers);
+        }
+    }
+});
@@ -0,0 +1,15 @@
+// structured decodes files generated by FuzzJpeg and re-encodes the coefficients of those that decode
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rsjpeg::{decode_image_with_options, optimize_jpeg, read_coefficients};
+use rsjpeg_fuzz::{decode_options, FuzzJpeg};
+
+fuzz_target!(|jpeg: FuzzJpeg| {
+    let file = jpeg.to_bytes();
+    if decode_image_with_options(&file, &decode_options()).is_ok()
+        && read_coefficients(&file, &decode_options()).is_ok()
+    {
+        let _ = optimize_jpeg(&file);
+    }
+});
@@ -0,0 +1,203 @@
+// Structured inputs for the fuzz targets. FuzzJpeg is built from arbitrary data and written as a file made of
+// well formed segments whose content is odd: tables the scans don't reference, sampling factors no encoder
+// writes, spectral selections out of order and entropy coded data of random bits. Raw byte fuzzing rarely gets
+// past the segment parser, these files reach the decoder itself.
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use rsjpeg::{
+    encode_markers, ColorComponentMetaData, DHTType, DecodeOptions, HuffmanTable, ImageStream,
+    Limits, Marker, QuantizationTable, ScanComponentMetaData, SomeMarker, StartOfFrameMetaData,
+    StartOfStreamMetaData,
+};
+
+#[derive(Debug, Arbitrary)]
+pub struct FuzzJpeg {
+    // process selects baseline, extended sequential or progressive
+    pub process: u8,
+    pub width: u16,
+    pub height: u16,
+    pub components: Vec<FuzzComponent>,
+    pub quantization_tables: Vec<FuzzQuantizationTable>,
+    pub huffman_tables: Vec<FuzzHuffmanTable>,
+    pub restart_interval: Option<u16>,
+    pub scans: Vec<FuzzScan>,
+    pub segments: Vec<FuzzSegment>,
+}
+
+#[derive(Debug, Arbitrary)]
+pub struct FuzzComponent {
+    pub id: u8,
+    pub horizontal_sampling: u8,
+    pub vertical_sampling: u8,
+    pub quantization_table: u8,
+}
+
+#[derive(Debug, Arbitrary)]
+pub struct FuzzQuantizationTable {
+    pub id: u8,
+    pub entries: [u8; 64],
+}
+
+#[derive(Debug, Arbitrary)]
+pub struct FuzzHuffmanTable {
+    pub ac: bool,
+    // id is taken modulo 2, the decoder supports the two table destinations of baseline files
+    pub id: u8,
+    // counts and symbols are the raw DHT content, counts are cut down to the first 256 symbols
+    pub counts: [u8; 16],
+    pub symbols: Vec<u8>,
+}
+
+#[derive(Debug, Arbitrary)]
+pub struct FuzzScan {
+    // components are indices into the frame components, wrapped around
+    pub components: Vec<FuzzScanComponent>,
+    pub spectral_start: u8,
+    pub spectral_end: u8,
+    pub approximation: u8,
+    // data is the entropy coded data before byte stuffing
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Arbitrary)]
+pub struct FuzzScanComponent {
+    pub component: u8,
+    pub dc_table: u8,
+    pub ac_table: u8,
+}
+
+// FuzzSegment is an APPn or COM segment with arbitrary content
+#[derive(Debug, Arbitrary)]
+pub struct FuzzSegment {
+    pub tag: u8,
+    pub data: Vec<u8>,
+}
+
+impl FuzzJpeg {
+    // to_bytes writes the file, dimensions are capped at 512 pixels to keep every input fast
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let components: Vec<ColorComponentMetaData> = self
+            .components
+            .iter()
+            .take(4)
+            .map(|component| ColorComponentMetaData {
+                id: u64::from(component.id),
+                sampling_resolution: u64::from(component.horizontal_sampling % 4 + 1) << 4
+                    | u64::from(component.vertical_sampling % 4 + 1),
+                quantization_table: u64::from(component.quantization_table % 4),
+            })
+            .collect();
+        if components.is_empty() {
+            return Vec::new();
+        }
+        let frame = StartOfFrameMetaData {
+            marker: 0xc0 + self.process % 3,
+            precision: 8,
+            height: u64::from(self.height % 512 + 1),
+            width: u64::from(self.width % 512 + 1),
+            component_metadata: components.clone(),
+        };
+
+        let stuffed: Vec<Vec<u8>> = self.scans.iter().map(|scan| stuff(&scan.data)).collect();
+        let mut markers = Vec::new();
+        for segment in self.segments.iter().take(8) {
+            let tag = match segment.tag % 17 {
+                16 => 0xfe,
+                n => 0xe0 + n,
+            };
+            markers.push(Marker::Other(SomeMarker {
+                tag,
+                length: 0,
+                data: &segment.data[..segment.data.len().min(0xfffd)],
+            }));
+        }
+        for table in self.quantization_tables.iter().take(4) {
+            markers.push(Marker::DQT(vec![QuantizationTable {
+                id: u64::from(table.id % 4),
+                precision: 0,
+                data: &table.entries,
+            }]));
+        }
+        let tables: Vec<HuffmanTable> = self
+            .huffman_tables
+            .iter()
+            .take(8)
+            .map(FuzzHuffmanTable::table)
+            .collect();
+        if !tables.is_empty() {
+            markers.push(Marker::DHT(tables));
+        }
+        if let Some(interval) = self.restart_interval {
+            markers.push(Marker::DRI(interval));
+        }
+        markers.push(Marker::SOF(frame));
+        for (scan, data) in self.scans.iter().zip(&stuffed).take(16) {
+            let component_metadata: Vec<ScanComponentMetaData> = scan
+                .components
+                .iter()
+                .take(4)
+                .map(|component| ScanComponentMetaData {
+                    id: components[usize::from(component.component) % components.len()].id,
+                    dc_table: u64::from(component.dc_table % 4),
+                    ac_table: u64::from(component.ac_table % 4),
+                })
+                .collect();
+            if component_metadata.is_empty() {
+                continue;
+            }
+            markers.push(Marker::Image(ImageStream {
+                metadata: StartOfStreamMetaData {
+                    component_metadata,
+                    spectral_start: u64::from(scan.spectral_start % 64),
+                    spectral_end: u64::from(scan.spectral_end % 64),
+                    approximation_high: u64::from(scan.approximation >> 4),
+                    approximation_low: u64::from(scan.approximation & 0x0f),
+                },
+                data,
+            }));
+        }
+        // every field is in range, so writing can't fail
+        encode_markers(&markers).unwrap_or_default()
+    }
+}
+
+impl FuzzHuffmanTable {
+    fn table(&self) -> HuffmanTable {
+        let class = DHTType::from_selector(self.ac as u8, self.id % 4).unwrap();
+        let mut counts = [0u8; 16];
+        let mut remaining = self.symbols.len().min(256);
+        for (count, &wanted) in counts.iter_mut().zip(&self.counts) {
+            *count = wanted.min(remaining.min(255) as u8);
+            remaining -= usize::from(*count);
+        }
+        let values = self.symbols[..self.symbols.len().min(256) - remaining].to_vec();
+        HuffmanTable {
+            class,
+            counts,
+            values,
+        }
+    }
+}
+
+// stuff inserts a zero byte after every 0xff, so the data can't end the scan early
+fn stuff(data: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(data.len() + data.len() / 64);
+    for &byte in data {
+        stuffed.push(byte);
+        if byte == 0xff {
+            stuffed.push(0);
+        }
+    }
+    stuffed
+}
+
+// decode_options bounds the memory a fuzz input can make the decoder allocate
+pub fn decode_options() -> DecodeOptions {
+    DecodeOptions {
+        limits: Limits {
+            max_width: Some(4096),
+            max_height: Some(4096),
+            max_memory: Some(256 << 20),
+        },
+        ..DecodeOptions::default()
+    }
+}
@@ -0,0 +1,245 @@
+// rsjpeg-decode decodes a JPEG file into a netpbm or PNG file, for checking decoded images by eye or comparing
+// them with image diff tools. Run it as
+//
+//     cargo run --bin rsjpeg-decode -- [--gray] [--stored] INPUT OUTPUT
+//
+// The extension of OUTPUT selects the format: .ppm, .pgm and .pnm write binary PPM or PGM, .pam writes PAM and
+// .png writes PNG if the png feature is enabled. An OUTPUT of - writes PPM or PGM to stdout.
+extern crate rsjpeg;
+
+#[cfg(feature = "png")]
+extern crate miniz_oxide;
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+
+use rsjpeg::{decode_image_with_options, ColorType, DecodeOptions, DecodedImage};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+    Pnm,
+    Pam,
+    Png,
+}
+
+fn main() {
+    let mut options = DecodeOptions::default();
+    let mut paths = Vec::new();
+    for argument in env::args().skip(1) {
+        match argument.as_str() {
+            "--gray" => options.color_type = Some(ColorType::Gray),
+            // --stored keeps the pixels as stored instead of applying the EXIF orientation
+            "--stored" => options.apply_orientation = false,
+            // --skip-corrupt leaves restart intervals that don't decode gray instead of failing
+            "--skip-corrupt" => options.skip_corrupt_intervals = true,
+            _ if argument.starts_with("--") => usage(),
+            _ => paths.push(argument),
+        }
+    }
+    if paths.len() != 2 {
+        usage();
+    }
+
+    if let Err(e) = run(&paths[0], &paths[1], &options) {
+        eprintln!("rsjpeg-decode: {}", e);
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: rsjpeg-decode [--gray] [--stored] [--skip-corrupt] INPUT OUTPUT");
+    process::exit(2);
+}
+
+fn run(input: &str, output: &str, options: &DecodeOptions) -> Result<(), String> {
+    let format = if output == "-" {
+        Format::Pnm
+    } else {
+        format(output)?
+    };
+    let jpeg_file = fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let image =
+        decode_image_with_options(&jpeg_file, options).map_err(|e| format!("{}: {}", input, e))?;
+    if let Some(truncation) = image.truncation {
+        eprintln!(
+            "{}: truncated, decoded {} of {} MCUs",
+            input, truncation.decoded_mcus, truncation.total_mcus
+        );
+    }
+    if !image.lost_mcus.is_empty() {
+        let lost: usize = image.lost_mcus.iter().map(|mcus| mcus.len()).sum();
+        eprintln!("{}: corrupt, lost {} MCUs", input, lost);
+    }
+
+    let encoded = encode(&image, format)?;
+    if output == "-" {
+        io::stdout().write_all(&encoded)
+    } else {
+        fs::write(output, &encoded)
+    }
+    .map_err(|e| format!("{}: {}", output, e))
+}
+
+fn format(path: &str) -> Result<Format, String> {
+    let extension = path.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "ppm" | "pgm" | "pnm" => Ok(Format::Pnm),
+        "pam" => Ok(Format::Pam),
+        "png" => Ok(Format::Png),
+        _ => Err(format!(
+            "{}: unknown output format, use .ppm, .pgm, .pam or .png",
+            path
+        )),
+    }
+}
+
+fn encode(image: &DecodedImage, format: Format) -> Result<Vec<u8>, String> {
+    let (channels, pnm, tuple_type) = match image.color_type {
+        ColorType::Gray => (1, "P5", "GRAYSCALE"),
+        ColorType::Rgb => (3, "P6", "RGB"),
+    };
+    let header = match format {
+        Format::Pnm => format!("{}\n{} {}\n255\n", pnm, image.width, image.height),
+        Format::Pam => format!(
+            "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL 255\nTUPLTYPE {}\nENDHDR\n",
+            image.width, image.height, channels, tuple_type
+        ),
+        Format::Png => return png(image, channels),
+    };
+    Ok([header.as_bytes(), &image.pixels].concat())
+}
+
+#[cfg(not(feature = "png"))]
+fn png(_image: &DecodedImage, _channels: usize) -> Result<Vec<u8>, String> {
+    Err("PNG output needs the png feature".to_string())
+}
+
+// png writes 8 bit gray or RGB pixels without filtering the lines, which compress well enough for test images
+#[cfg(feature = "png")]
+fn png(image: &DecodedImage, channels: usize) -> Result<Vec<u8>, String> {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    if image.width > 0x7fff_ffff || image.height > 0x7fff_ffff {
+        return Err("image too large for PNG".to_string());
+    }
+    let line = image.width as usize * channels;
+    let mut lines = Vec::with_capacity((line + 1) * image.height as usize);
+    for pixels in image.pixels.chunks(line) {
+        lines.push(0);
+        lines.extend_from_slice(pixels);
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(image.width as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height as u32).to_be_bytes());
+    let color_type = if channels == 1 { 0 } else { 2 };
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+    let mut output = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut output, b"IHDR", &header);
+    write_chunk(&mut output, b"IDAT", &compress_to_vec_zlib(&lines, 6));
+    write_chunk(&mut output, b"IEND", &[]);
+    Ok(output)
+}
+
+#[cfg(feature = "png")]
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = output.len();
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+    let crc = crc32(&output[start..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+// crc32 is the CRC of ISO 3309 that PNG chunks end with, computed bit by bit as the chunks are small
+#[cfg(feature = "png")]
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rsjpeg::PixelFormat;
+
+    fn image(color_type: ColorType, pixels: Vec<u8>) -> DecodedImage {
+        DecodedImage {
+            width: 2,
+            height: 1,
+            color_type,
+            pixel_format: match color_type {
+                ColorType::Gray => PixelFormat::Gray8,
+                ColorType::Rgb => PixelFormat::Rgb8,
+            },
+            pixels,
+            truncation: None,
+            lost_mcus: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn encode_test() {
+        let rgb = image(ColorType::Rgb, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            encode(&rgb, Format::Pnm).unwrap(),
+            b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06".to_vec()
+        );
+        let gray = image(ColorType::Gray, vec![7, 8]);
+        assert_eq!(
+            encode(&gray, Format::Pam).unwrap(),
+            b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\x07\x08"
+                .to_vec()
+        );
+
+        assert_eq!(format("out.PGM"), Ok(Format::Pnm));
+        assert_eq!(format("out.png"), Ok(Format::Png));
+        assert!(format("out.bmp").is_err());
+        assert!(format("out").is_err());
+    }
+
+    #[cfg(not(feature = "png"))]
+    #[test]
+    fn png_feature_test() {
+        let gray = image(ColorType::Gray, vec![7, 8]);
+        assert_eq!(
+            encode(&gray, Format::Png).unwrap_err(),
+            "PNG output needs the png feature"
+        );
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png_test() {
+        use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+        let rgb = image(ColorType::Rgb, vec![1, 2, 3, 4, 5, 6]);
+        let png = encode(&rgb, Format::Png).unwrap();
+        assert!(png.starts_with(
+            b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x02\x00\x00\x00\x01\x08\x02"
+        ));
+        // every PNG ends with the same IEND chunk
+        assert!(png.ends_with(b"\x00\x00\x00\x00IEND\xae\x42\x60\x82"));
+
+        let length = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let lines = decompress_to_vec_zlib(&png[41..41 + length]).unwrap();
+        assert_eq!(lines, vec![0, 1, 2, 3, 4, 5, 6]);
+        let crc = crc32(&png[37..41 + length]);
+        assert_eq!(png[41 + length..45 + length], crc.to_be_bytes());
+    }
+}
@@ -0,0 +1,192 @@
+// rsjpeg-info prints every marker of JPEG files with its offset, length and parsed content, for debugging files
+// the library rejects or decodes unexpectedly. --codes adds the code of every Huffman symbol. Run it as
+//
+//     cargo run --bin rsjpeg-info -- [--codes] FILE...
+extern crate rsjpeg;
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::process;
+
+use rsjpeg::{
+    decode_spanned, read_icc_profile, read_mpf, read_orientation, read_thumbnail, Error,
+    ParseOptions, Span, Thumbnail,
+};
+
+fn main() {
+    let (flags, paths): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let codes = flags.iter().any(|flag| flag == "--codes");
+    if paths.is_empty() || flags.len() > usize::from(codes) {
+        eprintln!("usage: rsjpeg-info [--codes] FILE...");
+        process::exit(2);
+    }
+
+    let mut failed = false;
+    for path in &paths {
+        if paths.len() > 1 {
+            println!("{}:", path);
+        }
+        let described = fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| describe(&file, codes).map_err(|e| e.to_string()));
+        match described {
+            Ok(description) => print!("{}", description),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+// describe lists the markers of jpeg_file with their offset and length, followed by a summary of its EXIF, ICC
+// and MPF metadata. codes lists the codes of the symbols of Huffman tables.
+fn describe(jpeg_file: &[u8], codes: bool) -> Result<String, Error> {
+    let markers = decode_spanned(jpeg_file, &ParseOptions::default())?;
+    let mut output = String::new();
+    writeln!(output, "  offset length  marker").unwrap();
+    write_entry(
+        &mut output,
+        Some(Span {
+            offset: 0,
+            length: 2,
+        }),
+        "SOI",
+    );
+    for spanned in &markers {
+        // standard Huffman tables inserted in front of scans have an empty span
+        let span = Some(spanned.span).filter(|span| span.length > 0);
+        let summary = if codes {
+            format!("{:#}", spanned.marker.summarize())
+        } else {
+            spanned.marker.summarize().to_string()
+        };
+        write_entry(&mut output, span, &summary);
+    }
+    let end = markers.last().map_or(2, |spanned| spanned.span.end());
+    if jpeg_file[end..].starts_with(b"\xff\xd9") {
+        write_entry(
+            &mut output,
+            Some(Span {
+                offset: end,
+                length: 2,
+            }),
+            "EOI",
+        );
+        if jpeg_file.len() > end + 2 {
+            writeln!(output, "{} bytes after EOI", jpeg_file.len() - end - 2).unwrap();
+        }
+    } else {
+        writeln!(output, "no EOI").unwrap();
+    }
+
+    describe_metadata(&mut output, jpeg_file);
+    Ok(output)
+}
+
+// write_entry writes the lines of a marker summary behind the columns of its span, which is missing for markers
+// that aren't part of the file
+fn write_entry(output: &mut String, span: Option<Span>, summary: &str) {
+    for (index, line) in summary.lines().enumerate() {
+        match span {
+            Some(span) if index == 0 => {
+                writeln!(output, "{:8} {:6}  {}", span.offset, span.length, line).unwrap()
+            }
+            None if index == 0 => writeln!(output, "{:>8} {:>6}  {}", "-", "-", line).unwrap(),
+            _ => writeln!(output, "{:17}{}", "", line).unwrap(),
+        }
+    }
+}
+
+fn describe_metadata(output: &mut String, jpeg_file: &[u8]) {
+    if let Some(orientation) = read_orientation(jpeg_file) {
+        writeln!(output, "EXIF orientation: {:?}", orientation).unwrap();
+    }
+    match read_thumbnail(jpeg_file) {
+        Some(Thumbnail::Jpeg(thumbnail)) => {
+            writeln!(output, "thumbnail: JPEG of {} bytes", thumbnail.len()).unwrap();
+        }
+        Some(Thumbnail::Rgb(thumbnail)) => {
+            writeln!(
+                output,
+                "thumbnail: RGB of {}x{} pixels",
+                thumbnail.width, thumbnail.height
+            )
+            .unwrap();
+        }
+        None => {}
+    }
+    if let Some(profile) = read_icc_profile(jpeg_file) {
+        writeln!(output, "ICC profile: {} bytes", profile.len()).unwrap();
+    }
+    if let Ok(Some(index)) = read_mpf(jpeg_file) {
+        writeln!(output, "MPF: {} images", index.images.len()).unwrap();
+        for image in &index.images {
+            writeln!(
+                output,
+                "  {:?} at {}, {} bytes",
+                image.image_type(),
+                image.offset,
+                image.length
+            )
+            .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rsjpeg::{encode_image_with_options, ColorType, EncodeOptions};
+
+    #[test]
+    fn describe_test() {
+        let options = EncodeOptions {
+            comments: vec![b"hello".to_vec()],
+            icc_profile: Some(vec![1; 20]),
+            restart_interval: 1,
+            ..EncodeOptions::default()
+        };
+        let file =
+            encode_image_with_options(&[100; 16 * 8 * 3], 16, 8, ColorType::Rgb, &options).unwrap();
+        let description = describe(&file, false).unwrap();
+        assert!(description.starts_with(concat!(
+            "  offset length  marker\n",
+            "       0      2  SOI\n",
+            "       2     18  APP0: JFIF, 14 bytes\n",
+        )));
+        assert!(description.contains("  COM: \"hello\", 5 bytes\n"));
+        assert!(description.contains("  SOF0: baseline, 8 bit, 16x8 pixels, 3 components\n"));
+        // lines following the first of a summary are indented past the offset and length
+        assert!(description.contains(&format!(
+            "\n{:17}  component 1: 2x2 sampling, quantization table 0\n",
+            ""
+        )));
+        assert!(description.contains("  DRI: restart interval of 1 MCUs\n"));
+        assert!(description.contains(&format!(
+            "\n{:17}    codes    0   1   5   1   1   1   1   1   1   0   0   0   0   0   0   0\n",
+            ""
+        )));
+        assert!(
+            description.contains("  SOS: coefficients 0 to 63, successive approximation 0 to 0\n")
+        );
+        // the standard luminance table at quality 75 starts with 8 6 5 in its first row
+        assert!(description.contains("    8    6    5    8   12   20   26   31\n"));
+        assert!(description.ends_with(&format!(
+            "{:8}      2  EOI\nICC profile: 20 bytes\n",
+            file.len() - 2
+        )));
+
+        let codes = describe(&file, true).unwrap();
+        assert!(codes.contains(&format!("\n{:17}    symbol 0x00: 00\n", "")));
+        assert!(!description.contains("symbol"));
+
+        assert!(describe(b"\xff\xd8\xff", false).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+// Reading of entropy coded data bit by bit, most significant bit first, as ITU T.81 F.2.2.5 reads it.
+
+// BitReader reads the bits of entropy coded data through a 64 bit accumulator, which is refilled with whole bytes
+// when a read needs more bits than it holds. It removes the zero bytes stuffed after 0xFF bytes and stops in front
+// of the first marker, so reads past the end of a scan or restart interval fail instead of returning the bits of
+// the marker.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    // position is the offset of the next byte to load into the accumulator
+    position: usize,
+    // accumulator holds the loaded bits that weren't read yet in its high bits, the other bits are zero
+    accumulator: u64,
+    loaded: usize,
+    read: usize,
+    stuffed: bool,
+    // exhausted is set once a peek asked for more bits than were left
+    exhausted: bool,
+}
+
+impl<'a> BitReader<'a> {
+    // new reads entropy coded data with stuffed bytes up to its first marker
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            position: 0,
+            accumulator: 0,
+            loaded: 0,
+            read: 0,
+            stuffed: true,
+            exhausted: false,
+        }
+    }
+
+    // unstuffed reads all bits of data as they are, for codes that aren't part of a file
+    pub fn unstuffed(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            stuffed: false,
+            ..BitReader::new(data)
+        }
+    }
+
+    // peek returns the next count bits, at most 32, as a number without reading them, or None if fewer are left
+    pub fn peek(&mut self, count: usize) -> Option<u32> {
+        if count == 0 {
+            return Some(0);
+        }
+        if self.loaded < count {
+            self.refill();
+            if self.loaded < count {
+                self.exhausted = true;
+                return None;
+            }
+        }
+        Some((self.accumulator >> (64 - count)) as u32)
+    }
+
+    // consume skips count bits that peek returned
+    pub fn consume(&mut self, count: usize) {
+        debug_assert!(count <= self.loaded);
+        self.accumulator = self.accumulator.checked_shl(count as u32).unwrap_or(0);
+        self.loaded -= count;
+        self.read += count;
+    }
+
+    // read returns the next count bits, at most 32, as a number, or None if fewer are left
+    pub fn read(&mut self, count: usize) -> Option<u32> {
+        let value = self.peek(count)?;
+        self.consume(count);
+        Some(value)
+    }
+
+    // receive reads size bits, at most 16, as an unsigned value, the RECEIVE procedure of ITU T.81 F.2.2.4
+    pub fn receive(&mut self, size: u8) -> Option<u32> {
+        if size > 16 {
+            return None;
+        }
+        self.read(size as usize)
+    }
+
+    // receive_extend reads the size bits following a DC difference or AC coefficient symbol and sign extends them
+    // into its value with the EXTEND procedure of ITU T.81 F.2.2.1. Values of size bits with the leading bit 0 are
+    // negative, so the values of every size cover the ranges of Table F.2.
+    pub fn receive_extend(&mut self, size: u8) -> Option<i32> {
+        if size == 0 {
+            return Some(0);
+        }
+
+        let mut value = self.receive(size)? as i32;
+        if value < 1 << (size - 1) {
+            value -= (1 << size) - 1;
+        }
+        Some(value)
+    }
+
+    // bits_read returns the number of bits read so far
+    pub fn bits_read(&self) -> usize {
+        self.read
+    }
+
+    // exhausted returns true once a read has failed for lack of bits, which a failing decode can't tell from
+    // invalid data until more data follows
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    // remaining returns the number of bits that are left to be read
+    pub fn remaining(&self) -> usize {
+        let mut bytes = 0;
+        let mut position = self.position;
+        while let Some(&byte) = self.data.get(position) {
+            if byte == 0xff && self.stuffed {
+                match self.data.get(position + 1) {
+                    Some(0x00) => position += 1,
+                    Some(_) => break,
+                    None => {}
+                }
+            }
+            position += 1;
+            bytes += 1;
+        }
+        self.loaded + bytes * 8
+    }
+
+    // refill loads whole bytes until the accumulator holds more than 56 bits or the data ends
+    fn refill(&mut self) {
+        while self.loaded <= 56 {
+            let byte = match self.data.get(self.position) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            if byte == 0xff && self.stuffed {
+                match self.data.get(self.position + 1) {
+                    Some(0x00) => self.position += 2,
+                    // a marker ends the data, a 0xFF byte at the end of truncated data is still read
+                    Some(_) => break,
+                    None => self.position += 1,
+                }
+            } else {
+                self.position += 1;
+            }
+            self.accumulator |= u64::from(byte) << (56 - self.loaded);
+            self.loaded += 8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_reader_test() {
+        let data = [
+            0b1010_0110,
+            0b0101_1100,
+            0b1111_0000,
+            0b0000_0001,
+            0b1000_0000,
+        ];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.peek(3), Some(0b101));
+        assert_eq!(reader.read(6), Some(0b10_1001));
+        assert_eq!(reader.read(0), Some(0));
+        assert_eq!(reader.read(6), Some(0b10_0101));
+        assert_eq!(reader.read(3), Some(0b110));
+        assert_eq!(reader.read(18), Some(0b01_1110_0000_0000_0011));
+        assert_eq!(reader.bits_read(), 33);
+        assert_eq!(reader.remaining(), 7);
+        assert_eq!(reader.bits_read(), 33);
+        assert!(!reader.exhausted());
+        assert_eq!(reader.read(8), None);
+        assert!(reader.exhausted());
+        assert_eq!(reader.read(7), Some(0));
+        assert_eq!((reader.remaining(), reader.peek(1)), (0, None));
+    }
+
+    #[test]
+    fn receive_extend_test() {
+        let data = vec![0b1011_0000, 0b0111_1111];
+        let mut reader = BitReader::new(&data);
+
+        assert_eq!(reader.receive_extend(1), Some(1));
+        assert_eq!(reader.receive_extend(3), Some(-4));
+        assert_eq!(reader.receive_extend(4), Some(-15));
+        assert_eq!(reader.receive_extend(0), Some(0));
+        assert_eq!(reader.receive_extend(8), Some(-128));
+        assert_eq!(reader.bits_read(), 16);
+        assert_eq!(reader.receive_extend(1), None);
+        assert_eq!(BitReader::new(&[0; 4]).receive(17), None);
+    }
+
+    #[test]
+    fn table_f2_test() {
+        // every bit pattern of every size, followed by a set bit to check that exactly size bits are read
+        for size in 0..=16u8 {
+            let mut values = Vec::new();
+            for bits in 0..1u32 << size {
+                let word = (bits << 1 | 1) << (31 - size);
+                let data = word.to_be_bytes();
+                let mut reader = BitReader::unstuffed(&data);
+                values.push(reader.receive_extend(size).unwrap());
+                assert_eq!(
+                    (reader.bits_read(), reader.read(1)),
+                    (size as usize, Some(1))
+                );
+                assert_eq!(BitReader::unstuffed(&data).receive(size), Some(bits));
+            }
+
+            // the negative values of Table F.2 come first, in increasing order, followed by the positive ones
+            let expected: Vec<i32> = if size == 0 {
+                vec![0]
+            } else {
+                let (low, high) = (1 << (size - 1), (1 << size) - 1);
+                (-high..=-low).chain(low..=high).collect()
+            };
+            assert_eq!(values, expected, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn stuffing_test() {
+        // zero bytes after 0xFF are stuffed, reading stops in front of the RST0 marker
+        let data = [0x12, 0xff, 0x00, 0xff, 0x00, 0x34, 0xff, 0xd0, 0x56];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.remaining(), 32);
+        assert_eq!(reader.read(4), Some(0x1));
+        assert_eq!(reader.read(24), Some(0x2f_fff3));
+        assert_eq!(reader.read(8), None);
+        assert_eq!(reader.read(4), Some(0x4));
+        assert_eq!(reader.peek(1), None);
+
+        // a 0xFF byte ending the data is read as data
+        let mut reader = BitReader::new(&[0x00, 0xff]);
+        assert_eq!(reader.read(16), Some(0x00ff));
+
+        let mut reader = BitReader::unstuffed(&data);
+        assert_eq!(reader.remaining(), 72);
+        assert_eq!(reader.read(32), Some(0x12ff_00ff));
+        assert_eq!(reader.read(24), Some(0x34ff));
+    }
+}
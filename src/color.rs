@@ -0,0 +1,279 @@
+// YCbCr and RGB conversions as defined by JFIF, using 16 bit fixed-point constants unless decoders ask for fewer.
+
+#[cfg(feature = "simd")]
+use simd;
+
+const SCALE_BITS: i32 = 16;
+const HALF: i32 = 1 << (SCALE_BITS - 1);
+
+const CR_TO_R: i32 = 91_881; // 1.402
+const CB_TO_G: i32 = 22_554; // 0.34414, rounded like libjpeg
+const CR_TO_G: i32 = 46_802; // 0.71414
+const CB_TO_B: i32 = 116_130; // 1.772
+
+const R_TO_Y: i32 = 19_595; // 0.299
+const G_TO_Y: i32 = 38_470; // 0.587
+const B_TO_Y: i32 = 7_471; // 0.114
+const R_TO_CB: i32 = 11_059; // 0.168736
+const G_TO_CB: i32 = 21_709; // 0.331264
+const G_TO_CR: i32 = 27_439; // 0.418688
+const B_TO_CR: i32 = 5_329; // 0.081312
+
+// ColorConstants holds the conversion from YCbCr to RGB with constants of bits fractional bits, from 8 to 16, as
+// tables of what every chroma sample adds to the luma like libjpeg's. Converting a pixel then takes lookups and
+// adds instead of multiplies and gives the same values.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ColorConstants {
+    bits: i32,
+    // cr_to_r and cb_to_b hold the rounded amounts Cr adds to R and Cb to B
+    cr_to_r: [i32; 256],
+    cb_to_b: [i32; 256],
+    // cb_to_g and cr_to_g hold the amounts Cb and Cr add to G before their sum is rounded by bits
+    cb_to_g: [i32; 256],
+    cr_to_g: [i32; 256],
+}
+
+// STANDARD_CONSTANTS holds the tables of the 16 bit constants ycbcr_to_rgb uses and the vector instructions
+// implement
+pub static STANDARD_CONSTANTS: ColorConstants =
+    ColorConstants::tables(SCALE_BITS, [CR_TO_R, CB_TO_G, CR_TO_G, CB_TO_B]);
+
+impl ColorConstants {
+    // new rounds the factors to bits fractional bits, clamped to 8 to 16
+    pub fn new(bits: u8) -> ColorConstants {
+        let bits = i32::from(bits.clamp(8, 16));
+        let scaled = |factor: f64| (factor * f64::from(1 << bits) + 0.5) as i32;
+        let factors = [
+            scaled(1.402),
+            scaled(0.34414),
+            scaled(0.71414),
+            scaled(1.772),
+        ];
+        ColorConstants::tables(bits, factors)
+    }
+
+    // tables builds the tables of the factors of Cr to R, Cb to G, Cr to G and Cb to B
+    const fn tables(bits: i32, factors: [i32; 4]) -> ColorConstants {
+        let half = 1 << (bits - 1);
+        let mut constants = ColorConstants {
+            bits,
+            cr_to_r: [0; 256],
+            cb_to_b: [0; 256],
+            cb_to_g: [0; 256],
+            cr_to_g: [0; 256],
+        };
+        let mut sample = 0;
+        while sample < 256 {
+            let chroma = sample as i32 - 128;
+            constants.cr_to_r[sample] = (factors[0] * chroma + half) >> bits;
+            constants.cb_to_g[sample] = half - factors[1] * chroma;
+            constants.cr_to_g[sample] = -factors[2] * chroma;
+            constants.cb_to_b[sample] = (factors[3] * chroma + half) >> bits;
+            sample += 1;
+        }
+        constants
+    }
+
+    fn convert(&self, y: u8, cb: u8, cr: u8) -> [u8; 3] {
+        let (y, cb, cr) = (i32::from(y), usize::from(cb), usize::from(cr));
+        let r = y + self.cr_to_r[cr];
+        let g = y + ((self.cb_to_g[cb] + self.cr_to_g[cr]) >> self.bits);
+        let b = y + self.cb_to_b[cb];
+
+        [clamp(r), clamp(g), clamp(b)]
+    }
+}
+
+pub fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    STANDARD_CONSTANTS.convert(y, cb, cr)
+}
+
+// ycbcr_to_rgb_row converts a row of pixels from their y, cb and cr samples into rgb with the given constants. The
+// standard constants use the vector instructions the processor supports if the simd feature is enabled.
+pub fn ycbcr_to_rgb_row(
+    y: &[u8],
+    cb: &[u8],
+    cr: &[u8],
+    rgb: &mut [[u8; 3]],
+    constants: &ColorConstants,
+) {
+    #[cfg(feature = "simd")]
+    let converted = if constants.bits == SCALE_BITS {
+        simd::ycbcr_to_rgb(y, cb, cr, rgb)
+    } else {
+        0
+    };
+    #[cfg(not(feature = "simd"))]
+    let converted = 0;
+
+    for (((pixel, &y), &cb), &cr) in rgb.iter_mut().zip(y).zip(cb).zip(cr).skip(converted) {
+        *pixel = constants.convert(y, cb, cr);
+    }
+}
+
+pub fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> [u8; 3] {
+    let ycc = to_ycbcr(i32::from(r), i32::from(g), i32::from(b), 128);
+    [clamp(ycc[0]), clamp(ycc[1]), clamp(ycc[2])]
+}
+
+// rgb_to_ycbcr_12 converts 12 bit samples, with the chroma centered at 2048
+pub fn rgb_to_ycbcr_12(r: u16, g: u16, b: u16) -> [u16; 3] {
+    let ycc = to_ycbcr(i32::from(r), i32::from(g), i32::from(b), 2048);
+    [
+        ycc[0].clamp(0, 4095) as u16,
+        ycc[1].clamp(0, 4095) as u16,
+        ycc[2].clamp(0, 4095) as u16,
+    ]
+}
+
+// to_ycbcr converts without clamping, center is the chroma value of gray
+fn to_ycbcr(r: i32, g: i32, b: i32, center: i32) -> [i32; 3] {
+    let offset = center << SCALE_BITS;
+    let y = (R_TO_Y * r + G_TO_Y * g + B_TO_Y * b + HALF) >> SCALE_BITS;
+    let cb = (-R_TO_CB * r - G_TO_CB * g + (b << (SCALE_BITS - 1)) + offset + HALF) >> SCALE_BITS;
+    let cr = ((r << (SCALE_BITS - 1)) - G_TO_CR * g - B_TO_CR * b + offset + HALF) >> SCALE_BITS;
+    [y, cb, cr]
+}
+
+fn clamp(value: i32) -> u8 {
+    value.clamp(0, 255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ycbcr_to_rgb_test() {
+        assert_eq!(ycbcr_to_rgb(0, 128, 128), [0, 0, 0]);
+        assert_eq!(ycbcr_to_rgb(255, 128, 128), [255, 255, 255]);
+        assert_eq!(ycbcr_to_rgb(76, 85, 255), [254, 0, 0]);
+        assert_eq!(ycbcr_to_rgb(150, 44, 21), [0, 255, 1]);
+        assert_eq!(ycbcr_to_rgb(29, 255, 107), [0, 0, 254]);
+    }
+
+    #[test]
+    fn ycbcr_to_rgb_row_test() {
+        // rows of any length convert like single pixels, including the extremes of every sample
+        let mut state = 0x2545_f491u32;
+        let mut samples: Vec<[u8; 3]> = (0..4099)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                [(state >> 24) as u8, (state >> 16) as u8, (state >> 8) as u8]
+            })
+            .collect();
+        for (i, extremes) in samples.iter_mut().take(8).enumerate() {
+            *extremes = [
+                if i & 1 == 0 { 0 } else { 255 },
+                if i & 2 == 0 { 0 } else { 255 },
+                if i & 4 == 0 { 0 } else { 255 },
+            ];
+        }
+        let plane = |component: usize| -> Vec<u8> {
+            samples.iter().map(|pixel| pixel[component]).collect()
+        };
+        let (y, cb, cr) = (plane(0), plane(1), plane(2));
+        for &length in &[0, 5, 8, 17, samples.len()] {
+            let mut rgb = vec![[0u8; 3]; length];
+            ycbcr_to_rgb_row(
+                &y[..length],
+                &cb[..length],
+                &cr[..length],
+                &mut rgb,
+                &STANDARD_CONSTANTS,
+            );
+            for (pixel, ycbcr) in rgb.iter().zip(&samples) {
+                assert_eq!(*pixel, ycbcr_to_rgb(ycbcr[0], ycbcr[1], ycbcr[2]));
+            }
+        }
+    }
+
+    #[test]
+    fn tables_test() {
+        // the tables give the values of converting with multiplies
+        let multiplied = |y: u8, cb: u8, cr: u8| -> [u8; 3] {
+            let y = i32::from(y) << SCALE_BITS;
+            let cb = i32::from(cb) - 128;
+            let cr = i32::from(cr) - 128;
+            let r = (y + CR_TO_R * cr + HALF) >> SCALE_BITS;
+            let g = (y - CB_TO_G * cb - CR_TO_G * cr + HALF) >> SCALE_BITS;
+            let b = (y + CB_TO_B * cb + HALF) >> SCALE_BITS;
+            [clamp(r), clamp(g), clamp(b)]
+        };
+        for &y in &[0, 1, 60, 128, 201, 254, 255] {
+            for cb in 0..=255 {
+                for cr in 0..=255 {
+                    assert_eq!(ycbcr_to_rgb(y, cb, cr), multiplied(y, cb, cr));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn color_constants_test() {
+        assert_eq!(ColorConstants::new(16), STANDARD_CONSTANTS);
+        assert_eq!(ColorConstants::new(0), ColorConstants::new(8));
+
+        let exact = |y: u8, cb: u8, cr: u8| -> [f64; 3] {
+            let (y, cb, cr) = (f64::from(y), f64::from(cb) - 128.0, f64::from(cr) - 128.0);
+            [
+                y + 1.402 * cr,
+                y - 0.344136 * cb - 0.714136 * cr,
+                y + 1.772 * cb,
+            ]
+        };
+        // the largest difference from the exact conversion for every number of bits
+        let mut errors = [0f64; 17];
+        let constants: Vec<ColorConstants> = (0..=16u8).map(ColorConstants::new).collect();
+        for y in (0..=255).step_by(3) {
+            for cb in (0..=255).step_by(5) {
+                for cr in (0..=255).step_by(5) {
+                    let expected = exact(y, cb, cr);
+                    for bits in 8..=16u8 {
+                        let rgb = constants[usize::from(bits)].convert(y, cb, cr);
+                        for (&converted, &expected) in rgb.iter().zip(&expected) {
+                            let error = (f64::from(converted) - expected.clamp(0.0, 255.0)).abs();
+                            let largest = &mut errors[usize::from(bits)];
+                            *largest = largest.max(error);
+                        }
+                    }
+                }
+            }
+        }
+        // the rounding of the output takes 0.5 of the error
+        assert!(errors[8] < 0.64);
+        assert!(errors[13..].iter().all(|&error| error < 0.51));
+        assert!(errors[8..].windows(2).all(|pair| pair[0] >= pair[1]));
+    }
+
+    #[test]
+    fn rgb_to_ycbcr_test() {
+        assert_eq!(rgb_to_ycbcr(0, 0, 0), [0, 128, 128]);
+        assert_eq!(rgb_to_ycbcr(255, 255, 255), [255, 128, 128]);
+        assert_eq!(rgb_to_ycbcr(255, 0, 0), [76, 85, 255]);
+
+        for &rgb in &[[12, 200, 99], [250, 3, 128], [64, 64, 200]] {
+            let [y, cb, cr] = rgb_to_ycbcr(rgb[0], rgb[1], rgb[2]);
+            for (converted, original) in ycbcr_to_rgb(y, cb, cr).iter().zip(&rgb) {
+                assert!((i32::from(*converted) - i32::from(*original)).abs() <= 2);
+            }
+        }
+    }
+
+    #[test]
+    fn rgb_to_ycbcr_12_test() {
+        assert_eq!(rgb_to_ycbcr_12(0, 0, 0), [0, 2048, 2048]);
+        assert_eq!(rgb_to_ycbcr_12(4095, 4095, 4095), [4095, 2048, 2048]);
+        // 12 bit samples convert like 8 bit samples with 4 more bits of precision
+        for &rgb in &[[255u8, 0, 0], [12, 200, 99], [250, 3, 128]] {
+            let wide = rgb_to_ycbcr_12(
+                u16::from(rgb[0]) << 4,
+                u16::from(rgb[1]) << 4,
+                u16::from(rgb[2]) << 4,
+            );
+            for (wide, narrow) in wide.iter().zip(&rgb_to_ycbcr(rgb[0], rgb[1], rgb[2])) {
+                assert!((i32::from(*wide) - (i32::from(*narrow) << 4)).abs() <= 8);
+            }
+        }
+    }
+}
@@ -0,0 +1,3732 @@
+#[cfg(not(feature = "std"))]
+use prelude::*;
+#[cfg(feature = "std")]
+use std::io::Read;
+
+use core::mem;
+use core::ops::Range;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use bitreader::BitReader;
+use color::{ycbcr_to_rgb_row, ColorConstants};
+use errors::{Error, ErrorContext, ParserError, Stage};
+use exif::{exif_orientation, Orientation};
+#[cfg(feature = "std")]
+use huffman::HuffmanTable;
+use huffman::{decode_symbol, DHTType, HuffmanDecoder, TableClass, TableSet};
+#[cfg(feature = "std")]
+use icc::{assemble_profile, icc_chunk, IccTransform};
+use idct::{idct_block, idct_block_float, idct_block_reference, FixedPointCosines};
+#[cfg(feature = "std")]
+use parser::{check_length, parse_scan_header_at, parse_segment_at, referenced_huffman_tables};
+use parser::{
+    decode_spanned, ColorComponentMetaData, Marker, ParseOptions, StartOfFrameMetaData,
+    StartOfStreamMetaData,
+};
+use rows::{for_each_row, for_each_row_init};
+use stats::{DecodeStats, Stopwatch};
+#[cfg(feature = "std")]
+use stream::StreamReader;
+use upsample::{upsample, SampledPlane, Upsampling};
+
+// ZIGZAG maps the position of a coefficient in the entropy coded stream to its natural (row major) position
+pub const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ColorType {
+    Gray,
+    Rgb,
+}
+
+// Truncation describes how much of the scan could be decoded before the entropy coded data ran out
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Truncation {
+    pub decoded_mcus: u64,
+    pub total_mcus: u64,
+}
+
+// PixelFormat is the memory layout of decoded pixels
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PixelFormat {
+    Gray8,
+    Rgb8,
+    // Rgba8 and Bgra8 pad every pixel with an opaque alpha byte
+    Rgba8,
+    Bgr8,
+    Bgra8,
+    // Rgb565 packs every pixel into a little endian u16 with 5 bits red, 6 bits green and 5 bits blue
+    Rgb565,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct DecodedImage {
+    pub width: u64,
+    pub height: u64,
+    pub color_type: ColorType,
+    pub pixel_format: PixelFormat,
+    // pixels contains width * height pixels laid out according to pixel_format
+    pub pixels: Vec<u8>,
+    // truncation is set if the input ended before the image was complete, the missing part is gray
+    pub truncation: Option<Truncation>,
+    // lost_mcus lists the runs of MCUs in line order, before the EXIF orientation is applied, of restart
+    // intervals that didn't decode. They are gray like the missing part of a truncated image.
+    pub lost_mcus: Vec<Range<usize>>,
+}
+
+// ComponentPlane holds the samples of one component at its native resolution. samples contains stride bytes per
+// line, of which the first width are inside the image, for at least height lines.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ComponentPlane {
+    pub id: u64,
+    pub width: u64,
+    pub height: u64,
+    pub stride: usize,
+    pub samples: Vec<u8>,
+}
+
+// DecodedPlanes is a decoded image without color conversion, for YCbCr files the components are Y, Cb and Cr
+#[derive(Debug, Eq, PartialEq)]
+pub struct DecodedPlanes {
+    pub width: u64,
+    pub height: u64,
+    pub components: Vec<ComponentPlane>,
+    pub truncation: Option<Truncation>,
+    pub lost_mcus: Vec<Range<usize>>,
+}
+
+// ComponentCoefficients holds the DCT blocks of one component, line by line. Blocks are padded to whole MCUs, so
+// lines and columns at the end may lie outside of the image.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ComponentCoefficients {
+    pub id: u64,
+    pub horizontal_sampling: usize,
+    pub vertical_sampling: usize,
+    pub blocks_per_line: usize,
+    pub blocks_per_column: usize,
+    // blocks holds the quantized coefficients of every block in natural (row major) order
+    pub blocks: Vec<[i16; 64]>,
+    // quantization_table is the component's table in natural order
+    pub quantization_table: [u16; 64],
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct DecodedCoefficients {
+    pub width: u64,
+    pub height: u64,
+    pub components: Vec<ComponentCoefficients>,
+    pub truncation: Option<Truncation>,
+}
+
+// SalvagedImage is an image decoded from a corrupt file by salvage_image, along with the parts of it that were
+// lost
+#[derive(Debug, Eq, PartialEq)]
+pub struct SalvagedImage {
+    pub image: DecodedImage,
+    pub damage: DamageMap,
+}
+
+// DamageMap marks the MCUs of a salvaged image that couldn't be decoded. Their blocks are filled with the average
+// DC coefficient of the neighbouring blocks, which shows as flat patches blending into their surroundings.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DamageMap {
+    // mcu_width and mcu_height are the size of an MCU in pixels
+    pub mcu_width: u64,
+    pub mcu_height: u64,
+    pub mcus_per_line: usize,
+    pub mcus_per_column: usize,
+    // damaged holds one entry per MCU, line by line
+    pub damaged: Vec<bool>,
+}
+
+impl DamageMap {
+    pub fn damaged_mcus(&self) -> usize {
+        self.damaged.iter().filter(|&&damaged| damaged).count()
+    }
+
+    // damaged_ranges returns the runs of consecutive damaged MCUs in line order, like the restart intervals a
+    // scan lost
+    pub fn damaged_ranges(&self) -> Vec<Range<usize>> {
+        mcu_ranges(&self.damaged)
+    }
+
+    // is_damaged tells if the pixel at x, y is part of a damaged MCU
+    pub fn is_damaged(&self, x: u64, y: u64) -> bool {
+        let (mcu_x, mcu_y) = (
+            (x / self.mcu_width) as usize,
+            (y / self.mcu_height) as usize,
+        );
+        mcu_x < self.mcus_per_line
+            && mcu_y < self.mcus_per_column
+            && self.damaged[mcu_y * self.mcus_per_line + mcu_x]
+    }
+}
+
+impl ComponentCoefficients {
+    // block returns the quantized coefficients of the block in column x of block line y
+    pub fn block(&self, x: usize, y: usize) -> &[i16; 64] {
+        &self.blocks[y * self.blocks_per_line + x]
+    }
+
+    // dequantized returns the coefficients of the block in column x of block line y multiplied by the
+    // quantization table, as they are fed into the IDCT
+    pub fn dequantized(&self, x: usize, y: usize) -> [i32; 64] {
+        let block = self.block(x, y);
+        let mut dequantized = [0i32; 64];
+        for i in 0..64 {
+            dequantized[i] = i32::from(block[i]) * i32::from(self.quantization_table[i]);
+        }
+        dequantized
+    }
+}
+
+impl ComponentPlane {
+    // line returns the samples of line y inside the image
+    pub fn line(&self, y: usize) -> &[u8] {
+        &self.samples[y * self.stride..y * self.stride + self.width as usize]
+    }
+}
+
+impl ColorType {
+    pub fn channels(self) -> usize {
+        match self {
+            ColorType::Gray => 1,
+            ColorType::Rgb => 3,
+        }
+    }
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Gray8 => 1,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+        }
+    }
+
+    pub fn color_type(self) -> ColorType {
+        match self {
+            PixelFormat::Gray8 => ColorType::Gray,
+            _ => ColorType::Rgb,
+        }
+    }
+
+    // write stores a pixel of the given RGB color in the bytes_per_pixel bytes of pixel, gray formats use the red
+    // sample as luma
+    fn write(self, pixel: &mut [u8], [r, g, b]: [u8; 3]) {
+        match self {
+            PixelFormat::Gray8 => pixel[0] = r,
+            PixelFormat::Rgb8 => pixel.copy_from_slice(&[r, g, b]),
+            PixelFormat::Rgba8 => pixel.copy_from_slice(&[r, g, b, 255]),
+            PixelFormat::Bgr8 => pixel.copy_from_slice(&[b, g, r]),
+            PixelFormat::Bgra8 => pixel.copy_from_slice(&[b, g, r, 255]),
+            PixelFormat::Rgb565 => {
+                let packed =
+                    (u16::from(r) >> 3) << 11 | (u16::from(g) >> 2) << 5 | u16::from(b) >> 3;
+                pixel.copy_from_slice(&packed.to_le_bytes());
+            }
+        }
+    }
+}
+
+impl From<ColorType> for PixelFormat {
+    fn from(color_type: ColorType) -> PixelFormat {
+        match color_type {
+            ColorType::Gray => PixelFormat::Gray8,
+            ColorType::Rgb => PixelFormat::Rgb8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum IdctMethod {
+    // Integer uses fixed-point arithmetic with the constants of FixedPoint, 13 bits by default
+    #[default]
+    Integer,
+    // Float uses single precision floating point arithmetic, which is slower but slightly more accurate
+    Float,
+    // Reference computes the transform ITU T.81 defines in double precision, slowly but exactly and with the
+    // same samples on every machine
+    Reference,
+}
+
+// FixedPoint sets the fractional bits of the fixed-point constants of the integer IDCT, from 8 to 15, and of the
+// conversion from YCbCr to RGB, from 8 to 16, clamping values out of these ranges. Fewer bits keep the products
+// smaller, for processors with narrow multipliers, and cost accuracy. Samples of the integer IDCT differ from the
+// reference IDCT by at most 3 at 8 bits, where almost half of them are off, and by at most 1 from 9 bits on, about
+// one in twenty from 11 bits on. Pixels differ from the exact conversion by at most 0.64 at 8 bits and 0.51 from
+// 13 bits on, 0.5 of it from rounding.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FixedPoint {
+    pub idct_bits: u8,
+    pub color_bits: u8,
+}
+
+impl Default for FixedPoint {
+    fn default() -> FixedPoint {
+        FixedPoint {
+            idct_bits: 13,
+            color_bits: 16,
+        }
+    }
+}
+
+// Limits rejects frames exceeding the given dimensions before any sample buffers are allocated. By default frames
+// may take up to 512 MiB, like image-rs allows, callers decoding larger images raise max_memory.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+    // max_memory caps the bytes of coefficient, sample and output buffers the frame needs
+    pub max_memory: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_width: None,
+            max_height: None,
+            max_memory: Some(512 << 20),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DecodeOptions {
+    // color_type converts the output into the given color type, by default it follows the number of components
+    pub color_type: Option<ColorType>,
+    // pixel_format selects the output layout and takes precedence over color_type, by default it is Gray8 or Rgb8
+    // depending on the color type
+    pub pixel_format: Option<PixelFormat>,
+    pub upsampling: Upsampling,
+    pub idct: IdctMethod,
+    pub fixed_point: FixedPoint,
+    pub limits: Limits,
+    // apply_orientation rotates and mirrors the pixels as the EXIF orientation tag requests, swapping width and
+    // height for quarter turns. Planes and coefficients are always returned as stored.
+    pub apply_orientation: bool,
+    // color_management converts RGB output into sRGB if the file has a matrix-shaper ICC profile, other profiles
+    // are ignored. Without the std feature the transform is unavailable and the option has no effect.
+    pub color_management: bool,
+    // parse_options controls which malformed files are tolerated, truncated scans are accepted by default
+    pub parse_options: ParseOptions,
+    // skip_corrupt_intervals resynchronizes scans with restart markers at the marker following an invalid
+    // Huffman code or data running out, leaving the restart interval gray and listing it in lost_mcus instead of
+    // failing the decode
+    pub skip_corrupt_intervals: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            color_type: None,
+            pixel_format: None,
+            upsampling: Upsampling::default(),
+            idct: IdctMethod::default(),
+            fixed_point: FixedPoint::default(),
+            limits: Limits::default(),
+            apply_orientation: true,
+            color_management: false,
+            parse_options: ParseOptions {
+                allow_truncated_scan: true,
+                ..Default::default()
+            },
+            skip_corrupt_intervals: false,
+        }
+    }
+}
+
+// Decoder decodes a JPEG file streamed from reader with the configured options:
+//
+//     let image = Decoder::new(&jpeg_file[..])
+//         .color_type(ColorType::Gray)
+//         .upsampling(Upsampling::Triangle)
+//         .decode()?;
+#[cfg(feature = "std")]
+pub struct Decoder<R: Read> {
+    stream: StreamReader<R>,
+    options: DecodeOptions,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Decoder<R> {
+    pub fn new(reader: R) -> Decoder<R> {
+        Decoder {
+            stream: StreamReader::new(reader),
+            options: DecodeOptions::default(),
+        }
+    }
+
+    pub fn options(mut self, options: DecodeOptions) -> Decoder<R> {
+        self.options = options;
+        self
+    }
+
+    pub fn color_type(mut self, color_type: ColorType) -> Decoder<R> {
+        self.options.color_type = Some(color_type);
+        self
+    }
+
+    pub fn pixel_format(mut self, pixel_format: PixelFormat) -> Decoder<R> {
+        self.options.pixel_format = Some(pixel_format);
+        self
+    }
+
+    pub fn apply_orientation(mut self, apply_orientation: bool) -> Decoder<R> {
+        self.options.apply_orientation = apply_orientation;
+        self
+    }
+
+    pub fn color_management(mut self, color_management: bool) -> Decoder<R> {
+        self.options.color_management = color_management;
+        self
+    }
+
+    pub fn upsampling(mut self, upsampling: Upsampling) -> Decoder<R> {
+        self.options.upsampling = upsampling;
+        self
+    }
+
+    pub fn idct(mut self, idct: IdctMethod) -> Decoder<R> {
+        self.options.idct = idct;
+        self
+    }
+
+    pub fn fixed_point(mut self, fixed_point: FixedPoint) -> Decoder<R> {
+        self.options.fixed_point = fixed_point;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Decoder<R> {
+        self.options.limits = limits;
+        self
+    }
+
+    pub fn parse_options(mut self, parse_options: ParseOptions) -> Decoder<R> {
+        self.options.parse_options = parse_options;
+        self
+    }
+
+    pub fn skip_corrupt_intervals(mut self, skip_corrupt_intervals: bool) -> Decoder<R> {
+        self.options.skip_corrupt_intervals = skip_corrupt_intervals;
+        self
+    }
+
+    // estimated_memory reads ahead to the frame header and returns the bytes decoding the frame allocates, as the
+    // max_memory limit counts them, so decodes can be scheduled within a memory budget before any sample buffer
+    // exists. The headers read stay buffered, decoding afterwards still starts at the beginning of the file.
+    pub fn estimated_memory(&mut self) -> Result<u64, Error> {
+        let stream = &mut self.stream;
+        let mut offset = 0;
+        if self.options.parse_options.skip_leading_garbage {
+            while stream.fill(offset + 3)?
+                && (
+                    stream.peek(offset),
+                    stream.peek(offset + 1),
+                    stream.peek(offset + 2),
+                ) != (Some(0xff), Some(0xd8), Some(0xff))
+            {
+                offset += 1;
+            }
+        }
+        if !stream.fill(offset + 2)?
+            || (stream.peek(offset), stream.peek(offset + 1)) != (Some(0xff), Some(0xd8))
+        {
+            return Err(ParserError::MissingStartOfImage.into());
+        }
+        offset += 2;
+
+        loop {
+            // fill bytes may precede any marker
+            while stream.fill(offset + 2)?
+                && (stream.peek(offset), stream.peek(offset + 1)) == (Some(0xff), Some(0xff))
+            {
+                offset += 1;
+            }
+            if !stream.fill(offset + 4)? || stream.peek(offset) != Some(0xff) {
+                return Err(ParserError::UnexpectedEof.into());
+            }
+            check_length(&stream.peek_range(offset, 4), offset)?;
+            let tag = stream.peek(offset + 1).unwrap();
+            let length = u16::from_be_bytes([
+                stream.peek(offset + 2).unwrap(),
+                stream.peek(offset + 3).unwrap(),
+            ]);
+            let size = usize::from(length) + 2;
+            match tag {
+                0xc4 | 0xc8 | 0xcc => {}
+                0xc0..=0xcf => {
+                    if !stream.fill(offset + size)? {
+                        return Err(ParserError::UnexpectedEof.into());
+                    }
+                    if let Marker::SOF(ref frame) =
+                        parse_segment_at(&stream.peek_range(offset, size), offset)?
+                    {
+                        let (max_horizontal_sampling, max_vertical_sampling) =
+                            check_frame(frame, &self.options.limits)?;
+                        let mcus_per_line =
+                            (frame.width as usize).div_ceil(8 * max_horizontal_sampling);
+                        let mcus_per_column =
+                            (frame.height as usize).div_ceil(8 * max_vertical_sampling);
+                        return Ok(required_memory(frame, mcus_per_line, mcus_per_column));
+                    }
+                }
+                0xd9 | 0xda => return Err(ParserError::MissingFrameHeader.into()),
+                _ => {}
+            }
+            offset += size;
+        }
+    }
+
+    // decode reads the file segment by segment, only the current segment and the restart interval being decoded
+    // are buffered. Scans of files without DRI segment are a single interval, so their entropy coded data is
+    // buffered as a whole.
+    pub fn decode(self) -> Result<DecodedImage, Error> {
+        self.decode_with_scratch(&mut DecoderScratch::default())
+    }
+
+    // decode_with_scratch decodes like decode, reusing the buffers of scratch
+    pub fn decode_with_scratch(self, scratch: &mut DecoderScratch) -> Result<DecodedImage, Error> {
+        let mut decoder = FrameDecoder::new(self.options, mem::take(scratch));
+        let result = self
+            .read_frame(&mut decoder, |_| {})
+            .and_then(|()| decoder.finish());
+        *scratch = decoder.into_scratch();
+        result
+    }
+
+    // decode_with_stats decodes like decode, also returning statistics about the decoding work
+    pub fn decode_with_stats(self) -> Result<(DecodedImage, DecodeStats), Error> {
+        let mut decoder = FrameDecoder::new(self.options, DecoderScratch::default());
+        self.read_frame(&mut decoder, |_| {})?;
+        let image = decoder.finish()?;
+        Ok((image, decoder.stats))
+    }
+
+    // decode_with_previews decodes like decode, calling preview with the image reconstructed from the scans read
+    // so far after every complete scan. Progressive files refine it from coarse to fine, sequential files are
+    // complete after their first scan. The last preview equals the returned image.
+    pub fn decode_with_previews<F: FnMut(&DecodedImage)>(
+        self,
+        mut preview: F,
+    ) -> Result<DecodedImage, Error> {
+        let mut decoder = FrameDecoder::new(self.options, DecoderScratch::default());
+        let mut scratch = DecoderScratch::default();
+        self.read_frame(&mut decoder, |decoder| {
+            // scans before the tables of later components are defined can't be shown yet
+            if let Ok(image) = decoder.render(&mut scratch, &mut DecodeStats::default()) {
+                preview(&image);
+                scratch.recycle(image);
+            }
+        })?;
+        decoder.finish()
+    }
+
+    // decode_planes reads the file like decode, but returns the component planes without upsampling or color
+    // conversion
+    pub fn decode_planes(self) -> Result<DecodedPlanes, Error> {
+        let mut decoder = FrameDecoder::new(self.options, DecoderScratch::default());
+        self.read_frame(&mut decoder, |_| {})?;
+        decoder.finish_planes()
+    }
+
+    // read_coefficients reads the file like decode, but stops before the IDCT and returns the coefficients
+    pub fn read_coefficients(self) -> Result<DecodedCoefficients, Error> {
+        let mut decoder = FrameDecoder::new(self.options, DecoderScratch::default());
+        self.read_frame(&mut decoder, |_| {})?;
+        decoder.finish_coefficients()
+    }
+
+    // read_frame decodes all scans of the frame into decoder, calling on_scan after every complete one
+    fn read_frame<F: FnMut(&FrameDecoder)>(
+        self,
+        decoder: &mut FrameDecoder,
+        mut on_scan: F,
+    ) -> Result<(), Error> {
+        let mut stream = self.stream;
+        let parse_options = self.options.parse_options;
+        skip_to_soi(&mut stream, parse_options.skip_leading_garbage)
+            .map_err(|e| e.with_context(stream_context(Stage::Parse, None, 0)))?;
+
+        while decoder.truncation.is_none() {
+            skip_fill_bytes(&mut stream)?;
+            if stream.fill(2)? && stream.peek(0) == Some(0xff) && stream.peek(1) == Some(0xd9) {
+                break;
+            }
+
+            let offset = stream.position();
+            let tag = stream.peek(1);
+            let marker = read_segment(&mut stream)
+                .map_err(|e| e.with_context(stream_context(Stage::Parse, tag, offset)))?;
+            let result = match marker {
+                Some(ref segment) if segment[1] == 0xda => {
+                    let metadata = parse_scan_header_at(segment, offset)
+                        .map_err(|e| e.at(Stage::Parse, Some(0xda), offset))?;
+                    let context = stream_context(Stage::Entropy, Some(0xda), offset);
+                    if decoder
+                        .stream_scan(&mut stream, &metadata)
+                        .map_err(|e| e.with_context(context))?
+                    {
+                        break;
+                    }
+                    if decoder.truncation.is_none() {
+                        on_scan(decoder);
+                    }
+                    continue;
+                }
+                Some(ref segment) => parse_segment_at(segment, offset)
+                    .map_err(|e| e.at(Stage::Parse, Some(segment[1]), offset)),
+                None => {
+                    let marker = match (stream.peek(0), stream.peek(1)) {
+                        (Some(0xff), tag) => tag,
+                        _ => None,
+                    };
+                    let buffered = stream.peek_range(0, stream.buffered());
+                    Err(ParserError::truncated(&buffered).at(Stage::Parse, marker, offset))
+                }
+            };
+
+            match result {
+                Ok(marker) => decoder.process_at(&marker, offset, false)?,
+                Err(_) if parse_options.ignore_trailing_data => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        decoder.end = stream.position();
+        Ok(())
+    }
+}
+
+type Block = [i16; 64];
+
+struct Component {
+    id: u64,
+    horizontal_sampling: usize,
+    vertical_sampling: usize,
+    quantization_table: usize,
+    blocks_per_line: usize,
+    blocks_per_column: usize,
+    coefficients: Vec<Block>,
+}
+
+struct Frame {
+    width: usize,
+    height: usize,
+    max_horizontal_sampling: usize,
+    max_vertical_sampling: usize,
+    mcus_per_line: usize,
+    mcus_per_column: usize,
+    // progressive frames spread the coefficients over several scans
+    progressive: bool,
+    components: Vec<Component>,
+}
+
+#[derive(Default)]
+struct FrameDecoder {
+    options: DecodeOptions,
+    quantization_tables: [Option<[u16; 64]>; 4],
+    // huffman_tables holds the decoding tables by class and destination id, as scan headers reference them
+    huffman_tables: TableSet<HuffmanDecoder>,
+    restart_interval: usize,
+    frame: Option<Frame>,
+    truncation: Option<Truncation>,
+    // lost marks the MCUs of the frame of restart intervals that didn't decode
+    lost: Vec<bool>,
+    // orientation is taken from the first EXIF segment that has one
+    orientation: Option<Orientation>,
+    // icc_chunks holds the sequence number, chunk count and data of every ICC profile chunk
+    #[cfg(feature = "std")]
+    icc_chunks: Vec<(u8, u8, Vec<u8>)>,
+    scratch: DecoderScratch,
+    stats: DecodeStats,
+    // salvage keeps decoding past corrupt entropy coded data, marking the blocks it loses in damage, which holds
+    // one entry per block of every component
+    salvage: bool,
+    damage: Vec<Vec<bool>>,
+    // frame_segment is the marker and offset of the frame header, blamed for errors after the last scan, and end
+    // the offset decoding stopped at
+    frame_segment: Option<(u8, usize)>,
+    end: usize,
+}
+
+// DecoderScratch holds the buffers decoding needs in proportion to the image size: coefficients, sample planes,
+// entropy coded data read from streams and output pixels. Decoding a series of images, like the frames of a video,
+// with the same scratch reuses them, so only the first image allocates them if the output pixels are handed back
+// with recycle. Marker segments and Huffman tables still take small allocations independent of the image size.
+#[derive(Debug, Default)]
+pub struct DecoderScratch {
+    coefficients: Vec<Vec<Block>>,
+    planes: Vec<Vec<u8>>,
+    upsampled: Vec<Vec<u8>>,
+    lines: Vec<u8>,
+    interval: Vec<u8>,
+    intervals: Vec<Range<usize>>,
+    pixels: Vec<u8>,
+    oriented: Vec<u8>,
+}
+
+impl DecoderScratch {
+    // recycle keeps the pixel buffer of an image decoded before for the next decode
+    pub fn recycle(&mut self, image: DecodedImage) {
+        if image.pixels.capacity() > self.pixels.capacity() {
+            self.pixels = image.pixels;
+        }
+    }
+
+    // allocated_bytes is the capacity of the buffers in bytes
+    fn allocated_bytes(&self) -> usize {
+        let coefficients: usize = self.coefficients.iter().map(Vec::capacity).sum();
+        let planes: usize = self
+            .planes
+            .iter()
+            .chain(&self.upsampled)
+            .map(Vec::capacity)
+            .sum();
+        coefficients * mem::size_of::<Block>()
+            + planes
+            + self.lines.capacity()
+            + self.interval.capacity()
+            + self.intervals.capacity() * mem::size_of::<Range<usize>>()
+            + self.pixels.capacity()
+            + self.oriented.capacity()
+    }
+}
+
+// decode_image decodes sequential and progressive huffman coded JPEG files into pixels. Files ending in the middle
+// of the entropy coded data are decoded as far as possible.
+pub fn decode_image(jpeg_file: &[u8]) -> Result<DecodedImage, Error> {
+    decode_image_with_options(jpeg_file, &DecodeOptions::default())
+}
+
+pub fn decode_image_with_options(
+    jpeg_file: &[u8],
+    options: &DecodeOptions,
+) -> Result<DecodedImage, Error> {
+    decode_image_with_scratch(jpeg_file, options, &mut DecoderScratch::default())
+}
+
+// decode_image_with_scratch decodes like decode_image_with_options, reusing the buffers of scratch
+pub fn decode_image_with_scratch(
+    jpeg_file: &[u8],
+    options: &DecodeOptions,
+    scratch: &mut DecoderScratch,
+) -> Result<DecodedImage, Error> {
+    decode_image_with_tables(jpeg_file, options, scratch, &mut TableState::default())
+}
+
+// TableState holds the quantization and Huffman tables defined at the end of a file, for streams whose files
+// leave out the tables of the files before them
+#[derive(Debug, Default)]
+pub(crate) struct TableState {
+    pub(crate) quantization_tables: [Option<[u16; 64]>; 4],
+    pub(crate) huffman_tables: TableSet<HuffmanDecoder>,
+}
+
+// decode_image_with_tables decodes like decode_image_with_scratch, starting with the tables of state instead of
+// none and leaving the tables defined at the end of the file in it
+pub(crate) fn decode_image_with_tables(
+    jpeg_file: &[u8],
+    options: &DecodeOptions,
+    scratch: &mut DecoderScratch,
+    state: &mut TableState,
+) -> Result<DecodedImage, Error> {
+    let mut decoder = FrameDecoder::new(*options, mem::take(scratch));
+    decoder.quantization_tables = state.quantization_tables;
+    decoder.huffman_tables = mem::take(&mut state.huffman_tables);
+    let result = decoder
+        .process_markers(jpeg_file)
+        .and_then(|()| decoder.finish());
+    state.quantization_tables = decoder.quantization_tables;
+    state.huffman_tables = mem::take(&mut decoder.huffman_tables);
+    *scratch = decoder.into_scratch();
+    result
+}
+
+// decode_batch decodes many files with the default options, see decode_batch_with_options
+pub fn decode_batch(inputs: &[&[u8]]) -> Vec<Result<DecodedImage, Error>> {
+    decode_batch_with_options(inputs, &DecodeOptions::default())
+}
+
+// decode_batch_with_options decodes every file of inputs, returning their results in the same order. With the
+// rayon feature the files are spread over the threads of the current rayon pool, each thread reusing one
+// DecoderScratch for the files it decodes, otherwise they are decoded one by one with a single scratch.
+pub fn decode_batch_with_options(
+    inputs: &[&[u8]],
+    options: &DecodeOptions,
+) -> Vec<Result<DecodedImage, Error>> {
+    let decode = |scratch: &mut DecoderScratch, input: &&[u8]| {
+        decode_image_with_scratch(input, options, scratch)
+    };
+    #[cfg(feature = "rayon")]
+    let images = inputs
+        .par_iter()
+        .map_init(DecoderScratch::default, decode)
+        .collect();
+    #[cfg(not(feature = "rayon"))]
+    let images = {
+        let mut scratch = DecoderScratch::default();
+        inputs
+            .iter()
+            .map(|input| decode(&mut scratch, input))
+            .collect()
+    };
+    images
+}
+
+// decode_image_with_stats decodes like decode_image_with_options, also returning statistics about the decoding
+// work
+pub fn decode_image_with_stats(
+    jpeg_file: &[u8],
+    options: &DecodeOptions,
+) -> Result<(DecodedImage, DecodeStats), Error> {
+    let mut decoder = FrameDecoder::new(*options, DecoderScratch::default());
+    decoder.process_markers(jpeg_file)?;
+    let image = decoder.finish()?;
+    Ok((image, decoder.stats))
+}
+
+// decode_planes returns the component planes of jpeg_file at their native resolution, without upsampling or
+// color conversion. Color related options don't apply to them.
+pub fn decode_planes(jpeg_file: &[u8], options: &DecodeOptions) -> Result<DecodedPlanes, Error> {
+    let mut decoder = FrameDecoder::new(*options, DecoderScratch::default());
+    decoder.process_markers(jpeg_file)?;
+    decoder.finish_planes()
+}
+
+// read_coefficients returns the quantized DCT coefficients of every component of jpeg_file
+pub fn read_coefficients(
+    jpeg_file: &[u8],
+    options: &DecodeOptions,
+) -> Result<DecodedCoefficients, Error> {
+    let mut decoder = FrameDecoder::new(*options, DecoderScratch::default());
+    decoder.process_markers(jpeg_file)?;
+    decoder.finish_coefficients()
+}
+
+// decode_blocks decodes count blocks of a sequential scan of a single component from its entropy coded data, with
+// stuffed bytes, into quantized coefficients in natural order. Unlike HuffmanTable::huffman_decode, which returns
+// the symbols of a code, it reads the DC differences and AC run and size symbols together with their magnitude
+// bits, predicting every DC coefficient from the block before.
+pub fn decode_blocks(
+    data: &[u8],
+    dc_table: &HuffmanDecoder,
+    ac_table: &HuffmanDecoder,
+    count: usize,
+) -> Result<Vec<Block>, ParserError> {
+    let mut component = ScanComponent {
+        index: 0,
+        dc_table: Some(dc_table),
+        ac_table: Some(ac_table),
+        prediction: 0,
+        eob_run: 0,
+        dc_symbols: 0,
+        ac_symbols: 0,
+    };
+    let mut reader = BitReader::new(data);
+    let mut blocks = vec![[0; 64]; count];
+    for block in &mut blocks {
+        decode_block(&mut component, block, &mut reader).ok_or(
+            ParserError::InvalidHuffmanCode {
+                position: reader.bits_read(),
+            },
+        )?;
+    }
+    Ok(blocks)
+}
+
+// DecodeProgress is the state of a StreamBlockDecoder after a chunk of data
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DecodeProgress {
+    // NeedsMoreData is returned while blocks are left that the data fed so far doesn't complete
+    NeedsMoreData,
+    Complete,
+}
+
+// StreamBlockDecoder decodes blocks like decode_blocks from entropy coded data fed in arbitrarily sized chunks,
+// like StreamParser parses segments. A block the data ends in is decoded again from its start once the next
+// chunk arrives, so only the bytes since the last complete block are kept. A marker ends the data, after which
+// blocks that don't decode are an error.
+#[derive(Debug)]
+pub struct StreamBlockDecoder<'a> {
+    dc_table: &'a HuffmanDecoder,
+    ac_table: &'a HuffmanDecoder,
+    count: usize,
+    blocks: Vec<Block>,
+    prediction: i32,
+    // data holds the entropy coded data without stuffed bytes from the byte the next block starts in, which is
+    // skip bits into it, discarded bits before it
+    data: Vec<u8>,
+    skip: usize,
+    discarded: usize,
+    // pending is set for a 0xff ending the last chunk, which the next one tells to be stuffed or a marker
+    pending: bool,
+    ended: bool,
+}
+
+impl<'a> StreamBlockDecoder<'a> {
+    pub fn new(
+        dc_table: &'a HuffmanDecoder,
+        ac_table: &'a HuffmanDecoder,
+        count: usize,
+    ) -> StreamBlockDecoder<'a> {
+        StreamBlockDecoder {
+            dc_table,
+            ac_table,
+            count,
+            blocks: Vec::with_capacity(count),
+            prediction: 0,
+            data: Vec::new(),
+            skip: 0,
+            discarded: 0,
+            pending: false,
+            ended: false,
+        }
+    }
+
+    // feed appends chunk to the data and decodes the blocks it completes
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<DecodeProgress, ParserError> {
+        self.unstuff(chunk);
+
+        let mut component = ScanComponent {
+            index: 0,
+            dc_table: Some(self.dc_table),
+            ac_table: Some(self.ac_table),
+            prediction: self.prediction,
+            eob_run: 0,
+            dc_symbols: 0,
+            ac_symbols: 0,
+        };
+        let mut reader = BitReader::unstuffed(&self.data);
+        reader.read(self.skip);
+        let mut start = reader.bits_read();
+        while self.blocks.len() < self.count {
+            let mut block = [0; 64];
+            if decode_block(&mut component, &mut block, &mut reader).is_none() {
+                if self.ended || !reader.exhausted() {
+                    return Err(ParserError::InvalidHuffmanCode {
+                        position: self.discarded + reader.bits_read(),
+                    });
+                }
+                break;
+            }
+            self.blocks.push(block);
+            self.prediction = component.prediction;
+            start = reader.bits_read();
+        }
+
+        self.data.drain(..start / 8);
+        self.discarded += start / 8 * 8;
+        self.skip = start % 8;
+        if self.blocks.len() < self.count {
+            Ok(DecodeProgress::NeedsMoreData)
+        } else {
+            Ok(DecodeProgress::Complete)
+        }
+    }
+
+    // blocks returns the blocks decoded so far
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn into_blocks(self) -> Vec<Block> {
+        self.blocks
+    }
+
+    // unstuff appends the bytes of chunk in front of the first marker to data, leaving out stuffed bytes
+    fn unstuff(&mut self, chunk: &[u8]) {
+        let mut bytes = chunk.iter();
+        while !self.ended {
+            let &byte = match bytes.next() {
+                Some(byte) => byte,
+                None => break,
+            };
+            if self.pending {
+                self.pending = false;
+                if byte != 0x00 {
+                    self.ended = true;
+                    break;
+                }
+                self.data.push(0xff);
+            } else if byte == 0xff {
+                self.pending = true;
+            } else {
+                self.data.push(byte);
+            }
+        }
+    }
+}
+
+// salvage_image decodes a corrupt jpeg_file as far as possible. Scans resynchronize at the restart marker after
+// corrupt entropy coded data and the parser at the next valid segment after bytes that don't form one. The MCUs
+// that couldn't be decoded are concealed and listed in the returned damage map. The EXIF orientation isn't
+// applied, so the map lines up with the pixels. Files without a usable frame header still fail.
+pub fn salvage_image(jpeg_file: &[u8], options: &DecodeOptions) -> Result<SalvagedImage, Error> {
+    let mut options = *options;
+    options.apply_orientation = false;
+    options.parse_options.allow_truncated_scan = true;
+    options.parse_options.resynchronize = true;
+    options.parse_options.ignore_trailing_data = true;
+    let mut decoder = FrameDecoder::new(options, DecoderScratch::default());
+    decoder.salvage = true;
+    decoder.process_markers(jpeg_file)?;
+    let damage = decoder.conceal_damage()?;
+    let image = decoder.finish()?;
+    Ok(SalvagedImage { image, damage })
+}
+
+impl FrameDecoder {
+    fn new(options: DecodeOptions, scratch: DecoderScratch) -> FrameDecoder {
+        FrameDecoder {
+            options,
+            scratch,
+            ..Default::default()
+        }
+    }
+
+    // into_scratch returns the scratch buffers, including the coefficients of the frame
+    fn into_scratch(self) -> DecoderScratch {
+        let mut scratch = self.scratch;
+        if let Some(frame) = self.frame {
+            for component in frame.components {
+                scratch.coefficients.push(component.coefficients);
+            }
+        }
+        scratch
+    }
+
+    fn process_markers(&mut self, jpeg_file: &[u8]) -> Result<(), Error> {
+        let markers = decode_spanned(jpeg_file, &self.options.parse_options)?;
+        self.end = jpeg_file.len();
+        for spanned in &markers {
+            // only scans the parser accepted as truncated run up to the end of the file
+            let truncated = spanned.span.end() == jpeg_file.len();
+            self.process_at(&spanned.marker, spanned.span.offset, truncated)?;
+            if self.truncation.is_some() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // process_at processes the marker found at offset, adding the position to errors
+    fn process_at(&mut self, marker: &Marker, offset: usize, truncated: bool) -> Result<(), Error> {
+        let tag = marker.tag();
+        if let Marker::SOF(_) = *marker {
+            self.frame_segment = Some((tag, offset));
+        }
+        let stage = if tag == 0xda {
+            Stage::Entropy
+        } else {
+            Stage::Parse
+        };
+        self.process(marker, truncated)
+            .map_err(|e| e.at(stage, Some(tag), offset))
+    }
+
+    // after_scans returns the context of errors turning the coefficients into the output
+    fn after_scans(&self) -> ErrorContext {
+        match self.frame_segment {
+            Some((marker, offset)) => ErrorContext {
+                stage: Stage::Idct,
+                marker: Some(marker),
+                offset,
+            },
+            None => ErrorContext {
+                stage: Stage::Parse,
+                marker: None,
+                offset: self.end,
+            },
+        }
+    }
+
+    // process applies a parsed marker to the frame. truncated tells if the entropy coded data of a scan was cut
+    // off by the end of the input.
+    fn process(&mut self, marker: &Marker, truncated: bool) -> Result<(), ParserError> {
+        match *marker {
+            Marker::DQT(ref tables) => {
+                for table in tables {
+                    let mut natural = [0u16; 64];
+                    for (k, &position) in ZIGZAG.iter().enumerate() {
+                        natural[position] = if table.precision == 0 {
+                            u16::from(table.data[k])
+                        } else {
+                            u16::from_be_bytes([table.data[2 * k], table.data[2 * k + 1]])
+                        };
+                    }
+                    let slot = self
+                        .quantization_tables
+                        .get_mut(table.id as usize)
+                        .ok_or(ParserError::BadQuantizationTable { id: table.id })?;
+                    *slot = Some(natural);
+                }
+            }
+            Marker::DHT(ref tables) => {
+                for table in tables {
+                    self.huffman_tables
+                        .insert(table.class, HuffmanDecoder::new(table));
+                }
+            }
+            Marker::DRI(interval) => self.restart_interval = interval as usize,
+            Marker::SOF(ref frame) => {
+                let buffers = &mut self.scratch.coefficients;
+                let frame = Frame::new(frame, &self.options.limits, buffers)?;
+                if self.salvage {
+                    self.damage = frame
+                        .components
+                        .iter()
+                        .map(|component| vec![false; component.coefficients.len()])
+                        .collect();
+                }
+                self.frame = Some(frame);
+            }
+            Marker::Image(ref image) if self.salvage => {
+                self.salvage_scan(&image.metadata, image.data)
+            }
+            Marker::Image(ref image) => self.decode_scan(&image.metadata, image.data, truncated)?,
+            Marker::Other(ref marker) => {
+                if marker.tag == 0xe1 && self.orientation.is_none() {
+                    self.orientation = exif_orientation(marker.data);
+                }
+                #[cfg(feature = "std")]
+                {
+                    if let (0xe2, Some((sequence, count, data))) =
+                        (marker.tag, icc_chunk(marker.data))
+                    {
+                        self.icc_chunks.push((sequence, count, data.to_vec()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // decode_scan decodes the entropy coded data of a scan. Data ending before the last MCU is an error, unless
+    // it was truncated by the end of the input, which leaves the rest of the scan to finish_scan.
+    fn decode_scan(
+        &mut self,
+        metadata: &StartOfStreamMetaData,
+        data: &[u8],
+        truncated: bool,
+    ) -> Result<(), ParserError> {
+        let _span = trace_span!(
+            DEBUG,
+            "scan",
+            components = metadata.component_metadata.len()
+        );
+        let mut scan = self.start_scan(metadata)?;
+        let mut intervals = mem::take(&mut self.scratch.intervals);
+        restart_intervals(data, &mut intervals);
+        let numbers = restart_numbers(data);
+
+        let mut result = Ok(());
+        let mut index = 0;
+        for (i, interval) in intervals.iter().enumerate() {
+            if i > 0 {
+                index = self.interval_after(index, numbers[i - 1]);
+            }
+            let last = i + 1 == intervals.len();
+            result =
+                self.decode_interval(&mut scan, index, &data[interval.clone()], truncated && last);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.scratch.intervals = intervals;
+        result?;
+        self.finish_scan(&mut scan, truncated)
+    }
+
+    // stream_scan decodes the entropy coded data following a scan header, stopping in front of the next marker.
+    // It returns true if the stream ended inside of the scan.
+    #[cfg(feature = "std")]
+    fn stream_scan<R: Read>(
+        &mut self,
+        stream: &mut StreamReader<R>,
+        metadata: &StartOfStreamMetaData,
+    ) -> Result<bool, Error> {
+        if self.options.parse_options.default_huffman_tables {
+            for class in referenced_huffman_tables(metadata) {
+                if !self.huffman_tables.contains(class) {
+                    let table = HuffmanDecoder::new(&HuffmanTable::standard(class));
+                    self.huffman_tables.insert(class, table);
+                }
+            }
+        }
+
+        let _span = trace_span!(
+            DEBUG,
+            "scan",
+            components = metadata.component_metadata.len()
+        );
+        let mut scan = self.start_scan(metadata)?;
+        let mut interval = mem::take(&mut self.scratch.interval);
+        interval.clear();
+        let mut index = 0;
+        let mut ended = false;
+
+        let result = loop {
+            if !stream.fill(2)? {
+                if !self.options.parse_options.allow_truncated_scan {
+                    break Err(ParserError::UnexpectedEof);
+                }
+                if let Some(byte) = stream.peek(0) {
+                    interval.push(byte);
+                    stream.consume(1);
+                }
+                ended = true;
+                break self.decode_interval(&mut scan, index, &interval, true);
+            }
+
+            let (byte, next) = (stream.peek(0).unwrap(), stream.peek(1).unwrap());
+            if byte != 0xff {
+                interval.push(byte);
+                stream.consume(1);
+                continue;
+            }
+
+            match next {
+                0x00 => {
+                    interval.extend_from_slice(&[0xff, 0x00]);
+                    stream.consume(2);
+                }
+                0xd0..=0xd7 => {
+                    if let Err(error) = self.decode_interval(&mut scan, index, &interval, false) {
+                        break Err(error);
+                    }
+                    index = self.interval_after(index, next - 0xd0);
+                    interval.clear();
+                    stream.consume(2);
+                }
+                _ => break self.decode_interval(&mut scan, index, &interval, false),
+            }
+        };
+
+        self.scratch.interval = interval;
+        result?;
+        self.finish_scan(&mut scan, ended)?;
+        Ok(ended)
+    }
+
+    // interval_after returns the index of the restart interval following the one at index, whose restart marker
+    // has number. Intervals are placed in order, unless corrupt intervals are skipped, which places them by the
+    // number to also skip intervals whose markers went missing.
+    fn interval_after(&self, index: usize, number: u8) -> usize {
+        if self.options.skip_corrupt_intervals {
+            next_interval(index, number)
+        } else {
+            index + 1
+        }
+    }
+
+    // decode_interval decodes the restart interval at index of scan. If corrupt intervals are skipped, an interval
+    // with an invalid Huffman code or too little data is lost, like the intervals before it whose markers went
+    // missing. Data running out is accepted if it was truncated, intervals following the end of the scan are
+    // ignored.
+    fn decode_interval(
+        &mut self,
+        scan: &mut Scan,
+        index: usize,
+        data: &[u8],
+        truncated: bool,
+    ) -> Result<(), ParserError> {
+        let start = index * scan.interval;
+        if start >= scan.total_mcus {
+            return Ok(());
+        }
+        if start > scan.mcu {
+            self.lose_mcus(scan, scan.mcu..start);
+        }
+        scan.mcu = start;
+        match self.read_interval(scan, data) {
+            Ok(_) => Ok(()),
+            Err(ParserError::UnexpectedEof) if truncated => Ok(()),
+            Err(ParserError::InvalidHuffmanCode { .. }) | Err(ParserError::UnexpectedEof)
+                if self.options.skip_corrupt_intervals && self.restart_interval != 0 =>
+            {
+                let end = scan.total_mcus.min(start + scan.interval);
+                self.lose_mcus(scan, start..end);
+                scan.mcu = end;
+                Ok(())
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    // lose_mcus clears the blocks of MCUs of scan that couldn't be decoded, which leaves them gray, and marks
+    // the MCUs of the frame they belong to as lost
+    fn lose_mcus(&mut self, scan: &Scan, mcus: Range<usize>) {
+        trace_event!(
+            debug,
+            start = mcus.start,
+            end = mcus.end,
+            "lost restart interval"
+        );
+        let frame = match self.frame.as_mut() {
+            Some(frame) => frame,
+            None => return,
+        };
+        if self.lost.is_empty() {
+            self.lost = vec![false; frame.mcus_per_line * frame.mcus_per_column];
+        }
+        for mcu in mcus {
+            for (index, block) in mcu_blocks(frame, scan, mcu) {
+                let component = &mut frame.components[index];
+                component.coefficients[block] = [0; 64];
+                let (x, y) = (
+                    block % component.blocks_per_line,
+                    block / component.blocks_per_line,
+                );
+                let (mcu_x, mcu_y) = (
+                    x / component.horizontal_sampling,
+                    y / component.vertical_sampling,
+                );
+                if mcu_x < frame.mcus_per_line && mcu_y < frame.mcus_per_column {
+                    self.lost[mcu_y * frame.mcus_per_line + mcu_x] = true;
+                }
+            }
+        }
+    }
+
+    fn start_scan(&self, metadata: &StartOfStreamMetaData) -> Result<Scan, ParserError> {
+        let frame = self.frame.as_ref().ok_or(ParserError::MissingFrameHeader)?;
+        let coding = ScanCoding {
+            progressive: frame.progressive,
+            spectral_start: metadata.spectral_start as usize,
+            spectral_end: metadata.spectral_end as usize,
+            approximation_high: metadata.approximation_high as u8,
+            approximation_low: metadata.approximation_low as u8,
+        };
+        if coding.progressive && !coding.is_valid(metadata.component_metadata.len()) {
+            return Err(ParserError::InvalidSegment { marker: 0xda });
+        }
+        // sequential scans use both tables, progressive ones only the tables of their coefficients
+        let uses_dc_table =
+            !coding.progressive || (coding.spectral_start == 0 && coding.approximation_high == 0);
+        let uses_ac_table = !coding.progressive || coding.spectral_start > 0;
+
+        let mut components = Vec::with_capacity(metadata.component_metadata.len());
+        for component in &metadata.component_metadata {
+            let index = frame
+                .components
+                .iter()
+                .position(|c| c.id == component.id)
+                .ok_or(ParserError::InvalidComponent { id: component.id })?;
+            let table = |used: bool, class, id| {
+                if !used {
+                    return Ok(None);
+                }
+                DHTType::new(class, id as u8)
+                    .filter(|&table| self.huffman_tables.contains(table))
+                    .map(Some)
+                    .ok_or(ParserError::BadHuffmanTable { id })
+            };
+            components.push(ScanSelector {
+                index,
+                dc_table: table(uses_dc_table, TableClass::DC, component.dc_table)?,
+                ac_table: table(uses_ac_table, TableClass::AC, component.ac_table)?,
+            });
+        }
+
+        // a single component scan isn't interleaved and covers only the blocks inside the image
+        let (mcus_per_line, mcus_per_column) = if components.len() == 1 {
+            let component = &frame.components[components[0].index];
+            (
+                (frame.width * component.horizontal_sampling)
+                    .div_ceil(frame.max_horizontal_sampling)
+                    .div_ceil(8),
+                (frame.height * component.vertical_sampling)
+                    .div_ceil(frame.max_vertical_sampling)
+                    .div_ceil(8),
+            )
+        } else {
+            (frame.mcus_per_line, frame.mcus_per_column)
+        };
+
+        let total_mcus = mcus_per_line * mcus_per_column;
+        let interval = if self.restart_interval == 0 {
+            total_mcus
+        } else {
+            self.restart_interval
+        };
+
+        Ok(Scan {
+            components,
+            coding,
+            mcus_per_line,
+            total_mcus,
+            interval,
+            mcu: 0,
+        })
+    }
+
+    // read_interval decodes the MCUs of the restart interval starting at scan.mcu and returns the number of bits
+    // it left unread. It stops at the first MCU that doesn't decode, with UnexpectedEof if the data ran out and
+    // InvalidHuffmanCode if it holds a code the tables don't define.
+    fn read_interval(&mut self, scan: &mut Scan, data: &[u8]) -> Result<usize, ParserError> {
+        let stopwatch = Stopwatch::start();
+        if scan.mcu > 0 {
+            self.stats.restart_markers += 1;
+        }
+        let huffman_tables = &self.huffman_tables;
+        let frame = self.frame.as_mut().ok_or(ParserError::MissingFrameHeader)?;
+
+        let mut scan_components: Vec<ScanComponent> = scan
+            .components
+            .iter()
+            .map(|selector| ScanComponent {
+                index: selector.index,
+                dc_table: selector
+                    .dc_table
+                    .and_then(|table| huffman_tables.get(table)),
+                ac_table: selector
+                    .ac_table
+                    .and_then(|table| huffman_tables.get(table)),
+                prediction: 0,
+                eob_run: 0,
+                dc_symbols: 0,
+                ac_symbols: 0,
+            })
+            .collect();
+
+        let mut reader = BitReader::new(data);
+        let end = scan.total_mcus.min(scan.mcu + scan.interval);
+        if is_420_scan(frame, &scan_components, scan.coding) {
+            decode_mcus_420(frame, &mut scan_components, scan, end, &mut reader);
+        } else {
+            while scan.mcu < end {
+                let position = (scan.mcu % scan.mcus_per_line, scan.mcu / scan.mcus_per_line);
+                let decoded = decode_mcu(
+                    frame,
+                    &mut scan_components,
+                    scan.coding,
+                    position,
+                    &mut reader,
+                );
+                if decoded.is_none() {
+                    break;
+                }
+                scan.mcu += 1;
+            }
+        }
+        let result = if scan.mcu == end {
+            Ok(reader.remaining())
+        } else if reader.exhausted() {
+            Err(ParserError::UnexpectedEof)
+        } else {
+            Err(ParserError::InvalidHuffmanCode {
+                position: reader.bits_read(),
+            })
+        };
+
+        self.stats.entropy_coded_bits += reader.bits_read() as u64;
+        for (selector, component) in scan.components.iter().zip(&scan_components) {
+            if let Some(class) = selector.dc_table {
+                self.stats.add_symbols(class, component.dc_symbols);
+            }
+            if let Some(class) = selector.ac_table {
+                self.stats.add_symbols(class, component.ac_symbols);
+            }
+        }
+        stopwatch.add_to(&mut self.stats.entropy_decoding);
+        result
+    }
+
+    // finish_scan checks that a scan decoded all of its MCUs. The rest of a truncated scan stays gray, if corrupt
+    // intervals are skipped the missing intervals of any other scan with restart markers are lost.
+    fn finish_scan(&mut self, scan: &mut Scan, truncated: bool) -> Result<(), ParserError> {
+        if scan.mcu < scan.total_mcus && !truncated {
+            if !self.options.skip_corrupt_intervals || self.restart_interval == 0 {
+                return Err(ParserError::UnexpectedEof);
+            }
+            self.lose_mcus(scan, scan.mcu..scan.total_mcus);
+            scan.mcu = scan.total_mcus;
+        }
+        self.stats.scans += 1;
+        if scan.mcu < scan.total_mcus {
+            trace_event!(
+                debug,
+                decoded_mcus = scan.mcu,
+                total_mcus = scan.total_mcus,
+                "scan ended early"
+            );
+            self.truncation = Some(Truncation {
+                decoded_mcus: scan.mcu as u64,
+                total_mcus: scan.total_mcus as u64,
+            });
+        }
+        Ok(())
+    }
+
+    // salvage_scan decodes a scan like decode_scan, but resynchronizes at the restart marker following corrupt
+    // data. Restart markers count modulo 8, so the number of the next marker also places the intervals following
+    // lost markers. Intervals that don't decode, or decode without using up their data, are marked as damaged.
+    // Without restart markers there is nothing to resynchronize at and only the MCUs after an error are lost.
+    fn salvage_scan(&mut self, metadata: &StartOfStreamMetaData, data: &[u8]) {
+        let mut scan = match self.start_scan(metadata) {
+            Ok(scan) => scan,
+            Err(_) => {
+                // none of the data of a scan with an invalid header can be used
+                for scan_component in &metadata.component_metadata {
+                    let index = self.frame.as_ref().and_then(|frame| {
+                        frame
+                            .components
+                            .iter()
+                            .position(|c| c.id == scan_component.id)
+                    });
+                    if let Some(index) = index {
+                        self.damage[index]
+                            .iter_mut()
+                            .for_each(|damaged| *damaged = true);
+                    }
+                }
+                return;
+            }
+        };
+        let mut intervals = mem::take(&mut self.scratch.intervals);
+        restart_intervals(data, &mut intervals);
+        let numbers = restart_numbers(data);
+
+        // damaged_from holds the first damaged MCU of every interval, intervals that never show up are lost
+        let mut damaged_from: Vec<usize> = (0..scan.total_mcus).step_by(scan.interval).collect();
+        let mut index = 0;
+        for (i, interval) in intervals.iter().enumerate() {
+            if i > 0 {
+                // the interval following RSTn is the next one with an index of n + 1 modulo 8
+                index = next_interval(index, numbers[i - 1]);
+            }
+            if index >= damaged_from.len() {
+                break;
+            }
+            scan.mcu = index * scan.interval;
+            let last = scan.total_mcus.min(scan.mcu + scan.interval);
+            let result = self.read_interval(&mut scan, &data[interval.clone()]);
+            // only the up to 7 bits padding the last byte may be left over
+            if matches!(result, Ok(left_over) if left_over < 8) {
+                damaged_from[index] = last;
+            } else if self.restart_interval == 0 {
+                damaged_from[index] = scan.mcu;
+            }
+        }
+
+        for (index, &from) in damaged_from.iter().enumerate() {
+            let last = scan.total_mcus.min((index + 1) * scan.interval);
+            for mcu in from..last {
+                self.mark_damaged(&scan, mcu);
+            }
+        }
+        self.stats.scans += 1;
+        self.scratch.intervals = intervals;
+    }
+
+    // mark_damaged marks the blocks the given MCU of scan covers as damaged
+    fn mark_damaged(&mut self, scan: &Scan, mcu: usize) {
+        let frame = match self.frame.as_ref() {
+            Some(frame) => frame,
+            None => return,
+        };
+        for (index, block) in mcu_blocks(frame, scan, mcu) {
+            self.damage[index][block] = true;
+        }
+    }
+
+    // conceal_damage replaces every damaged block with the average DC coefficient of its neighbours, which
+    // includes the blocks above and to the left once they are concealed themselves, and returns the MCUs it
+    // touched
+    fn conceal_damage(&mut self) -> Result<DamageMap, ParserError> {
+        let frame = self.frame.as_mut().ok_or(ParserError::MissingFrameHeader)?;
+        let mut damaged = vec![false; frame.mcus_per_line * frame.mcus_per_column];
+        for (component, damage) in frame.components.iter_mut().zip(&self.damage) {
+            let width = component.blocks_per_line;
+            for (block, _) in damage.iter().enumerate().filter(|&(_, &damaged)| damaged) {
+                let (x, y) = (block % width, block / width);
+                let mut neighbours = Vec::with_capacity(4);
+                if x > 0 {
+                    neighbours.push(block - 1);
+                }
+                if y > 0 {
+                    neighbours.push(block - width);
+                }
+                if x + 1 < width && !damage[block + 1] {
+                    neighbours.push(block + 1);
+                }
+                if block + width < damage.len() && !damage[block + width] {
+                    neighbours.push(block + width);
+                }
+
+                let mut concealed = [0; 64];
+                if !neighbours.is_empty() {
+                    let sum: i32 = neighbours
+                        .iter()
+                        .map(|&neighbour| i32::from(component.coefficients[neighbour][0]))
+                        .sum();
+                    concealed[0] = (sum / neighbours.len() as i32) as i16;
+                }
+                component.coefficients[block] = concealed;
+                let mcu_x = x / component.horizontal_sampling;
+                let mcu_y = y / component.vertical_sampling;
+                damaged[mcu_y * frame.mcus_per_line + mcu_x] = true;
+            }
+        }
+
+        Ok(DamageMap {
+            mcu_width: 8 * frame.max_horizontal_sampling as u64,
+            mcu_height: 8 * frame.max_vertical_sampling as u64,
+            mcus_per_line: frame.mcus_per_line,
+            mcus_per_column: frame.mcus_per_column,
+            damaged,
+        })
+    }
+
+    // reconstruct transforms the coefficients of every component of frame into a plane of samples
+    fn reconstruct(&self, frame: &Frame, planes: &mut Vec<Vec<u8>>) -> Result<(), ParserError> {
+        planes.resize_with(frame.components.len(), Vec::new);
+        let tables = frame
+            .components
+            .iter()
+            .map(|component| {
+                self.quantization_tables[component.quantization_table].ok_or(
+                    ParserError::BadQuantizationTable {
+                        id: component.quantization_table as u64,
+                    },
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let method = self.options.idct;
+        let cosines = FixedPointCosines::new(self.options.fixed_point.idct_bits);
+        let reconstruct_component =
+            |((component, table), plane): ((&Component, &[u16; 64]), &mut Vec<u8>)| {
+                component.reconstruct(table, method, &cosines, plane)
+            };
+        // the coefficients of progressive files are all there before the first block is transformed, so nothing
+        // orders the components either. With rayon they are transformed at the same time, spreading the block rows
+        // of all of them over the threads.
+        #[cfg(feature = "rayon")]
+        frame
+            .components
+            .par_iter()
+            .zip(&tables)
+            .zip(planes.par_iter_mut())
+            .for_each(reconstruct_component);
+        #[cfg(not(feature = "rayon"))]
+        frame
+            .components
+            .iter()
+            .zip(&tables)
+            .zip(planes.iter_mut())
+            .for_each(reconstruct_component);
+        Ok(())
+    }
+
+    fn finish_planes(self) -> Result<DecodedPlanes, Error> {
+        let context = self.after_scans();
+        self.planes().map_err(|e| e.with_context(context))
+    }
+
+    fn planes(self) -> Result<DecodedPlanes, Error> {
+        let frame = self.frame.as_ref().ok_or(ParserError::MissingFrameHeader)?;
+        let mut planes = Vec::new();
+        self.reconstruct(frame, &mut planes)?;
+
+        let components = frame
+            .components
+            .iter()
+            .zip(planes)
+            .map(|(component, samples)| ComponentPlane {
+                id: component.id,
+                width: (frame.width * component.horizontal_sampling)
+                    .div_ceil(frame.max_horizontal_sampling) as u64,
+                height: (frame.height * component.vertical_sampling)
+                    .div_ceil(frame.max_vertical_sampling) as u64,
+                stride: component.blocks_per_line * 8,
+                samples,
+            })
+            .collect();
+
+        Ok(DecodedPlanes {
+            width: frame.width as u64,
+            height: frame.height as u64,
+            components,
+            truncation: self.truncation,
+            lost_mcus: mcu_ranges(&self.lost),
+        })
+    }
+
+    fn finish_coefficients(self) -> Result<DecodedCoefficients, Error> {
+        let context = self.after_scans();
+        self.coefficients().map_err(|e| e.with_context(context))
+    }
+
+    fn coefficients(self) -> Result<DecodedCoefficients, Error> {
+        let frame = self.frame.ok_or(ParserError::MissingFrameHeader)?;
+
+        let mut components = Vec::with_capacity(frame.components.len());
+        for component in frame.components {
+            let quantization_table = self.quantization_tables[component.quantization_table].ok_or(
+                ParserError::BadQuantizationTable {
+                    id: component.quantization_table as u64,
+                },
+            )?;
+            components.push(ComponentCoefficients {
+                id: component.id,
+                horizontal_sampling: component.horizontal_sampling,
+                vertical_sampling: component.vertical_sampling,
+                blocks_per_line: component.blocks_per_line,
+                blocks_per_column: component.blocks_per_column,
+                blocks: component.coefficients,
+                quantization_table,
+            });
+        }
+
+        Ok(DecodedCoefficients {
+            width: frame.width as u64,
+            height: frame.height as u64,
+            components,
+            truncation: self.truncation,
+        })
+    }
+
+    fn finish(&mut self) -> Result<DecodedImage, Error> {
+        let mut scratch = mem::take(&mut self.scratch);
+        let mut stats = mem::take(&mut self.stats);
+        let result = self.render(&mut scratch, &mut stats);
+        self.scratch = scratch;
+        self.stats = stats;
+        result.map_err(|e| e.with_context(self.after_scans()))
+    }
+
+    // render converts the coefficients decoded so far into an image, using the buffers of scratch and recording
+    // the time it takes in stats
+    fn render(
+        &self,
+        scratch: &mut DecoderScratch,
+        stats: &mut DecodeStats,
+    ) -> Result<DecodedImage, Error> {
+        let frame = self.frame.as_ref().ok_or(ParserError::MissingFrameHeader)?;
+        let stopwatch = Stopwatch::start();
+        self.reconstruct(frame, &mut scratch.planes)?;
+        stopwatch.add_to(&mut stats.reconstruction);
+        let planes = &scratch.planes;
+        let stopwatch = Stopwatch::start();
+
+        let (width, height) = (frame.width, frame.height);
+        let frame_color_type = match frame.components.len() {
+            1 => ColorType::Gray,
+            3 => ColorType::Rgb,
+            count => return Err(ParserError::UnsupportedComponentCount { count }.into()),
+        };
+        let color_type = self.options.color_type.unwrap_or(frame_color_type);
+        let pixel_format = self
+            .options
+            .pixel_format
+            .unwrap_or(PixelFormat::from(color_type));
+        let color_type = pixel_format.color_type();
+        let upsampling = self.options.upsampling;
+
+        // grayscale output only needs the luma component
+        let used_components = if color_type == ColorType::Gray {
+            1
+        } else {
+            frame.components.len()
+        };
+        let upsampled = &mut scratch.upsampled;
+        upsampled.resize_with(used_components, Vec::new);
+        for ((component, plane), output) in frame
+            .components
+            .iter()
+            .zip(planes)
+            .zip(upsampled.iter_mut())
+        {
+            let horizontal = (component.horizontal_sampling, frame.max_horizontal_sampling);
+            let vertical = (component.vertical_sampling, frame.max_vertical_sampling);
+            let plane = SampledPlane {
+                samples: plane,
+                stride: component.blocks_per_line * 8,
+                width: (width * horizontal.0).div_ceil(horizontal.1),
+                height: (height * vertical.0).div_ceil(vertical.1),
+            };
+            let size = (width, height);
+            upsample(
+                upsampling,
+                &plane,
+                horizontal,
+                vertical,
+                size,
+                output,
+                &mut scratch.lines,
+            );
+        }
+        let upsampled = &scratch.upsampled;
+        let constants = ColorConstants::new(self.options.fixed_point.color_bits);
+
+        #[cfg(feature = "std")]
+        let transform = if self.options.color_management && upsampled.len() == 3 {
+            let chunks: Vec<_> = self
+                .icc_chunks
+                .iter()
+                .map(|&(sequence, count, ref data)| (sequence, count, &data[..]))
+                .collect();
+            assemble_profile(&chunks).and_then(|profile| IccTransform::from_profile(&profile))
+        } else {
+            None
+        };
+
+        let mut pixels = mem::take(&mut scratch.pixels);
+        let bytes_per_pixel = pixel_format.bytes_per_pixel();
+        pixels.clear();
+        pixels.resize(width * height * bytes_per_pixel, 0);
+        // convert_line converts line y of the upsampled planes into output, through the RGB pixels of row
+        let convert_line = |row: &mut Vec<[u8; 3]>, (y, output): (usize, &mut [u8])| {
+            let line = y * width..(y + 1) * width;
+            match upsampled.len() {
+                1 => {
+                    for (pixel, &luma) in row.iter_mut().zip(&upsampled[0][line]) {
+                        *pixel = [luma; 3];
+                    }
+                }
+                _ => ycbcr_to_rgb_row(
+                    &upsampled[0][line.clone()],
+                    &upsampled[1][line.clone()],
+                    &upsampled[2][line],
+                    row,
+                    &constants,
+                ),
+            }
+            for (pixel, &rgb) in output.chunks_exact_mut(bytes_per_pixel).zip(row.iter()) {
+                #[cfg(feature = "std")]
+                let rgb = match transform {
+                    Some(ref transform) => transform.apply(rgb),
+                    None => rgb,
+                };
+                pixel_format.write(pixel, rgb);
+            }
+        };
+        for_each_row_init(
+            &mut pixels,
+            width * bytes_per_pixel,
+            || vec![[0u8; 3]; width],
+            convert_line,
+        );
+
+        let (pixels, width, height) = match self.orientation {
+            Some(orientation) if self.options.apply_orientation => {
+                let mut oriented = mem::take(&mut scratch.oriented);
+                let bytes_per_pixel = pixel_format.bytes_per_pixel();
+                let (width, height) =
+                    orientation.apply_into(&pixels, width, height, bytes_per_pixel, &mut oriented);
+                scratch.oriented = pixels;
+                (oriented, width, height)
+            }
+            _ => (pixels, width, height),
+        };
+        stopwatch.add_to(&mut stats.conversion);
+        let coefficients: usize = frame
+            .components
+            .iter()
+            .map(|component| component.coefficients.capacity() * mem::size_of::<Block>())
+            .sum();
+        stats.allocated_bytes =
+            (scratch.allocated_bytes() + coefficients + pixels.capacity()) as u64;
+
+        Ok(DecodedImage {
+            width: width as u64,
+            height: height as u64,
+            color_type,
+            pixel_format,
+            pixels,
+            truncation: self.truncation,
+            lost_mcus: mcu_ranges(&self.lost),
+        })
+    }
+}
+
+impl Frame {
+    // new checks the frame header against limits and allocates the coefficients, reusing buffers if there are any
+    fn new(
+        metadata: &StartOfFrameMetaData,
+        limits: &Limits,
+        buffers: &mut Vec<Vec<Block>>,
+    ) -> Result<Frame, ParserError> {
+        let (max_horizontal_sampling, max_vertical_sampling) = check_frame(metadata, limits)?;
+        let sampling = |c: &ColorComponentMetaData| {
+            (
+                c.horizontal_sampling() as usize,
+                c.vertical_sampling() as usize,
+            )
+        };
+
+        let (width, height) = (metadata.width as usize, metadata.height as usize);
+        let mcus_per_line = width.div_ceil(8 * max_horizontal_sampling);
+        let mcus_per_column = height.div_ceil(8 * max_vertical_sampling);
+
+        if let Some(limit) = limits.max_memory {
+            let required = required_memory(metadata, mcus_per_line, mcus_per_column);
+            if required > limit {
+                return Err(ParserError::MemoryLimitExceeded { required, limit });
+            }
+        }
+
+        let components = metadata
+            .component_metadata
+            .iter()
+            .map(|component| {
+                let (h, v) = sampling(component);
+                let blocks_per_line = mcus_per_line * h;
+                let blocks_per_column = mcus_per_column * v;
+                let mut coefficients = buffers.pop().unwrap_or_default();
+                coefficients.clear();
+                coefficients.resize(blocks_per_line * blocks_per_column, [0; 64]);
+                Component {
+                    id: component.id,
+                    horizontal_sampling: h,
+                    vertical_sampling: v,
+                    quantization_table: component.quantization_table as usize,
+                    blocks_per_line,
+                    blocks_per_column,
+                    coefficients,
+                }
+            })
+            .collect();
+
+        Ok(Frame {
+            width,
+            height,
+            max_horizontal_sampling,
+            max_vertical_sampling,
+            mcus_per_line,
+            mcus_per_column,
+            progressive: metadata.marker == 0xc2,
+            components,
+        })
+    }
+}
+
+impl Component {
+    // reconstruct dequantizes and transforms all blocks into plane, with blocks_per_line * 8 samples per line
+    fn reconstruct(
+        &self,
+        quantization_table: &[u16; 64],
+        method: IdctMethod,
+        cosines: &FixedPointCosines,
+        plane: &mut Vec<u8>,
+    ) {
+        let stride = self.blocks_per_line * 8;
+        plane.clear();
+        plane.resize(stride * self.blocks_per_column * 8, 0);
+
+        // transform_row transforms row y of blocks into the 8 lines of samples it covers
+        let transform_row = |(y, output): (usize, &mut [u8])| {
+            let blocks =
+                &self.coefficients[y * self.blocks_per_line..(y + 1) * self.blocks_per_line];
+            for (x, block) in blocks.iter().enumerate() {
+                let mut dequantized = [0i32; 64];
+                for i in 0..64 {
+                    dequantized[i] = i32::from(block[i]) * i32::from(quantization_table[i]);
+                }
+
+                let output = &mut output[x * 8..];
+                match method {
+                    IdctMethod::Integer => idct_block(&dequantized, output, stride, cosines),
+                    IdctMethod::Float => idct_block_float(&dequantized, output, stride),
+                    IdctMethod::Reference => idct_block_reference(&dequantized, output, stride),
+                }
+            }
+        };
+        for_each_row(plane, 8 * stride, transform_row);
+    }
+}
+
+// Scan is the progress of decoding a scan, which is fed one restart interval at a time
+struct Scan {
+    components: Vec<ScanSelector>,
+    coding: ScanCoding,
+    mcus_per_line: usize,
+    total_mcus: usize,
+    interval: usize,
+    mcu: usize,
+}
+
+// ScanCoding is the part of the coefficients a scan holds. Sequential scans hold all of them, progressive scans
+// the band from spectral_start to spectral_end in zigzag order, coarsened by approximation_low bits.
+// approximation_high is zero for the first scan of a band and the previous approximation_low for refinements.
+#[derive(Clone, Copy)]
+struct ScanCoding {
+    progressive: bool,
+    spectral_start: usize,
+    spectral_end: usize,
+    approximation_high: u8,
+    approximation_low: u8,
+}
+
+impl ScanCoding {
+    // is_valid checks the progressive scan parameters of a scan of component_count components, as in ITU T.81
+    // G.1.1.1.1. Bands either hold only the DC coefficients or only AC ones of a single component.
+    fn is_valid(&self, component_count: usize) -> bool {
+        let band = if self.spectral_start == 0 {
+            self.spectral_end == 0
+        } else {
+            self.spectral_start <= self.spectral_end
+                && self.spectral_end <= 63
+                && component_count == 1
+        };
+        band && self.approximation_high <= 13 && self.approximation_low <= 13
+    }
+}
+
+struct ScanSelector {
+    index: usize,
+    dc_table: Option<DHTType>,
+    ac_table: Option<DHTType>,
+}
+
+struct ScanComponent<'a> {
+    index: usize,
+    dc_table: Option<&'a HuffmanDecoder>,
+    ac_table: Option<&'a HuffmanDecoder>,
+    prediction: i32,
+    // eob_run is the number of following blocks without coefficients in the band of a progressive AC scan
+    eob_run: usize,
+    dc_symbols: u64,
+    ac_symbols: u64,
+}
+
+// decode_mcu decodes all blocks of one MCU, returning None if the entropy coded data is exhausted or corrupt
+fn decode_mcu(
+    frame: &mut Frame,
+    scan_components: &mut [ScanComponent],
+    coding: ScanCoding,
+    (mcu_x, mcu_y): (usize, usize),
+    reader: &mut BitReader,
+) -> Option<()> {
+    let interleaved = scan_components.len() > 1;
+
+    for scan_component in scan_components.iter_mut() {
+        let component = &mut frame.components[scan_component.index];
+        let (h, v) = if interleaved {
+            (component.horizontal_sampling, component.vertical_sampling)
+        } else {
+            (1, 1)
+        };
+
+        for block_y in 0..v {
+            for block_x in 0..h {
+                let x = mcu_x * h + block_x;
+                let y = mcu_y * v + block_y;
+                let block = &mut component.coefficients[y * component.blocks_per_line + x];
+                if coding.progressive {
+                    decode_progressive_block(scan_component, block, coding, reader)?;
+                } else {
+                    decode_block(scan_component, block, reader)?;
+                }
+            }
+        }
+    }
+
+    Some(())
+}
+
+// is_420_scan checks if the MCUs of a scan consist of 2x2 blocks of the first component and one block of the
+// second and third, the sequential interleaved scans of YCbCr 4:2:0 frames decode_mcus_420 decodes
+fn is_420_scan(frame: &Frame, scan_components: &[ScanComponent], coding: ScanCoding) -> bool {
+    let sampling = frame
+        .components
+        .iter()
+        .map(|component| (component.horizontal_sampling, component.vertical_sampling));
+    !coding.progressive
+        && sampling.eq([(2, 2), (1, 1), (1, 1)].iter().cloned())
+        && scan_components
+            .iter()
+            .map(|component| component.index)
+            .eq(0..3)
+}
+
+// decode_mcus_420 decodes the MCUs of a scan is_420_scan accepts up to end like decode_mcu, one line of MCUs
+// after the other. The blocks of a line are taken from the coefficients up front instead of looking up the
+// sampling and position of every component and block, which most photos spend the entropy decoding on. It
+// stops at an MCU that doesn't decode, leaving its number in scan.mcu.
+fn decode_mcus_420(
+    frame: &mut Frame,
+    scan_components: &mut [ScanComponent],
+    scan: &mut Scan,
+    end: usize,
+    reader: &mut BitReader,
+) -> Option<()> {
+    let (luma, chroma) = frame.components.split_at_mut(1);
+    let (blue, red) = chroma.split_at_mut(1);
+    let (luma, blue, red) = (&mut luma[0], &mut blue[0], &mut red[0]);
+    let (luma_scan, chroma_scans) = scan_components.split_at_mut(1);
+    let (blue_scan, red_scan) = chroma_scans.split_at_mut(1);
+    let (luma_scan, blue_scan, red_scan) = (&mut luma_scan[0], &mut blue_scan[0], &mut red_scan[0]);
+
+    while scan.mcu < end {
+        let (mcu_x, mcu_y) = (scan.mcu % scan.mcus_per_line, scan.mcu / scan.mcus_per_line);
+        let count = (scan.mcus_per_line - mcu_x).min(end - scan.mcu);
+        let line = luma.blocks_per_line;
+        let (top, bottom) = luma.coefficients[2 * mcu_y * line..][..2 * line].split_at_mut(line);
+        let start = mcu_y * blue.blocks_per_line + mcu_x;
+        let blocks = top[2 * mcu_x..]
+            .chunks_exact_mut(2)
+            .zip(bottom[2 * mcu_x..].chunks_exact_mut(2))
+            .zip(blue.coefficients[start..start + count].iter_mut())
+            .zip(red.coefficients[start..start + count].iter_mut());
+        for (((top, bottom), blue), red) in blocks {
+            for block in top.iter_mut().chain(bottom) {
+                decode_block(luma_scan, block, reader)?;
+            }
+            decode_block(blue_scan, blue, reader)?;
+            decode_block(red_scan, red, reader)?;
+            scan.mcu += 1;
+        }
+    }
+
+    Some(())
+}
+
+fn decode_block(
+    component: &mut ScanComponent,
+    block: &mut Block,
+    reader: &mut BitReader,
+) -> Option<()> {
+    decode_dc(component, block, 0, reader)?;
+
+    let ac_table = component.ac_table?;
+    let mut k = 1usize;
+    while k < 64 {
+        let symbol = decode_symbol(ac_table, reader)?;
+        component.ac_symbols += 1;
+        let run = (symbol >> 4) as usize;
+        let size = symbol & 0x0f;
+
+        if size == 0 {
+            if run == 15 {
+                // ZRL: a run of 16 zeros
+                k += 16;
+                continue;
+            }
+            // EOB: all remaining coefficients are zero
+            break;
+        }
+
+        k += run;
+        if k > 63 {
+            return None;
+        }
+        block[ZIGZAG[k]] = reader.receive_extend(size)? as i16;
+        k += 1;
+    }
+
+    Some(())
+}
+
+// decode_dc decodes the difference to the previous DC coefficient of the component, storing the DC coefficient
+// shifted left by shift bits
+fn decode_dc(
+    component: &mut ScanComponent,
+    block: &mut Block,
+    shift: u8,
+    reader: &mut BitReader,
+) -> Option<()> {
+    let size = decode_symbol(component.dc_table?, reader)?;
+    component.dc_symbols += 1;
+    let difference = reader.receive_extend(size)?;
+    component.prediction = component.prediction.wrapping_add(difference);
+    block[0] = (component.prediction << shift) as i16;
+    Some(())
+}
+
+// decode_progressive_block decodes the part of a block a progressive scan holds, as in ITU T.81 G.1.2
+fn decode_progressive_block(
+    component: &mut ScanComponent,
+    block: &mut Block,
+    coding: ScanCoding,
+    reader: &mut BitReader,
+) -> Option<()> {
+    let shift = coding.approximation_low;
+    match (coding.spectral_start, coding.approximation_high) {
+        (0, 0) => decode_dc(component, block, shift, reader),
+        (0, _) => {
+            // DC refinements append one raw bit
+            if reader.receive(1)? == 1 {
+                block[0] |= 1 << shift;
+            }
+            Some(())
+        }
+        (_, 0) => decode_ac_first(component, block, coding, reader),
+        _ => decode_ac_refinement(component, block, coding, reader),
+    }
+}
+
+// decode_ac_first decodes the first scan of an AC band. Besides the baseline symbols, size 0 symbols with a run
+// below 15 start an end of band run over this and 2^run - 1 plus run extra bits following blocks.
+fn decode_ac_first(
+    component: &mut ScanComponent,
+    block: &mut Block,
+    coding: ScanCoding,
+    reader: &mut BitReader,
+) -> Option<()> {
+    if component.eob_run > 0 {
+        component.eob_run -= 1;
+        return Some(());
+    }
+
+    let ac_table = component.ac_table?;
+    let mut k = coding.spectral_start;
+    while k <= coding.spectral_end {
+        let symbol = decode_symbol(ac_table, reader)?;
+        component.ac_symbols += 1;
+        let run = symbol >> 4;
+        let size = symbol & 0x0f;
+
+        if size == 0 {
+            if run == 15 {
+                k += 16;
+                continue;
+            }
+            component.eob_run = (1 << run) - 1 + reader.receive(run)? as usize;
+            break;
+        }
+
+        k += run as usize;
+        if k > coding.spectral_end {
+            return None;
+        }
+        block[ZIGZAG[k]] = (reader.receive_extend(size)? << coding.approximation_low) as i16;
+        k += 1;
+    }
+
+    Some(())
+}
+
+// decode_ac_refinement adds the next bit to the coefficients of an AC band, as in ITU T.81 G.1.2.3. Coefficients
+// that were zero so far become plus or minus one bit, every nonzero coefficient passed on the way gets a
+// correction bit.
+fn decode_ac_refinement(
+    component: &mut ScanComponent,
+    block: &mut Block,
+    coding: ScanCoding,
+    reader: &mut BitReader,
+) -> Option<()> {
+    let bit = 1i16 << coding.approximation_low;
+    let mut k = coding.spectral_start;
+
+    if component.eob_run == 0 {
+        let ac_table = component.ac_table?;
+        while k <= coding.spectral_end {
+            let symbol = decode_symbol(ac_table, reader)?;
+            component.ac_symbols += 1;
+            let mut run = symbol >> 4;
+            let size = symbol & 0x0f;
+
+            let mut value = 0;
+            if size == 1 {
+                value = if reader.receive(1)? == 1 { bit } else { -bit };
+            } else if size != 0 {
+                return None;
+            } else if run != 15 {
+                component.eob_run = (1 << run) + reader.receive(run)? as usize;
+                break;
+            }
+
+            // skip run zero coefficients, refining the nonzero ones in between
+            while k <= coding.spectral_end {
+                let coefficient = &mut block[ZIGZAG[k]];
+                if *coefficient != 0 {
+                    refine_coefficient(coefficient, bit, reader)?;
+                } else if run == 0 {
+                    break;
+                } else {
+                    run -= 1;
+                }
+                k += 1;
+            }
+
+            if value != 0 {
+                if k > coding.spectral_end {
+                    return None;
+                }
+                block[ZIGZAG[k]] = value;
+            }
+            k += 1;
+        }
+    }
+
+    if component.eob_run > 0 {
+        // the rest of the band only has correction bits
+        while k <= coding.spectral_end {
+            let coefficient = &mut block[ZIGZAG[k]];
+            if *coefficient != 0 {
+                refine_coefficient(coefficient, bit, reader)?;
+            }
+            k += 1;
+        }
+        component.eob_run -= 1;
+    }
+
+    Some(())
+}
+
+// refine_coefficient reads the correction bit of a nonzero coefficient, moving it bit further from zero if set
+fn refine_coefficient(coefficient: &mut i16, bit: i16, reader: &mut BitReader) -> Option<()> {
+    if reader.receive(1)? == 1 && *coefficient & bit == 0 {
+        *coefficient += if *coefficient > 0 { bit } else { -bit };
+    }
+    Some(())
+}
+
+// check_frame checks that the frame header describes a frame the decoder supports within limits, returning the
+// largest horizontal and vertical sampling factors of its components
+fn check_frame(
+    metadata: &StartOfFrameMetaData,
+    limits: &Limits,
+) -> Result<(usize, usize), ParserError> {
+    if metadata.marker != 0xc0 && metadata.marker != 0xc1 && metadata.marker != 0xc2 {
+        return Err(ParserError::UnsupportedSofProcess {
+            marker: metadata.marker,
+        });
+    }
+    if metadata.precision != 8 {
+        return Err(ParserError::UnsupportedPrecision {
+            precision: metadata.precision,
+        });
+    }
+    if metadata.width == 0 || metadata.height == 0 || metadata.component_metadata.is_empty() {
+        return Err(ParserError::InvalidFrame);
+    }
+    if limits.max_width.is_some_and(|max| metadata.width > max)
+        || limits.max_height.is_some_and(|max| metadata.height > max)
+    {
+        return Err(ParserError::LimitsExceeded {
+            width: metadata.width,
+            height: metadata.height,
+        });
+    }
+
+    let sampling = |c: &ColorComponentMetaData| {
+        (
+            c.horizontal_sampling() as usize,
+            c.vertical_sampling() as usize,
+        )
+    };
+    let mut max_horizontal_sampling = 1;
+    let mut max_vertical_sampling = 1;
+    for component in &metadata.component_metadata {
+        let (h, v) = sampling(component);
+        if h == 0 || h > 4 || v == 0 || v > 4 || component.quantization_table > 3 {
+            return Err(ParserError::InvalidComponent { id: component.id });
+        }
+        max_horizontal_sampling = max_horizontal_sampling.max(h);
+        max_vertical_sampling = max_vertical_sampling.max(v);
+    }
+    Ok((max_horizontal_sampling, max_vertical_sampling))
+}
+
+// required_memory estimates the bytes decoding the frame allocates: the coefficients and reconstructed samples of
+// every block, plus an output image of up to four bytes per pixel. MCU counts are bounded by the 16 bit
+// dimensions, so this can't overflow.
+fn required_memory(
+    metadata: &StartOfFrameMetaData,
+    mcus_per_line: usize,
+    mcus_per_column: usize,
+) -> u64 {
+    let mcus = (mcus_per_line * mcus_per_column) as u64;
+    let blocks: u64 = metadata
+        .component_metadata
+        .iter()
+        .map(|c| mcus * c.horizontal_sampling() * c.vertical_sampling())
+        .sum();
+    let block_size = (mem::size_of::<Block>() + 64) as u64;
+
+    blocks * block_size + metadata.width * metadata.height * 4
+}
+
+// skip_to_soi consumes the start of image marker, optionally skipping any bytes in front of it
+#[cfg(feature = "std")]
+fn skip_to_soi<R: Read>(
+    stream: &mut StreamReader<R>,
+    skip_leading_garbage: bool,
+) -> Result<(), Error> {
+    if skip_leading_garbage {
+        while stream.fill(3)? {
+            if (stream.peek(0), stream.peek(1), stream.peek(2))
+                == (Some(0xff), Some(0xd8), Some(0xff))
+            {
+                break;
+            }
+            stream.consume(1);
+        }
+    }
+
+    match stream.take(2)? {
+        Some(ref soi) if soi[..] == b"\xff\xd8"[..] => Ok(()),
+        _ => Err(ParserError::MissingStartOfImage.into()),
+    }
+}
+
+// stream_context is the context of errors of the Read based Decoder
+#[cfg(feature = "std")]
+fn stream_context(stage: Stage, marker: Option<u8>, offset: usize) -> ErrorContext {
+    ErrorContext {
+        stage,
+        marker,
+        offset,
+    }
+}
+
+// skip_fill_bytes consumes the 0xFF fill bytes in front of the next marker
+#[cfg(feature = "std")]
+fn skip_fill_bytes<R: Read>(stream: &mut StreamReader<R>) -> Result<(), Error> {
+    while stream.fill(2)? && (stream.peek(0), stream.peek(1)) == (Some(0xff), Some(0xff)) {
+        stream.consume(1);
+    }
+    Ok(())
+}
+
+// read_segment returns the next marker segment including its marker and length bytes, or None if the stream
+// doesn't continue with a complete segment
+#[cfg(feature = "std")]
+fn read_segment<R: Read>(stream: &mut StreamReader<R>) -> Result<Option<Vec<u8>>, Error> {
+    if !stream.fill(4)? || stream.peek(0) != Some(0xff) {
+        return Ok(None);
+    }
+
+    let header = stream.peek_range(0, 4);
+    check_length(&header, stream.position())?;
+    let length = u16::from_be_bytes([header[2], header[3]]);
+    Ok(stream.take(usize::from(length) + 2)?)
+}
+
+// restart_numbers returns the number of every restart marker in scan data, in the order restart_intervals
+// splits the data at them
+fn restart_numbers(data: &[u8]) -> Vec<u8> {
+    let mut numbers = Vec::new();
+    let mut i = 0usize;
+    while i + 1 < data.len() {
+        match (data[i], data[i + 1]) {
+            (0xff, 0x00) => i += 2,
+            (0xff, marker @ 0xd0..=0xd7) => {
+                numbers.push(marker - 0xd0);
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+    numbers
+}
+
+// mcu_blocks returns the component indices and block indices of the blocks the given MCU of scan covers
+fn mcu_blocks(frame: &Frame, scan: &Scan, mcu: usize) -> Vec<(usize, usize)> {
+    let (x, y) = (mcu % scan.mcus_per_line, mcu / scan.mcus_per_line);
+    let mut blocks = Vec::new();
+    for selector in &scan.components {
+        let component = &frame.components[selector.index];
+        if scan.components.len() == 1 {
+            blocks.push((selector.index, y * component.blocks_per_line + x));
+            continue;
+        }
+        let (h, v) = (component.horizontal_sampling, component.vertical_sampling);
+        for block_y in y * v..(y + 1) * v {
+            for block_x in x * h..(x + 1) * h {
+                blocks.push((
+                    selector.index,
+                    block_y * component.blocks_per_line + block_x,
+                ));
+            }
+        }
+    }
+    blocks
+}
+
+// mcu_ranges returns the runs of consecutive MCUs that are set in mcus
+fn mcu_ranges(mcus: &[bool]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (mcu, _) in mcus.iter().enumerate().filter(|&(_, &set)| set) {
+        match ranges.last_mut() {
+            Some(range) if range.end == mcu => range.end += 1,
+            _ => ranges.push(mcu..mcu + 1),
+        }
+    }
+    ranges
+}
+
+// next_interval returns the index of the restart interval following the marker RSTn that ends the interval at
+// index. Restart markers count modulo 8, so intervals whose markers were lost are skipped.
+fn next_interval(index: usize, number: u8) -> usize {
+    index + 1 + (usize::from(number) + 8 - index % 8) % 8
+}
+
+// restart_intervals splits scan data at its restart markers into the ranges of the entropy coded data of every
+// restart interval, still with its stuffed zero bytes
+pub(crate) fn restart_intervals(data: &[u8], intervals: &mut Vec<Range<usize>>) {
+    intervals.clear();
+    let (mut start, mut i) = (0usize, 0usize);
+    while i + 1 < data.len() {
+        match (data[i], data[i + 1]) {
+            (0xff, 0x00) => i += 2,
+            (0xff, 0xd0..=0xd7) => {
+                intervals.push(start..i);
+                i += 2;
+                start = i;
+            }
+            _ => i += 1,
+        }
+    }
+    intervals.push(start..data.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use color::ycbcr_to_rgb;
+    use encoder::{encode_image, encode_image_with_options, EncodeOptions};
+    use huffman::HuffmanTable;
+    use icc::read_icc_profile;
+    use icc::tests::linear_profile;
+    use parser::{decode, parse_segment};
+    use writer::encode_markers;
+
+    #[test]
+    fn restart_intervals_test() {
+        let data = vec![
+            0x01, 0xFF, 0x00, 0x02, 0xFF, 0xD0, 0x03, 0xFF, 0xD1, 0xFF, 0x00,
+        ];
+        let mut intervals = vec![7..8, 9..10];
+        restart_intervals(&data, &mut intervals);
+        assert_eq!(intervals, vec![0..4, 6..7, 9..11]);
+        restart_intervals(&[], &mut intervals);
+        assert_eq!(intervals, vec![0..0]);
+    }
+
+    fn pixel_error(image: &DecodedImage, reference: &[u8]) -> f64 {
+        let total: u64 = image
+            .pixels
+            .iter()
+            .zip(reference)
+            .map(|(&a, &b)| (i64::from(a) - i64::from(b)).unsigned_abs())
+            .sum();
+        total as f64 / reference.len() as f64
+    }
+
+    // gradient returns the pattern the test images were encoded from
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push((x * 8) as u8);
+                pixels.push((y * 10) as u8);
+                pixels.push((255 - (x + y) * 4) as u8);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn decode_image_test() {
+        for file in &[
+            &include_bytes!("../tests/images/gradient_444.jpg")[..],
+            &include_bytes!("../tests/images/gradient_422.jpg")[..],
+            &include_bytes!("../tests/images/gradient_420.jpg")[..],
+            &include_bytes!("../tests/images/restart_420.jpg")[..],
+        ] {
+            let image = decode_image(file).unwrap();
+
+            assert_eq!((image.width, image.height), (32, 24));
+            assert_eq!(image.color_type, ColorType::Rgb);
+            assert_eq!(image.truncation, None);
+            assert!(pixel_error(&image, &gradient(32, 24)) < 6.0);
+        }
+    }
+
+    #[test]
+    fn decode_gray_image_test() {
+        let image = decode_image(include_bytes!("../tests/images/gray.jpg")).unwrap();
+        let reference: Vec<u8> = gradient(32, 24)
+            .chunks(3)
+            .map(|rgb| rgb[0] / 2 + rgb[1] / 2)
+            .collect();
+
+        assert_eq!(image.color_type, ColorType::Gray);
+        assert_eq!(image.pixels.len(), 32 * 24);
+        assert!(pixel_error(&image, &reference) < 2.0);
+    }
+
+    #[test]
+    fn truncated_image_test() {
+        let file = include_bytes!("../tests/images/gradient_420.jpg");
+        let image = decode_image(&file[..file.len() - 60]).unwrap();
+
+        let truncation = image.truncation.unwrap();
+        assert_eq!(truncation.total_mcus, 4);
+        assert!(truncation.decoded_mcus > 0 && truncation.decoded_mcus < 4);
+
+        // the first MCU is intact, the last one gray filled
+        let reference = gradient(32, 24);
+        for (&decoded, &expected) in image.pixels[..3].iter().zip(&reference[..3]) {
+            assert!((i32::from(decoded) - i32::from(expected)).abs() < 12);
+        }
+        assert_eq!(&image.pixels[image.pixels.len() - 3..], &[128, 128, 128]);
+    }
+
+    #[test]
+    fn corrupt_scan_test() {
+        let file = &include_bytes!("../tests/images/gray.jpg")[..];
+        // the scan at offset 387 holds codes the Huffman tables don't define, but isn't cut off
+        let mut corrupt = file.to_vec();
+        for pair in corrupt[420..432].chunks_mut(2) {
+            pair.copy_from_slice(&[0xff, 0x00]);
+        }
+        let strict = DecodeOptions {
+            parse_options: ParseOptions {
+                allow_truncated_scan: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for options in &[DecodeOptions::default(), strict] {
+            let errors = [
+                decode_image_with_options(&corrupt, options).unwrap_err(),
+                Decoder::new(&corrupt[..])
+                    .options(*options)
+                    .decode()
+                    .unwrap_err(),
+            ];
+            for error in &errors {
+                assert!(
+                    matches!(
+                        error.parser_error(),
+                        Some(ParserError::InvalidHuffmanCode { .. })
+                    ),
+                    "{}",
+                    error
+                );
+                let context = error.context().unwrap();
+                assert_eq!(
+                    (context.stage, context.marker),
+                    (Stage::Entropy, Some(0xda))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lost_interval_test() {
+        let file = &include_bytes!("../tests/images/restart_420.jpg")[..];
+        let clean = decode_image(file).unwrap();
+        assert!(clean.lost_mcus.is_empty());
+
+        // the scan data starts at offset 629 with the first of two intervals of two MCUs, RST0 is at 726
+        let mut corrupt = file[..629].to_vec();
+        for _ in 0..48 {
+            corrupt.extend_from_slice(&[0xff, 0x00]);
+        }
+        corrupt.extend_from_slice(&file[726..]);
+        // by default the invalid Huffman code fails the decode
+        assert_eq!(
+            decode_image(&corrupt).unwrap_err().parser_error(),
+            Some(&ParserError::InvalidHuffmanCode { position: 0 })
+        );
+
+        let options = DecodeOptions {
+            skip_corrupt_intervals: true,
+            ..DecodeOptions::default()
+        };
+        let image = decode_image_with_options(&corrupt, &options).unwrap();
+        let streamed = Decoder::new(&corrupt[..])
+            .skip_corrupt_intervals(true)
+            .decode()
+            .unwrap();
+        assert_eq!(streamed, image);
+        assert_eq!(image.truncation, None);
+        assert_eq!(image.lost_mcus, vec![0..2]);
+        // the upper line of MCUs is gray, the lower one decodes as before
+        let line = 32 * 3;
+        assert!(image.pixels[..16 * line].iter().all(|&value| value == 128));
+        assert!(image.pixels[16 * line..] == clean.pixels[16 * line..]);
+
+        // the second interval is lost if RST0 is
+        let mut lost_marker = file.to_vec();
+        lost_marker[727] = 0x00;
+        assert!(decode_image(&lost_marker).is_err());
+        let image = decode_image_with_options(&lost_marker, &options).unwrap();
+        assert_eq!(image.lost_mcus, vec![2..4]);
+        let planes = decode_planes(&lost_marker, &options).unwrap();
+        assert_eq!(planes.lost_mcus, vec![2..4]);
+    }
+
+    // restart_offsets returns the offsets of the restart markers in file
+    fn restart_offsets(file: &[u8]) -> Vec<usize> {
+        (0..file.len() - 1)
+            .filter(|&i| file[i] == 0xff && (0xd0..=0xd7).contains(&file[i + 1]))
+            .collect()
+    }
+
+    #[test]
+    fn salvage_test() {
+        let pixels: Vec<u8> = (0..32 * 16).map(|i| (i * 7 % 251) as u8).collect();
+        let options = EncodeOptions {
+            restart_interval: 1,
+            ..EncodeOptions::default()
+        };
+        let file = encode_image_with_options(&pixels, 32, 16, ColorType::Gray, &options).unwrap();
+        let clean = decode_image(&file).unwrap();
+        let salvaged = salvage_image(&file, &DecodeOptions::default()).unwrap();
+        assert_eq!(salvaged.image, clean);
+        assert_eq!(salvaged.damage.damaged_mcus(), 0);
+        assert_eq!(salvaged.damage.damaged.len(), 8);
+
+        // overwrite the data of the third interval, between RST1 and RST2
+        let restarts = restart_offsets(&file);
+        assert_eq!(restarts.len(), 7);
+        let mut corrupt = file.clone();
+        for byte in &mut corrupt[restarts[1] + 2..restarts[2]] {
+            *byte = 0;
+        }
+        let salvaged = salvage_image(&corrupt, &DecodeOptions::default()).unwrap();
+        let damaged: Vec<usize> = (0..8).filter(|&mcu| salvaged.damage.damaged[mcu]).collect();
+        assert_eq!(damaged, vec![2]);
+        assert_eq!(salvaged.damage.damaged_ranges(), vec![2..3]);
+        assert!(salvaged.damage.is_damaged(16, 0) && !salvaged.damage.is_damaged(24, 7));
+        for y in 0..16 {
+            for x in 0..32 {
+                let pixel = salvaged.image.pixels[y * 32 + x];
+                if salvaged.damage.is_damaged(x as u64, y as u64) {
+                    // concealed blocks are flat
+                    assert_eq!(pixel, salvaged.image.pixels[16]);
+                } else {
+                    assert_eq!(pixel, clean.pixels[y * 32 + x]);
+                }
+            }
+        }
+
+        // dropping RST4 merges the fifth interval into the fourth, RST5 places the intervals after them
+        let mut corrupt = file.clone();
+        corrupt.drain(restarts[4]..restarts[4] + 2);
+        let salvaged = salvage_image(&corrupt, &DecodeOptions::default()).unwrap();
+        let damaged: Vec<usize> = (0..8).filter(|&mcu| salvaged.damage.damaged[mcu]).collect();
+        assert_eq!(damaged, vec![4, 5]);
+        assert_eq!(salvaged.damage.damaged_ranges(), vec![4..6]);
+        assert_eq!(salvaged.image.pixels[..8], clean.pixels[..8]);
+        assert_eq!(
+            salvaged.image.pixels[15 * 32 + 31],
+            clean.pixels[15 * 32 + 31]
+        );
+
+        // a file cut off in the middle of the scan loses its last intervals
+        let salvaged = salvage_image(&file[..restarts[5]], &DecodeOptions::default()).unwrap();
+        assert_eq!(salvaged.image.truncation, None);
+        let damaged: Vec<usize> = (0..8).filter(|&mcu| salvaged.damage.damaged[mcu]).collect();
+        assert_eq!(damaged, vec![6, 7]);
+
+        // intervals lost apart from each other are separate ranges
+        let mut corrupt = file.clone();
+        for &interval in &[0, 4] {
+            for byte in &mut corrupt[restarts[interval] + 2..restarts[interval + 1]] {
+                *byte = 0;
+            }
+        }
+        let salvaged = salvage_image(&corrupt, &DecodeOptions::default()).unwrap();
+        assert_eq!(salvaged.damage.damaged_ranges(), vec![1..2, 5..6]);
+
+        assert!(salvage_image(&file[..20], &DecodeOptions::default()).is_err());
+    }
+
+    #[test]
+    fn decode_blocks_test() {
+        let pixels: Vec<u8> = (0..32 * 16).map(|i| (i * 7 % 251) as u8).collect();
+        let file = encode_image(&pixels, 32, 16, ColorType::Gray).unwrap();
+        let sos = file.windows(2).position(|w| w == b"\xff\xda").unwrap();
+        let length = usize::from(file[sos + 2]) << 8 | usize::from(file[sos + 3]);
+        let data = &file[sos + 2 + length..];
+        let dc = HuffmanDecoder::new(&HuffmanTable::standard(DHTType::LuminanceDC));
+        let ac = HuffmanDecoder::new(&HuffmanTable::standard(DHTType::LuminanceAC));
+
+        let coefficients = read_coefficients(&file, &DecodeOptions::default()).unwrap();
+        assert_eq!(
+            decode_blocks(data, &dc, &ac, 8).unwrap(),
+            coefficients.components[0].blocks
+        );
+        // the data ends at the EOI marker in front of a ninth block
+        assert!(matches!(
+            decode_blocks(data, &dc, &ac, 9),
+            Err(ParserError::InvalidHuffmanCode { .. })
+        ));
+    }
+
+    #[test]
+    fn stream_block_decoder_test() {
+        let pixels: Vec<u8> = (0..64 * 32).map(|i| (i * 7 % 251) as u8).collect();
+        let file = encode_image(&pixels, 64, 32, ColorType::Gray).unwrap();
+        let sos = file.windows(2).position(|w| w == b"\xff\xda").unwrap();
+        let length = usize::from(file[sos + 2]) << 8 | usize::from(file[sos + 3]);
+        let data = &file[sos + 2 + length..];
+        assert!(data.windows(2).any(|w| w == [0xff, 0x00]));
+        let dc = HuffmanDecoder::new(&HuffmanTable::standard(DHTType::LuminanceDC));
+        let ac = HuffmanDecoder::new(&HuffmanTable::standard(DHTType::LuminanceAC));
+        let expected = decode_blocks(data, &dc, &ac, 32).unwrap();
+
+        // byte by byte the decoder waits for every block until its last byte, also between 0xff and stuffed 0x00
+        let mut decoder = StreamBlockDecoder::new(&dc, &ac, 32);
+        let mut decoded = 0;
+        for (i, byte) in data.iter().enumerate() {
+            let progress = decoder.feed(&[*byte]).unwrap();
+            assert!(decoder.blocks().len() >= decoded);
+            decoded = decoder.blocks().len();
+            if progress == DecodeProgress::Complete {
+                assert_eq!(i, data.len() - 3);
+                break;
+            }
+        }
+        assert_eq!(decoder.into_blocks(), expected);
+
+        let mut decoder = StreamBlockDecoder::new(&dc, &ac, 32);
+        for chunk in data.chunks(5) {
+            decoder.feed(chunk).unwrap();
+        }
+        assert_eq!(decoder.blocks(), &expected[..]);
+        assert_eq!(decoder.feed(&[]), Ok(DecodeProgress::Complete));
+
+        // a marker in front of the last block ends the data
+        let mut decoder = StreamBlockDecoder::new(&dc, &ac, 33);
+        assert_eq!(
+            decoder.feed(&data[..data.len() - 2]),
+            Ok(DecodeProgress::NeedsMoreData)
+        );
+        assert_eq!(decoder.blocks().len(), 32);
+        assert!(matches!(
+            decoder.feed(b"\xff\xd9"),
+            Err(ParserError::InvalidHuffmanCode { .. })
+        ));
+    }
+
+    #[test]
+    fn table_destination_test() {
+        let pixels: Vec<u8> = (0..32 * 16 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let file = encode_image(&pixels, 32, 16, ColorType::Rgb).unwrap();
+        // move the tables of ids 0 and 1 to 3 and 2, which only scans referencing them by id can find
+        let mut markers = decode(&file).unwrap();
+        for marker in &mut markers {
+            match *marker {
+                Marker::DHT(ref mut tables) => {
+                    for table in tables {
+                        let (class, id) = table.class.selector();
+                        table.class = DHTType::from_selector(class, 3 - id).unwrap();
+                    }
+                }
+                Marker::Image(ref mut stream) => {
+                    for component in &mut stream.metadata.component_metadata {
+                        component.dc_table = 3 - component.dc_table;
+                        component.ac_table = 3 - component.ac_table;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let moved = encode_markers(&markers).unwrap();
+
+        let expected = decode_image(&file).unwrap();
+        assert_eq!(decode_image(&moved).unwrap().pixels, expected.pixels);
+    }
+
+    #[test]
+    fn eob_zrl_test() {
+        let mut symbols: [Vec<u8>; 16] = Default::default();
+        symbols[0] = vec![0x00, 0x01];
+        let dc = HuffmanDecoder::new(&HuffmanTable::from_symbols(DHTType::LuminanceDC, &symbols));
+        // codes 00 for EOB, 01 for ZRL, 10 for a single coefficient and 110 for one after 15 zeros
+        let mut symbols: [Vec<u8>; 16] = Default::default();
+        symbols[1] = vec![0x00, 0xf0, 0x01];
+        symbols[2] = vec![0xf1];
+        let ac = HuffmanDecoder::new(&HuffmanTable::from_symbols(DHTType::LuminanceAC, &symbols));
+
+        // DC 1, ZRL, -1, EOB, then DC difference 0, three ZRLs, 1, EOB
+        let blocks = decode_blocks(&[0xd8, 0x15, 0xa7], &dc, &ac, 2).unwrap();
+        let mut expected = [[0; 64]; 2];
+        expected[0][0] = 1;
+        expected[0][ZIGZAG[17]] = -1;
+        expected[1][0] = 1;
+        expected[1][ZIGZAG[49]] = 1;
+        assert_eq!(blocks, expected.to_vec());
+
+        // three ZRLs and a run of 15 zeros go past the last coefficient
+        assert!(decode_blocks(&[0x2b, 0xbf], &dc, &ac, 1).is_err());
+    }
+
+    #[test]
+    fn unsupported_process_test() {
+        // turn the baseline frame into a lossless one
+        let mut file = include_bytes!("../tests/images/gradient_420.jpg").to_vec();
+        let sof = file.windows(2).position(|w| w == b"\xff\xc0").unwrap();
+        file[sof + 1] = 0xc3;
+
+        assert!(matches!(
+            decode_image(&file),
+            Err(Error::Decode {
+                error: ParserError::UnsupportedSofProcess { marker: 0xc3 },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_progressive_test() {
+        let file = &include_bytes!("../tests/images/progressive_420.jpg")[..];
+        let image = decode_image(file).unwrap();
+
+        assert_eq!((image.width, image.height), (32, 24));
+        assert_eq!(image.truncation, None);
+        assert!(pixel_error(&image, &gradient(32, 24)) < 6.0);
+        assert_eq!(Decoder::new(file).decode().unwrap(), image);
+    }
+
+    #[test]
+    fn progressive_reconstruction_test() {
+        let options = EncodeOptions {
+            progressive: true,
+            ..EncodeOptions::default()
+        };
+        let pixels: Vec<u8> = (0..200 * 120 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let file = encode_image_with_options(&pixels, 200, 120, ColorType::Rgb, &options).unwrap();
+        let coefficients = read_coefficients(&file, &DecodeOptions::default()).unwrap();
+        let planes = decode_planes(&file, &DecodeOptions::default()).unwrap();
+
+        // every block of every component is transformed into its place in the plane
+        for (component, plane) in coefficients.components.iter().zip(&planes.components) {
+            for (x, y) in (0..component.blocks_per_column)
+                .flat_map(|y| (0..component.blocks_per_line).map(move |x| (x, y)))
+            {
+                let mut samples = [0u8; 64];
+                idct_block(
+                    &component.dequantized(x, y),
+                    &mut samples,
+                    8,
+                    &FixedPointCosines::default(),
+                );
+                for row in 0..8 {
+                    let start = (y * 8 + row) * plane.stride + x * 8;
+                    assert_eq!(
+                        &samples[row * 8..row * 8 + 8],
+                        &plane.samples[start..start + 8]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sequential_420_test() {
+        // 4:2:0 scans decode like the other scans, here the progressive ones of the same coefficients, also with
+        // restart intervals starting and ending within lines of MCUs
+        // the image covers whole MCUs, progressive AC scans leave out blocks beyond the image
+        let pixels: Vec<u8> = (0..80 * 48 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let coefficients = |progressive, restart_interval| {
+            let options = EncodeOptions {
+                progressive,
+                restart_interval,
+                ..EncodeOptions::default()
+            };
+            let file =
+                encode_image_with_options(&pixels, 80, 48, ColorType::Rgb, &options).unwrap();
+            read_coefficients(&file, &DecodeOptions::default()).unwrap()
+        };
+        let expected = coefficients(true, 0);
+        assert_eq!(expected.components[0].blocks_per_line, 10);
+        for &restart_interval in &[0, 1, 3, 5] {
+            let decoded = coefficients(false, restart_interval);
+            for (component, expected) in decoded.components.iter().zip(&expected.components) {
+                assert!(component.blocks == expected.blocks);
+            }
+        }
+    }
+
+    #[test]
+    fn progressive_preview_test() {
+        let file = &include_bytes!("../tests/images/progressive_420.jpg")[..];
+        let reference = gradient(32, 24);
+
+        let mut errors = Vec::new();
+        let image = Decoder::new(file)
+            .decode_with_previews(|preview| errors.push(pixel_error(preview, &reference)))
+            .unwrap();
+
+        // one preview per scan, starting with the luma DC coefficients only
+        let scans = file.windows(2).filter(|w| w == b"\xff\xda").count();
+        assert_eq!(errors.len(), scans);
+        assert!(errors[0] > errors[scans - 1] + 10.0);
+        assert_eq!(errors[scans - 1], pixel_error(&image, &reference));
+
+        let mut previews = 0;
+        Decoder::new(&include_bytes!("../tests/images/gradient_420.jpg")[..])
+            .decode_with_previews(|_| previews += 1)
+            .unwrap();
+        assert_eq!(previews, 1);
+    }
+
+    // ac_component is the first component of an AC scan coded with table
+    fn ac_component(table: &HuffmanDecoder) -> ScanComponent<'_> {
+        ScanComponent {
+            index: 0,
+            dc_table: None,
+            ac_table: Some(table),
+            prediction: 0,
+            eob_run: 0,
+            dc_symbols: 0,
+            ac_symbols: 0,
+        }
+    }
+
+    #[test]
+    fn eob_run_test() {
+        // codes 00 for EOB0, 01 for a single coefficient and 10 for EOB2
+        let mut symbols: [Vec<u8>; 16] = Default::default();
+        symbols[1] = vec![0x00, 0x01, 0x20];
+        let table =
+            HuffmanDecoder::new(&HuffmanTable::from_symbols(DHTType::LuminanceAC, &symbols));
+        let mut component = ac_component(&table);
+        let coding = ScanCoding {
+            progressive: true,
+            spectral_start: 1,
+            spectral_end: 5,
+            approximation_high: 0,
+            approximation_low: 0,
+        };
+
+        // 1, then EOB2 with the extra bits 01 ends the band of this block and the next 4 ones, which take no
+        // bits. The sixth block holds -1 and EOB0, ending only its own band.
+        let data = [0b0111_0010, 0b1000_1111];
+        let mut reader = BitReader::new(&data);
+        let mut blocks = [[0i16; 64]; 6];
+        decode_ac_first(&mut component, &mut blocks[0], coding, &mut reader).unwrap();
+        assert_eq!((reader.bits_read(), component.eob_run), (7, 4));
+        for block in &mut blocks[1..5] {
+            decode_ac_first(&mut component, block, coding, &mut reader).unwrap();
+        }
+        assert_eq!((reader.bits_read(), component.eob_run), (7, 0));
+        decode_ac_first(&mut component, &mut blocks[5], coding, &mut reader).unwrap();
+        assert_eq!((reader.bits_read(), component.eob_run), (12, 0));
+        let firsts: Vec<_> = blocks.iter().map(|block| block[ZIGZAG[1]]).collect();
+        assert_eq!(firsts, vec![1, 0, 0, 0, 0, -1]);
+
+        // in a refinement EOB2 with the extra bits 00 counts this block too, whose nonzero coefficient still
+        // reads its correction bit
+        let coding = ScanCoding {
+            approximation_high: 1,
+            ..coding
+        };
+        let mut block = [0i16; 64];
+        block[ZIGZAG[1]] = 2;
+        let mut reader = BitReader::new(&[0b1000_1111]);
+        decode_ac_refinement(&mut component, &mut block, coding, &mut reader).unwrap();
+        assert_eq!((reader.bits_read(), component.eob_run), (5, 3));
+        assert_eq!(block[ZIGZAG[1]], 3);
+    }
+
+    #[test]
+    fn band_overrun_test() {
+        // code 0 for a run of 4 zeros followed by a coefficient of size 1
+        let mut symbols: [Vec<u8>; 16] = Default::default();
+        symbols[0] = vec![0x41];
+        let table =
+            HuffmanDecoder::new(&HuffmanTable::from_symbols(DHTType::LuminanceAC, &symbols));
+        let mut component = ac_component(&table);
+        let coding = ScanCoding {
+            progressive: true,
+            spectral_start: 1,
+            spectral_end: 5,
+            approximation_high: 0,
+            approximation_low: 0,
+        };
+
+        // the coefficient lands on 5, the end of the band, a band ending at 3 is overrun
+        let mut block = [0i16; 64];
+        let mut reader = BitReader::new(&[0b0100_0000]);
+        decode_ac_first(&mut component, &mut block, coding, &mut reader).unwrap();
+        assert_eq!(block[ZIGZAG[5]], 1);
+        let coding = ScanCoding {
+            spectral_end: 3,
+            ..coding
+        };
+        let mut reader = BitReader::new(&[0b0100_0000]);
+        assert_eq!(
+            decode_ac_first(&mut component, &mut block, coding, &mut reader),
+            None
+        );
+
+        // a refinement skips the zeros 1 to 4 the same way before placing the new coefficient
+        let coding = ScanCoding {
+            spectral_end: 5,
+            approximation_high: 1,
+            ..coding
+        };
+        let mut block = [0i16; 64];
+        let mut reader = BitReader::new(&[0b0100_0000]);
+        decode_ac_refinement(&mut component, &mut block, coding, &mut reader).unwrap();
+        assert_eq!(block[ZIGZAG[5]], 1);
+        let coding = ScanCoding {
+            spectral_end: 3,
+            ..coding
+        };
+        let mut block = [0i16; 64];
+        let mut reader = BitReader::new(&[0b0100_0000]);
+        assert_eq!(
+            decode_ac_refinement(&mut component, &mut block, coding, &mut reader),
+            None
+        );
+        assert_eq!(block[ZIGZAG[4]], 0);
+    }
+
+    #[test]
+    fn progressive_refinement_test() {
+        let table = HuffmanDecoder::new(&HuffmanTable::from_symbols(
+            DHTType::LuminanceAC,
+            &[vec![], vec![0x00, 0x01, 0x11]],
+        ));
+        let mut component = ac_component(&table);
+        let coding = ScanCoding {
+            progressive: true,
+            spectral_start: 1,
+            spectral_end: 5,
+            approximation_high: 1,
+            approximation_low: 0,
+        };
+
+        let mut block = [0i16; 64];
+        block[ZIGZAG[1]] = 2;
+        block[ZIGZAG[3]] = -2;
+        block[0] = 4;
+        // run 1 with a positive new coefficient, correction bits 1 and 0, then the end of the band. The next
+        // refinement only has correction bits 0, 0 and 1 for the nonzero coefficients, followed by a DC bit.
+        let data = vec![0b1011_0000, 0b0111_1111];
+        let mut reader = BitReader::new(&data);
+
+        decode_ac_refinement(&mut component, &mut block, coding, &mut reader).unwrap();
+        assert_eq!(reader.bits_read(), 7);
+        assert_eq!(
+            (block[ZIGZAG[1]], block[ZIGZAG[3]], block[ZIGZAG[4]]),
+            (3, -2, 1)
+        );
+        assert_eq!(component.eob_run, 0);
+
+        let coding = ScanCoding {
+            approximation_high: 2,
+            approximation_low: 1,
+            ..coding
+        };
+        component.eob_run = 1;
+        decode_ac_refinement(&mut component, &mut block, coding, &mut reader).unwrap();
+        assert_eq!(reader.bits_read(), 10);
+        assert_eq!(
+            (block[ZIGZAG[1]], block[ZIGZAG[3]], block[ZIGZAG[4]]),
+            (3, -2, 3)
+        );
+        assert_eq!(component.eob_run, 0);
+
+        let coding = ScanCoding {
+            spectral_start: 0,
+            spectral_end: 0,
+            ..coding
+        };
+        decode_progressive_block(&mut component, &mut block, coding, &mut reader).unwrap();
+        assert_eq!((reader.bits_read(), block[0]), (11, 6));
+    }
+
+    #[test]
+    fn decoder_options_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let reference = gradient(32, 24);
+
+        let box_filtered = Decoder::new(file).decode().unwrap();
+        let triangle_filtered = Decoder::new(file)
+            .upsampling(Upsampling::Triangle)
+            .idct(IdctMethod::Float)
+            .decode()
+            .unwrap();
+        assert!(pixel_error(&triangle_filtered, &reference) < 6.0);
+        assert!(
+            pixel_error(&triangle_filtered, &reference) < pixel_error(&box_filtered, &reference)
+        );
+        let exact = Decoder::new(file)
+            .idct(IdctMethod::Reference)
+            .decode()
+            .unwrap();
+        let float = Decoder::new(file).idct(IdctMethod::Float).decode().unwrap();
+        for (&exact, &float) in exact.pixels.iter().zip(&float.pixels) {
+            assert!((i32::from(exact) - i32::from(float)).abs() <= 1);
+        }
+        let coarse = Decoder::new(file)
+            .fixed_point(FixedPoint {
+                idct_bits: 8,
+                color_bits: 8,
+            })
+            .decode()
+            .unwrap();
+        assert!(coarse.pixels != box_filtered.pixels);
+        assert!(pixel_error(&coarse, &reference) < pixel_error(&box_filtered, &reference) + 1.0);
+
+        let gray = Decoder::new(file)
+            .color_type(ColorType::Gray)
+            .decode()
+            .unwrap();
+        assert_eq!(gray.color_type, ColorType::Gray);
+        assert_eq!(gray.pixels.len(), 32 * 24);
+
+        let gray_file = &include_bytes!("../tests/images/gray.jpg")[..];
+        let gray = decode_image(gray_file).unwrap();
+        let rgb = Decoder::new(gray_file)
+            .color_type(ColorType::Rgb)
+            .decode()
+            .unwrap();
+        assert_eq!(rgb.color_type, ColorType::Rgb);
+        assert_eq!(&rgb.pixels[..3], &[gray.pixels[0]; 3][..]);
+    }
+
+    #[test]
+    fn limits_test() {
+        let file = &include_bytes!("../tests/images/gradient_444.jpg")[..];
+        let limits = |max_width, max_height| Limits {
+            max_width: Some(max_width),
+            max_height: Some(max_height),
+            ..Default::default()
+        };
+
+        assert!(Decoder::new(file).limits(limits(32, 24)).decode().is_ok());
+        assert!(Decoder::new(file).limits(limits(31, 24)).decode().is_err());
+        assert!(Decoder::new(file).limits(limits(32, 23)).decode().is_err());
+
+        let error = decode_image_with_options(
+            file,
+            &DecodeOptions {
+                limits: limits(16, 16),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        match error {
+            Error::Decode {
+                error: ParserError::LimitsExceeded { width, height },
+                ..
+            } => assert_eq!((width, height), (32, 24)),
+            other => panic!("unexpected error {}", other),
+        }
+    }
+
+    #[test]
+    fn pixel_format_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let rgb = decode_image(file).unwrap();
+        assert_eq!(rgb.pixel_format, PixelFormat::Rgb8);
+        let [r, g, b] = [rgb.pixels[0], rgb.pixels[1], rgb.pixels[2]];
+
+        let packed = (u16::from(r) >> 3) << 11 | (u16::from(g) >> 2) << 5 | u16::from(b) >> 3;
+        for &(format, ref first) in &[
+            (PixelFormat::Rgba8, vec![r, g, b, 255]),
+            (PixelFormat::Bgr8, vec![b, g, r]),
+            (PixelFormat::Bgra8, vec![b, g, r, 255]),
+            (PixelFormat::Rgb565, packed.to_le_bytes().to_vec()),
+        ] {
+            let image = Decoder::new(file).pixel_format(format).decode().unwrap();
+            assert_eq!(
+                (image.pixel_format, image.color_type),
+                (format, ColorType::Rgb)
+            );
+            assert_eq!(image.pixels.len(), 32 * 24 * format.bytes_per_pixel());
+            assert_eq!(&image.pixels[..first.len()], &first[..]);
+        }
+
+        // pixel_format takes precedence over color_type
+        let gray = Decoder::new(file)
+            .color_type(ColorType::Rgb)
+            .pixel_format(PixelFormat::Gray8)
+            .decode()
+            .unwrap();
+        assert_eq!(gray.color_type, ColorType::Gray);
+        assert_eq!(gray.pixels.len(), 32 * 24);
+    }
+
+    #[test]
+    fn decode_planes_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let planes = decode_planes(file, &DecodeOptions::default()).unwrap();
+
+        assert_eq!((planes.width, planes.height), (32, 24));
+        let sizes: Vec<_> = planes
+            .components
+            .iter()
+            .map(|plane| (plane.width, plane.height, plane.stride))
+            .collect();
+        assert_eq!(sizes, vec![(32, 24, 32), (16, 12, 16), (16, 12, 16)]);
+        assert_eq!(planes.components[1].line(11).len(), 16);
+
+        // box upsampling repeats the chroma samples, so converting the planes gives the RGB output
+        let rgb = decode_image(file).unwrap();
+        let (y, cb, cr) = (
+            &planes.components[0],
+            &planes.components[1],
+            &planes.components[2],
+        );
+        assert_eq!(
+            ycbcr_to_rgb(y.line(3)[5], cb.line(1)[2], cr.line(1)[2]),
+            [
+                rgb.pixels[(3 * 32 + 5) * 3],
+                rgb.pixels[(3 * 32 + 5) * 3 + 1],
+                rgb.pixels[(3 * 32 + 5) * 3 + 2]
+            ]
+        );
+
+        assert_eq!(Decoder::new(file).decode_planes().unwrap(), planes);
+    }
+
+    #[test]
+    fn read_coefficients_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let coefficients = read_coefficients(file, &DecodeOptions::default()).unwrap();
+        let planes = decode_planes(file, &DecodeOptions::default()).unwrap();
+
+        let blocks: Vec<_> = coefficients
+            .components
+            .iter()
+            .map(|component| (component.blocks_per_line, component.blocks_per_column))
+            .collect();
+        assert_eq!(blocks, vec![(4, 4), (2, 2), (2, 2)]);
+
+        // transforming the dequantized coefficients gives the samples of the planes
+        let (y, plane) = (&coefficients.components[0], &planes.components[0]);
+        let mut samples = [0u8; 64];
+        idct_block(
+            &y.dequantized(1, 2),
+            &mut samples,
+            8,
+            &FixedPointCosines::default(),
+        );
+        for row in 0..8 {
+            assert_eq!(
+                &samples[row * 8..row * 8 + 8],
+                &plane.samples[(16 + row) * plane.stride + 8..(16 + row) * plane.stride + 16]
+            );
+        }
+
+        assert_eq!(
+            Decoder::new(file).read_coefficients().unwrap(),
+            coefficients
+        );
+    }
+
+    #[test]
+    fn apply_orientation_test() {
+        let original = &include_bytes!("../tests/images/gradient_444.jpg")[..];
+        // SOI followed by an APP1 segment with orientation 6, which is a clockwise quarter turn
+        let mut file = vec![
+            0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x22, 0x45, 0x78, 0x69, 0x66, 0x00, 0x00, 0x4D, 0x4D,
+            0x00, 0x2A, 0x00, 0x00, 0x00, 0x08, 0x00, 0x01, 0x01, 0x12, 0x00, 0x03, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        file.extend(&original[2..]);
+
+        let stored = decode_image(original).unwrap();
+        let rotated = decode_image(&file).unwrap();
+        assert_eq!((rotated.width, rotated.height), (24, 32));
+        assert_eq!(
+            rotated.pixels,
+            Orientation::Rotate90.apply(&stored.pixels, 32, 24, 3).0
+        );
+        assert_eq!(Decoder::new(&file[..]).decode().unwrap(), rotated);
+
+        let unrotated = Decoder::new(&file[..])
+            .apply_orientation(false)
+            .decode()
+            .unwrap();
+        assert_eq!(unrotated, stored);
+    }
+
+    #[test]
+    fn color_management_test() {
+        let original = &include_bytes!("../tests/images/gradient_444.jpg")[..];
+        let profile = linear_profile();
+        let mut file = vec![0xFF, 0xD8, 0xFF, 0xE2];
+        file.extend(&((profile.len() + 16) as u16).to_be_bytes());
+        file.extend(b"ICC_PROFILE\x00\x01\x01");
+        file.extend(&profile);
+        file.extend(&original[2..]);
+        assert_eq!(read_icc_profile(&file).as_ref(), Some(&profile));
+
+        let stored = decode_image(&file).unwrap();
+        let managed = Decoder::new(&file[..])
+            .color_management(true)
+            .decode()
+            .unwrap();
+
+        let transform = IccTransform::from_profile(&profile).unwrap();
+        let expected: Vec<u8> = stored
+            .pixels
+            .chunks(3)
+            .flat_map(|rgb| transform.apply([rgb[0], rgb[1], rgb[2]]).to_vec())
+            .collect();
+        assert_eq!(managed.pixels, expected);
+        assert_ne!(managed.pixels, stored.pixels);
+    }
+
+    #[test]
+    fn memory_limit_test() {
+        let file = &include_bytes!("../tests/images/gradient_444.jpg")[..];
+        let limits = |max_memory| Limits {
+            max_memory: Some(max_memory),
+            ..Default::default()
+        };
+
+        // 36 blocks of coefficients and samples plus the largest output format
+        let required = 36 * (128 + 64) + 32 * 24 * 4;
+        assert!(Decoder::new(file).limits(limits(required)).decode().is_ok());
+        match Decoder::new(file).limits(limits(required - 1)).decode() {
+            Err(Error::Decode {
+                error: ParserError::MemoryLimitExceeded { required: r, .. },
+                ..
+            }) => assert_eq!(r, required),
+            other => panic!("unexpected result {:?}", other.map(|image| image.width)),
+        }
+
+        // a 65535x65535 frame is rejected by default, without allocating its buffers
+        let mut huge = include_bytes!("../tests/images/gray.jpg")[..400].to_vec();
+        huge[25..29].copy_from_slice(&[0xff; 4]);
+        assert!(Decoder::new(&huge[..]).estimated_memory().unwrap() > 512 << 20);
+        for error in &[
+            decode_image(&huge).unwrap_err(),
+            Decoder::new(&huge[..]).decode().unwrap_err(),
+        ] {
+            assert!(
+                matches!(
+                    error.parser_error(),
+                    Some(ParserError::MemoryLimitExceeded { limit, .. }) if *limit == 512 << 20
+                ),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn batch_test() {
+        let mut corrupt = include_bytes!("../tests/images/gradient_420.jpg").to_vec();
+        corrupt.truncate(100);
+        let inputs = [
+            &include_bytes!("../tests/images/gradient_420.jpg")[..],
+            &include_bytes!("../tests/images/gray.jpg")[..],
+            &corrupt,
+            &include_bytes!("../tests/images/progressive_420.jpg")[..],
+            &include_bytes!("../tests/images/restart_420.jpg")[..],
+        ];
+        let options = DecodeOptions {
+            pixel_format: Some(PixelFormat::Rgba8),
+            ..Default::default()
+        };
+
+        let images = decode_batch_with_options(&[&inputs[..]; 8].concat(), &options);
+        assert_eq!(images.len(), 40);
+        for (image, input) in images.iter().zip(inputs.iter().cycle()) {
+            match decode_image_with_options(input, &options) {
+                Ok(expected) => assert_eq!(image.as_ref().unwrap(), &expected),
+                Err(_) => assert!(image.is_err()),
+            }
+        }
+        assert!(decode_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn scratch_test() {
+        let options = DecodeOptions {
+            upsampling: Upsampling::Triangle,
+            ..Default::default()
+        };
+        let mut scratch = DecoderScratch::default();
+
+        let mut pixels = None;
+        for file in &[
+            &include_bytes!("../tests/images/gradient_420.jpg")[..],
+            &include_bytes!("../tests/images/restart_420.jpg")[..],
+            &include_bytes!("../tests/images/progressive_420.jpg")[..],
+        ] {
+            let image = decode_image_with_scratch(file, &options, &mut scratch).unwrap();
+            assert_eq!(image, decode_image_with_options(file, &options).unwrap());
+
+            // the images have the same size, so every decode after the first one reuses the recycled buffers
+            let address = image.pixels.as_ptr();
+            assert_eq!(*pixels.get_or_insert(address), address);
+            scratch.recycle(image);
+        }
+        let coefficients = scratch.coefficients[0].as_ptr();
+
+        let image = Decoder::new(&include_bytes!("../tests/images/gradient_444.jpg")[..])
+            .options(options)
+            .decode_with_scratch(&mut scratch)
+            .unwrap();
+        assert_eq!(image.pixels.as_ptr(), pixels.unwrap());
+        assert!(scratch
+            .coefficients
+            .iter()
+            .any(|c| c.as_ptr() == coefficients));
+        assert!(decode_image_with_scratch(&[0xff, 0xd8], &options, &mut scratch).is_err());
+    }
+
+    #[test]
+    fn decode_stats_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let (image, stats) = decode_image_with_stats(file, &DecodeOptions::default()).unwrap();
+        assert_eq!(image, decode_image(file).unwrap());
+
+        assert_eq!((stats.scans, stats.restart_markers), (1, 0));
+        // 4 MCUs of 4 luma and 2 chroma blocks, each starting with a DC symbol
+        let dc_symbols: Vec<_> = stats
+            .huffman_symbols
+            .iter()
+            .filter(|&&(class, _)| class == DHTType::LuminanceDC || class == DHTType::ChrominanceDC)
+            .cloned()
+            .collect();
+        assert_eq!(
+            dc_symbols,
+            vec![(DHTType::LuminanceDC, 16), (DHTType::ChrominanceDC, 8)]
+        );
+        assert!(stats.entropy_coded_bits > 0 && stats.entropy_coded_bits <= file.len() as u64 * 8);
+
+        let (_, restart) = Decoder::new(&include_bytes!("../tests/images/restart_420.jpg")[..])
+            .decode_with_stats()
+            .unwrap();
+        // an interval of 2 of the 4 MCUs
+        assert_eq!(restart.restart_markers, 1);
+
+        let progressive = &include_bytes!("../tests/images/progressive_420.jpg")[..];
+        let (_, stats) = decode_image_with_stats(progressive, &DecodeOptions::default()).unwrap();
+        assert_eq!(stats.scans, 12);
+    }
+
+    #[test]
+    fn memory_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let mut decoder = Decoder::new(file);
+        // 4 MCUs of 6 blocks of 128 bytes of coefficients and 64 samples, and 32x24 pixels of 4 bytes
+        assert_eq!(decoder.estimated_memory().unwrap(), 24 * 192 + 32 * 24 * 4);
+        let (image, stats) = decoder.decode_with_stats().unwrap();
+        assert_eq!(image, decode_image(file).unwrap());
+        // coefficients and samples of the blocks, three upsampled planes, the RGB pixels and the entropy coded data
+        let buffers = 24 * 192 + 32 * 24 * 3 + 32 * 24 * 3;
+        assert!(stats.allocated_bytes >= buffers as u64);
+        assert!(stats.allocated_bytes < (buffers + file.len()) as u64);
+
+        let limits = Limits {
+            max_width: Some(16),
+            ..Limits::default()
+        };
+        let mut decoder = Decoder::new(file).limits(limits);
+        assert!(matches!(
+            decoder.estimated_memory(),
+            Err(Error::Parser(ParserError::LimitsExceeded { .. }))
+        ));
+        assert!(matches!(
+            Decoder::new(&file[..20]).estimated_memory(),
+            Err(Error::Parser(ParserError::UnexpectedEof))
+        ));
+    }
+
+    #[test]
+    fn stream_decode_test() {
+        let truncated = {
+            let file = include_bytes!("../tests/images/gradient_420.jpg");
+            &file[..file.len() - 60]
+        };
+        let mut garbage = vec![0x00, 0xFF, 0xD8, 0x12];
+        garbage.extend(&include_bytes!("../tests/images/gradient_444.jpg")[..]);
+        let skip_garbage = DecodeOptions {
+            parse_options: ParseOptions {
+                skip_leading_garbage: true,
+                ..DecodeOptions::default().parse_options
+            },
+            ..Default::default()
+        };
+
+        for &(file, ref options) in &[
+            (
+                &include_bytes!("../tests/images/gradient_444.jpg")[..],
+                DecodeOptions::default(),
+            ),
+            (
+                &include_bytes!("../tests/images/gradient_420.jpg")[..],
+                DecodeOptions::default(),
+            ),
+            (
+                &include_bytes!("../tests/images/restart_420.jpg")[..],
+                DecodeOptions::default(),
+            ),
+            (
+                &include_bytes!("../tests/images/gray.jpg")[..],
+                DecodeOptions::default(),
+            ),
+            (truncated, DecodeOptions::default()),
+            (&garbage[..], skip_garbage),
+        ] {
+            assert_eq!(
+                Decoder::new(file).options(*options).decode().unwrap(),
+                decode_image_with_options(file, options).unwrap()
+            );
+        }
+
+        assert!(Decoder::new(&garbage[..]).decode().is_err());
+    }
+
+    #[test]
+    fn error_context_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let context = |stage, marker, offset| {
+            Some(ErrorContext {
+                stage,
+                marker: Some(marker),
+                offset,
+            })
+        };
+
+        // the DHT segment at offset 210 ends with the file
+        let truncated = &file[..300];
+        // the first component of the scan at offset 609 references undefined huffman tables
+        let mut undefined_tables = file.to_vec();
+        undefined_tables[615] = 0x22;
+        // the first component of the frame at offset 20 uses an undefined quantization table
+        let mut undefined_quantization = file.to_vec();
+        undefined_quantization[32] = 3;
+
+        for &(file, expected) in &[
+            (truncated, context(Stage::Parse, 0xc4, 210)),
+            (&undefined_tables[..], context(Stage::Entropy, 0xda, 609)),
+            (&undefined_quantization[..], context(Stage::Idct, 0xc0, 20)),
+        ] {
+            let error = decode_image(file).unwrap_err();
+            assert_eq!(error.context(), expected, "{}", error);
+            let error = Decoder::new(file).decode().unwrap_err();
+            assert_eq!(error.context(), expected, "{}", error);
+        }
+
+        assert!(matches!(
+            decode_image(&undefined_tables).unwrap_err().parser_error(),
+            Some(ParserError::BadHuffmanTable { id: 2 })
+        ));
+        assert_eq!(
+            decode_planes(&undefined_quantization, &DecodeOptions::default()).unwrap_err().to_string(),
+            "Parser Error: undefined or invalid quantization table 3 in SOF0 at offset 20, during the IDCT"
+        );
+    }
+
+    #[test]
+    fn differential_test() {
+        let gray = &include_bytes!("../tests/images/gray.jpg")[..];
+        // the slice decoder parses all segments before decoding the first scan, so the errors of both decoders
+        // can still differ
+        let agree = |file: &[u8]| match (decode_image(file), Decoder::new(file).decode()) {
+            (Ok(slice), Ok(stream)) => assert!(slice == stream),
+            (Err(_), Err(_)) => {}
+            (slice, stream) => panic!("{:?} != {:?}", slice.err(), stream.err()),
+        };
+
+        // the lengths of SOS and SOF segments have to match their content
+        let mut long_scan_header = gray.to_vec();
+        long_scan_header[390] = 12;
+        assert_eq!(
+            decode_image(&long_scan_header).unwrap_err().parser_error(),
+            Some(&ParserError::InvalidMarkerLength { offset: 387 })
+        );
+        let mut long_frame_header = gray.to_vec();
+        long_frame_header[23] += 1;
+        assert_eq!(
+            decode_image(&long_frame_header).unwrap_err().parser_error(),
+            Some(&ParserError::InvalidMarkerLength { offset: 20 })
+        );
+        // a stray marker in the middle of the entropy coded data ends the scan early
+        let gradient = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let mut stray_marker = gradient[..700].to_vec();
+        stray_marker.extend_from_slice(&[0xff, 0xa7]);
+        stray_marker.extend_from_slice(&gradient[700..]);
+        assert!(decode_image(&stray_marker).is_err());
+        for file in &[long_scan_header, long_frame_header, stray_marker] {
+            agree(file);
+        }
+        // every header byte replaced by a few values
+        let sos = gray.windows(2).position(|w| w == b"\xff\xda").unwrap();
+        for i in 2..sos + 10 {
+            for &value in &[0x00, 0x01, 0xff, gray[i].wrapping_add(4)] {
+                let mut file = gray.to_vec();
+                file[i] = value;
+                agree(&file);
+            }
+        }
+    }
+
+    #[test]
+    fn fill_bytes_test() {
+        let file = &include_bytes!("../tests/images/gray.jpg")[..];
+        let sos = file.windows(2).position(|w| w == b"\xff\xda").unwrap();
+        // fill bytes between header segments and between the scan and EOI
+        let mut filled = file[..sos].to_vec();
+        filled.extend_from_slice(&[0xff, 0xff]);
+        filled.extend_from_slice(&file[sos..file.len() - 2]);
+        filled.extend_from_slice(&[0xff, 0xff, 0xff, 0xd9]);
+
+        let expected = decode_image(file).unwrap();
+        assert_eq!(decode_image(&filled).unwrap(), expected);
+        assert_eq!(Decoder::new(&filled[..]).decode().unwrap(), expected);
+        assert_eq!(
+            Decoder::new(&filled[..]).estimated_memory().unwrap(),
+            Decoder::new(file).estimated_memory().unwrap()
+        );
+        assert_eq!(decode(&filled).unwrap(), decode(file).unwrap());
+    }
+
+    #[test]
+    fn short_length_test() {
+        let file = &b"\xff\xd8\xff\xe0\x00\x00\xff\xd9"[..];
+        let error = ParserError::InvalidMarkerLength { offset: 2 };
+        assert_eq!(decode_image(file).unwrap_err().parser_error(), Some(&error));
+        assert_eq!(
+            Decoder::new(file).decode().unwrap_err().parser_error(),
+            Some(&error)
+        );
+        assert_eq!(
+            Decoder::new(file)
+                .estimated_memory()
+                .unwrap_err()
+                .parser_error(),
+            Some(&error)
+        );
+    }
+
+    #[test]
+    fn truncated_segment_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+
+        // the SOF0 segment at offset 20 ends at 39, the first DQT segment at 108 and the second DHT at 393
+        for &(length, marker, missing) in &[(30, 0xc0, 9), (100, 0xdb, 8), (300, 0xc4, 93)] {
+            let expected = ParserError::TruncatedSegment { marker, missing };
+            let error = decode_image(&file[..length]).unwrap_err();
+            assert_eq!(error.parser_error(), Some(&expected));
+            let error = Decoder::new(&file[..length]).decode().unwrap_err();
+            assert_eq!(error.parser_error(), Some(&expected));
+        }
+
+        assert_eq!(
+            parse_segment(&file[20..30]),
+            Err(ParserError::TruncatedSegment {
+                marker: 0xc0,
+                missing: 9
+            })
+        );
+        assert_eq!(
+            ParserError::TruncatedSegment {
+                marker: 0xc4,
+                missing: 93
+            }
+            .to_string(),
+            "DHT segment truncated, 93 bytes missing"
+        );
+    }
+}
@@ -0,0 +1,335 @@
+// Editing of the metadata segments of JPEG files without decoding them.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use encoder::{write_segment, EXIF_HEADER, MAX_SEGMENT_DATA, XMP_HEADER};
+use errors::{EncodeError, Error};
+use exif::{exif_orientation, Orientation, ORIENTATION_TAG, SHORT};
+use parser::header_segments;
+use tiff::{ByteOrder, Tiff};
+
+// Segment is a marker segment in front of the first scan, data is its content without the length bytes
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    pub tag: u8,
+    pub data: Vec<u8>,
+}
+
+// JpegFile holds the segments of a file in front of its first scan as a list to edit and everything from the
+// first scan on as it is. to_bytes writes the file back with the edited segments, so the image doesn't change.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct JpegFile {
+    pub segments: Vec<Segment>,
+    // image holds the scans and everything following them, starting with the SOS marker of the first scan
+    pub image: Vec<u8>,
+}
+
+impl Segment {
+    pub fn new(tag: u8, data: Vec<u8>) -> Segment {
+        Segment { tag, data }
+    }
+
+    // is tells if the segment has the given tag and its data starts with identifier, like b"Exif\0\0" for APP1
+    pub fn is(&self, tag: u8, identifier: &[u8]) -> bool {
+        self.tag == tag && self.data.starts_with(identifier)
+    }
+}
+
+impl JpegFile {
+    pub fn parse(jpeg_file: &[u8]) -> Result<JpegFile, Error> {
+        let segments = header_segments(jpeg_file)?;
+        let image = segments
+            .last()
+            .map_or(2, |segment| segment.offset + 4 + segment.data.len());
+        Ok(JpegFile {
+            segments: segments
+                .iter()
+                .map(|segment| Segment::new(segment.tag, segment.data.to_vec()))
+                .collect(),
+            image: jpeg_file[image..].to_vec(),
+        })
+    }
+
+    // to_bytes writes the file, failing for segments that are too long or have a tag without a length field
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let length: usize = self.segments.iter().map(|s| s.data.len() + 4).sum();
+        let mut output = Vec::with_capacity(2 + length + self.image.len());
+        output.extend_from_slice(b"\xff\xd8");
+        for segment in &self.segments {
+            if matches!(segment.tag, 0x00 | 0x01 | 0xd0..=0xda | 0xff) {
+                return Err(EncodeError::InvalidSegment {
+                    marker: segment.tag,
+                }
+                .into());
+            }
+            if segment.data.len() > MAX_SEGMENT_DATA {
+                return Err(EncodeError::MetadataTooLarge {
+                    marker: segment.tag,
+                    length: segment.data.len(),
+                }
+                .into());
+            }
+            write_segment(&mut output, segment.tag, &segment.data);
+        }
+        output.extend_from_slice(&self.image);
+        Ok(output)
+    }
+
+    // find returns the first segment with the given tag whose data starts with identifier
+    pub fn find(&self, tag: u8, identifier: &[u8]) -> Option<&Segment> {
+        self.segments
+            .iter()
+            .find(|segment| segment.is(tag, identifier))
+    }
+
+    pub fn find_mut(&mut self, tag: u8, identifier: &[u8]) -> Option<&mut Segment> {
+        self.segments
+            .iter_mut()
+            .find(|segment| segment.is(tag, identifier))
+    }
+
+    // insert adds an APPn or COM segment after the last of these whose tag isn't greater, keeping APP0 in front
+    // and tables and the frame header behind the metadata. Without such a segment it goes first.
+    pub fn insert(&mut self, segment: Segment) {
+        let index = self
+            .segments
+            .iter()
+            .rposition(|s| matches!(s.tag, 0xe0..=0xef | 0xfe) && s.tag <= segment.tag)
+            .map_or(0, |index| index + 1);
+        self.segments.insert(index, segment);
+    }
+
+    // remove removes every segment with the given tag whose data starts with identifier, returning how many
+    pub fn remove(&mut self, tag: u8, identifier: &[u8]) -> usize {
+        let count = self.segments.len();
+        self.segments.retain(|segment| !segment.is(tag, identifier));
+        count - self.segments.len()
+    }
+
+    // replace replaces the first segment with the tag of segment whose data starts with identifier, removing
+    // the other ones. Without such a segment, segment is inserted.
+    pub fn replace(&mut self, segment: Segment, identifier: &[u8]) {
+        match self
+            .segments
+            .iter()
+            .position(|s| s.is(segment.tag, identifier))
+        {
+            Some(index) => {
+                let tag = segment.tag;
+                self.segments[index] = segment;
+                let rest = self.segments.split_off(index + 1);
+                self.segments
+                    .extend(rest.into_iter().filter(|s| !s.is(tag, identifier)));
+            }
+            None => self.insert(segment),
+        }
+    }
+
+    // orientation returns the orientation of the first EXIF segment that has one, like read_orientation
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.segments
+            .iter()
+            .filter(|segment| segment.tag == 0xe1)
+            .find_map(|segment| exif_orientation(&segment.data))
+    }
+
+    // set_orientation writes orientation into the EXIF segment, adding the segment if there is none. It fails
+    // for EXIF data that isn't a valid TIFF structure.
+    pub fn set_orientation(&mut self, orientation: Orientation) -> Result<(), Error> {
+        let value = orientation.to_exif();
+        match self.find_mut(0xe1, EXIF_HEADER) {
+            Some(segment) => {
+                let tiff = exif_with_orientation(&segment.data[EXIF_HEADER.len()..], value)
+                    .ok_or(EncodeError::InvalidExif)?;
+                segment.data.truncate(EXIF_HEADER.len());
+                segment.data.extend_from_slice(&tiff);
+            }
+            None => {
+                // a big endian header followed by an IFD0 holding only the orientation
+                let mut data = EXIF_HEADER.to_vec();
+                data.extend_from_slice(b"MM\x00\x2a\x00\x00\x00\x08\x00\x01");
+                data.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+                data.extend_from_slice(&SHORT.to_be_bytes());
+                data.extend_from_slice(&1u32.to_be_bytes());
+                data.extend_from_slice(&value.to_be_bytes());
+                data.extend_from_slice(&[0; 6]);
+                self.insert(Segment::new(0xe1, data));
+            }
+        }
+        Ok(())
+    }
+
+    // xmp returns the XMP packet of the file
+    pub fn xmp(&self) -> Option<&[u8]> {
+        self.find(0xe1, XMP_HEADER)
+            .map(|segment| &segment.data[XMP_HEADER.len()..])
+    }
+
+    // set_xmp replaces the XMP packet of the file or adds one. Packets that don't fit a segment make to_bytes
+    // fail, as extended XMP isn't written.
+    pub fn set_xmp(&mut self, xmp: &[u8]) {
+        let data = [XMP_HEADER, xmp].concat();
+        self.replace(Segment::new(0xe1, data), XMP_HEADER);
+    }
+}
+
+// exif_with_orientation returns the TIFF structure of an EXIF segment with its orientation set to value. An
+// existing orientation entry is overwritten in place. Otherwise IFD0 is copied to the end of the data with the
+// entry added, as other values may follow IFD0 and its entries have to stay sorted by tag.
+fn exif_with_orientation(tiff: &[u8], value: u16) -> Option<Vec<u8>> {
+    let parsed = Tiff::new(tiff).ok()?;
+    let ifd0 = parsed.first_ifd_offset().ok()?;
+    let (entries, next_ifd) = parsed.read_ifd(ifd0).ok()?;
+    let byte_order = parsed.byte_order;
+    let u16_bytes = |value: u16| match byte_order {
+        ByteOrder::LittleEndian => value.to_le_bytes(),
+        ByteOrder::BigEndian => value.to_be_bytes(),
+    };
+    let u32_bytes = |value: u32| match byte_order {
+        ByteOrder::LittleEndian => value.to_le_bytes(),
+        ByteOrder::BigEndian => value.to_be_bytes(),
+    };
+
+    // the orientation is a single SHORT, left justified in its value field
+    let mut orientation = Vec::with_capacity(12);
+    orientation.extend_from_slice(&u16_bytes(ORIENTATION_TAG));
+    orientation.extend_from_slice(&u16_bytes(SHORT));
+    orientation.extend_from_slice(&u32_bytes(1));
+    orientation.extend_from_slice(&u16_bytes(value));
+    orientation.extend_from_slice(&[0, 0]);
+
+    let mut output = tiff.to_vec();
+    let entry = |index: usize| ifd0 + 2 + index * 12;
+    if let Some(index) = entries.iter().position(|e| e.tag == ORIENTATION_TAG) {
+        output[entry(index)..entry(index + 1)].copy_from_slice(&orientation);
+        return Some(output);
+    }
+
+    // IFDs start at word boundaries
+    if output.len() % 2 == 1 {
+        output.push(0);
+    }
+    let offset = output.len();
+    output.extend_from_slice(&u16_bytes(entries.len() as u16 + 1));
+    let position = entries.partition_point(|e| e.tag < ORIENTATION_TAG);
+    output.extend_from_slice(&tiff[entry(0)..entry(position)]);
+    output.extend_from_slice(&orientation);
+    output.extend_from_slice(&tiff[entry(position)..entry(entries.len())]);
+    output.extend_from_slice(&u32_bytes(next_ifd as u32));
+    output[4..8].copy_from_slice(&u32_bytes(offset as u32));
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::{decode_image, ColorType};
+    use encoder::{encode_image_with_options, EncodeOptions};
+    use exif::read_orientation;
+    use transcode::copy_metadata;
+
+    fn file(exif: Option<Vec<u8>>) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..32 * 16 * 3).map(|i| (i % 251) as u8).collect();
+        let options = EncodeOptions {
+            comments: vec![b"hello".to_vec()],
+            icc_profile: Some(vec![5; 30]),
+            exif,
+            ..EncodeOptions::default()
+        };
+        encode_image_with_options(&pixels, 32, 16, ColorType::Rgb, &options).unwrap()
+    }
+
+    #[test]
+    fn parse_test() {
+        let file = file(None);
+        let parsed = JpegFile::parse(&file).unwrap();
+        assert_eq!(parsed.to_bytes().unwrap(), file);
+        let tags: Vec<u8> = parsed.segments.iter().map(|segment| segment.tag).collect();
+        assert_eq!(tags, vec![0xe0, 0xe2, 0xfe, 0xdb, 0xc0, 0xc4]);
+        assert!(parsed.image.starts_with(b"\xff\xda") && parsed.image.ends_with(b"\xff\xd9"));
+        assert!(parsed.find(0xe0, b"JFIF\x00").is_some());
+        assert!(parsed.find(0xe1, EXIF_HEADER).is_none());
+
+        assert!(JpegFile::parse(b"\x00\x01").is_err());
+    }
+
+    #[test]
+    fn edit_test() {
+        let file = file(None);
+        let mut edited = JpegFile::parse(&file).unwrap();
+        assert_eq!(edited.remove(0xfe, b""), 1);
+        assert_eq!(edited.remove(0xfe, b""), 0);
+        edited.insert(Segment::new(0xfe, b"second".to_vec()));
+        edited.insert(Segment::new(0xe1, b"first".to_vec()));
+        edited.set_xmp(b"<x:xmpmeta/>");
+        edited.set_xmp(b"<x:xmpmeta>2</x:xmpmeta>");
+        let tags: Vec<u8> = edited.segments.iter().map(|segment| segment.tag).collect();
+        assert_eq!(tags, vec![0xe0, 0xe1, 0xe1, 0xe2, 0xfe, 0xdb, 0xc0, 0xc4]);
+        assert_eq!(edited.xmp(), Some(&b"<x:xmpmeta>2</x:xmpmeta>"[..]));
+
+        let bytes = edited.to_bytes().unwrap();
+        assert_eq!(decode_image(&bytes).unwrap(), decode_image(&file).unwrap());
+        let mut options = EncodeOptions::default();
+        copy_metadata(&bytes, &mut options).unwrap();
+        assert_eq!(options.comments, vec![b"second".to_vec()]);
+        assert_eq!(options.xmp, Some(b"<x:xmpmeta>2</x:xmpmeta>".to_vec()));
+
+        edited.set_xmp(&[0; MAX_SEGMENT_DATA]);
+        assert!(matches!(
+            edited.to_bytes(),
+            Err(Error::Encoder(EncodeError::MetadataTooLarge {
+                marker: 0xe1,
+                ..
+            }))
+        ));
+        edited.set_xmp(b"");
+        edited.insert(Segment::new(0xda, Vec::new()));
+        assert!(matches!(
+            edited.to_bytes(),
+            Err(Error::Encoder(EncodeError::InvalidSegment { marker: 0xda }))
+        ));
+    }
+
+    #[test]
+    fn set_orientation_test() {
+        // without EXIF data a segment is added behind JFIF
+        let mut edited = JpegFile::parse(&file(None)).unwrap();
+        assert_eq!(edited.orientation(), None);
+        edited.set_orientation(Orientation::Rotate90).unwrap();
+        assert_eq!(edited.segments[1].tag, 0xe1);
+        let bytes = edited.to_bytes().unwrap();
+        assert_eq!(read_orientation(&bytes), Some(Orientation::Rotate90));
+
+        // an existing entry is overwritten in place
+        edited.set_orientation(Orientation::Transverse).unwrap();
+        assert_eq!(edited.to_bytes().unwrap().len(), bytes.len());
+        assert_eq!(edited.orientation(), Some(Orientation::Transverse));
+
+        // little endian EXIF data without an orientation, but with a make tag in front of where it belongs and
+        // an artist tag behind it, pointing at values after IFD0
+        let mut tiff = b"II\x2a\x00\x08\x00\x00\x00\x02\x00".to_vec();
+        tiff.extend_from_slice(b"\x0f\x01\x02\x00\x05\x00\x00\x00\x26\x00\x00\x00");
+        tiff.extend_from_slice(b"\x3b\x01\x02\x00\x04\x00\x00\x00\x2b\x00\x00\x00");
+        tiff.extend_from_slice(b"\x00\x00\x00\x00");
+        tiff.extend_from_slice(b"Make\x00Ann\x00");
+        let mut edited = JpegFile::parse(&file(Some(tiff))).unwrap();
+        edited.set_orientation(Orientation::MirrorVertical).unwrap();
+        assert_eq!(edited.orientation(), Some(Orientation::MirrorVertical));
+        let exif = &edited.find(0xe1, EXIF_HEADER).unwrap().data[EXIF_HEADER.len()..];
+        let parsed = Tiff::new(exif).unwrap();
+        let (entries, next) = parsed.read_ifd(parsed.first_ifd_offset().unwrap()).unwrap();
+        let tags: Vec<u16> = entries.iter().map(|entry| entry.tag).collect();
+        assert_eq!(tags, vec![0x010f, ORIENTATION_TAG, 0x013b]);
+        assert_eq!(next, 0);
+        assert_eq!(
+            parsed.bytes(entries[2].value_offset as usize, 4),
+            Ok(&b"Ann\x00"[..])
+        );
+
+        let mut edited =
+            JpegFile::parse(&file(Some(b"II\x2a\x00\xff\x00\x00\x00".to_vec()))).unwrap();
+        assert!(edited.set_orientation(Orientation::Normal).is_err());
+    }
+}
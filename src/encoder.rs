@@ -0,0 +1,2957 @@
+// Baseline and progressive JPEG encoding of RGB and grayscale pixels into JFIF files.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use core::ops::Range;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use color::{rgb_to_ycbcr, rgb_to_ycbcr_12, ycbcr_to_rgb};
+use decoder::{ColorType, DecodedCoefficients, ZIGZAG};
+use errors::{EncodeError, Error};
+use huffman::{DHTType, HuffmanTable};
+use icc::ICC_MARKER;
+use idct::FLOAT_COSINES;
+use rows::for_each_row;
+use thumbnail::{JPEG_INTERCHANGE_FORMAT_LENGTH_TAG, JPEG_INTERCHANGE_FORMAT_TAG};
+use tiff::{ByteOrder, Tiff};
+
+// LUMINANCE_QUANTIZATION and CHROMINANCE_QUANTIZATION are the example tables of ITU T.81 Annex K.1 in natural
+// order
+const LUMINANCE_QUANTIZATION: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMINANCE_QUANTIZATION: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+// ScanDefinition is a scan of the built in scan scripts: the components, the spectral band and the successive
+// approximation bit positions
+type ScanDefinition = (&'static [usize], (usize, usize), u8, u8);
+
+const fn scan(
+    components: &'static [usize],
+    band: (usize, usize),
+    approximation_high: u8,
+    approximation_low: u8,
+) -> ScanDefinition {
+    (components, band, approximation_high, approximation_low)
+}
+
+const COLOR_BASELINE: [ScanDefinition; 1] = [scan(&[0, 1, 2], (0, 63), 0, 0)];
+const GRAY_BASELINE: [ScanDefinition; 1] = [scan(&[0], (0, 63), 0, 0)];
+const CMYK_BASELINE: [ScanDefinition; 1] = [scan(&[0, 1, 2, 3], (0, 63), 0, 0)];
+
+// COLOR_SCRIPT and GRAY_SCRIPT are the scan scripts of libjpeg's jpeg_simple_progression: the DC coefficients
+// without their lowest bit first, the low luma frequencies and all chroma frequencies next, and the lowest bits
+// of everything last
+const COLOR_SCRIPT: [ScanDefinition; 10] = [
+    scan(&[0, 1, 2], (0, 0), 0, 1),
+    scan(&[0], (1, 5), 0, 2),
+    scan(&[2], (1, 63), 0, 1),
+    scan(&[1], (1, 63), 0, 1),
+    scan(&[0], (6, 63), 0, 2),
+    scan(&[0], (1, 63), 2, 1),
+    scan(&[0, 1, 2], (0, 0), 1, 0),
+    scan(&[2], (1, 63), 1, 0),
+    scan(&[1], (1, 63), 1, 0),
+    scan(&[0], (1, 63), 1, 0),
+];
+const GRAY_SCRIPT: [ScanDefinition; 6] = [
+    scan(&[0], (0, 0), 0, 1),
+    scan(&[0], (1, 5), 0, 2),
+    scan(&[0], (6, 63), 0, 2),
+    scan(&[0], (1, 63), 2, 1),
+    scan(&[0], (0, 0), 1, 0),
+    scan(&[0], (1, 63), 1, 0),
+];
+// CMYK_SCRIPT is libjpeg's script for other component counts, refining the bands of every component in turn
+const CMYK_SCRIPT: [ScanDefinition; 18] = [
+    scan(&[0, 1, 2, 3], (0, 0), 0, 1),
+    scan(&[0], (1, 5), 0, 2),
+    scan(&[1], (1, 5), 0, 2),
+    scan(&[2], (1, 5), 0, 2),
+    scan(&[3], (1, 5), 0, 2),
+    scan(&[0], (6, 63), 0, 2),
+    scan(&[1], (6, 63), 0, 2),
+    scan(&[2], (6, 63), 0, 2),
+    scan(&[3], (6, 63), 0, 2),
+    scan(&[0], (1, 63), 2, 1),
+    scan(&[1], (1, 63), 2, 1),
+    scan(&[2], (1, 63), 2, 1),
+    scan(&[3], (1, 63), 2, 1),
+    scan(&[0, 1, 2, 3], (0, 0), 1, 0),
+    scan(&[0], (1, 63), 1, 0),
+    scan(&[1], (1, 63), 1, 0),
+    scan(&[2], (1, 63), 1, 0),
+    scan(&[3], (1, 63), 1, 0),
+];
+
+// TABLE_CLASSES orders the Huffman tables as they are indexed while encoding, the AC tables start at AC_TABLES
+const TABLE_CLASSES: [DHTType; 4] = [
+    DHTType::LuminanceDC,
+    DHTType::ChrominanceDC,
+    DHTType::LuminanceAC,
+    DHTType::ChrominanceAC,
+];
+const AC_TABLES: usize = 2;
+
+// MAX_SEGMENT_DATA is the most data a segment can hold besides its length bytes
+pub(crate) const MAX_SEGMENT_DATA: usize = 0xffff - 2;
+pub(crate) const EXIF_HEADER: &[u8] = b"Exif\x00\x00";
+pub(crate) const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\x00";
+// JFXX_JPEG_HEADER starts JFXX segments with thumbnails coded as JPEG
+const JFXX_JPEG_HEADER: &[u8] = b"JFXX\x00\x10";
+const COMPRESSION_TAG: u16 = 0x0103;
+
+// MAX_EOB_RUN is the longest end of band run EOBn symbols can code
+const MAX_EOB_RUN: u32 = 0x7fff;
+
+// TRELLIS_LAMBDA weighs a bit of the coded size against the squared quantization error of a coefficient, measured
+// in quantizer steps
+const TRELLIS_LAMBDA: f32 = 0.1;
+
+type Block = [i16; 64];
+// CodeTable holds the Huffman code and its length for every symbol
+pub(crate) type CodeTable = [(u16, u8); 256];
+
+// ChromaSubsampling selects the resolution of the chroma components RGB pixels are stored with. Gray stores only
+// the luma component.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ChromaSubsampling {
+    Chroma444,
+    Chroma422,
+    #[default]
+    Chroma420,
+    Gray,
+}
+
+impl ChromaSubsampling {
+    // luma_sampling returns the sampling factors of the luma component relative to the chroma components
+    fn luma_sampling(self) -> (usize, usize) {
+        match self {
+            ChromaSubsampling::Chroma444 | ChromaSubsampling::Gray => (1, 1),
+            ChromaSubsampling::Chroma422 => (2, 1),
+            ChromaSubsampling::Chroma420 => (2, 2),
+        }
+    }
+}
+
+// DensityUnit is the unit of the pixel density in the JFIF header
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DensityUnit {
+    // AspectRatio gives no physical size, the densities only describe the pixel aspect ratio
+    #[default]
+    AspectRatio,
+    DotsPerInch,
+    DotsPerCentimeter,
+}
+
+// Density is the number of pixels per unit horizontally and vertically
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Density {
+    pub unit: DensityUnit,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl Default for Density {
+    // default describes square pixels
+    fn default() -> Density {
+        Density {
+            unit: DensityUnit::AspectRatio,
+            x: 1,
+            y: 1,
+        }
+    }
+}
+
+// CmykTransform selects how CMYK pixels are stored, as recorded in the transform flag of the Adobe APP14 segment
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum CmykTransform {
+    // None stores the four inks as they are
+    #[default]
+    None,
+    // Ycck converts cyan, magenta and yellow like RGB into YCbCr, which compresses better and allows subsampling
+    // the chroma components
+    Ycck,
+}
+
+// Optimization selects how coefficients are quantized
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Optimization {
+    // None rounds every coefficient to the nearest multiple of its quantizer
+    #[default]
+    None,
+    // Trellis chooses the AC coefficients of every block like mozjpeg, trading quantization error against the
+    // bits the Annex K tables code them with. Small coefficients are often rounded down or dropped.
+    Trellis,
+}
+
+// ThumbnailSegment selects where a generated thumbnail is embedded
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ThumbnailSegment {
+    // Exif stores the thumbnail in IFD1 of the EXIF segment, which is created if the options have no exif
+    #[default]
+    Exif,
+    // Jfxx stores it in a JFXX APP0 segment following the JFIF header, CMYK files have neither
+    Jfxx,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ThumbnailOptions {
+    pub segment: ThumbnailSegment,
+    // max_size is the length of the long edge of the thumbnail, smaller images are embedded at their size. It
+    // defaults to 160.
+    pub max_size: u16,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> ThumbnailOptions {
+        ThumbnailOptions {
+            segment: ThumbnailSegment::default(),
+            max_size: 160,
+        }
+    }
+}
+
+// Scan is one scan of a scan script. components are indices into the components of the frame, in increasing
+// order: Y, Cb and Cr for color images, C, M, Y and K for CMYK. The spectral band covers the coefficients
+// spectral_start to spectral_end in zigzag order. Progressive scans code the coefficients shifted right by
+// approximation_low bits, refining scans code the bit below approximation_high, the shift of the previous scan of
+// the band.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Scan {
+    pub components: Vec<usize>,
+    pub spectral_start: usize,
+    pub spectral_end: usize,
+    pub approximation_high: u8,
+    pub approximation_low: u8,
+}
+
+impl Scan {
+    fn from_definition(
+        &(components, (spectral_start, spectral_end), high, low): &ScanDefinition,
+    ) -> Scan {
+        Scan {
+            components: components.to_vec(),
+            spectral_start,
+            spectral_end,
+            approximation_high: high,
+            approximation_low: low,
+        }
+    }
+}
+
+// PixelLayout is the kind of pixels an image is encoded from
+#[derive(Clone, Copy)]
+enum PixelLayout {
+    Gray,
+    Rgb,
+    Cmyk(CmykTransform),
+}
+
+impl PixelLayout {
+    fn channels(self) -> usize {
+        match self {
+            PixelLayout::Gray => 1,
+            PixelLayout::Rgb => 3,
+            PixelLayout::Cmyk(_) => 4,
+        }
+    }
+
+    // components returns the horizontal and vertical sampling factors and the table of every component
+    fn components(self, subsampling: ChromaSubsampling) -> Vec<(usize, usize, usize)> {
+        let (h, v) = subsampling.luma_sampling();
+        match self {
+            PixelLayout::Gray => vec![(1, 1, 0)],
+            PixelLayout::Rgb if subsampling == ChromaSubsampling::Gray => vec![(1, 1, 0)],
+            PixelLayout::Rgb => vec![(h, v, 0), (1, 1, 1), (1, 1, 1)],
+            PixelLayout::Cmyk(CmykTransform::None) => vec![(1, 1, 0); 4],
+            PixelLayout::Cmyk(CmykTransform::Ycck) => {
+                vec![(h, v, 0), (1, 1, 1), (1, 1, 1), (h, v, 0)]
+            }
+        }
+    }
+}
+
+// Plane is a borrowed component plane with stride bytes per line
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Plane<'a> {
+    pub samples: &'a [u8],
+    pub stride: usize,
+}
+
+// YCbCrPlanes are the planes of an image already converted into YCbCr and subsampled, as cameras and video
+// decoders produce them
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct YCbCrPlanes<'a> {
+    pub y: Plane<'a>,
+    pub cb: Plane<'a>,
+    pub cr: Plane<'a>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EncodeOptions {
+    // quality scales the Annex K quantization tables like libjpeg's -quality, from 1 (smallest files) to 100
+    // (all quantizers 1). 50 uses the tables as given, values outside of 1..=100 are clamped. It defaults to 75
+    // as in libjpeg.
+    pub quality: u8,
+    // quantization_tables replaces the scaled tables with caller defined luma and chroma tables in natural order,
+    // ignoring quality. Tables with entries above 255 are written with 16 bit precision, which baseline files
+    // don't allow, so sequential files use the extended sequential process then.
+    pub quantization_tables: Option<[[u16; 64]; 2]>,
+    // subsampling applies to RGB pixels and the chroma components of YCCK, gray pixels are always stored as a
+    // single component. YCCK keeps all components for Gray.
+    pub subsampling: ChromaSubsampling,
+    // progressive spreads the coefficients over the scans of the standard scan script instead of a single
+    // baseline scan
+    pub progressive: bool,
+    // scan_script replaces the standard scan script. Sequential scripts code every component once with all
+    // coefficients, progressive scripts have to send the DC coefficients of every component before their AC
+    // coefficients and refine every band one bit at a time.
+    pub scan_script: Option<Vec<Scan>>,
+    // optimize_huffman gathers the symbol frequencies of the image in a first pass and writes Huffman tables
+    // built from them instead of the Annex K tables
+    pub optimize_huffman: bool,
+    // optimize selects rate distortion optimized quantization
+    pub optimize: Optimization,
+    // restart_interval is the number of MCUs between restart markers, 0 writes none. Restart intervals limit the
+    // damage of transmission errors and, with the rayon feature, are entropy coded in parallel.
+    pub restart_interval: u16,
+    // density is written to the JFIF header, CMYK files have none
+    pub density: Density,
+    // exif is the TIFF structure of an EXIF APP1 segment, with or without the Exif header
+    pub exif: Option<Vec<u8>>,
+    // xmp is the XMP packet of an APP1 segment
+    pub xmp: Option<Vec<u8>>,
+    // icc_profile is split into as many APP2 chunks as it needs
+    pub icc_profile: Option<Vec<u8>>,
+    // comments are written as COM segments, comments longer than a segment continue in the next one
+    pub comments: Vec<Vec<u8>>,
+    // thumbnail embeds a thumbnail encoded from the image with the same quality
+    pub thumbnail: Option<ThumbnailOptions>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            quality: 75,
+            quantization_tables: None,
+            subsampling: ChromaSubsampling::default(),
+            progressive: false,
+            scan_script: None,
+            optimize_huffman: false,
+            optimize: Optimization::None,
+            restart_interval: 0,
+            density: Density::default(),
+            exif: None,
+            xmp: None,
+            icc_profile: None,
+            comments: Vec::new(),
+            thumbnail: None,
+        }
+    }
+}
+
+struct Component {
+    id: u8,
+    horizontal_sampling: usize,
+    vertical_sampling: usize,
+    // table is 0 for luma, K and CMYK inks and 1 for chroma, selecting the quantization and Huffman tables
+    table: usize,
+    blocks_per_line: usize,
+    // scan_blocks are the blocks per line and column inside the image, which non-interleaved scans cover
+    scan_blocks: (usize, usize),
+    // blocks holds the quantized coefficients of every block in zigzag order
+    blocks: Vec<Block>,
+}
+
+// SourcePlane is a plane of width x height samples a component is encoded from. ratio is the number of samples
+// per component sample horizontally and vertically.
+struct SourcePlane<'a, T> {
+    samples: &'a [T],
+    width: usize,
+    height: usize,
+    stride: usize,
+    ratio: (usize, usize),
+}
+
+struct Frame {
+    // precision is the number of bits per sample, 8 or 12
+    precision: u8,
+    mcus_per_line: usize,
+    mcus_per_column: usize,
+    components: Vec<Component>,
+}
+
+// encode_image encodes width x height pixels of the given color type into a baseline JFIF file of quality 75. RGB
+// pixels are stored as YCbCr with 4:2:0 chroma subsampling, gray pixels as a single component.
+pub fn encode_image(
+    pixels: &[u8],
+    width: u64,
+    height: u64,
+    color_type: ColorType,
+) -> Result<Vec<u8>, Error> {
+    encode_image_with_options(pixels, width, height, color_type, &EncodeOptions::default())
+}
+
+pub fn encode_image_with_options(
+    pixels: &[u8],
+    width: u64,
+    height: u64,
+    color_type: ColorType,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, Error> {
+    let layout = match color_type {
+        ColorType::Gray => PixelLayout::Gray,
+        ColorType::Rgb => PixelLayout::Rgb,
+    };
+    encode(pixels, (width, height), layout, options)
+}
+
+// encode_image_with_target_size encodes like encode_image_with_options with the highest quality whose file has at
+// most max_size bytes, found by a binary search over quality, so it takes about eight encodings. Caller defined
+// quantization tables are replaced by scaled standard tables. File sizes grow with the quality nearly everywhere,
+// where they don't the search may settle on a slightly lower quality than possible.
+pub fn encode_image_with_target_size(
+    pixels: &[u8],
+    (width, height): (u64, u64),
+    color_type: ColorType,
+    options: &EncodeOptions,
+    max_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let encode_quality = |quality| {
+        let options = EncodeOptions {
+            quality,
+            quantization_tables: None,
+            ..options.clone()
+        };
+        encode_image_with_options(pixels, width, height, color_type, &options)
+    };
+
+    let mut best = encode_quality(1)?;
+    if best.len() > max_size {
+        return Err(EncodeError::TargetSizeUnreachable {
+            target: max_size,
+            smallest: best.len(),
+        }
+        .into());
+    }
+    // the best quality is at least low and below high
+    let (mut low, mut high) = (1u8, 101u8);
+    while high - low > 1 {
+        let quality = low + (high - low) / 2;
+        let file = encode_quality(quality)?;
+        if file.len() <= max_size {
+            low = quality;
+            best = file;
+        } else {
+            high = quality;
+        }
+    }
+    Ok(best)
+}
+
+// encode_cmyk_image encodes width x height CMYK pixels, four bytes with 255 for full ink each, into a file with an
+// Adobe APP14 segment. Like Photoshop, the inks and K of YCCK are stored inverted.
+pub fn encode_cmyk_image(
+    pixels: &[u8],
+    width: u64,
+    height: u64,
+    transform: CmykTransform,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, Error> {
+    encode(
+        pixels,
+        (width, height),
+        PixelLayout::Cmyk(transform),
+        options,
+    )
+}
+
+// encode_ycbcr_planes encodes width x height pixels given as YCbCr planes into a JFIF file, skipping color
+// conversion and downsampling. options.subsampling is the resolution of the chroma planes, which have
+// ceil(width / 2) samples per line for 4:2:2 and 4:2:0 and ceil(height / 2) lines for 4:2:0. For Gray only the Y
+// plane is encoded.
+pub fn encode_ycbcr_planes(
+    planes: &YCbCrPlanes,
+    width: u64,
+    height: u64,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = check_dimensions(width, height)?;
+    let layout = match options.subsampling {
+        ChromaSubsampling::Gray => PixelLayout::Gray,
+        _ => PixelLayout::Rgb,
+    };
+    let sampling = layout.components(options.subsampling);
+    let (max_horizontal_sampling, max_vertical_sampling, _) = sampling[0];
+
+    let mut sources = Vec::with_capacity(sampling.len());
+    for (plane, &(h, v, _)) in [planes.y, planes.cb, planes.cr].iter().zip(&sampling) {
+        let plane_width = (width * h).div_ceil(max_horizontal_sampling);
+        let plane_height = (height * v).div_ceil(max_vertical_sampling);
+        if plane.stride < plane_width {
+            return Err(EncodeError::InvalidStride {
+                stride: plane.stride,
+                width: plane_width,
+            }
+            .into());
+        }
+        let expected = plane.stride * (plane_height - 1) + plane_width;
+        if plane.samples.len() < expected {
+            return Err(EncodeError::InvalidBufferSize {
+                expected,
+                actual: plane.samples.len(),
+            }
+            .into());
+        }
+        sources.push(SourcePlane {
+            samples: plane.samples,
+            width: plane_width,
+            height: plane_height,
+            stride: plane.stride,
+            ratio: (1, 1),
+        });
+    }
+
+    let quantization_tables = quantization_tables(options)?;
+    let frame = Frame::new(
+        &sources,
+        (width, height),
+        &sampling,
+        (&quantization_tables, options.optimize),
+        8,
+    );
+    let thumbnail = encode_thumbnail(options, (width, height), layout, |x, y| {
+        let luma = sources[0].samples[y * sources[0].stride + x];
+        if sources.len() == 1 {
+            return [luma; 3];
+        }
+        let (h, v, _) = sampling[1];
+        let chroma = (
+            x * h / max_horizontal_sampling,
+            y * v / max_vertical_sampling,
+        );
+        let at = |plane: &SourcePlane<u8>| plane.samples[chroma.1 * plane.stride + chroma.0];
+        ycbcr_to_rgb(luma, at(&sources[1]), at(&sources[2]))
+    })?;
+    write_file(
+        &frame,
+        (width, height),
+        layout,
+        &quantization_tables,
+        (options, thumbnail.as_deref()),
+    )
+}
+
+// encode_image_12bit encodes width x height pixels of 12 bit samples into an extended sequential or progressive
+// file with 12 bit precision. The samples are the low bits of pixels, gray or RGB as in encode_image_with_options.
+// The Annex K Huffman tables don't cover the coefficients of 12 bit samples, so the tables are always built from
+// the image.
+pub fn encode_image_12bit(
+    pixels: &[u16],
+    width: u64,
+    height: u64,
+    color_type: ColorType,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = check_dimensions(width, height)?;
+    let layout = match color_type {
+        ColorType::Gray => PixelLayout::Gray,
+        ColorType::Rgb => PixelLayout::Rgb,
+    };
+    let expected = width * height * layout.channels();
+    if pixels.len() != expected {
+        return Err(EncodeError::InvalidBufferSize {
+            expected,
+            actual: pixels.len(),
+        }
+        .into());
+    }
+    if let Some(&value) = pixels.iter().find(|&&value| value > 0xfff) {
+        return Err(EncodeError::SampleOutOfRange {
+            value,
+            precision: 12,
+        }
+        .into());
+    }
+
+    let quantization_tables = quantization_tables(options)?;
+    let sampling = layout.components(options.subsampling);
+    let (max_horizontal_sampling, max_vertical_sampling, _) = sampling[0];
+    let planes: Vec<Vec<u16>> = match layout {
+        PixelLayout::Rgb => {
+            let mut planes = vec![Vec::with_capacity(width * height); sampling.len()];
+            for pixel in pixels.chunks(3) {
+                let ycc = rgb_to_ycbcr_12(pixel[0], pixel[1], pixel[2]);
+                for (plane, &sample) in planes.iter_mut().zip(&ycc) {
+                    plane.push(sample);
+                }
+            }
+            planes
+        }
+        _ => vec![pixels.to_vec()],
+    };
+    let sources: Vec<_> = planes
+        .iter()
+        .zip(&sampling)
+        .map(|(plane, &(h, v, _))| SourcePlane {
+            samples: plane,
+            width,
+            height,
+            stride: width,
+            ratio: (max_horizontal_sampling / h, max_vertical_sampling / v),
+        })
+        .collect();
+    let frame = Frame::new(
+        &sources,
+        (width, height),
+        &sampling,
+        (&quantization_tables, options.optimize),
+        12,
+    );
+    let thumbnail = encode_thumbnail(options, (width, height), layout, |x, y| {
+        let index = (y * width + x) * layout.channels();
+        match layout {
+            PixelLayout::Rgb => [
+                (pixels[index] >> 4) as u8,
+                (pixels[index + 1] >> 4) as u8,
+                (pixels[index + 2] >> 4) as u8,
+            ],
+            _ => [(pixels[index] >> 4) as u8; 3],
+        }
+    })?;
+    write_file(
+        &frame,
+        (width, height),
+        layout,
+        &quantization_tables,
+        (options, thumbnail.as_deref()),
+    )
+}
+
+fn encode(
+    pixels: &[u8],
+    (width, height): (u64, u64),
+    layout: PixelLayout,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = check_dimensions(width, height)?;
+    let expected = width * height * layout.channels();
+    if pixels.len() != expected {
+        return Err(EncodeError::InvalidBufferSize {
+            expected,
+            actual: pixels.len(),
+        }
+        .into());
+    }
+
+    let quantization_tables = quantization_tables(options)?;
+    let sampling = layout.components(options.subsampling);
+    let (max_horizontal_sampling, max_vertical_sampling, _) = sampling[0];
+    let planes = component_planes(pixels, layout, sampling.len());
+    let sources: Vec<_> = planes
+        .iter()
+        .zip(&sampling)
+        .map(|(plane, &(h, v, _))| SourcePlane {
+            samples: plane,
+            width,
+            height,
+            stride: width,
+            ratio: (max_horizontal_sampling / h, max_vertical_sampling / v),
+        })
+        .collect();
+    let frame = Frame::new(
+        &sources,
+        (width, height),
+        &sampling,
+        (&quantization_tables, options.optimize),
+        8,
+    );
+    let thumbnail = encode_thumbnail(options, (width, height), layout, |x, y| {
+        let index = (y * width + x) * layout.channels();
+        match layout {
+            PixelLayout::Gray => [pixels[index]; 3],
+            PixelLayout::Rgb => [pixels[index], pixels[index + 1], pixels[index + 2]],
+            // the thumbnails of CMYK images are RGB, leaving out the color management of the inks
+            PixelLayout::Cmyk(_) => {
+                let white = 255 - u32::from(pixels[index + 3]);
+                let mut rgb = [0; 3];
+                for (value, &ink) in rgb.iter_mut().zip(&pixels[index..index + 3]) {
+                    *value = ((255 - u32::from(ink)) * white / 255) as u8;
+                }
+                rgb
+            }
+        }
+    })?;
+    write_file(
+        &frame,
+        (width, height),
+        layout,
+        &quantization_tables,
+        (options, thumbnail.as_deref()),
+    )
+}
+
+// encode_coefficients writes the quantized DCT coefficients of gray or YCbCr images as they are, with their
+// quantization tables, so re-encoding them loses nothing. Together with read_coefficients this edits images in
+// the DCT domain, for watermarking or filtering coefficients. Components sharing a table share the Huffman tables,
+// which allows two distinct quantization tables. DC coefficients must be within -1024..=1023 and AC coefficients
+// within -1023..=1023, as with 8 bit samples. quality, quantization_tables, subsampling, optimize and thumbnail of
+// options are ignored.
+pub fn encode_coefficients(
+    coefficients: &DecodedCoefficients,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = check_dimensions(coefficients.width, coefficients.height)?;
+    let layout = match coefficients.components.len() {
+        1 => PixelLayout::Gray,
+        3 => PixelLayout::Rgb,
+        count => return Err(EncodeError::UnsupportedComponentCount { count }.into()),
+    };
+
+    let mut quantization_tables: Vec<[u16; 64]> = Vec::with_capacity(2);
+    for component in &coefficients.components {
+        if !quantization_tables.contains(&component.quantization_table) {
+            quantization_tables.push(component.quantization_table);
+        }
+    }
+    if let Some(id) = quantization_tables
+        .iter()
+        .position(|table| table.contains(&0))
+    {
+        return Err(EncodeError::InvalidQuantizationTable { id }.into());
+    }
+    let quantization_tables = match *quantization_tables {
+        [luma] => [luma, luma],
+        [luma, chroma] => [luma, chroma],
+        ref tables => {
+            return Err(EncodeError::UnsupportedQuantizationTables {
+                count: tables.len(),
+            }
+            .into())
+        }
+    };
+
+    let max_horizontal_sampling = coefficients
+        .components
+        .iter()
+        .map(|c| c.horizontal_sampling)
+        .max();
+    let max_vertical_sampling = coefficients
+        .components
+        .iter()
+        .map(|c| c.vertical_sampling)
+        .max();
+    let (max_horizontal_sampling, max_vertical_sampling) = (
+        max_horizontal_sampling.unwrap_or(1),
+        max_vertical_sampling.unwrap_or(1),
+    );
+    let mcus_per_line = width.div_ceil(8 * max_horizontal_sampling);
+    let mcus_per_column = height.div_ceil(8 * max_vertical_sampling);
+
+    let mut components = Vec::with_capacity(coefficients.components.len());
+    for component in &coefficients.components {
+        let (h, v) = (component.horizontal_sampling, component.vertical_sampling);
+        let (blocks_per_line, blocks_per_column) = (mcus_per_line * h, mcus_per_column * v);
+        let available = component.blocks_per_line * component.blocks_per_column;
+        if component.blocks_per_line < blocks_per_line
+            || component.blocks_per_column < blocks_per_column
+        {
+            return Err(EncodeError::InvalidBufferSize {
+                expected: blocks_per_line * blocks_per_column,
+                actual: available,
+            }
+            .into());
+        }
+        if component.blocks.len() != available {
+            return Err(EncodeError::InvalidBufferSize {
+                expected: available,
+                actual: component.blocks.len(),
+            }
+            .into());
+        }
+
+        let mut blocks = Vec::with_capacity(blocks_per_line * blocks_per_column);
+        for y in 0..blocks_per_column {
+            for x in 0..blocks_per_line {
+                let natural = &component.blocks[y * component.blocks_per_line + x];
+                let mut block = [0i16; 64];
+                for (coefficient, &position) in block.iter_mut().zip(ZIGZAG.iter()) {
+                    *coefficient = natural[position];
+                }
+                let minimum = |k| if k == 0 { -1024 } else { -1023 };
+                if let Some((_, &value)) = block
+                    .iter()
+                    .enumerate()
+                    .find(|&(k, &value)| value < minimum(k) || value > 1023)
+                {
+                    return Err(EncodeError::CoefficientOutOfRange {
+                        component: component.id,
+                        value,
+                    }
+                    .into());
+                }
+                blocks.push(block);
+            }
+        }
+        let table = quantization_tables
+            .iter()
+            .position(|table| *table == component.quantization_table)
+            .unwrap_or(0);
+        components.push(Component {
+            id: component.id as u8,
+            horizontal_sampling: h,
+            vertical_sampling: v,
+            table,
+            blocks_per_line,
+            scan_blocks: (
+                (width * h).div_ceil(max_horizontal_sampling).div_ceil(8),
+                (height * v).div_ceil(max_vertical_sampling).div_ceil(8),
+            ),
+            blocks,
+        });
+    }
+
+    let frame = Frame {
+        precision: 8,
+        mcus_per_line,
+        mcus_per_column,
+        components,
+    };
+    let options = EncodeOptions {
+        thumbnail: None,
+        ..options.clone()
+    };
+    write_file(
+        &frame,
+        (width, height),
+        layout,
+        &quantization_tables,
+        (&options, None),
+    )
+}
+
+pub(crate) fn check_dimensions(width: u64, height: u64) -> Result<(usize, usize), EncodeError> {
+    if width == 0 || height == 0 || width > 0xffff || height > 0xffff {
+        return Err(EncodeError::InvalidDimensions { width, height });
+    }
+    Ok((width as usize, height as usize))
+}
+
+// quantization_tables returns the caller defined tables of options or the scaled Annex K tables
+fn quantization_tables(options: &EncodeOptions) -> Result<[[u16; 64]; 2], EncodeError> {
+    match options.quantization_tables {
+        Some(tables) => match tables.iter().position(|table| table.contains(&0)) {
+            Some(id) => Err(EncodeError::InvalidQuantizationTable { id }),
+            None => Ok(tables),
+        },
+        None => Ok([
+            scale_quantization_table(&LUMINANCE_QUANTIZATION, options.quality),
+            scale_quantization_table(&CHROMINANCE_QUANTIZATION, options.quality),
+        ]),
+    }
+}
+
+// write_file writes the segments and scans of the encoded frame, with the encoded thumbnail if options ask for one
+fn write_file(
+    frame: &Frame,
+    (width, height): (usize, usize),
+    layout: PixelLayout,
+    quantization_tables: &[[u16; 64]; 2],
+    (options, thumbnail): (&EncodeOptions, Option<&[u8]>),
+) -> Result<Vec<u8>, Error> {
+    let components = &frame.components;
+    let mut output = vec![0xff, 0xd8];
+    match layout {
+        PixelLayout::Cmyk(transform) => {
+            // version 100 without flags, followed by the transform
+            let transform = match transform {
+                CmykTransform::None => 0,
+                CmykTransform::Ycck => 2,
+            };
+            write_segment(
+                &mut output,
+                0xee,
+                &[b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, transform],
+            );
+        }
+        _ => {
+            write_jfif_header(&mut output, options.density)?;
+            if let (Some(thumbnail), Some(ThumbnailSegment::Jfxx)) = (
+                thumbnail,
+                options.thumbnail.map(|thumbnail| thumbnail.segment),
+            ) {
+                write_prefixed_segment(&mut output, 0xe0, JFXX_JPEG_HEADER, thumbnail)?;
+            }
+        }
+    }
+
+    let exif_thumbnail = match options.thumbnail {
+        Some(ThumbnailOptions {
+            segment: ThumbnailSegment::Exif,
+            ..
+        }) => thumbnail,
+        _ => None,
+    };
+    write_metadata(&mut output, options, exif_thumbnail)?;
+
+    let tables = components.iter().map(|c| c.table).max().unwrap_or(0) + 1;
+    let mut dqt = Vec::with_capacity(129 * tables);
+    let mut extended = false;
+    for (id, table) in quantization_tables[..tables].iter().enumerate() {
+        if table.iter().all(|&value| value <= 0xff) {
+            dqt.push(id as u8);
+            dqt.extend(ZIGZAG.iter().map(|&position| table[position] as u8));
+        } else {
+            extended = true;
+            dqt.push(1 << 4 | id as u8);
+            for &position in ZIGZAG.iter() {
+                dqt.extend_from_slice(&table[position].to_be_bytes());
+            }
+        }
+    }
+    write_segment(&mut output, 0xdb, &dqt);
+
+    let mut sof = vec![frame.precision];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.push(components.len() as u8);
+    for component in components {
+        sof.push(component.id);
+        sof.push((component.horizontal_sampling << 4 | component.vertical_sampling) as u8);
+        sof.push(component.table as u8);
+    }
+    // baseline files have 8 bit samples and quantization tables
+    let sof_marker = match (options.progressive, extended || frame.precision > 8) {
+        (true, _) => 0xc2,
+        (false, true) => 0xc1,
+        (false, false) => 0xc0,
+    };
+    write_segment(&mut output, sof_marker, &sof);
+
+    let standard_script: Vec<Scan>;
+    let script = match options.scan_script {
+        Some(ref script) => {
+            validate_script(frame, script, options.progressive)?;
+            script
+        }
+        None => {
+            let definitions: &[ScanDefinition] = match (options.progressive, components.len()) {
+                (false, 1) => &GRAY_BASELINE,
+                (false, 3) => &COLOR_BASELINE,
+                (false, _) => &CMYK_BASELINE,
+                (true, 1) => &GRAY_SCRIPT,
+                (true, 3) => &COLOR_SCRIPT,
+                (true, _) => &CMYK_SCRIPT,
+            };
+            standard_script = definitions.iter().map(Scan::from_definition).collect();
+            &standard_script
+        }
+    };
+
+    // the Annex K tables lack codes for the larger coefficients of 12 bit samples
+    let (huffman_tables, max_eob_runs) = if options.optimize_huffman || frame.precision > 8 {
+        let mut counter = SymbolCounter {
+            frequencies: vec![[0; 256]; TABLE_CLASSES.len()],
+        };
+        for scan in script {
+            for mcus in restart_intervals(frame, scan, options.restart_interval) {
+                encode_scan(
+                    &mut counter,
+                    (frame, scan),
+                    mcus,
+                    options.progressive,
+                    [MAX_EOB_RUN; 2],
+                );
+            }
+        }
+        let tables: Vec<_> = TABLE_CLASSES
+            .iter()
+            .zip(&counter.frequencies)
+            .map(|(&class, frequencies)| HuffmanTable::optimal(class, frequencies))
+            .collect();
+        // the tables have codes for all EOBn symbols of the first pass, so the runs have to end at the same blocks
+        (tables, [MAX_EOB_RUN; 2])
+    } else {
+        let tables: Vec<_> = TABLE_CLASSES
+            .iter()
+            .map(|&class| HuffmanTable::standard(class))
+            .collect();
+        let max_eob_runs = [
+            max_eob_run(&tables[AC_TABLES].make_code_table()),
+            max_eob_run(&tables[AC_TABLES + 1].make_code_table()),
+        ];
+        (tables, max_eob_runs)
+    };
+
+    let mut dht = Vec::new();
+    // chroma tables have odd indices and are only written for color images
+    for (_, table) in huffman_tables
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| index % 2 < tables)
+    {
+        let (class, id) = table.class.selector();
+        dht.push(class << 4 | id);
+        dht.extend_from_slice(&table.counts);
+        dht.extend_from_slice(&table.values);
+    }
+    write_segment(&mut output, 0xc4, &dht);
+    if options.restart_interval > 0 {
+        write_segment(&mut output, 0xdd, &options.restart_interval.to_be_bytes());
+    }
+
+    let codes: Vec<CodeTable> = huffman_tables
+        .iter()
+        .map(HuffmanTable::make_code_table)
+        .collect();
+    for scan in script {
+        let band = (scan.spectral_start, scan.spectral_end);
+        let approximation = scan.approximation_high << 4 | scan.approximation_low;
+        write_scan_header(&mut output, frame, &scan.components, band, approximation);
+
+        let intervals = restart_intervals(frame, scan, options.restart_interval);
+        let encode_interval = |output: &mut Vec<u8>, mcus: Range<usize>| {
+            let mut writer = BitWriter::new(output, &codes);
+            encode_scan(
+                &mut writer,
+                (frame, scan),
+                mcus,
+                options.progressive,
+                max_eob_runs,
+            );
+            writer.flush();
+        };
+        // restart intervals are coded independently, so they can be coded in parallel and joined afterwards
+        #[cfg(feature = "rayon")]
+        let intervals: Vec<Vec<u8>> = intervals
+            .into_par_iter()
+            .map(|mcus| {
+                let mut segment = Vec::new();
+                encode_interval(&mut segment, mcus);
+                segment
+            })
+            .collect();
+        for (index, interval) in intervals.into_iter().enumerate() {
+            if index > 0 {
+                output.extend_from_slice(&[0xff, 0xd0 + ((index - 1) % 8) as u8]);
+            }
+            #[cfg(feature = "rayon")]
+            output.extend_from_slice(&interval);
+            #[cfg(not(feature = "rayon"))]
+            encode_interval(&mut output, interval);
+        }
+    }
+
+    output.extend_from_slice(&[0xff, 0xd9]);
+    Ok(output)
+}
+
+// scale_quantization_table scales a table with the IJG formula, limiting the quantizers to 1..=255 so they fit
+// into 8 bit DQT entries
+fn scale_quantization_table(table: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = u32::from(quality.clamp(1, 100));
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+
+    let mut scaled = [0u16; 64];
+    for (scaled, &value) in scaled.iter_mut().zip(table) {
+        *scaled = ((u32::from(value) * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+    scaled
+}
+
+impl Frame {
+    // new converts the planes into the transformed and quantized blocks of every component
+    fn new<T: Copy + Into<u32>>(
+        planes: &[SourcePlane<T>],
+        (width, height): (usize, usize),
+        sampling: &[(usize, usize, usize)],
+        (quantization_tables, optimization): (&[[u16; 64]; 2], Optimization),
+        precision: u8,
+    ) -> Frame {
+        let max_horizontal_sampling = sampling[0].0;
+        let max_vertical_sampling = sampling[0].1;
+        let mcus_per_line = width.div_ceil(8 * max_horizontal_sampling);
+        let mcus_per_column = height.div_ceil(8 * max_vertical_sampling);
+
+        let components = planes
+            .iter()
+            .zip(sampling)
+            .enumerate()
+            .map(|(index, (plane, &(h, v, table)))| {
+                let blocks_per_line = mcus_per_line * h;
+                let blocks_per_column = mcus_per_column * v;
+                let samples = downsample(plane, (blocks_per_line * 8, blocks_per_column * 8));
+                // the trellis estimates the rate with the code lengths of the standard AC table
+                let code_lengths = match optimization {
+                    Optimization::None => None,
+                    Optimization::Trellis => Some(code_lengths(TABLE_CLASSES[AC_TABLES + table])),
+                };
+                let scan_blocks = (
+                    (width * h).div_ceil(max_horizontal_sampling).div_ceil(8),
+                    (height * v).div_ceil(max_vertical_sampling).div_ceil(8),
+                );
+                Component {
+                    id: index as u8 + 1,
+                    horizontal_sampling: h,
+                    vertical_sampling: v,
+                    table,
+                    blocks_per_line,
+                    scan_blocks,
+                    blocks: forward_blocks(
+                        &samples,
+                        blocks_per_line,
+                        blocks_per_column,
+                        (&quantization_tables[table], code_lengths.as_ref()),
+                        precision,
+                    ),
+                }
+            })
+            .collect();
+
+        Frame {
+            precision,
+            mcus_per_line,
+            mcus_per_column,
+            components,
+        }
+    }
+
+    // for_each_block calls f with the component index and block of every block of a scan of the given
+    // components, in the order they are coded. Scans of several components interleave them MCU by MCU, single
+    // component scans only cover the blocks inside the image.
+    fn for_each_block<F: FnMut(usize, &Block)>(
+        &self,
+        scan_components: &[usize],
+        mcus: Range<usize>,
+        mut f: F,
+    ) {
+        let (mcus_per_line, _) = self.scan_mcus(scan_components);
+        if let [index] = *scan_components {
+            let component = &self.components[index];
+            for mcu in mcus {
+                let (x, y) = (mcu % mcus_per_line, mcu / mcus_per_line);
+                f(index, &component.blocks[y * component.blocks_per_line + x]);
+            }
+            return;
+        }
+
+        for mcu in mcus {
+            let (mcu_x, mcu_y) = (mcu % mcus_per_line, mcu / mcus_per_line);
+            for &index in scan_components {
+                let component = &self.components[index];
+                let (h, v) = (component.horizontal_sampling, component.vertical_sampling);
+                for block_y in 0..v {
+                    for block_x in 0..h {
+                        let x = mcu_x * h + block_x;
+                        let y = mcu_y * v + block_y;
+                        f(index, &component.blocks[y * component.blocks_per_line + x]);
+                    }
+                }
+            }
+        }
+    }
+
+    // scan_mcus returns the MCUs per line and column of a scan of the given components, single blocks for scans of
+    // one component
+    fn scan_mcus(&self, scan_components: &[usize]) -> (usize, usize) {
+        match *scan_components {
+            [index] => self.components[index].scan_blocks,
+            _ => (self.mcus_per_line, self.mcus_per_column),
+        }
+    }
+}
+
+// validate_script checks that a caller defined scan script codes every coefficient of the frame in a valid order,
+// following the rules of ITU T.81 G.1.1.1. Scans are numbered from 0, scripts that don't code everything fail
+// at the number of scans.
+fn validate_script(frame: &Frame, script: &[Scan], progressive: bool) -> Result<(), EncodeError> {
+    let components = &frame.components;
+    let max_approximation = if frame.precision > 8 { 13 } else { 10 };
+    // sent holds the successive approximation bit position every coefficient of every component was coded down
+    // to, None for coefficients that weren't coded yet
+    let mut sent = vec![[None::<u8>; 64]; components.len()];
+    for (index, scan) in script.iter().enumerate() {
+        let invalid = EncodeError::InvalidScanScript { scan: index };
+        let count = scan.components.len();
+        if count == 0 || count > 4 {
+            return Err(invalid);
+        }
+        if scan.components.windows(2).any(|pair| pair[0] >= pair[1])
+            || scan.components[count - 1] >= components.len()
+        {
+            return Err(invalid);
+        }
+        let blocks: usize = scan
+            .components
+            .iter()
+            .map(|&c| components[c].horizontal_sampling * components[c].vertical_sampling)
+            .sum();
+        if count > 1 && blocks > 10 {
+            return Err(invalid);
+        }
+
+        let (start, end) = (scan.spectral_start, scan.spectral_end);
+        let (high, low) = (scan.approximation_high, scan.approximation_low);
+        if !progressive {
+            if (start, end, high, low) != (0, 63, 0, 0) {
+                return Err(invalid);
+            }
+        } else if start > end
+            || end > 63
+            || (start == 0 && end != 0)
+            || (start > 0 && count != 1)
+            || low > max_approximation
+        {
+            return Err(invalid);
+        }
+
+        for &component in &scan.components {
+            let sent = &mut sent[component];
+            // AC coefficients can only be sent after the DC coefficient
+            if start > 0 && sent[0].is_none() {
+                return Err(invalid);
+            }
+            for coefficient in &mut sent[start..=end] {
+                let valid = match *coefficient {
+                    None => high == 0,
+                    Some(previous) => progressive && high == previous && low + 1 == high,
+                };
+                if !valid {
+                    return Err(invalid);
+                }
+                *coefficient = Some(low);
+            }
+        }
+    }
+
+    // every coefficient has to be coded down to the last bit
+    if sent
+        .iter()
+        .any(|coefficients| coefficients.iter().any(|&bits| bits != Some(0)))
+    {
+        return Err(EncodeError::InvalidScanScript { scan: script.len() });
+    }
+    Ok(())
+}
+
+// restart_intervals splits the MCUs of a scan into intervals of restart_interval MCUs, all of them for zero
+fn restart_intervals(frame: &Frame, scan: &Scan, restart_interval: u16) -> Vec<Range<usize>> {
+    let (mcus_per_line, mcus_per_column) = frame.scan_mcus(&scan.components);
+    let mcus = mcus_per_line * mcus_per_column;
+    let length = match restart_interval {
+        0 => mcus,
+        length => usize::from(length),
+    };
+    (0..mcus)
+        .step_by(length)
+        .map(|start| start..(start + length).min(mcus))
+        .collect()
+}
+
+fn write_scan_header(
+    output: &mut Vec<u8>,
+    frame: &Frame,
+    scan_components: &[usize],
+    (spectral_start, spectral_end): (usize, usize),
+    approximation: u8,
+) {
+    let mut sos = vec![scan_components.len() as u8];
+    for &index in scan_components {
+        let component = &frame.components[index];
+        sos.push(component.id);
+        sos.push((component.table << 4 | component.table) as u8);
+    }
+    sos.extend_from_slice(&[spectral_start as u8, spectral_end as u8, approximation]);
+    write_segment(output, 0xda, &sos);
+}
+
+// component_planes splits pixels into full resolution planes of the stored components: a single gray plane, Y, Cb
+// and Cr samples, inverted inks or Y, Cb, Cr and inverted K. Converting RGB pixels into a single component keeps
+// only the Y plane.
+fn component_planes(pixels: &[u8], layout: PixelLayout, components: usize) -> Vec<Vec<u8>> {
+    if let PixelLayout::Gray = layout {
+        return vec![pixels.to_vec()];
+    }
+
+    let channels = layout.channels();
+    let mut planes: Vec<Vec<u8>> = (0..components)
+        .map(|_| Vec::with_capacity(pixels.len() / channels))
+        .collect();
+    for pixel in pixels.chunks(channels) {
+        let samples = match layout {
+            PixelLayout::Cmyk(CmykTransform::None) => [
+                255 - pixel[0],
+                255 - pixel[1],
+                255 - pixel[2],
+                255 - pixel[3],
+            ],
+            // YCCK converts the inks as if they were red, green and blue, as the inverse of the inverted inks
+            PixelLayout::Cmyk(CmykTransform::Ycck) => {
+                let ycc = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+                [ycc[0], ycc[1], ycc[2], 255 - pixel[3]]
+            }
+            _ => {
+                let ycc = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+                [ycc[0], ycc[1], ycc[2], 0]
+            }
+        };
+        for (plane, &sample) in planes.iter_mut().zip(&samples) {
+            plane.push(sample);
+        }
+    }
+    planes
+}
+
+// downsample averages ratio sized areas of a source plane into a plane of the given size. Areas reaching past the
+// edges of the source repeat its last line and column.
+fn downsample<T: Copy + Into<u32>>(
+    plane: &SourcePlane<T>,
+    (output_width, output_height): (usize, usize),
+) -> Vec<u16> {
+    let (horizontal, vertical) = plane.ratio;
+    let area = (horizontal * vertical) as u32;
+    let mut output = Vec::with_capacity(output_width * output_height);
+
+    for y in 0..output_height {
+        for x in 0..output_width {
+            let mut sum = 0u32;
+            for dy in 0..vertical {
+                let source_y = (y * vertical + dy).min(plane.height - 1);
+                for dx in 0..horizontal {
+                    let source_x = (x * horizontal + dx).min(plane.width - 1);
+                    sum += plane.samples[source_y * plane.stride + source_x].into();
+                }
+            }
+            output.push(((sum + area / 2) / area) as u16);
+        }
+    }
+
+    output
+}
+
+// forward_blocks transforms and quantizes the blocks of a plane of samples of the given precision with
+// blocks_per_line * 8 samples per line. Given the code lengths of an AC table, the AC coefficients are trellis
+// quantized.
+fn forward_blocks(
+    samples: &[u16],
+    blocks_per_line: usize,
+    blocks_per_column: usize,
+    (quantization_table, code_lengths): (&[u16; 64], Option<&[u8; 256]>),
+    precision: u8,
+) -> Vec<Block> {
+    let stride = blocks_per_line * 8;
+    let level_shift = f32::from(1u16 << (precision - 1));
+    // the largest coefficient magnitude the size categories of the precision allow, 1023 for 8 bit samples
+    let max_coefficient = (1 << (precision + 2)) - 1;
+    let mut blocks = vec![[0i16; 64]; blocks_per_line * blocks_per_column];
+    let transform_row = |(block_y, row): (usize, &mut [Block])| {
+        for (block_x, block) in row.iter_mut().enumerate() {
+            let mut shifted = [0f32; 64];
+            for y in 0..8 {
+                for x in 0..8 {
+                    let sample = samples[(block_y * 8 + y) * stride + block_x * 8 + x];
+                    shifted[y * 8 + x] = f32::from(sample) - level_shift;
+                }
+            }
+
+            let coefficients = forward_dct(&shifted);
+            let mut scaled = [0f32; 64];
+            for (k, &position) in ZIGZAG.iter().enumerate() {
+                scaled[k] = coefficients[position] / f32::from(quantization_table[position]);
+            }
+            let quantized = match code_lengths {
+                Some(lengths) => trellis_quantize(&scaled, lengths),
+                None => {
+                    let mut quantized = [0i32; 64];
+                    for (value, &coefficient) in quantized.iter_mut().zip(scaled.iter()) {
+                        *value = round(coefficient);
+                    }
+                    quantized
+                }
+            };
+
+            for (k, &quantized) in quantized.iter().enumerate() {
+                // keep the values in the range of the magnitude categories of the precision
+                block[k] = if k == 0 {
+                    quantized.clamp(-max_coefficient - 1, max_coefficient)
+                } else {
+                    quantized.clamp(-max_coefficient, max_coefficient)
+                } as i16;
+            }
+        }
+    };
+
+    for_each_row(&mut blocks, blocks_per_line, transform_row);
+
+    blocks
+}
+
+// forward_dct is the inverse of idct_block_float, transforming level shifted samples into coefficients in
+// natural order
+fn forward_dct(samples: &[f32; 64]) -> [f32; 64] {
+    let mut workspace = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0f32;
+            for x in 0..8 {
+                sum += samples[y * 8 + x] * FLOAT_COSINES[x][u];
+            }
+            workspace[y * 8 + u] = sum;
+        }
+    }
+
+    let mut coefficients = [0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0f32;
+            for y in 0..8 {
+                sum += workspace[y * 8 + u] * FLOAT_COSINES[y][v];
+            }
+            coefficients[v * 8 + u] = sum;
+        }
+    }
+    coefficients
+}
+
+// code_lengths returns the lengths of the codes of the standard table of the class, zero for symbols without one
+fn code_lengths(class: DHTType) -> [u8; 256] {
+    let codes = HuffmanTable::standard(class).make_code_table();
+    let mut lengths = [0u8; 256];
+    for (length, &(_, code_length)) in lengths.iter_mut().zip(codes.iter()) {
+        *length = code_length;
+    }
+    lengths
+}
+
+// trellis_quantize quantizes coefficients given in quantizer steps in zigzag order, choosing the AC coefficients
+// with the least squared error plus TRELLIS_LAMBDA times the bits of coding them with the given code lengths.
+// Every coefficient is either dropped, rounded or rounded towards zero. The cost of a block up to a nonzero
+// coefficient only depends on the position of the previous nonzero one, so the best choice is found by dynamic
+// programming over those positions.
+fn trellis_quantize(coefficients: &[f32; 64], code_lengths: &[u8; 256]) -> [i32; 64] {
+    // symbols without a code, like the larger sizes of 12 bit samples, are estimated with the longest codes
+    let bits = |symbol: u8| match code_lengths[symbol as usize] {
+        0 => 16.0,
+        length => f32::from(length),
+    };
+
+    // zero_errors[k] is the error of dropping the AC coefficients up to k
+    let mut zero_errors = [0f32; 64];
+    for k in 1..64 {
+        zero_errors[k] = zero_errors[k - 1] + coefficients[k] * coefficients[k];
+    }
+
+    // costs[k] is the least cost of the coefficients up to k with k the last nonzero one, choices[k] holds its
+    // value and the previous nonzero coefficient
+    let mut costs = [f32::INFINITY; 64];
+    let mut choices = [(0i32, 0usize); 64];
+    costs[0] = 0.0;
+    for k in 1..64 {
+        let rounded = round(coefficients[k]).clamp(-1023, 1023);
+        if rounded == 0 {
+            continue;
+        }
+        let towards_zero = rounded - rounded.signum();
+        for &value in &[rounded, towards_zero] {
+            if value == 0 {
+                continue;
+            }
+            let error = (coefficients[k] - value as f32) * (coefficients[k] - value as f32);
+            let size = magnitude_category(value);
+            for previous in 0..k {
+                if costs[previous].is_infinite() {
+                    continue;
+                }
+                let run = k - previous - 1;
+                let rate = (run / 16) as f32 * bits(0xf0) + bits(((run % 16) as u8) << 4 | size);
+                let cost = costs[previous] + zero_errors[k - 1] - zero_errors[previous]
+                    + error
+                    + TRELLIS_LAMBDA * (rate + f32::from(size));
+                if cost < costs[k] {
+                    costs[k] = cost;
+                    choices[k] = (value, previous);
+                }
+            }
+        }
+    }
+
+    // the block ends with an EOB unless its last coefficient is nonzero
+    let mut last = 0;
+    let mut best = f32::INFINITY;
+    for (k, &cost) in costs.iter().enumerate() {
+        let end = if k < 63 {
+            TRELLIS_LAMBDA * bits(0x00)
+        } else {
+            0.0
+        };
+        let cost = cost + zero_errors[63] - zero_errors[k] + end;
+        if cost < best {
+            best = cost;
+            last = k;
+        }
+    }
+
+    let mut quantized = [0i32; 64];
+    quantized[0] = round(coefficients[0]);
+    while last > 0 {
+        let (value, previous) = choices[last];
+        quantized[last] = value;
+        last = previous;
+    }
+    quantized
+}
+
+// round rounds half away from zero, without needing the float functions of std
+fn round(value: f32) -> i32 {
+    if value < 0.0 {
+        (value - 0.5) as i32
+    } else {
+        (value + 0.5) as i32
+    }
+}
+
+// encode_scan codes the blocks of a range of MCUs of one scan of the script, sequential scans as in ITU T.81 F.1.2
+// and progressive scans as in G.1.2. max_eob_runs limits the end of band runs of the luma and chroma AC tables.
+fn encode_scan<W: EntropyCoder>(
+    coder: &mut W,
+    (frame, scan): (&Frame, &Scan),
+    mcus: Range<usize>,
+    progressive: bool,
+    max_eob_runs: [u32; 2],
+) {
+    let components = &frame.components;
+    if !progressive {
+        let mut predictions = vec![0i32; components.len()];
+        frame.for_each_block(&scan.components, mcus, |index, block| {
+            encode_block(
+                coder,
+                block,
+                &mut predictions[index],
+                components[index].table,
+            );
+        });
+        return;
+    }
+
+    let shift = scan.approximation_low;
+    if scan.spectral_start == 0 {
+        let mut predictions = vec![0i32; components.len()];
+        frame.for_each_block(&scan.components, mcus, |index, block| {
+            // the point transform of DC coefficients is an arithmetic shift
+            let value = i32::from(block[0]) >> shift;
+            if scan.approximation_high == 0 {
+                encode_dc(
+                    coder,
+                    value,
+                    &mut predictions[index],
+                    components[index].table,
+                );
+            } else {
+                coder.write(value as u32, 1);
+            }
+        });
+    } else {
+        let table = components[scan.components[0]].table;
+        let mut encoder = AcEncoder::new(AC_TABLES + table, scan, max_eob_runs[table]);
+        frame.for_each_block(&scan.components, mcus, |_, block| {
+            if scan.approximation_high == 0 {
+                encoder.encode_first(coder, block);
+            } else {
+                encoder.encode_refinement(coder, block);
+            }
+        });
+        encoder.write_eob_run(coder);
+    }
+}
+
+// encode_block writes the Huffman coded DC difference and AC coefficients of a block of a sequential scan
+fn encode_block<W: EntropyCoder>(coder: &mut W, block: &Block, prediction: &mut i32, table: usize) {
+    encode_dc(coder, i32::from(block[0]), prediction, table);
+
+    let mut run = 0u8;
+    for &coefficient in &block[1..] {
+        if coefficient == 0 {
+            run += 1;
+            continue;
+        }
+        while run >= 16 {
+            // ZRL: a run of 16 zeros
+            coder.write_symbol(AC_TABLES + table, 0xf0);
+            run -= 16;
+        }
+        let size = magnitude_category(i32::from(coefficient));
+        coder.write_symbol(AC_TABLES + table, run << 4 | size);
+        coder.write_value(i32::from(coefficient), size);
+        run = 0;
+    }
+    if run > 0 {
+        coder.write_symbol(AC_TABLES + table, 0x00);
+    }
+}
+
+fn encode_dc<W: EntropyCoder>(coder: &mut W, value: i32, prediction: &mut i32, table: usize) {
+    let difference = value - *prediction;
+    *prediction = value;
+    let size = magnitude_category(difference);
+    coder.write_symbol(table, size);
+    coder.write_value(difference, size);
+}
+
+// max_eob_run is the longest end of band run the AC codes can express. EOBn symbols n << 4 stand for runs of up
+// to 2^(n + 1) - 1 blocks, EOB0 is a single block.
+fn max_eob_run(codes: &CodeTable) -> u32 {
+    let symbols = (0..15).take_while(|&n| codes[n << 4].1 > 0).count() as u32;
+    ((1 << symbols) - 1).min(MAX_EOB_RUN)
+}
+
+// AcEncoder codes the AC bands of progressive scans. Blocks without further coefficients in the band are
+// collected into end of band runs of up to max_eob_run blocks.
+struct AcEncoder {
+    table: usize,
+    spectral_start: usize,
+    spectral_end: usize,
+    shift: u8,
+    eob_run: u32,
+    max_eob_run: u32,
+    // corrections holds the correction bits of the blocks of the pending end of band run
+    corrections: Vec<u8>,
+    // block_corrections holds the correction bits of the current block that aren't written yet
+    block_corrections: Vec<u8>,
+}
+
+impl AcEncoder {
+    fn new(table: usize, scan: &Scan, max_eob_run: u32) -> AcEncoder {
+        AcEncoder {
+            table,
+            spectral_start: scan.spectral_start,
+            spectral_end: scan.spectral_end,
+            shift: scan.approximation_low,
+            eob_run: 0,
+            max_eob_run,
+            corrections: Vec::new(),
+            block_corrections: Vec::new(),
+        }
+    }
+
+    // encode_first codes the first scan of a band, with coefficients reduced by the point transform
+    fn encode_first<W: EntropyCoder>(&mut self, coder: &mut W, block: &Block) {
+        let mut run = 0u8;
+        for &coefficient in &block[self.spectral_start..=self.spectral_end] {
+            // the point transform of AC coefficients divides, rounding towards zero
+            let magnitude = i32::from(coefficient).abs() >> self.shift;
+            if magnitude == 0 {
+                run += 1;
+                continue;
+            }
+
+            self.write_eob_run(coder);
+            while run >= 16 {
+                coder.write_symbol(self.table, 0xf0);
+                run -= 16;
+            }
+            let value = if coefficient < 0 {
+                -magnitude
+            } else {
+                magnitude
+            };
+            let size = magnitude_category(value);
+            coder.write_symbol(self.table, run << 4 | size);
+            coder.write_value(value, size);
+            run = 0;
+        }
+
+        if run > 0 {
+            self.extend_eob_run(coder);
+        }
+    }
+
+    // encode_refinement codes the next bit of a band. Coefficients that become nonzero are coded like in the first
+    // scan with a size of 1, coefficients that were nonzero before only get a correction bit.
+    fn encode_refinement<W: EntropyCoder>(&mut self, coder: &mut W, block: &Block) {
+        let band = &block[self.spectral_start..=self.spectral_end];
+        let shift = self.shift;
+        let magnitude = |coefficient: i16| i32::from(coefficient).abs() >> shift;
+        // zero runs only end in ZRL symbols before the last coefficient becoming nonzero
+        let last_new = band.iter().rposition(|&c| magnitude(c) == 1);
+
+        self.block_corrections.clear();
+        let mut run = 0u8;
+        for (k, &coefficient) in band.iter().enumerate() {
+            let value = magnitude(coefficient);
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+
+            while run >= 16 && last_new.is_some_and(|last| k <= last) {
+                self.write_eob_run(coder);
+                coder.write_symbol(self.table, 0xf0);
+                run -= 16;
+                self.write_block_corrections(coder);
+            }
+            if value > 1 {
+                self.block_corrections.push((value & 1) as u8);
+                continue;
+            }
+
+            self.write_eob_run(coder);
+            coder.write_symbol(self.table, run << 4 | 1);
+            coder.write(u32::from(coefficient > 0), 1);
+            self.write_block_corrections(coder);
+            run = 0;
+        }
+
+        if run > 0 || !self.block_corrections.is_empty() {
+            let corrections = &self.block_corrections;
+            self.corrections.extend_from_slice(corrections);
+            self.extend_eob_run(coder);
+        }
+    }
+
+    fn extend_eob_run<W: EntropyCoder>(&mut self, coder: &mut W) {
+        self.eob_run += 1;
+        if self.eob_run >= self.max_eob_run {
+            self.write_eob_run(coder);
+        }
+    }
+
+    // write_eob_run ends the pending end of band run, followed by the correction bits of its blocks
+    fn write_eob_run<W: EntropyCoder>(&mut self, coder: &mut W) {
+        if self.eob_run == 0 {
+            return;
+        }
+
+        let size = (31 - self.eob_run.leading_zeros()) as u8;
+        coder.write_symbol(self.table, size << 4);
+        coder.write(self.eob_run, size);
+        self.eob_run = 0;
+
+        for &bit in &self.corrections {
+            coder.write(u32::from(bit), 1);
+        }
+        self.corrections.clear();
+    }
+
+    fn write_block_corrections<W: EntropyCoder>(&mut self, coder: &mut W) {
+        for &bit in &self.block_corrections {
+            coder.write(u32::from(bit), 1);
+        }
+        self.block_corrections.clear();
+    }
+}
+
+// EntropyCoder receives the Huffman coded symbols and raw bits of the scans. BitWriter writes them, SymbolCounter
+// only counts the symbols for building optimized tables.
+pub(crate) trait EntropyCoder {
+    // write_symbol codes symbol with the table at the given index of TABLE_CLASSES
+    fn write_symbol(&mut self, table: usize, symbol: u8);
+
+    fn write(&mut self, value: u32, length: u8);
+
+    // write_value writes the size low bits of value, negative values minus one as in ITU T.81 F.1.2.1
+    fn write_value(&mut self, value: i32, size: u8) {
+        let bits = if value < 0 { value - 1 } else { value };
+        self.write(bits as u32, size);
+    }
+}
+
+pub(crate) struct SymbolCounter {
+    // frequencies holds the number of times every symbol was coded per table
+    pub(crate) frequencies: Vec<[u32; 256]>,
+}
+
+impl EntropyCoder for SymbolCounter {
+    fn write_symbol(&mut self, table: usize, symbol: u8) {
+        self.frequencies[table][symbol as usize] += 1;
+    }
+
+    fn write(&mut self, _value: u32, _length: u8) {}
+}
+
+// magnitude_category is the number of bits needed for the absolute value
+pub(crate) fn magnitude_category(value: i32) -> u8 {
+    (32 - value.unsigned_abs().leading_zeros()) as u8
+}
+
+// BitWriter packs codes into the bytes of a scan, stuffing a zero byte after every 0xFF
+pub(crate) struct BitWriter<'a> {
+    output: &'a mut Vec<u8>,
+    // codes are the code tables in the order of TABLE_CLASSES
+    codes: &'a [CodeTable],
+    accumulator: u32,
+    bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    pub(crate) fn new(output: &'a mut Vec<u8>, codes: &'a [CodeTable]) -> BitWriter<'a> {
+        BitWriter {
+            output,
+            codes,
+            accumulator: 0,
+            bits: 0,
+        }
+    }
+
+    fn write_code(&mut self, (code, length): (u16, u8)) {
+        self.write(u32::from(code), length);
+    }
+
+    // flush pads the last byte with one bits
+    pub(crate) fn flush(mut self) {
+        let padding = (8 - self.bits % 8) % 8;
+        self.write(0xff, padding as u8);
+    }
+}
+
+impl<'a> EntropyCoder for BitWriter<'a> {
+    fn write_symbol(&mut self, table: usize, symbol: u8) {
+        let code = self.codes[table][symbol as usize];
+        self.write_code(code);
+    }
+
+    fn write(&mut self, value: u32, length: u8) {
+        let length = u32::from(length);
+        self.accumulator = self.accumulator << length | (value & ((1 << length) - 1));
+        self.bits += length;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            let byte = (self.accumulator >> self.bits) as u8;
+            self.output.push(byte);
+            if byte == 0xff {
+                self.output.push(0x00);
+            }
+        }
+    }
+}
+
+// write_jfif_header writes a JFIF 1.01 APP0 segment without thumbnail
+fn write_jfif_header(output: &mut Vec<u8>, density: Density) -> Result<(), EncodeError> {
+    if density.x == 0 || density.y == 0 {
+        return Err(EncodeError::InvalidDensity {
+            x: density.x,
+            y: density.y,
+        });
+    }
+
+    let unit = match density.unit {
+        DensityUnit::AspectRatio => 0,
+        DensityUnit::DotsPerInch => 1,
+        DensityUnit::DotsPerCentimeter => 2,
+    };
+    let mut data = vec![b'J', b'F', b'I', b'F', 0, 1, 1, unit];
+    data.extend_from_slice(&density.x.to_be_bytes());
+    data.extend_from_slice(&density.y.to_be_bytes());
+    // no thumbnail
+    data.extend_from_slice(&[0, 0]);
+    write_segment(output, 0xe0, &data);
+    Ok(())
+}
+
+// write_metadata writes the EXIF, XMP and ICC segments and the comments of options
+fn write_metadata(
+    output: &mut Vec<u8>,
+    options: &EncodeOptions,
+    thumbnail: Option<&[u8]>,
+) -> Result<(), EncodeError> {
+    let exif = options
+        .exif
+        .as_ref()
+        .map(|exif| exif.strip_prefix(EXIF_HEADER).unwrap_or(exif));
+    match thumbnail {
+        Some(thumbnail) => {
+            let tiff = exif_with_thumbnail(exif, thumbnail)?;
+            write_prefixed_segment(output, 0xe1, EXIF_HEADER, &tiff)?;
+        }
+        None => {
+            if let Some(tiff) = exif {
+                write_prefixed_segment(output, 0xe1, EXIF_HEADER, tiff)?;
+            }
+        }
+    }
+    if let Some(ref xmp) = options.xmp {
+        write_prefixed_segment(output, 0xe1, XMP_HEADER, xmp)?;
+    }
+
+    if let Some(ref profile) = options.icc_profile {
+        // every chunk starts with its sequence number and the chunk count
+        let chunk_size = MAX_SEGMENT_DATA - ICC_MARKER.len() - 2;
+        let count = profile.len().div_ceil(chunk_size);
+        if count > 0xff {
+            return Err(EncodeError::MetadataTooLarge {
+                marker: 0xe2,
+                length: profile.len(),
+            });
+        }
+        for (index, chunk) in profile.chunks(chunk_size).enumerate() {
+            let mut data = Vec::with_capacity(ICC_MARKER.len() + 2 + chunk.len());
+            data.extend_from_slice(ICC_MARKER);
+            data.extend_from_slice(&[index as u8 + 1, count as u8]);
+            data.extend_from_slice(chunk);
+            write_segment(output, 0xe2, &data);
+        }
+    }
+
+    for comment in &options.comments {
+        for part in comment.chunks(MAX_SEGMENT_DATA) {
+            write_segment(output, 0xfe, part);
+        }
+    }
+    Ok(())
+}
+
+// exif_with_thumbnail appends an IFD1 with a JPEG thumbnail to a TIFF structure, or to an empty one without
+// tiff. A previous IFD1 stays in place, but is no longer referenced.
+fn exif_with_thumbnail(tiff: Option<&[u8]>, thumbnail: &[u8]) -> Result<Vec<u8>, EncodeError> {
+    // a big endian header followed by an IFD0 without entries
+    let mut tiff = tiff
+        .unwrap_or(b"MM\x00\x2a\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00")
+        .to_vec();
+    let (byte_order, next_ifd) = {
+        let parsed = Tiff::new(&tiff).map_err(|_| EncodeError::InvalidExif)?;
+        let next_ifd = parsed
+            .first_ifd_offset()
+            .and_then(|offset| Ok(offset + 2 + parsed.u16_at(offset)? as usize * 12))
+            .map_err(|_| EncodeError::InvalidExif)?;
+        parsed
+            .u32_at(next_ifd)
+            .map_err(|_| EncodeError::InvalidExif)?;
+        (parsed.byte_order, next_ifd)
+    };
+    let u16_bytes = |value: u16| match byte_order {
+        ByteOrder::LittleEndian => value.to_le_bytes(),
+        ByteOrder::BigEndian => value.to_be_bytes(),
+    };
+    let u32_bytes = |value: u32| match byte_order {
+        ByteOrder::LittleEndian => value.to_le_bytes(),
+        ByteOrder::BigEndian => value.to_be_bytes(),
+    };
+
+    // IFDs start at word boundaries
+    if tiff.len() % 2 == 1 {
+        tiff.push(0);
+    }
+    let ifd1 = tiff.len();
+    tiff[next_ifd..next_ifd + 4].copy_from_slice(&u32_bytes(ifd1 as u32));
+    let data = ifd1 + 2 + 3 * 12 + 4;
+    tiff.extend_from_slice(&u16_bytes(3));
+    // the compression is a SHORT of 6 for JPEG, left justified in its value field
+    tiff.extend_from_slice(&u16_bytes(COMPRESSION_TAG));
+    tiff.extend_from_slice(&u16_bytes(3));
+    tiff.extend_from_slice(&u32_bytes(1));
+    tiff.extend_from_slice(&u16_bytes(6));
+    tiff.extend_from_slice(&[0, 0]);
+    for &(tag, value) in &[
+        (JPEG_INTERCHANGE_FORMAT_TAG, data),
+        (JPEG_INTERCHANGE_FORMAT_LENGTH_TAG, thumbnail.len()),
+    ] {
+        tiff.extend_from_slice(&u16_bytes(tag));
+        tiff.extend_from_slice(&u16_bytes(4));
+        tiff.extend_from_slice(&u32_bytes(1));
+        tiff.extend_from_slice(&u32_bytes(value as u32));
+    }
+    tiff.extend_from_slice(&u32_bytes(0));
+    tiff.extend_from_slice(thumbnail);
+    Ok(tiff)
+}
+
+// encode_thumbnail encodes the thumbnail options ask for, averaging the pixels of a width x height image into at
+// most max_size pixels on the long edge. pixel returns the RGB pixel at a position, gray thumbnails use its first
+// sample.
+fn encode_thumbnail<F: Fn(usize, usize) -> [u8; 3]>(
+    options: &EncodeOptions,
+    (width, height): (usize, usize),
+    layout: PixelLayout,
+    pixel: F,
+) -> Result<Option<Vec<u8>>, Error> {
+    let max_size = match options.thumbnail {
+        Some(thumbnail) => usize::from(thumbnail.max_size.max(1)),
+        None => return Ok(None),
+    };
+    let long_edge = width.max(height);
+    let (thumbnail_width, thumbnail_height) = if long_edge <= max_size {
+        (width, height)
+    } else {
+        (
+            ((width * max_size + long_edge / 2) / long_edge).max(1),
+            ((height * max_size + long_edge / 2) / long_edge).max(1),
+        )
+    };
+    let (color_type, channels) = match layout {
+        PixelLayout::Gray => (ColorType::Gray, 1),
+        _ => (ColorType::Rgb, 3),
+    };
+
+    let mut pixels = Vec::with_capacity(thumbnail_width * thumbnail_height * channels);
+    for y in 0..thumbnail_height {
+        let rows = y * height / thumbnail_height..(y + 1) * height / thumbnail_height;
+        for x in 0..thumbnail_width {
+            let columns = x * width / thumbnail_width..(x + 1) * width / thumbnail_width;
+            let mut sums = [0u32; 3];
+            for source_y in rows.clone() {
+                for source_x in columns.clone() {
+                    for (sum, &value) in sums.iter_mut().zip(&pixel(source_x, source_y)) {
+                        *sum += u32::from(value);
+                    }
+                }
+            }
+            let area = (rows.len() * columns.len()) as u32;
+            pixels.extend(
+                sums[..channels]
+                    .iter()
+                    .map(|&sum| ((sum + area / 2) / area) as u8),
+            );
+        }
+    }
+
+    let thumbnail_options = EncodeOptions {
+        quality: options.quality,
+        ..EncodeOptions::default()
+    };
+    encode_image_with_options(
+        &pixels,
+        thumbnail_width as u64,
+        thumbnail_height as u64,
+        color_type,
+        &thumbnail_options,
+    )
+    .map(Some)
+}
+
+// write_prefixed_segment writes a segment of header followed by data, which has to fit a single segment
+fn write_prefixed_segment(
+    output: &mut Vec<u8>,
+    marker: u8,
+    header: &[u8],
+    data: &[u8],
+) -> Result<(), EncodeError> {
+    if header.len() + data.len() > MAX_SEGMENT_DATA {
+        return Err(EncodeError::MetadataTooLarge {
+            marker,
+            length: data.len(),
+        });
+    }
+    let mut segment = Vec::with_capacity(header.len() + data.len());
+    segment.extend_from_slice(header);
+    segment.extend_from_slice(data);
+    write_segment(output, marker, &segment);
+    Ok(())
+}
+
+pub(crate) fn write_segment(output: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    output.extend_from_slice(&[0xff, marker]);
+    output.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    output.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::{
+        decode_image, decode_image_with_options, decode_image_with_stats, decode_planes,
+        DecodeOptions,
+    };
+    use exif::{read_orientation, Orientation};
+    use icc::read_icc_profile;
+    use info::read_info;
+    use parser::{self, header_segments, Marker, SubsamplingMode};
+    use thumbnail::{read_thumbnail, Thumbnail};
+
+    fn pattern(width: usize, height: usize) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push((x * 255 / width) as u8);
+                pixels.push((y * 255 / height) as u8);
+                pixels.push(255usize.saturating_sub((x + y) * 2) as u8);
+            }
+        }
+        pixels
+    }
+
+    // exif_tiff returns a big endian TIFF structure with IFD0 holding only the orientation tag
+    fn exif_tiff(orientation: u16) -> Vec<u8> {
+        let mut tiff = vec![
+            0x4D, 0x4D, 0x00, 0x2A, 0x00, 0x00, 0x00, 0x08, // header
+            0x00, 0x01, // one entry
+            0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, // orientation SHORT
+        ];
+        tiff.extend(&orientation.to_be_bytes());
+        tiff.extend(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        tiff
+    }
+
+    fn mean_error(decoded: &[u8], original: &[u8]) -> f64 {
+        assert_eq!(decoded.len(), original.len());
+        let total: u64 = decoded
+            .iter()
+            .zip(original)
+            .map(|(&a, &b)| (i64::from(a) - i64::from(b)).unsigned_abs())
+            .sum();
+        total as f64 / original.len() as f64
+    }
+
+    #[test]
+    fn encode_rgb_test() {
+        for &(width, height) in &[(32, 24), (37, 19), (1, 1)] {
+            let pixels = pattern(width, height);
+            let file = encode_image(&pixels, width as u64, height as u64, ColorType::Rgb).unwrap();
+
+            let info = read_info(&file).unwrap();
+            assert_eq!((info.width, info.height), (width as u64, height as u64));
+            assert_eq!(info.subsampling, SubsamplingMode::Chroma420);
+            assert!(!info.progressive);
+
+            let image = decode_image(&file).unwrap();
+            assert_eq!(image.color_type, ColorType::Rgb);
+            assert_eq!(image.truncation, None);
+            assert!(mean_error(&image.pixels, &pixels) < 6.0);
+        }
+    }
+
+    #[test]
+    fn encode_gray_test() {
+        let pixels: Vec<u8> = (0..40 * 30).map(|i| (i % 40 * 6) as u8).collect();
+        let file = encode_image(&pixels, 40, 30, ColorType::Gray).unwrap();
+
+        assert_eq!(read_info(&file).unwrap().subsampling, SubsamplingMode::Gray);
+        let image = decode_image(&file).unwrap();
+        assert_eq!(image.color_type, ColorType::Gray);
+        assert!(mean_error(&image.pixels, &pixels) < 2.0);
+    }
+
+    #[test]
+    fn encode_progressive_test() {
+        let progressive = EncodeOptions {
+            progressive: true,
+            ..EncodeOptions::default()
+        };
+        let gray: Vec<u8> = (0..40 * 30).map(|i| (i % 40 * 6) as u8).collect();
+        // noise has nonzero coefficients far apart in every band, exercising long zero runs in refinement scans
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..48 * 16 * 3)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 24) as u8
+            })
+            .collect();
+        for &(ref pixels, width, height, color_type, scans) in &[
+            (pattern(32, 24), 32, 24, ColorType::Rgb, 10),
+            (pattern(37, 19), 37, 19, ColorType::Rgb, 10),
+            (noise, 48, 16, ColorType::Rgb, 10),
+            (gray, 40, 30, ColorType::Gray, 6),
+        ] {
+            let baseline = encode_image(pixels, width, height, color_type).unwrap();
+            let file =
+                encode_image_with_options(pixels, width, height, color_type, &progressive).unwrap();
+            assert!(read_info(&file).unwrap().progressive);
+
+            // the scans together hold the same coefficients as the baseline scan
+            let (image, stats) = decode_image_with_stats(&file, &DecodeOptions::default()).unwrap();
+            assert_eq!(stats.scans, scans);
+            assert_eq!(image.truncation, None);
+            assert_eq!(image.pixels, decode_image(&baseline).unwrap().pixels);
+        }
+    }
+
+    #[test]
+    fn quality_test() {
+        assert_eq!(
+            scale_quantization_table(&LUMINANCE_QUANTIZATION, 50),
+            LUMINANCE_QUANTIZATION
+        );
+        assert_eq!(
+            scale_quantization_table(&LUMINANCE_QUANTIZATION, 100),
+            [1; 64]
+        );
+        let table = scale_quantization_table(&LUMINANCE_QUANTIZATION, 75);
+        assert_eq!((table[0], table[63]), (8, 50));
+        let table = scale_quantization_table(&CHROMINANCE_QUANTIZATION, 0);
+        assert_eq!((table[0], table[63]), (255, 255));
+
+        let pixels = pattern(32, 24);
+        let encode = |quality| {
+            let options = EncodeOptions {
+                quality,
+                ..EncodeOptions::default()
+            };
+            encode_image_with_options(&pixels, 32, 24, ColorType::Rgb, &options).unwrap()
+        };
+        let (low, high) = (encode(10), encode(95));
+        assert!(low.len() < high.len());
+        let low_error = mean_error(&decode_image(&low).unwrap().pixels, &pixels);
+        let high_error = mean_error(&decode_image(&high).unwrap().pixels, &pixels);
+        assert!(high_error < low_error);
+    }
+
+    #[test]
+    fn quantization_tables_test() {
+        let pixels = pattern(32, 24);
+        let mut tables = [[3u16; 64], [5u16; 64]];
+        let encode = |tables| {
+            let options = EncodeOptions {
+                quantization_tables: Some(tables),
+                ..EncodeOptions::default()
+            };
+            encode_image_with_options(&pixels, 32, 24, ColorType::Rgb, &options)
+        };
+
+        let file = encode(tables).unwrap();
+        let markers = parser::decode(&file).unwrap();
+        let dqt = markers
+            .iter()
+            .find_map(|marker| match *marker {
+                Marker::DQT(ref tables) => Some(tables),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(dqt.len(), 2);
+        assert_eq!((dqt[1].precision, dqt[1].data[0]), (0, 5));
+        let default = encode_image(&pixels, 32, 24, ColorType::Rgb).unwrap();
+        let error = mean_error(&decode_image(&file).unwrap().pixels, &pixels);
+        assert!(error < mean_error(&decode_image(&default).unwrap().pixels, &pixels));
+
+        // coarse 16 bit quantizers need the extended sequential process
+        tables[1][63] = 300;
+        let file = encode(tables).unwrap();
+        assert!(file.windows(2).any(|marker| marker == [0xff, 0xc1]));
+        assert_eq!(decode_image(&file).unwrap().pixels.len(), pixels.len());
+
+        tables[0][10] = 0;
+        assert!(matches!(
+            encode(tables),
+            Err(Error::Encoder(EncodeError::InvalidQuantizationTable {
+                id: 0
+            }))
+        ));
+    }
+
+    #[test]
+    fn optimize_huffman_test() {
+        let pixels = pattern(64, 48);
+        for &progressive in &[false, true] {
+            let encode = |optimize_huffman| {
+                let options = EncodeOptions {
+                    progressive,
+                    optimize_huffman,
+                    ..EncodeOptions::default()
+                };
+                encode_image_with_options(&pixels, 64, 48, ColorType::Rgb, &options).unwrap()
+            };
+            let (standard, optimized) = (encode(false), encode(true));
+            assert!(optimized.len() < standard.len());
+            assert_eq!(
+                decode_image(&optimized).unwrap().pixels,
+                decode_image(&standard).unwrap().pixels
+            );
+        }
+
+        let gray: Vec<u8> = (0..40 * 30).map(|i| (i % 40 * 6) as u8).collect();
+        let options = EncodeOptions {
+            optimize_huffman: true,
+            ..EncodeOptions::default()
+        };
+        let file = encode_image_with_options(&gray, 40, 30, ColorType::Gray, &options).unwrap();
+        let image = decode_image(&file).unwrap();
+        let baseline = decode_image(&encode_image(&gray, 40, 30, ColorType::Gray).unwrap());
+        assert_eq!(image.pixels, baseline.unwrap().pixels);
+    }
+
+    #[test]
+    fn trellis_test() {
+        let (width, height) = (64usize, 48usize);
+        // a gradient with noise leaves many small coefficients for the trellis to drop
+        let mut state = 7u32;
+        let pixels: Vec<u8> = pattern(width, height)
+            .iter()
+            .map(|&value| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                value.saturating_add((state >> 28) as u8)
+            })
+            .collect();
+        let encode = |optimize| {
+            let options = EncodeOptions {
+                optimize,
+                ..EncodeOptions::default()
+            };
+            let file =
+                encode_image_with_options(&pixels, 64, 48, ColorType::Rgb, &options).unwrap();
+            let error = mean_error(&decode_image(&file).unwrap().pixels, &pixels);
+            (file.len(), error)
+        };
+        let (rounded, trellis) = (encode(Optimization::None), encode(Optimization::Trellis));
+        assert!(
+            trellis.0 * 100 < rounded.0 * 95,
+            "{:?} {:?}",
+            trellis,
+            rounded
+        );
+        assert!(trellis.1 < rounded.1 * 1.02, "{:?} {:?}", trellis, rounded);
+
+        // large coefficients stay rounded, an isolated small one isn't worth its bits
+        let mut coefficients = [0f32; 64];
+        coefficients[0] = 3.2;
+        coefficients[1] = -10.4;
+        coefficients[2] = 4.6;
+        coefficients[5] = 0.55;
+        let code_lengths = code_lengths(DHTType::LuminanceAC);
+        let quantized = trellis_quantize(&coefficients, &code_lengths);
+        assert_eq!(quantized[..6], [3, -10, 5, 0, 0, 0]);
+        assert!(quantized[6..].iter().all(|&value| value == 0));
+    }
+
+    #[test]
+    fn subsampling_test() {
+        let pixels = pattern(37, 19);
+        let modes = [
+            (ChromaSubsampling::Chroma444, SubsamplingMode::Chroma444),
+            (ChromaSubsampling::Chroma422, SubsamplingMode::Chroma422),
+            (ChromaSubsampling::Chroma420, SubsamplingMode::Chroma420),
+            (ChromaSubsampling::Gray, SubsamplingMode::Gray),
+        ];
+        let rgb = DecodeOptions {
+            color_type: Some(ColorType::Rgb),
+            ..DecodeOptions::default()
+        };
+        let mut errors = Vec::new();
+        for &(subsampling, mode) in &modes {
+            for &progressive in &[false, true] {
+                let options = EncodeOptions {
+                    subsampling,
+                    progressive,
+                    ..EncodeOptions::default()
+                };
+                let file =
+                    encode_image_with_options(&pixels, 37, 19, ColorType::Rgb, &options).unwrap();
+                assert_eq!(read_info(&file).unwrap().subsampling, mode);
+
+                let image = decode_image_with_options(&file, &rgb).unwrap();
+                assert_eq!((image.width, image.height), (37, 19));
+                if !progressive {
+                    errors.push(mean_error(&image.pixels, &pixels));
+                }
+            }
+        }
+        // keeping more chroma samples loses less of the colors, dropping them loses the most
+        assert!(errors[0] < errors[1] && errors[1] < errors[2] && errors[2] < errors[3]);
+
+        let gray: Vec<u8> = (0..16 * 8).map(|i| i as u8).collect();
+        let options = EncodeOptions {
+            subsampling: ChromaSubsampling::Chroma444,
+            ..EncodeOptions::default()
+        };
+        let file = encode_image_with_options(&gray, 16, 8, ColorType::Gray, &options).unwrap();
+        assert_eq!(read_info(&file).unwrap().subsampling, SubsamplingMode::Gray);
+    }
+
+    // ycbcr_planes converts and subsamples pixels like the RGB path, storing stride samples per plane line
+    fn ycbcr_planes(
+        pixels: &[u8],
+        (width, height): (usize, usize),
+        subsampling: ChromaSubsampling,
+        padding: usize,
+    ) -> Vec<(Vec<u8>, usize)> {
+        let sampling = PixelLayout::Rgb.components(subsampling);
+        let (max_h, max_v, _) = sampling[0];
+        let planes = component_planes(pixels, PixelLayout::Rgb, 3);
+        planes
+            .iter()
+            .zip(&sampling)
+            .map(|(plane, &(h, v, _))| {
+                let source = SourcePlane {
+                    samples: plane,
+                    width,
+                    height,
+                    stride: width,
+                    ratio: (max_h / h, max_v / v),
+                };
+                let size = ((width * h).div_ceil(max_h), (height * v).div_ceil(max_v));
+                let samples: Vec<u8> = downsample(&source, size)
+                    .iter()
+                    .map(|&sample| sample as u8)
+                    .collect();
+                let stride = size.0 + padding;
+                let mut padded = vec![0xaa; stride * size.1];
+                for (line, row) in padded.chunks_mut(stride).zip(samples.chunks(size.0)) {
+                    line[..size.0].copy_from_slice(row);
+                }
+                (padded, stride)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode_ycbcr_planes_test() {
+        // odd sizes, so the edge samples of the planes are the ones the RGB path repeats
+        let (width, height) = (37usize, 19usize);
+        let pixels = pattern(width, height);
+        let modes = [
+            ChromaSubsampling::Chroma444,
+            ChromaSubsampling::Chroma422,
+            ChromaSubsampling::Chroma420,
+            ChromaSubsampling::Gray,
+        ];
+        for &subsampling in &modes {
+            for &padding in &[0, 5] {
+                let options = EncodeOptions {
+                    subsampling,
+                    ..EncodeOptions::default()
+                };
+                let planes = ycbcr_planes(&pixels, (width, height), subsampling, padding);
+                // gray images only have a Y plane, which stands in for the ignored chroma planes
+                let plane = |index: usize| {
+                    let (ref samples, stride) = planes[index.min(planes.len() - 1)];
+                    Plane { samples, stride }
+                };
+                let input = YCbCrPlanes {
+                    y: plane(0),
+                    cb: plane(1),
+                    cr: plane(2),
+                };
+                let file =
+                    encode_ycbcr_planes(&input, width as u64, height as u64, &options).unwrap();
+                let expected = encode_image_with_options(
+                    &pixels,
+                    width as u64,
+                    height as u64,
+                    ColorType::Rgb,
+                    &options,
+                )
+                .unwrap();
+                assert_eq!(file, expected, "{:?} padded by {}", subsampling, padding);
+            }
+        }
+
+        let planes = ycbcr_planes(&pixels, (width, height), ChromaSubsampling::Chroma420, 0);
+        let short = Plane {
+            samples: &planes[1].0,
+            stride: 18,
+        };
+        let input = YCbCrPlanes {
+            y: Plane {
+                samples: &planes[0].0,
+                stride: width,
+            },
+            cb: short,
+            cr: short,
+        };
+        let options = EncodeOptions::default();
+        assert!(match encode_ycbcr_planes(&input, 37, 19, &options) {
+            Err(Error::Encoder(EncodeError::InvalidStride { stride, width })) => {
+                (stride, width) == (18, 19)
+            }
+            _ => false,
+        });
+        let input = YCbCrPlanes {
+            y: Plane {
+                samples: &planes[0].0[1..],
+                stride: width,
+            },
+            ..input
+        };
+        assert!(match encode_ycbcr_planes(&input, 37, 19, &options) {
+            Err(Error::Encoder(EncodeError::InvalidBufferSize { expected, actual })) => {
+                (expected, actual) == (37 * 19, 37 * 19 - 1)
+            }
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn encode_cmyk_test() {
+        let (width, height) = (24usize, 20usize);
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(&[(x * 10) as u8, (y * 12) as u8, 128, (x + y) as u8 * 5]);
+            }
+        }
+
+        for &transform in &[CmykTransform::None, CmykTransform::Ycck] {
+            for &progressive in &[false, true] {
+                let options = EncodeOptions {
+                    progressive,
+                    optimize_huffman: progressive,
+                    ..EncodeOptions::default()
+                };
+                let file = encode_cmyk_image(&pixels, 24, 20, transform, &options).unwrap();
+                let flag = if transform == CmykTransform::Ycck {
+                    2
+                } else {
+                    0
+                };
+                let adobe = [
+                    0xff, 0xee, 0, 14, b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, flag,
+                ];
+                assert_eq!(&file[2..18], &adobe);
+
+                let planes = decode_planes(&file, &DecodeOptions::default()).unwrap();
+                assert_eq!(planes.components.len(), 4);
+                let sample = |component: usize, x: usize, y: usize| {
+                    let plane = &planes.components[component];
+                    i32::from(plane.samples[y * plane.stride + x])
+                };
+                for &(x, y) in &[(0, 0), (13, 7), (23, 19)] {
+                    let pixel = &pixels[(y * width + x) * 4..][..4];
+                    let expected = match transform {
+                        CmykTransform::None => 255 - i32::from(pixel[0]),
+                        CmykTransform::Ycck => {
+                            i32::from(rgb_to_ycbcr(pixel[0], pixel[1], pixel[2])[0])
+                        }
+                    };
+                    assert!((sample(0, x, y) - expected).abs() <= 4);
+                    assert!((sample(3, x, y) - (255 - i32::from(pixel[3]))).abs() <= 4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_test() {
+        let tiff = exif_tiff(6);
+        let profile: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        let long_comment = vec![b'c'; 70_000];
+        let options = EncodeOptions {
+            exif: Some(tiff.clone()),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            icc_profile: Some(profile.clone()),
+            comments: vec![b"hello".to_vec(), long_comment],
+            ..EncodeOptions::default()
+        };
+        let file = encode_image_with_options(&[128; 64], 8, 8, ColorType::Gray, &options).unwrap();
+
+        assert_eq!(read_orientation(&file), Some(Orientation::Rotate90));
+        assert_eq!(read_icc_profile(&file), Some(profile));
+        let segments = header_segments(&file).unwrap();
+        let tags: Vec<u8> = segments.iter().map(|segment| segment.tag).collect();
+        assert_eq!(
+            &tags[..9],
+            &[0xe0, 0xe1, 0xe1, 0xe2, 0xe2, 0xe2, 0xfe, 0xfe, 0xfe]
+        );
+        assert_eq!(
+            segments[2].data,
+            &b"http://ns.adobe.com/xap/1.0/\x00<x:xmpmeta/>"[..]
+        );
+        assert_eq!(segments[6].data, b"hello");
+        assert_eq!(segments[7].data.len() + segments[8].data.len(), 70_000);
+        assert_eq!(decode_image(&file).unwrap().pixels, vec![128; 64]);
+
+        // an already prefixed EXIF blob isn't prefixed again
+        let mut prefixed = b"Exif\x00\x00".to_vec();
+        prefixed.extend_from_slice(&tiff);
+        let options = EncodeOptions {
+            exif: Some(prefixed),
+            ..EncodeOptions::default()
+        };
+        let file = encode_image_with_options(&[128; 64], 8, 8, ColorType::Gray, &options).unwrap();
+        assert_eq!(read_orientation(&file), Some(Orientation::Rotate90));
+
+        let options = EncodeOptions {
+            xmp: Some(vec![b' '; 65_510]),
+            ..EncodeOptions::default()
+        };
+        assert!(matches!(
+            encode_image_with_options(&[128; 64], 8, 8, ColorType::Gray, &options),
+            Err(Error::Encoder(EncodeError::MetadataTooLarge {
+                marker: 0xe1,
+                length: 65_510
+            }))
+        ));
+    }
+
+    // scan_data returns the part of a file from its first scan header on
+    fn scan_data(file: &[u8]) -> &[u8] {
+        let start = file
+            .windows(2)
+            .position(|marker| marker == [0xff, 0xda])
+            .unwrap();
+        &file[start..]
+    }
+
+    #[test]
+    fn encode_12bit_test() {
+        // 12 bit samples sixteen times the 8 bit samples, quantized with sixteen times the quantizers, give the
+        // same coefficients. Gray RGB pixels convert exactly, as chroma stays at the center.
+        let gray: Vec<u8> = pattern(37, 19).iter().step_by(3).cloned().collect();
+        let rgb: Vec<u8> = gray.iter().flat_map(|&value| vec![value; 3]).collect();
+        let tables = [
+            scale_quantization_table(&LUMINANCE_QUANTIZATION, 80),
+            scale_quantization_table(&CHROMINANCE_QUANTIZATION, 80),
+        ];
+        let mut wide_tables = tables;
+        for value in wide_tables.iter_mut().flat_map(|table| table.iter_mut()) {
+            *value *= 16;
+        }
+
+        for &(pixels, color_type) in &[(&gray, ColorType::Gray), (&rgb, ColorType::Rgb)] {
+            for &progressive in &[false, true] {
+                let options = EncodeOptions {
+                    quantization_tables: Some(tables),
+                    subsampling: ChromaSubsampling::Chroma444,
+                    progressive,
+                    optimize_huffman: true,
+                    ..EncodeOptions::default()
+                };
+                let file = encode_image_with_options(pixels, 37, 19, color_type, &options).unwrap();
+                let wide: Vec<u16> = pixels.iter().map(|&value| u16::from(value) << 4).collect();
+                let options = EncodeOptions {
+                    quantization_tables: Some(wide_tables),
+                    optimize_huffman: false,
+                    ..options
+                };
+                let wide_file = encode_image_12bit(&wide, 37, 19, color_type, &options).unwrap();
+                assert_eq!(scan_data(&wide_file), scan_data(&file));
+
+                let sof = header_segments(&wide_file)
+                    .unwrap()
+                    .into_iter()
+                    .find(|segment| (0xc0..=0xc2).contains(&segment.tag))
+                    .unwrap();
+                assert_eq!(sof.tag, if progressive { 0xc2 } else { 0xc1 });
+                assert_eq!(sof.data[0], 12);
+            }
+        }
+
+        let options = EncodeOptions::default();
+        assert!(
+            match encode_image_12bit(&[0, 4096], 2, 1, ColorType::Gray, &options) {
+                Err(Error::Encoder(EncodeError::SampleOutOfRange { value, precision })) => {
+                    (value, precision) == (4096, 12)
+                }
+                _ => false,
+            }
+        );
+        let full: Vec<u16> = (0..64 * 48 * 3).map(|i| (i * 37 % 4096) as u16).collect();
+        for &optimize in &[Optimization::None, Optimization::Trellis] {
+            let options = EncodeOptions {
+                optimize,
+                ..EncodeOptions::default()
+            };
+            assert!(encode_image_12bit(&full, 64, 48, ColorType::Rgb, &options).is_ok());
+        }
+    }
+
+    // thumbnail_size decodes the JPEG thumbnail of a file, returning its size and color type
+    fn thumbnail_size(file: &[u8]) -> (u64, u64, ColorType) {
+        let image = match read_thumbnail(file) {
+            Some(Thumbnail::Jpeg(data)) => decode_image(data).unwrap(),
+            thumbnail => panic!("unexpected thumbnail {:?}", thumbnail),
+        };
+        (image.width, image.height, image.color_type)
+    }
+
+    #[test]
+    fn thumbnail_test() {
+        let pixels = pattern(400, 300);
+        for &segment in &[ThumbnailSegment::Exif, ThumbnailSegment::Jfxx] {
+            let options = EncodeOptions {
+                thumbnail: Some(ThumbnailOptions {
+                    segment,
+                    ..ThumbnailOptions::default()
+                }),
+                ..EncodeOptions::default()
+            };
+            let file =
+                encode_image_with_options(&pixels, 400, 300, ColorType::Rgb, &options).unwrap();
+            assert_eq!(thumbnail_size(&file), (160, 120, ColorType::Rgb));
+            assert_eq!(decode_image(&file).unwrap().width, 400);
+        }
+
+        // the thumbnail joins the caller's EXIF data, in either byte order
+        let mut little_endian = b"II\x2a\x00\x08\x00\x00\x00\x01\x00".to_vec();
+        little_endian.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]);
+        for exif in [exif_tiff(6), little_endian] {
+            let options = EncodeOptions {
+                exif: Some(exif),
+                thumbnail: Some(ThumbnailOptions {
+                    max_size: 50,
+                    ..ThumbnailOptions::default()
+                }),
+                ..EncodeOptions::default()
+            };
+            let file =
+                encode_image_with_options(&pixels, 400, 300, ColorType::Rgb, &options).unwrap();
+            assert_eq!(read_orientation(&file), Some(Orientation::Rotate90));
+            assert_eq!(thumbnail_size(&file), (50, 38, ColorType::Rgb));
+        }
+
+        // small images are embedded at their size, gray ones as gray thumbnails
+        let options = EncodeOptions {
+            thumbnail: Some(ThumbnailOptions::default()),
+            ..EncodeOptions::default()
+        };
+        let gray = vec![100u8; 90 * 20];
+        let file = encode_image_with_options(&gray, 20, 90, ColorType::Gray, &options).unwrap();
+        assert_eq!(thumbnail_size(&file), (20, 90, ColorType::Gray));
+        let planes = ycbcr_planes(&pixels, (400, 300), ChromaSubsampling::Chroma420, 0);
+        let plane = |index: usize| Plane {
+            samples: &planes[index].0,
+            stride: planes[index].1,
+        };
+        let input = YCbCrPlanes {
+            y: plane(0),
+            cb: plane(1),
+            cr: plane(2),
+        };
+        let file = encode_ycbcr_planes(&input, 400, 300, &options).unwrap();
+        assert_eq!(thumbnail_size(&file), (160, 120, ColorType::Rgb));
+        let cmyk = vec![30u8; 200 * 100 * 4];
+        let file = encode_cmyk_image(&cmyk, 200, 100, CmykTransform::None, &options).unwrap();
+        assert_eq!(thumbnail_size(&file), (160, 80, ColorType::Rgb));
+
+        let options = EncodeOptions {
+            exif: Some(b"not a TIFF".to_vec()),
+            ..options
+        };
+        let result = encode_image_with_options(&gray, 20, 90, ColorType::Gray, &options);
+        assert!(matches!(
+            result,
+            Err(Error::Encoder(EncodeError::InvalidExif))
+        ));
+    }
+
+    #[test]
+    fn restart_interval_test() {
+        let pixels = pattern(37, 19);
+        for &progressive in &[false, true] {
+            for &optimize_huffman in &[false, true] {
+                let encode = |restart_interval| {
+                    let options = EncodeOptions {
+                        progressive,
+                        optimize_huffman,
+                        restart_interval,
+                        ..EncodeOptions::default()
+                    };
+                    encode_image_with_options(&pixels, 37, 19, ColorType::Rgb, &options).unwrap()
+                };
+                let expected = decode_image(&encode(0)).unwrap().pixels;
+                for &restart_interval in &[1, 2, 5, 100] {
+                    let file = encode(restart_interval);
+                    let (image, stats) =
+                        decode_image_with_stats(&file, &DecodeOptions::default()).unwrap();
+                    assert_eq!(image.pixels, expected);
+
+                    let markers = parser::decode(&file).unwrap();
+                    assert!(markers.contains(&Marker::DRI(restart_interval)));
+                    // 3 x 2 MCUs of 4:2:0 in interleaved scans, 5 x 3 luma and 3 x 2 chroma blocks in the others
+                    let mcus: &[usize] = if progressive {
+                        &[6, 15, 6, 6, 15, 15, 6, 6, 6, 15]
+                    } else {
+                        &[6]
+                    };
+                    let restarts: usize = mcus
+                        .iter()
+                        .map(|&mcus| (mcus - 1) / usize::from(restart_interval))
+                        .sum();
+                    assert_eq!(stats.restart_markers, restarts as u64);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scan_script_test() {
+        let scan = |components: &[usize], (start, end), high, low| Scan {
+            components: components.to_vec(),
+            spectral_start: start,
+            spectral_end: end,
+            approximation_high: high,
+            approximation_low: low,
+        };
+        let pixels = pattern(37, 19);
+        let encode = |progressive, scan_script| {
+            let options = EncodeOptions {
+                progressive,
+                scan_script,
+                ..EncodeOptions::default()
+            };
+            encode_image_with_options(&pixels, 37, 19, ColorType::Rgb, &options)
+        };
+        let expected = decode_image(&encode(false, None).unwrap()).unwrap().pixels;
+
+        // one non-interleaved scan per component
+        let sequential = vec![
+            scan(&[2], (0, 63), 0, 0),
+            scan(&[0], (0, 63), 0, 0),
+            scan(&[1], (0, 63), 0, 0),
+        ];
+        let file = encode(false, Some(sequential)).unwrap();
+        assert_eq!(decode_image(&file).unwrap().pixels, expected);
+
+        // spectral selection and successive approximation of luma only, chroma in a single band
+        let progressive = vec![
+            scan(&[0, 1, 2], (0, 0), 0, 0),
+            scan(&[0], (1, 5), 0, 2),
+            scan(&[2], (1, 63), 0, 0),
+            scan(&[0], (6, 63), 0, 1),
+            scan(&[1], (1, 63), 0, 0),
+            scan(&[0], (1, 5), 2, 1),
+            scan(&[0], (1, 63), 1, 0),
+        ];
+        let file = encode(true, Some(progressive.clone())).unwrap();
+        let markers = parser::decode(&file).unwrap();
+        let scans = markers
+            .iter()
+            .filter(|marker| matches!(marker, Marker::Image(..)))
+            .count();
+        assert_eq!(scans, progressive.len());
+        assert_eq!(decode_image(&file).unwrap().pixels, expected);
+
+        let invalid = |progressive, script| match encode(progressive, Some(script)) {
+            Err(Error::Encoder(EncodeError::InvalidScanScript { scan })) => scan,
+            result => panic!("unexpected result {:?}", result.map(|file| file.len())),
+        };
+        assert_eq!(invalid(false, vec![]), 0);
+        // components out of order, missing or repeated
+        assert_eq!(invalid(false, vec![scan(&[1, 0, 2], (0, 63), 0, 0)]), 0);
+        assert_eq!(invalid(false, vec![scan(&[0, 1, 3], (0, 63), 0, 0)]), 0);
+        assert_eq!(invalid(false, vec![scan(&[0, 1], (0, 63), 0, 0)]), 1);
+        assert_eq!(
+            invalid(
+                false,
+                vec![scan(&[0, 1, 2], (0, 63), 0, 0), scan(&[0], (0, 63), 0, 0)]
+            ),
+            1
+        );
+        // sequential scans code all coefficients at once
+        assert_eq!(invalid(false, vec![scan(&[0, 1, 2], (0, 0), 0, 0)]), 0);
+        // AC bands of several components or mixed with DC, and AC before DC
+        let mut script = progressive.clone();
+        script[1] = scan(&[0, 1], (1, 5), 0, 2);
+        assert_eq!(invalid(true, script), 1);
+        let mut script = progressive.clone();
+        script[0] = scan(&[0, 1, 2], (0, 5), 0, 0);
+        assert_eq!(invalid(true, script), 0);
+        let mut script = progressive.clone();
+        script.swap(0, 1);
+        assert_eq!(invalid(true, script), 0);
+        // refinements have to follow the previous scan of the band bit by bit
+        let mut script = progressive.clone();
+        script[5] = scan(&[0], (1, 5), 2, 0);
+        assert_eq!(invalid(true, script), 5);
+        let mut script = progressive.clone();
+        script[6] = scan(&[0], (1, 63), 2, 1);
+        assert_eq!(invalid(true, script), 6);
+        // the last bit of luma stays uncoded
+        assert_eq!(invalid(true, progressive[..6].to_vec()), 6);
+    }
+
+    #[test]
+    fn target_size_test() {
+        let pixels = pattern(64, 48);
+        let options = EncodeOptions {
+            optimize_huffman: true,
+            ..EncodeOptions::default()
+        };
+        let encode = |quality| {
+            let options = EncodeOptions {
+                quality,
+                ..options.clone()
+            };
+            encode_image_with_options(&pixels, 64, 48, ColorType::Rgb, &options).unwrap()
+        };
+        let sizes: Vec<usize> = (1..=100).map(|quality| encode(quality).len()).collect();
+
+        for &quality in &[1u8, 30, 60, 90, 100] {
+            let target = sizes[usize::from(quality) - 1];
+            let file =
+                encode_image_with_target_size(&pixels, (64, 48), ColorType::Rgb, &options, target)
+                    .unwrap();
+            assert!(file.len() <= target);
+            // the chosen quality is at least the one the target was taken from
+            assert!((quality..=100).any(|quality| encode(quality) == file));
+        }
+        let file =
+            encode_image_with_target_size(&pixels, (64, 48), ColorType::Rgb, &options, 1 << 20)
+                .unwrap();
+        assert_eq!(file, encode(100));
+
+        assert_eq!(
+            encode_image_with_target_size(&pixels, (64, 48), ColorType::Rgb, &options, 100)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Encoder Error: smallest encoding of {} bytes exceeds the target of 100",
+                sizes[0]
+            )
+        );
+    }
+
+    #[test]
+    fn density_test() {
+        let jfif = |density| -> Result<Vec<u8>, Error> {
+            let options = EncodeOptions {
+                density,
+                ..EncodeOptions::default()
+            };
+            let file = encode_image_with_options(&[0; 64], 8, 8, ColorType::Gray, &options)?;
+            Ok(header_segments(&file).unwrap()[0].data.to_vec())
+        };
+
+        assert_eq!(
+            jfif(Density::default()).unwrap(),
+            b"JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00"
+        );
+        let density = Density {
+            unit: DensityUnit::DotsPerInch,
+            x: 300,
+            y: 600,
+        };
+        assert_eq!(
+            jfif(density).unwrap(),
+            b"JFIF\x00\x01\x01\x01\x01\x2c\x02\x58\x00\x00"
+        );
+        let density = Density {
+            unit: DensityUnit::DotsPerCentimeter,
+            x: 0,
+            y: 118,
+        };
+        assert!(matches!(
+            jfif(density),
+            Err(Error::Encoder(EncodeError::InvalidDensity { x: 0, y: 118 }))
+        ));
+    }
+
+    #[test]
+    fn encode_error_test() {
+        assert!(matches!(
+            encode_image(&[0; 12], 2, 2, ColorType::Gray),
+            Err(Error::Encoder(EncodeError::InvalidBufferSize {
+                expected: 4,
+                actual: 12
+            }))
+        ));
+        assert!(matches!(
+            encode_image(&[], 0, 2, ColorType::Rgb),
+            Err(Error::Encoder(EncodeError::InvalidDimensions { .. }))
+        ));
+    }
+
+    #[test]
+    fn bit_writer_test() {
+        let mut output = vec![0x42];
+        let codes = [[(0b1111_1111, 8); 256]];
+        let mut writer = BitWriter::new(&mut output, &codes);
+        writer.write_symbol(0, 0x42);
+        writer.write_value(-2, 2);
+        writer.write_value(3, 2);
+        writer.flush();
+
+        assert_eq!(output, vec![0x42, 0xff, 0x00, 0b0111_1111]);
+        assert_eq!(magnitude_category(0), 0);
+        assert_eq!(magnitude_category(-1023), 10);
+    }
+}
@@ -1,5 +1,533 @@
-#[derive(Fail, Debug, PartialEq, Eq)]
-#[fail(display = "Parser Error: {}", reason)]
-pub struct ParserError {
-    pub reason: String,
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
+use std::io;
+
+use huffman::DHTType;
+use summary::write_marker_name;
+
+// ParserError describes why a file couldn't be parsed or decoded. New kinds of failures may be added, so matches
+// need a wildcard arm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParserError {
+    // UnexpectedEof is returned if the input ends in the middle of a segment or scan
+    UnexpectedEof,
+    // TruncatedSegment is returned if the input ends missing bytes before the end of the segment with the given
+    // marker that its length field announces
+    TruncatedSegment { marker: u8, missing: usize },
+    // MissingStartOfImage is returned if the input doesn't start with an SOI marker
+    MissingStartOfImage,
+    // InvalidMarker is returned if a segment doesn't start with 0xFF, found is the byte at its place
+    InvalidMarker { found: u8 },
+    // InvalidMarkerLength is returned if the length of the segment at offset exceeds the input or is too short to
+    // include the length bytes themselves
+    InvalidMarkerLength { offset: usize },
+    // InvalidSegment is returned if the content of a segment with the given marker can't be parsed
+    InvalidSegment { marker: u8 },
+    // UnexpectedScan is returned if an SOS segment is parsed on its own, without its entropy coded data
+    UnexpectedScan,
+    // MissingFrameHeader is returned if a scan or the end of the image is reached before an SOF segment
+    MissingFrameHeader,
+    // UnsupportedSofProcess is returned for frames whose coding process isn't baseline, extended sequential or
+    // progressive Huffman coding, marker is the SOF marker
+    UnsupportedSofProcess { marker: u8 },
+    UnsupportedPrecision { precision: u64 },
+    UnsupportedComponentCount { count: usize },
+    // InvalidFrame is returned for frames without any samples
+    InvalidFrame,
+    // InvalidComponent is returned for components with out of range sampling factors or quantization tables,
+    // or scan components that aren't part of the frame
+    InvalidComponent { id: u64 },
+    BadHuffmanTable { id: u64 },
+    BadQuantizationTable { id: u64 },
+    // InvalidHuffmanCode is returned if the bits at position don't match any code of the Huffman table
+    InvalidHuffmanCode { position: usize },
+    // TooManyHuffmanSymbols is returned for DHT tables with more than the 256 symbols a byte holds
+    TooManyHuffmanSymbols { class: DHTType, count: usize },
+    // OversubscribedHuffmanTable is returned for DHT tables with more codes of a length than the shorter codes
+    // leave room for, which violates the Kraft inequality
+    OversubscribedHuffmanTable { class: DHTType },
+    // AllOnesHuffmanCode is returned for DHT tables using every code of 16 bits, including the code of only one
+    // bits that ITU T.81 C reserves
+    AllOnesHuffmanCode { class: DHTType },
+    // LimitsExceeded is returned for frames larger than the configured decoding limits
+    LimitsExceeded { width: u64, height: u64 },
+    // MemoryLimitExceeded is returned if decoding needs more than limit bytes of buffers
+    MemoryLimitExceeded { required: u64, limit: u64 },
+    InvalidTiffHeader,
+    // OutOfBounds is returned if length bytes at offset of a TIFF, MPF or AVI structure aren't part of the input
+    OutOfBounds { offset: usize, length: usize },
+    // MissingImage is returned if the multi picture index has no image with the given index
+    MissingImage { index: usize },
+    // InvalidMultipartHeader is returned for header lines of multipart streams that are too long and for
+    // Content-Length headers that aren't a number
+    InvalidMultipartHeader,
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParserError::UnexpectedEof => write!(f, "unexpected end of file"),
+            ParserError::TruncatedSegment { marker, missing } => {
+                write_marker_name(f, marker)?;
+                write!(f, " segment truncated, {} bytes missing", missing)
+            }
+            ParserError::MissingStartOfImage => write!(f, "no start of image marker found"),
+            ParserError::InvalidMarker { found } => {
+                write!(f, "expected marker, found {:#x}", found)
+            }
+            ParserError::InvalidMarkerLength { offset } => {
+                write!(f, "invalid length of segment at offset {}", offset)
+            }
+            ParserError::InvalidSegment { marker } => {
+                write!(f, "invalid segment with marker {:#x}", marker)
+            }
+            ParserError::UnexpectedScan => write!(f, "scans can't be parsed as a single segment"),
+            ParserError::MissingFrameHeader => write!(f, "missing frame header"),
+            ParserError::UnsupportedSofProcess { marker } => write!(
+                f,
+                "unsupported coding process SOF{}",
+                marker.wrapping_sub(0xc0)
+            ),
+            ParserError::UnsupportedPrecision { precision } => {
+                write!(f, "unsupported sample precision {}", precision)
+            }
+            ParserError::UnsupportedComponentCount { count } => {
+                write!(f, "unsupported number of components: {}", count)
+            }
+            ParserError::InvalidFrame => write!(f, "empty frame"),
+            ParserError::InvalidComponent { id } => {
+                write!(f, "invalid parameters for component {}", id)
+            }
+            ParserError::BadHuffmanTable { id } => {
+                write!(f, "undefined or invalid huffman table {}", id)
+            }
+            ParserError::BadQuantizationTable { id } => {
+                write!(f, "undefined or invalid quantization table {}", id)
+            }
+            ParserError::InvalidHuffmanCode { position } => {
+                write!(f, "no huffman code matches the bits at {}", position)
+            }
+            ParserError::TooManyHuffmanSymbols { class, count } => {
+                write!(
+                    f,
+                    "huffman table {:?} has {} symbols, more than 256",
+                    class, count
+                )
+            }
+            ParserError::OversubscribedHuffmanTable { class } => {
+                write!(
+                    f,
+                    "huffman table {:?} has more codes than fit their lengths",
+                    class
+                )
+            }
+            ParserError::AllOnesHuffmanCode { class } => {
+                write!(
+                    f,
+                    "huffman table {:?} has a 16 bit code of only one bits",
+                    class
+                )
+            }
+            ParserError::LimitsExceeded { width, height } => {
+                write!(f, "image dimensions {}x{} exceed limits", width, height)
+            }
+            ParserError::MemoryLimitExceeded { required, limit } => write!(
+                f,
+                "decoding needs {} bytes, exceeding the limit of {}",
+                required, limit
+            ),
+            ParserError::InvalidTiffHeader => write!(f, "invalid TIFF header"),
+            ParserError::OutOfBounds { offset, length } => write!(
+                f,
+                "data out of bounds: {} bytes at offset {}",
+                length, offset
+            ),
+            ParserError::MissingImage { index } => write!(f, "MPF image {} out of range", index),
+            ParserError::InvalidMultipartHeader => write!(f, "invalid multipart header"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for ParserError {}
+
+// EncodeError describes why pixels couldn't be encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EncodeError {
+    // InvalidDimensions is returned for images without pixels or more than 65535 pixels wide or high
+    InvalidDimensions {
+        width: u64,
+        height: u64,
+    },
+    // InvalidBufferSize is returned if the pixel buffer doesn't hold exactly the pixels of the image, or a plane
+    // is too short for its lines
+    InvalidBufferSize {
+        expected: usize,
+        actual: usize,
+    },
+    // InvalidQuantizationTable is returned if a caller defined quantization table has a zero entry
+    InvalidQuantizationTable {
+        id: usize,
+    },
+    // MetadataTooLarge is returned for metadata that doesn't fit the segments of the given marker, length is the
+    // size of the metadata
+    MetadataTooLarge {
+        marker: u8,
+        length: usize,
+    },
+    // InvalidDensity is returned for JFIF densities of zero
+    InvalidDensity {
+        x: u16,
+        y: u16,
+    },
+    // InvalidStride is returned for planes with fewer samples per line than their width
+    InvalidStride {
+        stride: usize,
+        width: usize,
+    },
+    // UnsupportedPrecision is returned for lossless sample precisions outside of 2 to 16 bits
+    UnsupportedPrecision {
+        precision: u8,
+    },
+    UnsupportedComponentCount {
+        count: usize,
+    },
+    // SampleOutOfRange is returned for samples that don't fit the precision
+    SampleOutOfRange {
+        value: u16,
+        precision: u8,
+    },
+    // InvalidExif is returned if a thumbnail should be added to exif data that isn't a valid TIFF structure
+    InvalidExif,
+    // InvalidScanScript is returned for scan scripts with an invalid scan at the given index, or that leave
+    // coefficients uncoded, with the number of scans as index
+    InvalidScanScript {
+        scan: usize,
+    },
+    // TargetSizeUnreachable is returned if even the lowest quality encodes into more than target bytes
+    TargetSizeUnreachable {
+        target: usize,
+        smallest: usize,
+    },
+    // InvalidSegment is returned for markers with values that don't fit the fields of the segment with the given
+    // marker, like table ids above 15
+    InvalidSegment {
+        marker: u8,
+    },
+    // UnsupportedQuantizationTables is returned for coefficients of components with more than two distinct
+    // quantization tables
+    UnsupportedQuantizationTables {
+        count: usize,
+    },
+    // InvalidCrop is returned for crop rectangles that are empty or start outside of the image
+    InvalidCrop {
+        x: u64,
+        y: u64,
+        width: u64,
+        height: u64,
+    },
+    // CoefficientOutOfRange is returned for quantized coefficients of the component with the given id whose
+    // magnitude exceeds what the size categories of 8 bit samples can code
+    CoefficientOutOfRange {
+        component: u64,
+        value: i16,
+    },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::InvalidDimensions { width, height } => {
+                write!(f, "image dimensions {}x{} can't be encoded", width, height)
+            }
+            EncodeError::InvalidBufferSize { expected, actual } => {
+                write!(f, "expected {} bytes of pixels, found {}", expected, actual)
+            }
+            EncodeError::InvalidQuantizationTable { id } => {
+                write!(f, "quantization table {} has a zero entry", id)
+            }
+            EncodeError::MetadataTooLarge { marker, length } => write!(
+                f,
+                "{} bytes of metadata don't fit segments with marker {:#x}",
+                length, marker
+            ),
+            EncodeError::InvalidDensity { x, y } => {
+                write!(f, "invalid pixel density {}x{}", x, y)
+            }
+            EncodeError::InvalidStride { stride, width } => write!(
+                f,
+                "stride {} is shorter than the plane width {}",
+                stride, width
+            ),
+            EncodeError::UnsupportedPrecision { precision } => {
+                write!(f, "unsupported sample precision {}", precision)
+            }
+            EncodeError::UnsupportedComponentCount { count } => {
+                write!(f, "unsupported number of components: {}", count)
+            }
+            EncodeError::SampleOutOfRange { value, precision } => {
+                write!(f, "sample {} exceeds {} bits", value, precision)
+            }
+            EncodeError::InvalidExif => write!(f, "invalid EXIF data"),
+            EncodeError::InvalidScanScript { scan } => {
+                write!(f, "invalid scan {} of scan script", scan)
+            }
+            EncodeError::TargetSizeUnreachable { target, smallest } => write!(
+                f,
+                "smallest encoding of {} bytes exceeds the target of {}",
+                smallest, target
+            ),
+            EncodeError::InvalidSegment { marker } => {
+                write!(f, "invalid fields in segment with marker {:#x}", marker)
+            }
+            EncodeError::UnsupportedQuantizationTables { count } => write!(
+                f,
+                "{} distinct quantization tables, at most 2 are supported",
+                count
+            ),
+            EncodeError::InvalidCrop {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "crop of {}x{} pixels at {},{} is outside of the image",
+                width, height, x, y
+            ),
+            EncodeError::CoefficientOutOfRange { component, value } => write!(
+                f,
+                "coefficient {} of component {} is out of range",
+                value, component
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for EncodeError {}
+
+impl ParserError {
+    // at adds where decoding failed to the error
+    pub(crate) fn at(self, stage: Stage, marker: Option<u8>, offset: usize) -> Error {
+        Error::Decode {
+            error: self,
+            context: ErrorContext {
+                stage,
+                marker,
+                offset,
+            },
+        }
+    }
+
+    // truncated returns the error for input ending in the segment at its start, TruncatedSegment if its length
+    // is known and exceeds the input, UnexpectedEof otherwise
+    pub(crate) fn truncated(input: &[u8]) -> ParserError {
+        match *input {
+            [0xff, marker, high, low, ..] => {
+                let length = 2 + usize::from(u16::from_be_bytes([high, low]));
+                if length > input.len() {
+                    ParserError::TruncatedSegment {
+                        marker,
+                        missing: length - input.len(),
+                    }
+                } else {
+                    ParserError::UnexpectedEof
+                }
+            }
+            _ => ParserError::UnexpectedEof,
+        }
+    }
+
+    // from_nom classifies a nom error of parsing the segment at the start of input. Segments longer than the input
+    // are truncated, whichever error the parser of their content gave up with.
+    pub(crate) fn from_nom<E>(input: &[u8], error: ::nom::Err<E>) -> ParserError {
+        if let truncated @ ParserError::TruncatedSegment { .. } = ParserError::truncated(input) {
+            return truncated;
+        }
+        match error {
+            ::nom::Err::Incomplete(_) => ParserError::UnexpectedEof,
+            _ => ParserError::InvalidSegment {
+                marker: input.get(1).cloned().unwrap_or(0),
+            },
+        }
+    }
+}
+
+// Stage is the step of decoding a file that failed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stage {
+    // Parse reads the segments of the file and sets up the frame they describe
+    Parse,
+    // Entropy decodes the entropy coded data of a scan with the tables its header references
+    Entropy,
+    // Idct dequantizes and transforms the coefficients of all scans and converts them into the output
+    Idct,
+}
+
+// ErrorContext tells where decoding a file failed. marker is the marker of the segment or scan that was being
+// processed and offset the absolute position of that marker in the file. Errors after the last scan refer to
+// the frame header, errors without any segment to blame have no marker and the offset of the byte decoding
+// stopped at.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ErrorContext {
+    pub stage: Stage,
+    pub marker: Option<u8>,
+    pub offset: usize,
+}
+
+// Error is returned by the public API. Besides malformed input, the Read based Decoder can fail reading its input.
+// Decoding a file returns Decode errors, which add where the ParserError happened.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    Parser(ParserError),
+    Decode {
+        error: ParserError,
+        context: ErrorContext,
+    },
+    Encoder(EncodeError),
+    #[cfg(feature = "std")]
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parser(ref e) => write!(f, "Parser Error: {}", e),
+            Error::Decode { ref error, context } => {
+                write!(f, "Parser Error: {} ", error)?;
+                if let Some(marker) = context.marker {
+                    f.write_str("in ")?;
+                    write_marker_name(f, marker)?;
+                    f.write_str(" ")?;
+                }
+                let stage = match context.stage {
+                    Stage::Parse => "parsing",
+                    Stage::Entropy => "entropy decoding",
+                    Stage::Idct => "the IDCT",
+                };
+                write!(f, "at offset {}, during {}", context.offset, stage)
+            }
+            Error::Encoder(ref e) => write!(f, "Encoder Error: {}", e),
+            #[cfg(feature = "std")]
+            Error::Io(ref e) => write!(f, "IO Error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Parser(ref e) => Some(e),
+            Error::Decode { ref error, .. } => Some(error),
+            Error::Encoder(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+        }
+    }
+}
+
+impl Error {
+    // parser_error returns the ParserError of Parser and Decode errors
+    pub fn parser_error(&self) -> Option<&ParserError> {
+        match *self {
+            Error::Parser(ref error) | Error::Decode { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    // context returns where decoding failed for Decode errors
+    pub fn context(&self) -> Option<ErrorContext> {
+        match *self {
+            Error::Decode { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    // with_context turns Parser errors into Decode errors with the given context, other errors stay as they are
+    pub(crate) fn with_context(self, context: ErrorContext) -> Error {
+        match self {
+            Error::Parser(error) => Error::Decode { error, context },
+            error => error,
+        }
+    }
+}
+
+impl From<ParserError> for Error {
+    fn from(error: ParserError) -> Error {
+        Error::Parser(error)
+    }
+}
+
+impl From<EncodeError> for Error {
+    fn from(error: EncodeError) -> Error {
+        Error::Encoder(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::error::Error as StdError;
+
+    #[test]
+    fn error_test() {
+        let error = Error::from(ParserError::BadHuffmanTable { id: 1 });
+        assert_eq!(
+            error.to_string(),
+            "Parser Error: undefined or invalid huffman table 1"
+        );
+        assert_eq!(
+            error.source().unwrap().to_string(),
+            "undefined or invalid huffman table 1"
+        );
+
+        let error = Error::from(EncodeError::InvalidDimensions {
+            width: 0,
+            height: 7,
+        });
+        assert_eq!(
+            error.to_string(),
+            "Encoder Error: image dimensions 0x7 can't be encoded"
+        );
+
+        let error = ParserError::BadHuffmanTable { id: 1 }.at(Stage::Entropy, Some(0xda), 620);
+        assert_eq!(
+            error.to_string(),
+            "Parser Error: undefined or invalid huffman table 1 in SOS at offset 620, during entropy decoding"
+        );
+        assert_eq!(
+            error.parser_error(),
+            Some(&ParserError::BadHuffmanTable { id: 1 })
+        );
+        assert_eq!(error.context().unwrap().offset, 620);
+        let error = Error::from(ParserError::UnexpectedEof).with_context(ErrorContext {
+            stage: Stage::Parse,
+            marker: None,
+            offset: 7,
+        });
+        assert_eq!(
+            error.to_string(),
+            "Parser Error: unexpected end of file at offset 7, during parsing"
+        );
+
+        let error = Error::from(io::Error::other("broken pipe"));
+        assert!(match error {
+            Error::Io(ref e) => e.kind() == io::ErrorKind::Other,
+            _ => false,
+        });
+    }
 }
@@ -0,0 +1,215 @@
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use parser::header_segments;
+use tiff::{ByteOrder, Tiff};
+
+pub(crate) const ORIENTATION_TAG: u16 = 0x0112;
+pub(crate) const SHORT: u16 = 3;
+
+// Orientation is the EXIF orientation tag, named after the transformation that displays the stored image upright
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Orientation {
+    Normal,
+    MirrorHorizontal,
+    Rotate180,
+    MirrorVertical,
+    // Transpose mirrors along the top left to bottom right diagonal
+    Transpose,
+    // Rotate90 rotates clockwise
+    Rotate90,
+    // Transverse mirrors along the top right to bottom left diagonal
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    pub fn from_exif(value: u16) -> Option<Orientation> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::MirrorHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::MirrorVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }
+    }
+
+    pub fn to_exif(self) -> u16 {
+        match self {
+            Orientation::Normal => 1,
+            Orientation::MirrorHorizontal => 2,
+            Orientation::Rotate180 => 3,
+            Orientation::MirrorVertical => 4,
+            Orientation::Transpose => 5,
+            Orientation::Rotate90 => 6,
+            Orientation::Transverse => 7,
+            Orientation::Rotate270 => 8,
+        }
+    }
+
+    // swaps_dimensions is true for the orientations that exchange width and height
+    pub fn swaps_dimensions(self) -> bool {
+        matches!(
+            self,
+            Orientation::Transpose
+                | Orientation::Rotate90
+                | Orientation::Transverse
+                | Orientation::Rotate270
+        )
+    }
+
+    // apply transforms width x height pixels of bytes_per_pixel bytes each, returning the transformed pixels and
+    // their dimensions
+    pub fn apply(
+        self,
+        pixels: &[u8],
+        width: usize,
+        height: usize,
+        bytes_per_pixel: usize,
+    ) -> (Vec<u8>, usize, usize) {
+        let mut output = Vec::with_capacity(pixels.len());
+        let (output_width, output_height) =
+            self.apply_into(pixels, width, height, bytes_per_pixel, &mut output);
+        (output, output_width, output_height)
+    }
+
+    // apply_into is apply writing the transformed pixels into output, which is cleared first
+    pub fn apply_into(
+        self,
+        pixels: &[u8],
+        width: usize,
+        height: usize,
+        bytes_per_pixel: usize,
+        output: &mut Vec<u8>,
+    ) -> (usize, usize) {
+        let (output_width, output_height) = if self.swaps_dimensions() {
+            (height, width)
+        } else {
+            (width, height)
+        };
+
+        output.clear();
+        output.reserve(pixels.len());
+        for y in 0..output_height {
+            for x in 0..output_width {
+                let (source_x, source_y) = match self {
+                    Orientation::Normal => (x, y),
+                    Orientation::MirrorHorizontal => (width - 1 - x, y),
+                    Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+                    Orientation::MirrorVertical => (x, height - 1 - y),
+                    Orientation::Transpose => (y, x),
+                    Orientation::Rotate90 => (y, height - 1 - x),
+                    Orientation::Transverse => (width - 1 - y, height - 1 - x),
+                    Orientation::Rotate270 => (width - 1 - y, x),
+                };
+                let offset = (source_y * width + source_x) * bytes_per_pixel;
+                output.extend_from_slice(&pixels[offset..offset + bytes_per_pixel]);
+            }
+        }
+
+        (output_width, output_height)
+    }
+}
+
+// read_orientation returns the orientation stored in the EXIF segment of jpeg_file. Files without EXIF data or
+// with a malformed one have no orientation.
+pub fn read_orientation(jpeg_file: &[u8]) -> Option<Orientation> {
+    header_segments(jpeg_file)
+        .ok()?
+        .into_iter()
+        .filter(|segment| segment.tag == 0xe1)
+        .find_map(|segment| exif_orientation(segment.data))
+}
+
+// exif_orientation reads the orientation tag from IFD0 of the content of an APP1 segment
+pub fn exif_orientation(data: &[u8]) -> Option<Orientation> {
+    if !data.starts_with(b"Exif\x00\x00") {
+        return None;
+    }
+
+    let tiff = Tiff::new(&data[6..]).ok()?;
+    let (entries, _) = tiff.read_ifd(tiff.first_ifd_offset().ok()?).ok()?;
+    let entry = entries
+        .iter()
+        .find(|entry| entry.tag == ORIENTATION_TAG && entry.field_type == SHORT)?;
+
+    // a single SHORT is stored in the first two bytes of the value field
+    let value = match tiff.byte_order {
+        ByteOrder::LittleEndian => entry.value_offset as u16,
+        ByteOrder::BigEndian => (entry.value_offset >> 16) as u16,
+    };
+    Orientation::from_exif(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // exif_segment returns an APP1 segment with IFD0 holding only the orientation tag
+    fn exif_segment(orientation: u16) -> Vec<u8> {
+        let mut segment = b"Exif\x00\x00".to_vec();
+        segment.extend(&[
+            0x4D, 0x4D, 0x00, 0x2A, 0x00, 0x00, 0x00, 0x08, // header
+            0x00, 0x01, // one entry
+            0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, // orientation SHORT
+        ]);
+        segment.extend(&orientation.to_be_bytes());
+        segment.extend(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        segment
+    }
+
+    #[test]
+    fn read_orientation_test() {
+        let segment = exif_segment(6);
+        let mut file = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        file.extend(&((segment.len() + 2) as u16).to_be_bytes());
+        file.extend(&segment);
+        file.extend(&[0xFF, 0xD9]);
+
+        assert_eq!(read_orientation(&file), Some(Orientation::Rotate90));
+        assert_eq!(exif_orientation(&exif_segment(9)), None);
+        assert_eq!(
+            read_orientation(include_bytes!("../tests/images/gray.jpg")),
+            None
+        );
+    }
+
+    #[test]
+    fn apply_test() {
+        // 1 2 3
+        // 4 5 6
+        let pixels = [1, 2, 3, 4, 5, 6];
+        let apply = |orientation: Orientation| orientation.apply(&pixels, 3, 2, 1);
+
+        assert_eq!(apply(Orientation::Normal), (pixels.to_vec(), 3, 2));
+        assert_eq!(
+            apply(Orientation::MirrorHorizontal),
+            (vec![3, 2, 1, 6, 5, 4], 3, 2)
+        );
+        assert_eq!(
+            apply(Orientation::Rotate180),
+            (vec![6, 5, 4, 3, 2, 1], 3, 2)
+        );
+        assert_eq!(
+            apply(Orientation::MirrorVertical),
+            (vec![4, 5, 6, 1, 2, 3], 3, 2)
+        );
+        assert_eq!(
+            apply(Orientation::Transpose),
+            (vec![1, 4, 2, 5, 3, 6], 2, 3)
+        );
+        assert_eq!(apply(Orientation::Rotate90), (vec![4, 1, 5, 2, 6, 3], 2, 3));
+        assert_eq!(
+            apply(Orientation::Transverse),
+            (vec![6, 3, 5, 2, 4, 1], 2, 3)
+        );
+        assert_eq!(
+            apply(Orientation::Rotate270),
+            (vec![3, 6, 2, 5, 1, 4], 2, 3)
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// C interface for one-shot decoding. Build it as a static library with
+//
+//     cargo rustc --release --features ffi --crate-type staticlib
+//
+// and generate a header with cbindgen. Every function documents the pointer requirements callers have to meet.
+#![allow(clippy::missing_safety_doc)]
+
+use std::panic;
+use std::ptr;
+use std::slice;
+
+use decoder::{ColorType, Decoder};
+use info::read_info;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RsjpegStatus {
+    Ok = 0,
+    InvalidArgument = 1,
+    DecodeError = 2,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RsjpegInfo {
+    pub width: u32,
+    pub height: u32,
+    pub precision: u32,
+    pub components: u32,
+    pub progressive: bool,
+}
+
+// RsjpegImage owns width * height * 3 bytes of interleaved RGB pixels, it has to be released with
+// rsjpeg_free_image
+#[repr(C)]
+#[derive(Debug)]
+pub struct RsjpegImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: *mut u8,
+    pub length: usize,
+}
+
+// rsjpeg_read_info parses the frame header of the length bytes at data into info. data has to point to length
+// readable bytes and info to a writable RsjpegInfo.
+#[no_mangle]
+pub unsafe extern "C" fn rsjpeg_read_info(
+    data: *const u8,
+    length: usize,
+    info: *mut RsjpegInfo,
+) -> RsjpegStatus {
+    if data.is_null() || info.is_null() {
+        return RsjpegStatus::InvalidArgument;
+    }
+    let jpeg_file = slice::from_raw_parts(data, length);
+
+    match panic::catch_unwind(|| read_info(jpeg_file)) {
+        Ok(Ok(image_info)) => {
+            *info = RsjpegInfo {
+                width: image_info.width as u32,
+                height: image_info.height as u32,
+                precision: image_info.precision as u32,
+                components: image_info.components as u32,
+                progressive: image_info.progressive,
+            };
+            RsjpegStatus::Ok
+        }
+        _ => RsjpegStatus::DecodeError,
+    }
+}
+
+// rsjpeg_decode_rgb decodes the length bytes at data into RGB pixels stored in image. data has to point to length
+// readable bytes and image to a writable RsjpegImage. image is left empty if decoding fails.
+#[no_mangle]
+pub unsafe extern "C" fn rsjpeg_decode_rgb(
+    data: *const u8,
+    length: usize,
+    image: *mut RsjpegImage,
+) -> RsjpegStatus {
+    if data.is_null() || image.is_null() {
+        return RsjpegStatus::InvalidArgument;
+    }
+    let jpeg_file = slice::from_raw_parts(data, length);
+    *image = RsjpegImage {
+        width: 0,
+        height: 0,
+        pixels: ptr::null_mut(),
+        length: 0,
+    };
+
+    let decoded =
+        panic::catch_unwind(|| Decoder::new(jpeg_file).color_type(ColorType::Rgb).decode());
+    match decoded {
+        Ok(Ok(decoded)) => {
+            let pixels = decoded.pixels.into_boxed_slice();
+            *image = RsjpegImage {
+                width: decoded.width as u32,
+                height: decoded.height as u32,
+                length: pixels.len(),
+                pixels: Box::into_raw(pixels) as *mut u8,
+            };
+            RsjpegStatus::Ok
+        }
+        _ => RsjpegStatus::DecodeError,
+    }
+}
+
+// rsjpeg_free_image releases the pixels of an image filled by rsjpeg_decode_rgb and resets it. image has to be
+// null or point to such an image, freeing it twice is safe.
+#[no_mangle]
+pub unsafe extern "C" fn rsjpeg_free_image(image: *mut RsjpegImage) {
+    if image.is_null() || (*image).pixels.is_null() {
+        return;
+    }
+
+    let pixels = slice::from_raw_parts_mut((*image).pixels, (*image).length);
+    drop(Box::from_raw(pixels as *mut [u8]));
+    (*image).pixels = ptr::null_mut();
+    (*image).length = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::decode_image;
+
+    fn empty_image() -> RsjpegImage {
+        RsjpegImage {
+            width: 0,
+            height: 0,
+            pixels: ptr::null_mut(),
+            length: 0,
+        }
+    }
+
+    #[test]
+    fn decode_rgb_test() {
+        let file = include_bytes!("../tests/images/gray.jpg");
+        let mut image = empty_image();
+
+        unsafe {
+            assert_eq!(
+                rsjpeg_decode_rgb(file.as_ptr(), file.len(), &mut image),
+                RsjpegStatus::Ok
+            );
+            assert_eq!(
+                (image.width, image.height, image.length),
+                (32, 24, 32 * 24 * 3)
+            );
+
+            let gray = decode_image(file).unwrap();
+            let pixels = slice::from_raw_parts(image.pixels, image.length);
+            assert_eq!(&pixels[..3], &[gray.pixels[0]; 3][..]);
+            assert_eq!(&pixels[3..6], &[gray.pixels[1]; 3][..]);
+
+            rsjpeg_free_image(&mut image);
+            assert!(image.pixels.is_null());
+            rsjpeg_free_image(&mut image);
+            rsjpeg_free_image(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn decode_error_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..100];
+        let mut image = empty_image();
+
+        unsafe {
+            assert_eq!(
+                rsjpeg_decode_rgb(file.as_ptr(), file.len(), &mut image),
+                RsjpegStatus::DecodeError
+            );
+            assert!(image.pixels.is_null());
+            assert_eq!(
+                rsjpeg_decode_rgb(ptr::null(), 0, &mut image),
+                RsjpegStatus::InvalidArgument
+            );
+        }
+    }
+
+    #[test]
+    fn read_info_test() {
+        let file = include_bytes!("../tests/images/progressive_420.jpg");
+        let mut info = RsjpegInfo {
+            width: 0,
+            height: 0,
+            precision: 0,
+            components: 0,
+            progressive: false,
+        };
+
+        unsafe {
+            assert_eq!(
+                rsjpeg_read_info(file.as_ptr(), file.len(), &mut info),
+                RsjpegStatus::Ok
+            );
+            assert_eq!(
+                rsjpeg_read_info(file.as_ptr(), 2, &mut info),
+                RsjpegStatus::DecodeError
+            );
+        }
+        assert_eq!(
+            info,
+            RsjpegInfo {
+                width: 32,
+                height: 24,
+                precision: 8,
+                components: 3,
+                progressive: true,
+            }
+        );
+    }
+}
@@ -1,83 +1,551 @@
-use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use prelude::*;
 
-use bitvec::prelude::*;
+use core::cmp::Ordering;
+use core::fmt;
 
+use bitreader::BitReader;
 use errors::ParserError;
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum DHTType {
-    LuminanceDC,
-    LuminanceAC,
-    ChrominanceDC,
-    ChrominanceAC,
+// TableClass is the class of a Huffman table, coding either DC differences or AC coefficients
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum TableClass {
+    DC,
+    AC,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+// DHTType names a Huffman table by its class and its destination id from 0 to 3, as DHT segments define and
+// scan headers reference it. Which components use it is up to the scans, the luminance and chrominance names
+// only follow the convention of baseline encoders to code Y with the tables of id 0 and the chroma with id 1.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DHTType {
+    class: TableClass,
+    id: u8,
+}
+
+// TableSet holds one value for every Huffman table a scan can reference, indexed by class and destination id
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TableSet<T> {
+    tables: [[Option<T>; 4]; 2],
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct HuffmanTable {
     pub class: DHTType,
-    // symbols contains the raw DHT read from the JPEG file. It's not really useful in that format, but needs to
-    // be translated via make_translation_map
-    pub symbols: [Vec<u8>; 16],
+    // counts holds the number of codes of every length from 1 to 16 bits and values their symbols in the order
+    // of the codes, as DHT segments store them. Decoding needs the codes HuffmanDecoder::new assigns them.
+    pub counts: [u8; 16],
+    pub values: Vec<u8>,
+}
+
+// HuffmanCode is the canonical code ITU T.81 C.2 assigns to a symbol of a HuffmanTable, in its length low bits
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HuffmanCode {
+    pub symbol: u8,
+    pub code: u16,
+    pub length: u8,
+}
+
+// HuffmanDecoder finds the symbols of the canonical codes of a HuffmanTable as in ITU T.81 F.2.2.3. The codes of
+// every length are consecutive numbers, so a lookup compares the code with the range of its length and doesn't
+// depend on anything but the table. Codes that don't fit their length in tables with too many codes never match.
+// Most symbols have short codes, which are found with a single lookup of the next LOOKAHEAD_BITS bits, like
+// libjpeg does. Building it takes the time of decoding a few hundred codes, so the decoder builds one for every
+// table a file defines and uses it for all scans and MCUs referencing the table.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct HuffmanDecoder {
+    // first_codes holds the first code of every length from 1 to 16 bits
+    first_codes: [u32; 16],
+    // first_indices holds the position of the symbol of the first code of every length in symbols
+    first_indices: [usize; 16],
+    counts: [usize; 16],
+    symbols: Vec<u8>,
+    // lookahead holds the length of the code the next LOOKAHEAD_BITS bits start with in its high and its symbol
+    // in its low byte, or 0 if the code is longer
+    lookahead: Vec<u16>,
+}
+
+const LOOKAHEAD_BITS: usize = 8;
+
+// Standard tables from ITU T.81 Annex K.3, stored as code counts per length followed by the symbol values.
+// Encoders like MJPEG webcams rely on these without ever emitting a DHT segment.
+const LUMINANCE_DC_COUNTS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const LUMINANCE_DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const CHROMINANCE_DC_COUNTS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const CHROMINANCE_DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const LUMINANCE_AC_COUNTS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const LUMINANCE_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+const CHROMINANCE_AC_COUNTS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const CHROMINANCE_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+#[allow(non_upper_case_globals)]
+impl DHTType {
+    pub const LuminanceDC: DHTType = DHTType {
+        class: TableClass::DC,
+        id: 0,
+    };
+    pub const LuminanceAC: DHTType = DHTType {
+        class: TableClass::AC,
+        id: 0,
+    };
+    pub const ChrominanceDC: DHTType = DHTType {
+        class: TableClass::DC,
+        id: 1,
+    };
+    pub const ChrominanceAC: DHTType = DHTType {
+        class: TableClass::AC,
+        id: 1,
+    };
+
+    // new returns the table of the given class and destination id, which must be below 4
+    pub fn new(class: TableClass, id: u8) -> Option<DHTType> {
+        if id < 4 {
+            Some(DHTType { class, id })
+        } else {
+            None
+        }
+    }
+
+    // from_selector maps a table class (0 = DC, 1 = AC) and destination id as found in DHT and SOS
+    // segments to the table it refers to
+    pub fn from_selector(class: u8, id: u8) -> Option<DHTType> {
+        match class {
+            0 => DHTType::new(TableClass::DC, id),
+            1 => DHTType::new(TableClass::AC, id),
+            _ => None,
+        }
+    }
+
+    // selector returns the table class and destination id of the table, the inverse of from_selector
+    pub fn selector(self) -> (u8, u8) {
+        (self.class as u8, self.id)
+    }
+
+    pub fn class(self) -> TableClass {
+        self.class
+    }
+
+    pub fn id(self) -> u8 {
+        self.id
+    }
+}
+
+// Debug prints the tables of ids 0 and 1 by their conventional names and the others by class and id
+impl fmt::Debug for DHTType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.class, self.id) {
+            (TableClass::DC, 0) => f.write_str("LuminanceDC"),
+            (TableClass::AC, 0) => f.write_str("LuminanceAC"),
+            (TableClass::DC, 1) => f.write_str("ChrominanceDC"),
+            (TableClass::AC, 1) => f.write_str("ChrominanceAC"),
+            (class, id) => write!(f, "{:?}{}", class, id),
+        }
+    }
+}
+
+impl<T> Default for TableSet<T> {
+    fn default() -> TableSet<T> {
+        TableSet {
+            tables: [[None, None, None, None], [None, None, None, None]],
+        }
+    }
+}
+
+impl<T> TableSet<T> {
+    pub fn new() -> TableSet<T> {
+        TableSet::default()
+    }
+
+    // get returns the value of the table, if one was inserted
+    pub fn get(&self, table: DHTType) -> Option<&T> {
+        self.tables[table.class as usize][usize::from(table.id)].as_ref()
+    }
+
+    // insert stores the value of the table, replacing and returning the one a previous segment defined
+    pub fn insert(&mut self, table: DHTType, value: T) -> Option<T> {
+        self.tables[table.class as usize][usize::from(table.id)].replace(value)
+    }
+
+    pub fn contains(&self, table: DHTType) -> bool {
+        self.get(table).is_some()
+    }
+
+    // iter returns the tables with a value, DC tables first and each class ordered by destination id
+    pub fn iter(&self) -> impl Iterator<Item = (DHTType, &T)> {
+        [TableClass::DC, TableClass::AC]
+            .iter()
+            .flat_map(move |&class| {
+                self.tables[class as usize]
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(id, value)| {
+                        value.as_ref().map(|value| {
+                            (
+                                DHTType {
+                                    class,
+                                    id: id as u8,
+                                },
+                                value,
+                            )
+                        })
+                    })
+            })
+    }
 }
 
 impl HuffmanTable {
+    // standard returns the Annex K table for the given class, the chrominance tables for ids other than 0
+    pub fn standard(class: DHTType) -> HuffmanTable {
+        let (counts, values): (&[u8; 16], &[u8]) = match (class.class, class.id) {
+            (TableClass::DC, 0) => (&LUMINANCE_DC_COUNTS, &LUMINANCE_DC_VALUES),
+            (TableClass::AC, 0) => (&LUMINANCE_AC_COUNTS, &LUMINANCE_AC_VALUES),
+            (TableClass::DC, _) => (&CHROMINANCE_DC_COUNTS, &CHROMINANCE_DC_VALUES),
+            (TableClass::AC, _) => (&CHROMINANCE_AC_COUNTS, &CHROMINANCE_AC_VALUES),
+        };
+
+        HuffmanTable {
+            class,
+            counts: *counts,
+            values: values.to_vec(),
+        }
+    }
+
+    // from_symbols builds the table with the given symbols of the codes of every length from 1 bit on. It panics
+    // for more than 16 lengths or 255 codes of a length, which DHT segments can't store.
+    pub fn from_symbols(class: DHTType, symbols: &[Vec<u8>]) -> HuffmanTable {
+        assert!(
+            symbols.len() <= 16,
+            "huffman codes are at most 16 bits long"
+        );
+        let mut counts = [0u8; 16];
+        for (count, symbols) in counts.iter_mut().zip(symbols) {
+            assert!(
+                symbols.len() <= 255,
+                "at most 255 huffman codes have the same length"
+            );
+            *count = symbols.len() as u8;
+        }
+        HuffmanTable {
+            class,
+            counts,
+            values: symbols.concat(),
+        }
+    }
+
+    // symbols returns the symbols of the codes of every length from 1 to 16 bits as slices of values. Lengths
+    // whose counts go beyond the end of values get the symbols that are left.
+    pub fn symbols(&self) -> impl Iterator<Item = &[u8]> {
+        let values = &self.values[..];
+        self.counts
+            .iter()
+            .scan(0, move |offset: &mut usize, &count| {
+                let start = (*offset).min(values.len());
+                *offset += usize::from(count);
+                Some(&values[start..(*offset).min(values.len())])
+            })
+    }
+
+    // check checks that the code lengths of the table form a prefix code as ITU T.81 C allows it: at most 256
+    // symbols, no more codes of a length than the shorter ones leave room for, and no 16 bit code of only one bits
+    pub fn check(&self) -> Result<(), ParserError> {
+        let count: usize = self.counts.iter().map(|&count| usize::from(count)).sum();
+        if count > 256 {
+            return Err(ParserError::TooManyHuffmanSymbols {
+                class: self.class,
+                count,
+            });
+        }
+
+        // every code of length bits takes 2^(16 - length) of the 2^16 codes of 16 bits. Using all of them makes
+        // the last code of the longest length consist of one bits, which is only reserved for 16 bit codes.
+        let kraft: usize = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(len, &count)| usize::from(count) << (15 - len))
+            .sum();
+        match kraft.cmp(&(1 << 16)) {
+            Ordering::Equal if self.counts[15] > 0 => {
+                Err(ParserError::AllOnesHuffmanCode { class: self.class })
+            }
+            Ordering::Less | Ordering::Equal => Ok(()),
+            Ordering::Greater => Err(ParserError::OversubscribedHuffmanTable { class: self.class }),
+        }
+    }
+
+    // huffman_decode decodes the symbols of code with a HuffmanDecoder built for this call. Decoding several
+    // codes with the same table is faster with one HuffmanDecoder.
     pub fn huffman_decode(&self, code: &[u8]) -> Result<Vec<u8>, ParserError> {
-        let translation = self.make_translation_map();
+        HuffmanDecoder::new(self).decode(code)
+    }
 
-        let bits: &BitSlice = code.into();
-        let mut result = Vec::new();
+    // codes returns the canonical codes of the symbols in the order of values: the codes of every length are
+    // consecutive numbers following the last code of the length before, shifted by a bit. The decoder, the
+    // encoder and summaries all read the codes from here. Codes that don't fit their length in tables with too
+    // many codes are left out.
+    pub fn codes(&self) -> impl Iterator<Item = HuffmanCode> + '_ {
+        self.symbols()
+            .enumerate()
+            .scan(0u32, |next: &mut u32, (len, symbols)| {
+                let first = *next;
+                *next = (first + symbols.len() as u32) << 1;
+                Some((first, len as u8 + 1, symbols))
+            })
+            .flat_map(|(first, length, symbols)| {
+                symbols
+                    .iter()
+                    .zip(first..)
+                    .filter(move |&(_, code)| code < 1 << length)
+                    .map(move |(&symbol, code)| HuffmanCode {
+                        symbol,
+                        code: code as u16,
+                        length,
+                    })
+            })
+    }
 
-        let mut cursor = 0usize;
+    // make_code_table returns the code of every symbol with its length in bits, for encoding. Symbols without a
+    // code have length 0.
+    pub fn make_code_table(&self) -> [(u16, u8); 256] {
+        let mut codes = [(0u16, 0u8); 256];
+        for code in self.codes() {
+            codes[usize::from(code.symbol)] = (code.code, code.length);
+        }
+        codes
+    }
 
-        while cursor < bits.len() {
-            let mut found = false;
-            for len in 1usize..=self.symbols.len() {
-                if cursor + len > bits.len() {
-                    break;
+    // optimal builds the table with the shortest codes for the given symbol frequencies, limited to 16 bits as in
+    // ITU T.81 K.2. Symbols that never occur get no code, and no code consists of one bits only.
+    pub fn optimal(class: DHTType, frequencies: &[u32; 256]) -> HuffmanTable {
+        // a reserved symbol with the lowest frequency takes the all ones code of the longest length
+        let mut frequencies: Vec<u64> = frequencies.iter().map(|&count| u64::from(count)).collect();
+        frequencies.push(1);
+        let mut code_sizes = vec![0usize; 257];
+        let mut others: Vec<Option<usize>> = vec![None; 257];
+
+        loop {
+            // merge the two least frequent trees, preferring larger symbols among equal frequencies
+            let mut smallest: Option<usize> = None;
+            let mut second: Option<usize> = None;
+            for (symbol, &frequency) in frequencies.iter().enumerate() {
+                if frequency == 0 {
+                    continue;
+                }
+                if smallest.is_none_or(|s| frequency <= frequencies[s]) {
+                    second = smallest;
+                    smallest = Some(symbol);
+                } else if second.is_none_or(|s| frequency <= frequencies[s]) {
+                    second = Some(symbol);
                 }
+            }
+            let (mut first, mut second) = match (smallest, second) {
+                (Some(first), Some(second)) => (first, second),
+                _ => break,
+            };
 
-                let sub_slice = &bits[cursor..(cursor + len)];
+            frequencies[first] += frequencies[second];
+            frequencies[second] = 0;
+            code_sizes[first] += 1;
+            while let Some(next) = others[first] {
+                first = next;
+                code_sizes[first] += 1;
+            }
+            others[first] = Some(second);
+            code_sizes[second] += 1;
+            while let Some(next) = others[second] {
+                second = next;
+                code_sizes[second] += 1;
+            }
+        }
 
-                if let Some(translated) = translation.get(&BitVec::from_bitslice(sub_slice)) {
-                    result.extend_from_slice(&translated.to_be_bytes());
-                    println!("translated {} to {:#X?}", sub_slice, translated);
-                    cursor = cursor + len;
-                    found = true;
-                    break;
+        let mut counts = vec![0usize; 258];
+        for &size in &code_sizes {
+            if size > 0 {
+                counts[size] += 1;
+            }
+        }
+        // move pairs of codes longer than 16 bits up the tree, replacing a shorter code by a prefix for two codes
+        for size in (17..counts.len()).rev() {
+            while counts[size] > 0 {
+                let mut shorter = size - 2;
+                while counts[shorter] == 0 {
+                    shorter -= 1;
                 }
+                counts[size] -= 2;
+                counts[size - 1] += 1;
+                counts[shorter + 1] += 2;
+                counts[shorter] -= 1;
             }
-            if !found {
-                return Err(ParserError {
-                    reason: format!(
-                        "error huffman decoding stream: symbol not found at cursor {} with length {}",
-                        cursor,
-                        bits.len()).into(),
-                });
+        }
+        if let Some(longest) = (1..=16).rev().find(|&size| counts[size] > 0) {
+            counts[longest] -= 1;
+        }
+
+        // hand out the lengths to the symbols ordered by their unlimited code sizes
+        let mut values: Vec<u8> = Vec::with_capacity(256);
+        for size in 1..code_sizes.len() {
+            for (symbol, _) in code_sizes[..256]
+                .iter()
+                .enumerate()
+                .filter(|&(_, &s)| s == size)
+            {
+                values.push(symbol as u8);
             }
         }
+        let mut lengths = [0u8; 16];
+        for (length, &count) in lengths.iter_mut().zip(&counts[1..=16]) {
+            *length = count as u8;
+        }
+
+        HuffmanTable {
+            class,
+            counts: lengths,
+            values,
+        }
+    }
+}
+
+impl HuffmanDecoder {
+    // new builds the decoder for the codes of huffman_table
+    pub fn new(huffman_table: &HuffmanTable) -> HuffmanDecoder {
+        let mut table = HuffmanDecoder::default();
+        let mut current_code = 0u32;
+
+        for (len, symbols) in huffman_table.symbols().enumerate() {
+            table.first_codes[len] = current_code;
+            table.first_indices[len] = table.symbols.len();
+            table.counts[len] = symbols.len();
+            table.symbols.extend_from_slice(symbols);
+            current_code = (current_code + symbols.len() as u32) << 1;
+        }
+
+        table.lookahead = vec![0; 1 << LOOKAHEAD_BITS];
+        let short = huffman_table
+            .codes()
+            .take_while(|code| usize::from(code.length) <= LOOKAHEAD_BITS);
+        for code in short {
+            let fill = LOOKAHEAD_BITS - usize::from(code.length);
+            let start = usize::from(code.code) << fill;
+            // codes of oversubscribed tables may overlap shorter ones, which match first like in lookup
+            for entry in &mut table.lookahead[start..start + (1 << fill)] {
+                if *entry == 0 {
+                    *entry = u16::from(code.length) << 8 | u16::from(code.symbol);
+                }
+            }
+        }
+        table
+    }
+
+    // decode decodes the symbols of code, which holds whole codes without stuffed bytes, like huffman_decode
+    pub fn decode(&self, code: &[u8]) -> Result<Vec<u8>, ParserError> {
+        let mut result = Vec::new();
+        self.decode_into(code, &mut result)?;
         Ok(result)
     }
 
-    fn make_translation_map(&self) -> HashMap<BitVec, u8> {
-        let mut translation = HashMap::<BitVec, u8>::new();
-        let mut current_code = 0u16;
-
-        for len in 0..self.symbols.len() {
-            for i in 0..self.symbols[len].len() {
-                let mut current_bits = BitVec::from_slice(&current_code.to_be_bytes());
-                translation.insert(
-                    BitVec::from_bitslice(
-                        &current_bits[(current_bits.len() - (len + 1))..current_bits.len()],
-                    ),
-                    self.symbols[len][i],
-                );
-                current_code = current_code + 1;
+    // decode_into decodes like decode, appending the symbols to output. Codes are looked up in the tables with
+    // the bits of an integer, so a decode into a buffer that already has room for the symbols doesn't allocate.
+    pub fn decode_into(&self, code: &[u8], output: &mut Vec<u8>) -> Result<(), ParserError> {
+        let _span = trace_span!(TRACE, "huffman_decode", length = code.len());
+        let mut reader = BitReader::unstuffed(code);
+
+        while reader.bits_read() < code.len() * 8 {
+            #[cfg(feature = "tracing")]
+            let start = reader.bits_read();
+            match decode_symbol(self, &mut reader) {
+                Some(translated) => {
+                    output.push(translated);
+                    trace_event!(
+                        trace,
+                        length = reader.bits_read() - start,
+                        symbol = translated,
+                        "decoded huffman symbol"
+                    );
+                }
+                None => {
+                    return Err(ParserError::InvalidHuffmanCode {
+                        position: reader.bits_read(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // lookup returns the symbol of the code of the given length in bits
+    pub fn lookup(&self, code: u32, length: usize) -> Option<u8> {
+        let len = length.checked_sub(1).filter(|&len| len < 16)?;
+        let offset = code.checked_sub(self.first_codes[len])? as usize;
+        if offset < self.counts[len] {
+            Some(self.symbols[self.first_indices[len] + offset])
+        } else {
+            None
+        }
+    }
+}
+
+// decode_symbol reads the next code from reader. None is returned if no code matches before the data ends.
+pub fn decode_symbol(table: &HuffmanDecoder, reader: &mut BitReader) -> Option<u8> {
+    if let Some(ahead) = reader.peek(16) {
+        let entry = table
+            .lookahead
+            .get(ahead as usize >> (16 - LOOKAHEAD_BITS))
+            .cloned()
+            .unwrap_or(0);
+        if entry != 0 {
+            reader.consume(usize::from(entry >> 8));
+            return Some(entry as u8);
+        }
+        // the code is longer than the bits looked at, or the table is empty
+        for len in LOOKAHEAD_BITS + 1..=16 {
+            if let Some(symbol) = table.lookup(ahead >> (16 - len), len) {
+                reader.consume(len);
+                return Some(symbol);
             }
-            current_code = current_code << 1;
         }
+        return None;
+    }
 
-        translation
+    // the last codes of the data are read one length after the other
+    for len in 1usize..=16 {
+        let code = reader.peek(len)?;
+        if let Some(symbol) = table.lookup(code, len) {
+            reader.consume(len);
+            return Some(symbol);
+        }
     }
+
+    None
 }
 
 #[cfg(test)]
@@ -107,39 +575,325 @@ mod tests {
     }
 
     #[test]
-    fn make_translation_map_test() {
-        let table = HuffmanTable {
-            class: DHTType::ChrominanceAC,
-            symbols: make_sample_symbols(),
-        };
-
-        let translation = table.make_translation_map();
-
-        let v1 = bitvec!(0, 0);
-        assert_eq!(translation.get(&v1), Some(&0x01));
+    fn huffman_decoder_test() {
+        let table = HuffmanTable::from_symbols(DHTType::ChrominanceAC, &make_sample_symbols());
 
-        let v2 = bitvec!(0, 1, 0);
-        assert_eq!(translation.get(&v2), Some(&0x02));
+        let decoding = HuffmanDecoder::new(&table);
 
-        let v3 = bitvec!(1, 0, 1, 0);
-        assert_eq!(translation.get(&v3), Some(&0x04));
+        assert_eq!(decoding.lookup(0b00, 2), Some(0x01));
+        assert_eq!(decoding.lookup(0b010, 3), Some(0x02));
+        assert_eq!(decoding.lookup(0b1010, 4), Some(0x04));
+        assert_eq!(decoding.lookup(0b1011, 4), Some(0x21));
+        assert_eq!(decoding.lookup(0b1001, 4), Some(0x03));
+        // 00 is a prefix of no longer code, and no code of length 1 exists
+        assert_eq!(decoding.lookup(0b000, 3), None);
+        assert_eq!(decoding.lookup(0b0, 1), None);
+        assert_eq!(decoding.lookup(0, 17), None);
 
-        let v4 = bitvec!(1, 0, 1, 1);
-        assert_eq!(translation.get(&v4), Some(&0x21));
+        // a table with more codes than a length holds, like three codes of one bit, decodes the same way every
+        // time, with the codes that fit
+        let mut symbols: [Vec<u8>; 16] = Default::default();
+        symbols[0] = vec![7, 8, 9];
+        symbols[1] = vec![10];
+        let decoding =
+            HuffmanDecoder::new(&HuffmanTable::from_symbols(DHTType::LuminanceDC, &symbols));
+        assert_eq!(decoding.lookup(0b0, 1), Some(7));
+        assert_eq!(decoding.lookup(0b1, 1), Some(8));
+        assert_eq!((0..4).find_map(|code| decoding.lookup(code, 2)), None);
+    }
 
-        let v5 = bitvec!(1, 0, 0, 1);
-        assert_eq!(translation.get(&v5), Some(&0x03));
+    #[test]
+    fn lookahead_test() {
+        // decode_symbol finds the same code with the lookahead table as a lookup of one length after the other,
+        // for any 16 bits and tables with short and long codes, empty and oversubscribed tables
+        let mut oversubscribed: [Vec<u8>; 16] = Default::default();
+        oversubscribed[0] = vec![7, 8, 9];
+        oversubscribed[2] = vec![10, 11, 12, 13, 14, 15, 16, 17, 18];
+        oversubscribed[9] = vec![19; 255];
+        let tables = [
+            HuffmanTable::standard(DHTType::LuminanceAC),
+            HuffmanTable::standard(DHTType::ChrominanceDC),
+            HuffmanTable::from_symbols(DHTType::ChrominanceAC, &make_sample_symbols()),
+            HuffmanTable::from_symbols(DHTType::LuminanceDC, &oversubscribed),
+        ];
+        for table in tables.iter() {
+            let decoding = HuffmanDecoder::new(table);
+            for word in 0..=u16::MAX {
+                let expected = (1..=16).find_map(|len| {
+                    decoding
+                        .lookup(u32::from(word) >> (16 - len), len)
+                        .map(|symbol| (symbol, len))
+                });
+                let coded = word.to_be_bytes();
+                let mut reader = BitReader::unstuffed(&coded);
+                let decoded = decode_symbol(&decoding, &mut reader);
+                assert_eq!(decoded.map(|symbol| (symbol, reader.bits_read())), expected);
+            }
+        }
+        let mut reader = BitReader::new(&[0; 4]);
+        assert_eq!(decode_symbol(&HuffmanDecoder::default(), &mut reader), None);
     }
 
     #[test]
     fn huffman_decode_test() {
         let coded = vec![0b00001010, 0b10111001];
         let decoded = vec![0x01, 0x01, 0x04, 0x21, 0x03];
-        let table = HuffmanTable {
-            class: DHTType::ChrominanceAC,
-            symbols: make_sample_symbols(),
+        let table = HuffmanTable::from_symbols(DHTType::ChrominanceAC, &make_sample_symbols());
+
+        assert_eq!(table.huffman_decode(coded.as_slice()), Ok(decoded.clone()));
+
+        // a decoder is built once and decodes any number of codes
+        let decoder = HuffmanDecoder::new(&table);
+        assert_eq!(decoder.decode(&coded), Ok(decoded));
+        assert_eq!(decoder.decode(&[0b10111010]), Ok(vec![0x21, 0x04]));
+        assert_eq!(
+            decoder.decode(&[0b11111111]),
+            Err(ParserError::InvalidHuffmanCode { position: 0 })
+        );
+
+        // decoding into a buffer with room for the symbols keeps using its memory
+        let mut output = Vec::with_capacity(7);
+        let buffer = output.as_ptr();
+        assert_eq!(decoder.decode_into(&coded, &mut output), Ok(()));
+        assert_eq!(decoder.decode_into(&[0b10111010], &mut output), Ok(()));
+        assert_eq!(output, [0x01, 0x01, 0x04, 0x21, 0x03, 0x21, 0x04]);
+        assert_eq!((output.as_ptr(), output.capacity()), (buffer, 7));
+    }
+
+    #[test]
+    fn standard_table_test() {
+        let table = HuffmanTable::standard(DHTType::LuminanceAC);
+        assert_eq!(table.values.len(), 162);
+        let symbols: Vec<&[u8]> = table.symbols().collect();
+        assert_eq!(symbols.len(), 16);
+        assert_eq!(symbols[1], [0x01, 0x02]);
+        assert_eq!(symbols[15].len(), 0x7d);
+        assert_eq!(symbols[15].last(), Some(&0xfa));
+
+        let decoding = HuffmanDecoder::new(&table);
+        // end of block is coded as 1010 in the standard luminance AC table
+        assert_eq!(decoding.lookup(0b1010, 4), Some(0x00));
+
+        let table = HuffmanTable::standard(DHTType::ChrominanceDC);
+        assert_eq!(table.symbols().nth(1), Some(&[0, 1, 2][..]));
+        assert_eq!(table.values.len(), 12);
+
+        // from_symbols stores the symbols of every length after each other, symbols leaves out missing ones
+        let mut table =
+            HuffmanTable::from_symbols(DHTType::LuminanceDC, &[vec![], vec![4, 5], vec![6]]);
+        assert_eq!(
+            table.counts,
+            [0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(table.values, [4, 5, 6]);
+        table.values.pop();
+        let symbols: Vec<&[u8]> = table.symbols().take(3).collect();
+        assert_eq!(symbols, [&[][..], &[4, 5], &[]]);
+    }
+
+    #[test]
+    fn check_test() {
+        for &class in &[
+            DHTType::LuminanceDC,
+            DHTType::LuminanceAC,
+            DHTType::ChrominanceDC,
+            DHTType::ChrominanceAC,
+        ] {
+            assert_eq!(HuffmanTable::standard(class).check(), Ok(()));
+        }
+        assert_eq!(
+            HuffmanTable::optimal(DHTType::LuminanceAC, &[1; 256]).check(),
+            Ok(())
+        );
+
+        let table = |lengths: &[u8]| {
+            let mut counts = [0; 16];
+            counts[..lengths.len()].copy_from_slice(lengths);
+            let count = lengths.iter().map(|&count| usize::from(count)).sum();
+            HuffmanTable {
+                class: DHTType::ChrominanceAC,
+                counts,
+                values: vec![0; count],
+            }
+        };
+        let class = DHTType::ChrominanceAC;
+        assert_eq!(table(&[]).check(), Ok(()));
+        assert_eq!(table(&[1, 1, 1]).check(), Ok(()));
+        // one, two and three bit codes use up all codes, the last one, 111, is a valid code
+        assert_eq!(table(&[1, 1, 2]).check(), Ok(()));
+        assert_eq!(table(&[2]).check(), Ok(()));
+        // a code of every length from 1 to 15 bits and two of 16 bits use up all codes, ending in 16 one bits
+        let mut counts = [1; 16];
+        counts[15] = 2;
+        assert_eq!(
+            table(&counts).check(),
+            Err(ParserError::AllOnesHuffmanCode { class })
+        );
+        assert_eq!(
+            table(&[1, 1, 1, 3]).check(),
+            Err(ParserError::OversubscribedHuffmanTable { class })
+        );
+        assert_eq!(
+            table(&[0, 0, 0, 0, 0, 0, 0, 0, 255, 2]).check(),
+            Err(ParserError::TooManyHuffmanSymbols { class, count: 257 })
+        );
+        let mut counts = [0; 16];
+        counts[15] = 255;
+        assert_eq!(table(&counts).check(), Ok(()));
+    }
+
+    #[test]
+    fn make_code_table_test() {
+        let codes = HuffmanTable::standard(DHTType::LuminanceDC).make_code_table();
+
+        assert_eq!(codes[0], (0b00, 2));
+        assert_eq!(codes[1], (0b010, 3));
+        assert_eq!(codes[6], (0b1110, 4));
+        assert_eq!(codes[11], (0b1_1111_1110, 9));
+        assert_eq!(codes[12], (0, 0));
+        assert_eq!(DHTType::from_selector(1, 1).unwrap().selector(), (1, 1));
+    }
+
+    #[test]
+    fn codes_test() {
+        let table = HuffmanTable::from_symbols(DHTType::ChrominanceAC, &make_sample_symbols());
+        let codes: Vec<HuffmanCode> = table.codes().collect();
+        assert_eq!(codes.len(), table.values.len());
+        assert_eq!(
+            codes[..3],
+            [
+                HuffmanCode {
+                    symbol: 0x01,
+                    code: 0b00,
+                    length: 2
+                },
+                HuffmanCode {
+                    symbol: 0x02,
+                    code: 0b010,
+                    length: 3
+                },
+                HuffmanCode {
+                    symbol: 0x11,
+                    code: 0b011,
+                    length: 3
+                },
+            ]
+        );
+        let last = codes.last().unwrap();
+        assert_eq!(
+            (last.symbol, last.code, last.length),
+            (0xe2, 0b111_1111_1111_1110, 15)
+        );
+        // the codes decode to their symbols
+        let decoder = HuffmanDecoder::new(&table);
+        for code in &codes {
+            let found = decoder.lookup(u32::from(code.code), usize::from(code.length));
+            assert_eq!(found, Some(code.symbol));
+        }
+
+        // the third code of a bit doesn't fit
+        let mut counts = [0; 16];
+        counts[0] = 3;
+        counts[1] = 1;
+        let oversubscribed = HuffmanTable {
+            class: DHTType::LuminanceDC,
+            counts,
+            values: vec![1, 2, 3, 4],
         };
+        let lengths: Vec<(u8, u8)> = oversubscribed
+            .codes()
+            .map(|code| (code.symbol, code.length))
+            .collect();
+        assert_eq!(lengths, vec![(1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn table_set_test() {
+        assert_eq!(DHTType::from_selector(1, 1), Some(DHTType::ChrominanceAC));
+        assert_eq!(DHTType::from_selector(0, 3).unwrap().selector(), (0, 3));
+        assert_eq!(DHTType::from_selector(0, 4), None);
+        assert_eq!(DHTType::from_selector(2, 0), None);
+        let table = DHTType::new(TableClass::AC, 2).unwrap();
+        assert_eq!(format!("{:?}", table), "AC2");
+
+        let mut tables = TableSet::new();
+        assert_eq!(tables.insert(table, 1), None);
+        assert_eq!(tables.insert(DHTType::LuminanceDC, 2), None);
+        assert_eq!(tables.insert(table, 3), Some(1));
+        assert_eq!(tables.get(table), Some(&3));
+        assert!(!tables.contains(DHTType::new(TableClass::DC, 2).unwrap()));
+        assert!(!tables.contains(DHTType::LuminanceAC));
+        let found: Vec<(DHTType, &i32)> = tables.iter().collect();
+        assert_eq!(found, vec![(DHTType::LuminanceDC, &2), (table, &3)]);
+    }
+
+    #[test]
+    fn optimal_table_test() {
+        let mut frequencies = [0u32; 256];
+        frequencies[0x00] = 100;
+        frequencies[0x01] = 50;
+        frequencies[0x11] = 25;
+        frequencies[0xf0] = 25;
+        let table = HuffmanTable::optimal(DHTType::LuminanceAC, &frequencies);
+        assert_eq!(&table.counts[..4], &[1, 1, 1, 1]);
+        assert_eq!(table.values, [0x00, 0x01, 0x11, 0xf0]);
+        let codes = table.make_code_table();
+        assert_eq!(codes[0x00], (0b0, 1));
+        assert_eq!(codes[0xf0], (0b1110, 4));
+        assert_eq!(codes[0x02], (0, 0));
 
-        assert_eq!(table.huffman_decode(coded.as_slice()), Ok(decoded))
+        // fibonacci frequencies make the unlimited codes up to 40 bits long
+        let mut frequencies = [0u32; 256];
+        let (mut a, mut b) = (1u32, 1u32);
+        for frequency in frequencies.iter_mut().take(40) {
+            *frequency = a;
+            let next = a.saturating_add(b);
+            a = b;
+            b = next;
+        }
+        let table = HuffmanTable::optimal(DHTType::LuminanceDC, &frequencies);
+        assert_eq!(table.values.len(), 40);
+        // the codes form a prefix code leaving room for the reserved all ones code
+        let kraft: u32 = table
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(len, &count)| u32::from(count) * (1 << (15 - len)))
+            .sum();
+        assert!(kraft < 1 << 16);
+    }
+
+    #[test]
+    fn decode_symbol_test() {
+        let table = HuffmanTable::from_symbols(DHTType::ChrominanceAC, &make_sample_symbols());
+        let decoding = HuffmanDecoder::new(&table);
+
+        let coded = vec![0b00101111, 0b11111111];
+        let mut reader = BitReader::unstuffed(&coded);
+
+        assert_eq!(decode_symbol(&decoding, &mut reader), Some(0x01));
+        assert_eq!(reader.bits_read(), 2);
+        assert_eq!(decode_symbol(&decoding, &mut reader), Some(0x21));
+        assert_eq!(reader.bits_read(), 6);
+        assert_eq!(decode_symbol(&decoding, &mut reader), None);
+        assert_eq!(reader.bits_read(), 6);
+
+        // every code of the standard AC table, from 2 to 16 bits, decodes in the middle of the data and in the
+        // last bytes, where fewer bits than a lookahead follow
+        let table = HuffmanTable::standard(DHTType::LuminanceAC);
+        let decoding = HuffmanDecoder::new(&table);
+        let mut code = 0u32;
+        for (len, symbols) in (1usize..=16).zip(table.symbols()) {
+            for &symbol in symbols {
+                let coded = (code << (32 - len)).to_be_bytes();
+                for &end in &[4, len.div_ceil(8)] {
+                    let mut reader = BitReader::unstuffed(&coded[..end]);
+                    let decoded = decode_symbol(&decoding, &mut reader);
+                    assert_eq!((decoded, reader.bits_read()), (Some(symbol), len));
+                }
+                code += 1;
+            }
+            code <<= 1;
+        }
     }
 }
@@ -0,0 +1,355 @@
+// ICC profiles embedded in APP2 segments and conversion of matrix-shaper RGB profiles to sRGB.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use parser::header_segments;
+
+pub(crate) const ICC_MARKER: &[u8] = b"ICC_PROFILE\x00";
+
+// icc_chunk returns the sequence number, the chunk count and the profile data of an APP2 segment's content
+pub fn icc_chunk(data: &[u8]) -> Option<(u8, u8, &[u8])> {
+    if !data.starts_with(ICC_MARKER) || data.len() < ICC_MARKER.len() + 2 {
+        return None;
+    }
+    let header = ICC_MARKER.len();
+    Some((data[header], data[header + 1], &data[header + 2..]))
+}
+
+// assemble_profile concatenates chunks ordered by their sequence numbers, returning None unless every chunk
+// from 1 to the chunk count is present exactly once
+pub fn assemble_profile(chunks: &[(u8, u8, &[u8])]) -> Option<Vec<u8>> {
+    let count = chunks.first()?.1;
+    let mut profile = Vec::new();
+
+    for sequence in 1..=count {
+        let mut matching = chunks.iter().filter(|chunk| chunk.0 == sequence);
+        match (matching.next(), matching.next()) {
+            (Some(chunk), None) if chunk.1 == count => profile.extend_from_slice(chunk.2),
+            _ => return None,
+        }
+    }
+
+    if chunks.len() == count as usize {
+        Some(profile)
+    } else {
+        None
+    }
+}
+
+// read_icc_profile returns the ICC profile split over the APP2 segments of jpeg_file, if there is a complete one
+pub fn read_icc_profile(jpeg_file: &[u8]) -> Option<Vec<u8>> {
+    let segments = header_segments(jpeg_file).ok()?;
+    let chunks: Vec<_> = segments
+        .iter()
+        .filter(|segment| segment.tag == 0xe2)
+        .filter_map(|segment| icc_chunk(segment.data))
+        .collect();
+
+    assemble_profile(&chunks)
+}
+
+// XYZ_D50_TO_SRGB converts the D50 profile connection space into linear sRGB, using the Bradford adapted matrix
+#[cfg(feature = "std")]
+const XYZ_D50_TO_SRGB: [[f32; 3]; 3] = [
+    [3.133_856, -1.616_867, -0.490_615],
+    [-0.978_768, 1.916_142, 0.033_454],
+    [0.071_945, -0.228_991, 1.405_243],
+];
+
+// ENCODE_STEPS is the resolution of the table encoding linear light into sRGB samples
+#[cfg(feature = "std")]
+const ENCODE_STEPS: usize = 4096;
+
+// IccTransform converts RGB samples of a matrix-shaper profile into sRGB. Per channel tone curves are evaluated
+// into tables when the transform is created, so converting a pixel is table lookups and a matrix multiplication.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct IccTransform {
+    linearize: [[f32; 256]; 3],
+    matrix: [[f32; 3]; 3],
+    encode: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl IccTransform {
+    // from_profile returns the transform for an RGB profile with colorant and tone curve tags, other profiles
+    // (LUT based, CMYK, gray) aren't supported
+    pub fn from_profile(profile: &[u8]) -> Option<IccTransform> {
+        if profile.get(16..20)? != b"RGB " || profile.get(20..24)? != b"XYZ " {
+            return None;
+        }
+
+        let mut colorants = [[0f32; 3]; 3];
+        let mut linearize = [[0f32; 256]; 3];
+        for (channel, &(xyz, trc)) in [(b"rXYZ", b"rTRC"), (b"gXYZ", b"gTRC"), (b"bXYZ", b"bTRC")]
+            .iter()
+            .enumerate()
+        {
+            colorants[channel] = read_xyz(find_tag(profile, xyz)?)?;
+            let curve = Curve::read(find_tag(profile, trc)?)?;
+            for (value, linear) in linearize[channel].iter_mut().enumerate() {
+                *linear = curve.evaluate(value as f32 / 255.0);
+            }
+        }
+
+        // the colorants are the columns of the matrix converting linear RGB into XYZ
+        let mut matrix = [[0f32; 3]; 3];
+        for (row, output) in matrix.iter_mut().enumerate() {
+            for (column, value) in output.iter_mut().enumerate() {
+                *value = (0..3)
+                    .map(|k| XYZ_D50_TO_SRGB[row][k] * colorants[column][k])
+                    .sum();
+            }
+        }
+
+        let encode = (0..ENCODE_STEPS)
+            .map(|step| {
+                let linear = step as f32 / (ENCODE_STEPS - 1) as f32;
+                let encoded = if linear <= 0.003_130_8 {
+                    12.92 * linear
+                } else {
+                    1.055 * linear.powf(1.0 / 2.4) - 0.055
+                };
+                (encoded * 255.0 + 0.5) as u8
+            })
+            .collect();
+
+        Some(IccTransform {
+            linearize,
+            matrix,
+            encode,
+        })
+    }
+
+    pub fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let linear = [
+            self.linearize[0][rgb[0] as usize],
+            self.linearize[1][rgb[1] as usize],
+            self.linearize[2][rgb[2] as usize],
+        ];
+
+        let mut output = [0u8; 3];
+        for (value, row) in output.iter_mut().zip(&self.matrix) {
+            let srgb = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+            let step = (srgb.clamp(0.0, 1.0) * (ENCODE_STEPS - 1) as f32 + 0.5) as usize;
+            *value = self.encode[step];
+        }
+        output
+    }
+}
+
+// Curve is a tone curve converting encoded samples in [0, 1] into linear light
+#[cfg(feature = "std")]
+enum Curve {
+    Gamma(f32),
+    Table(Vec<f32>),
+    // Parametric holds the function type and up to seven parameters g, a, b, c, d, e, f of a parametricCurveType
+    Parametric(u16, [f32; 7]),
+}
+
+#[cfg(feature = "std")]
+impl Curve {
+    fn read(tag: &[u8]) -> Option<Curve> {
+        match tag.get(0..4)? {
+            b"curv" => {
+                let count = u32_at(tag, 8)? as usize;
+                let entries = tag.get(12..12 + 2 * count)?;
+                match count {
+                    0 => Some(Curve::Gamma(1.0)),
+                    1 => Some(Curve::Gamma(
+                        f32::from(u16::from_be_bytes([entries[0], entries[1]])) / 256.0,
+                    )),
+                    _ => Some(Curve::Table(
+                        entries
+                            .chunks(2)
+                            .map(|entry| {
+                                f32::from(u16::from_be_bytes([entry[0], entry[1]])) / 65535.0
+                            })
+                            .collect(),
+                    )),
+                }
+            }
+            b"para" => {
+                let function = u16::from_be_bytes([*tag.get(8)?, *tag.get(9)?]);
+                let count = match function {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    _ => return None,
+                };
+                let mut parameters = [0f32; 7];
+                for (i, parameter) in parameters.iter_mut().take(count).enumerate() {
+                    *parameter = s15_fixed16(tag, 12 + 4 * i)?;
+                }
+                Some(Curve::Parametric(function, parameters))
+            }
+            _ => None,
+        }
+    }
+
+    fn evaluate(&self, x: f32) -> f32 {
+        let y = match *self {
+            Curve::Gamma(gamma) => x.powf(gamma),
+            Curve::Table(ref table) => {
+                let position = x * (table.len() - 1) as f32;
+                let index = (position as usize).min(table.len() - 2);
+                let fraction = position - index as f32;
+                table[index] * (1.0 - fraction) + table[index + 1] * fraction
+            }
+            Curve::Parametric(function, [g, a, b, c, d, e, f]) => match function {
+                0 => x.powf(g),
+                1 if x >= -b / a => (a * x + b).powf(g),
+                1 => 0.0,
+                2 if x >= -b / a => (a * x + b).powf(g) + c,
+                2 => c,
+                3 if x >= d => (a * x + b).powf(g),
+                3 => c * x,
+                _ if x >= d => (a * x + b).powf(g) + e,
+                _ => c * x + f,
+            },
+        };
+        y.clamp(0.0, 1.0)
+    }
+}
+
+// find_tag returns the data of the tag with the given signature from the profile's tag table
+#[cfg(feature = "std")]
+fn find_tag<'a>(profile: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    let count = u32_at(profile, 128)? as usize;
+    for i in 0..count {
+        let entry = 132 + 12 * i;
+        if profile.get(entry..entry + 4)? == signature {
+            let offset = u32_at(profile, entry + 4)? as usize;
+            let size = u32_at(profile, entry + 8)? as usize;
+            return profile.get(offset..offset.checked_add(size)?);
+        }
+    }
+    None
+}
+
+#[cfg(feature = "std")]
+fn read_xyz(tag: &[u8]) -> Option<[f32; 3]> {
+    if tag.get(0..4)? != b"XYZ " {
+        return None;
+    }
+    Some([
+        s15_fixed16(tag, 8)?,
+        s15_fixed16(tag, 12)?,
+        s15_fixed16(tag, 16)?,
+    ])
+}
+
+#[cfg(feature = "std")]
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(feature = "std")]
+fn s15_fixed16(data: &[u8], offset: usize) -> Option<f32> {
+    u32_at(data, offset).map(|value| value as i32 as f32 / 65536.0)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // SRGB_COLORANTS are the D50 adapted sRGB primaries
+    const SRGB_COLORANTS: [[f32; 3]; 3] = [
+        [0.436_075, 0.222_504, 0.013_932],
+        [0.385_065, 0.716_879, 0.097_105],
+        [0.143_080, 0.060_617, 0.714_173],
+    ];
+
+    fn fixed(value: f32) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    // make_profile returns an RGB matrix-shaper profile with the given colorants, using curve for all channels
+    pub fn make_profile(colorants: [[f32; 3]; 3], curve: &[u8]) -> Vec<u8> {
+        let mut profile = vec![0u8; 128];
+        profile[16..20].copy_from_slice(b"RGB ");
+        profile[20..24].copy_from_slice(b"XYZ ");
+
+        let signatures = [b"rXYZ", b"gXYZ", b"bXYZ", b"rTRC", b"gTRC", b"bTRC"];
+        profile.extend(&(signatures.len() as u32).to_be_bytes());
+        let mut data = Vec::new();
+        let data_offset = 132 + 12 * signatures.len();
+        for (i, signature) in signatures.iter().enumerate() {
+            let tag = if i < 3 {
+                let mut tag = b"XYZ \x00\x00\x00\x00".to_vec();
+                for &value in &colorants[i] {
+                    tag.extend(&fixed(value));
+                }
+                tag
+            } else {
+                curve.to_vec()
+            };
+            profile.extend(&signature[..]);
+            profile.extend(&((data_offset + data.len()) as u32).to_be_bytes());
+            profile.extend(&(tag.len() as u32).to_be_bytes());
+            data.extend(tag);
+        }
+
+        profile.extend(data);
+        profile
+    }
+
+    // srgb_curve is the sRGB tone curve as a parametric curve of type 3
+    pub fn srgb_curve() -> Vec<u8> {
+        let mut curve = b"para\x00\x00\x00\x00\x00\x03\x00\x00".to_vec();
+        for &value in &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.040_45] {
+            curve.extend(&fixed(value));
+        }
+        curve
+    }
+
+    // linear_profile has sRGB primaries, but no tone curve
+    pub fn linear_profile() -> Vec<u8> {
+        make_profile(SRGB_COLORANTS, b"curv\x00\x00\x00\x00\x00\x00\x00\x00")
+    }
+
+    #[test]
+    fn assemble_profile_test() {
+        let segments: Vec<Vec<u8>> = vec![
+            [ICC_MARKER, &[2, 2, 3, 4]].concat(),
+            [ICC_MARKER, &[1, 2, 1, 2]].concat(),
+        ];
+        let chunks: Vec<_> = segments.iter().filter_map(|s| icc_chunk(s)).collect();
+
+        assert_eq!(assemble_profile(&chunks), Some(vec![1, 2, 3, 4]));
+        assert_eq!(assemble_profile(&chunks[..1]), None);
+        assert_eq!(icc_chunk(b"Exif\x00\x00"), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn srgb_profile_test() {
+        let transform =
+            IccTransform::from_profile(&make_profile(SRGB_COLORANTS, &srgb_curve())).unwrap();
+
+        for &rgb in &[[0, 0, 0], [255, 255, 255], [200, 30, 90], [12, 128, 250]] {
+            let converted = transform.apply(rgb);
+            for channel in 0..3 {
+                assert!((i16::from(converted[channel]) - i16::from(rgb[channel])).abs() <= 1);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn linear_profile_test() {
+        let transform = IccTransform::from_profile(&linear_profile()).unwrap();
+
+        // linear 0.5 is encoded as 0.735 in sRGB
+        let gray = transform.apply([128, 128, 128]);
+        assert!(gray.iter().all(|&value| (187..=189).contains(&value)));
+
+        let mut gray = make_profile(SRGB_COLORANTS, &srgb_curve());
+        gray[16..20].copy_from_slice(b"GRAY");
+        assert!(IccTransform::from_profile(&gray).is_none());
+    }
+}
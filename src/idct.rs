@@ -0,0 +1,512 @@
+// Integer inverse DCT computed as two passes of 1-D matrix multiplications with fixed-point constants, 13 bits by
+// default.
+
+#[cfg(feature = "simd")]
+use simd;
+
+const CONST_BITS: i32 = 13;
+const PASS1_BITS: i32 = 2;
+
+// Matrix holds an 8x8 matrix row by row
+pub type Matrix<T> = [[T; 8]; 8];
+
+// COSINES[x][u] = C(u) / 2 * cos((2x + 1) * u * pi / 16) * 2^CONST_BITS with C(0) = 1 / sqrt(2) and C(u) = 1
+// otherwise, see ITU T.81 A.3.3
+const COSINES: Matrix<i16> = [
+    [2896, 4017, 3784, 3406, 2896, 2276, 1567, 799],
+    [2896, 3406, 1567, -799, -2896, -4017, -3784, -2276],
+    [2896, 2276, -1567, -4017, -2896, 799, 3784, 3406],
+    [2896, 799, -3784, -2276, 2896, 3406, -1567, -4017],
+    [2896, -799, -3784, 2276, 2896, -3406, -1567, 4017],
+    [2896, -2276, -1567, 4017, -2896, -799, 3784, -3406],
+    [2896, -3406, 1567, 799, -2896, 4017, -3784, 2276],
+    [2896, -4017, 3784, -3406, 2896, -2276, 1567, -799],
+];
+
+const TRANSPOSED_COSINES: Matrix<i16> = transpose(&COSINES);
+
+// FixedPointCosines holds the cosines of COSINES with bits fractional bits, from 8 to 15. Above 13 bits the sums
+// of the products of the largest 16 bit entries overflow, so limit clamps the entries of both passes to a smaller
+// range, which still holds those of conformant files by far.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FixedPointCosines {
+    bits: i32,
+    limit: i32,
+    cosines: Matrix<i16>,
+    transposed: Matrix<i16>,
+}
+
+impl Default for FixedPointCosines {
+    // default returns the 13 bit constants of COSINES
+    fn default() -> FixedPointCosines {
+        FixedPointCosines {
+            bits: CONST_BITS,
+            limit: i32::from(i16::MAX),
+            cosines: COSINES,
+            transposed: TRANSPOSED_COSINES,
+        }
+    }
+}
+
+impl FixedPointCosines {
+    // new rounds the cosines to bits fractional bits, clamped to 8 to 15
+    pub fn new(bits: u8) -> FixedPointCosines {
+        let bits = i32::from(bits.clamp(8, 15));
+        let scale = f64::from(1 << bits);
+        let mut cosines = [[0i16; 8]; 8];
+        for (row, reference) in cosines.iter_mut().zip(&REFERENCE_COSINES) {
+            for (cosine, &exact) in row.iter_mut().zip(reference) {
+                // rounds half away from zero, the cast truncates towards it
+                let half = if exact < 0.0 { -0.5 } else { 0.5 };
+                *cosine = (exact * scale + half) as i16;
+            }
+        }
+        // every row of cosines holds the same values in another order and sign
+        let sum: i32 = cosines[0]
+            .iter()
+            .map(|&cosine| i32::from(cosine).abs())
+            .sum();
+        FixedPointCosines {
+            bits,
+            limit: ((i32::MAX - (1 << bits)) / sum).min(i32::from(i16::MAX)),
+            cosines,
+            transposed: transpose(&cosines),
+        }
+    }
+}
+
+// idct_block transforms dequantized coefficients in natural order into level shifted samples, written as an 8x8
+// block into output with the given stride. The first pass multiplies every row of coefficients with the cosines
+// of its horizontal frequencies, the second pass every column of the result with those of its vertical ones.
+pub fn idct_block(
+    coefficients: &[i32; 64],
+    output: &mut [u8],
+    stride: usize,
+    cosines: &FixedPointCosines,
+) {
+    // valid coefficients never leave the range of limit, clamping guarantees the sums can't overflow
+    let limit = cosines.limit;
+    let mut rows = [[0i16; 8]; 8];
+    for (row, coefficients) in rows.iter_mut().zip(coefficients.chunks(8)) {
+        for (entry, &coefficient) in row.iter_mut().zip(coefficients) {
+            *entry = coefficient.clamp(-limit, limit) as i16;
+        }
+    }
+
+    let mut workspace = [[0i16; 8]; 8];
+    let first_bits = cosines.bits - PASS1_BITS;
+    for (row, sums) in workspace
+        .iter_mut()
+        .zip(&product(&rows, &cosines.transposed))
+    {
+        for (entry, &sum) in row.iter_mut().zip(sums) {
+            *entry = descale(sum, first_bits).clamp(-limit, limit) as i16;
+        }
+    }
+
+    let second_bits = cosines.bits + PASS1_BITS;
+    for (y, sums) in product(&cosines.cosines, &workspace).iter().enumerate() {
+        for (sample, &sum) in output[y * stride..y * stride + 8].iter_mut().zip(sums) {
+            *sample = (descale(sum, second_bits) + 128).clamp(0, 255) as u8;
+        }
+    }
+}
+
+// product returns the matrix product of left and right, whose entries are small enough for the sums of two of
+// their products to fit into 32 bits. With the simd feature it uses the vector instructions the processor
+// supports.
+fn product(left: &Matrix<i16>, right: &Matrix<i16>) -> Matrix<i32> {
+    #[cfg(feature = "simd")]
+    {
+        if let Some(product) = simd::product(left, right) {
+            return product;
+        }
+    }
+    scalar_product(left, right)
+}
+
+fn scalar_product(left: &Matrix<i16>, right: &Matrix<i16>) -> Matrix<i32> {
+    let mut product = [[0i32; 8]; 8];
+    for (row, output) in left.iter().zip(product.iter_mut()) {
+        for (j, sum) in output.iter_mut().enumerate() {
+            *sum = (0..8)
+                .map(|k| i32::from(row[k]) * i32::from(right[k][j]))
+                .sum();
+        }
+    }
+    product
+}
+
+const fn transpose(matrix: &Matrix<i16>) -> Matrix<i16> {
+    let mut transposed = [[0i16; 8]; 8];
+    let mut i = 0;
+    while i < 64 {
+        transposed[i % 8][i / 8] = matrix[i / 8][i % 8];
+        i += 1;
+    }
+    transposed
+}
+
+// FLOAT_COSINES contains the same factors as COSINES without fixed-point scaling
+pub const FLOAT_COSINES: [[f32; 8]; 8] = [
+    [
+        0.3535534, 0.4903926, 0.4619398, 0.4157348, 0.3535534, 0.2777851, 0.1913417, 0.0975452,
+    ],
+    [
+        0.3535534, 0.4157348, 0.1913417, -0.0975452, -0.3535534, -0.4903926, -0.4619398, -0.2777851,
+    ],
+    [
+        0.3535534, 0.2777851, -0.1913417, -0.4903926, -0.3535534, 0.0975452, 0.4619398, 0.4157348,
+    ],
+    [
+        0.3535534, 0.0975452, -0.4619398, -0.2777851, 0.3535534, 0.4157348, -0.1913417, -0.4903926,
+    ],
+    [
+        0.3535534, -0.0975452, -0.4619398, 0.2777851, 0.3535534, -0.4157348, -0.1913417, 0.4903926,
+    ],
+    [
+        0.3535534, -0.2777851, -0.1913417, 0.4903926, -0.3535534, -0.0975452, 0.4619398, -0.4157348,
+    ],
+    [
+        0.3535534, -0.4157348, 0.1913417, 0.0975452, -0.3535534, 0.4903926, -0.4619398, 0.2777851,
+    ],
+    [
+        0.3535534, -0.4903926, 0.4619398, -0.4157348, 0.3535534, -0.2777851, 0.1913417, -0.0975452,
+    ],
+];
+
+// idct_block_float works like idct_block with single precision floating point arithmetic, trading speed for
+// slightly more accurate results
+pub fn idct_block_float(coefficients: &[i32; 64], output: &mut [u8], stride: usize) {
+    let mut workspace = [0f32; 64];
+
+    for v in 0..8 {
+        let row = &coefficients[v * 8..v * 8 + 8];
+        for x in 0..8 {
+            let mut sum = 0f32;
+            for u in 0..8 {
+                sum += row[u] as f32 * FLOAT_COSINES[x][u];
+            }
+            workspace[v * 8 + x] = sum;
+        }
+    }
+
+    for x in 0..8 {
+        for y in 0..8 {
+            let mut sum = 0f32;
+            for v in 0..8 {
+                sum += workspace[v * 8 + x] * FLOAT_COSINES[y][v];
+            }
+            // rounds to nearest, the clamped value is never negative
+            output[y * stride + x] = ((sum + 128.0).clamp(0.0, 255.0) + 0.5) as u8;
+        }
+    }
+}
+
+// REFERENCE_COSINES holds C(u) / 2 * cos((2x + 1) u pi / 16) of ITU T.81 A.3.3 for sample x and frequency u, as
+// the double precision numbers closest to them
+const REFERENCE_COSINES: [[f64; 8]; 8] = [
+    [
+        0.3535533905932738,
+        0.4903926402016152,
+        0.46193976625564337,
+        0.4157348061512726,
+        0.3535533905932738,
+        0.27778511650980114,
+        0.19134171618254492,
+        0.09754516100806417,
+    ],
+    [
+        0.3535533905932738,
+        0.4157348061512726,
+        0.19134171618254492,
+        -0.09754516100806417,
+        -0.3535533905932738,
+        -0.4903926402016152,
+        -0.46193976625564337,
+        -0.27778511650980114,
+    ],
+    [
+        0.3535533905932738,
+        0.27778511650980114,
+        -0.19134171618254492,
+        -0.4903926402016152,
+        -0.3535533905932738,
+        0.09754516100806417,
+        0.46193976625564337,
+        0.4157348061512726,
+    ],
+    [
+        0.3535533905932738,
+        0.09754516100806417,
+        -0.46193976625564337,
+        -0.27778511650980114,
+        0.3535533905932738,
+        0.4157348061512726,
+        -0.19134171618254492,
+        -0.4903926402016152,
+    ],
+    [
+        0.3535533905932738,
+        -0.09754516100806417,
+        -0.46193976625564337,
+        0.27778511650980114,
+        0.3535533905932738,
+        -0.4157348061512726,
+        -0.19134171618254492,
+        0.4903926402016152,
+    ],
+    [
+        0.3535533905932738,
+        -0.27778511650980114,
+        -0.19134171618254492,
+        0.4903926402016152,
+        -0.3535533905932738,
+        -0.09754516100806417,
+        0.46193976625564337,
+        -0.4157348061512726,
+    ],
+    [
+        0.3535533905932738,
+        -0.4157348061512726,
+        0.19134171618254492,
+        0.09754516100806417,
+        -0.3535533905932738,
+        0.4903926402016152,
+        -0.46193976625564337,
+        0.27778511650980114,
+    ],
+    [
+        0.3535533905932738,
+        -0.4903926402016152,
+        0.46193976625564337,
+        -0.4157348061512726,
+        0.3535533905932738,
+        -0.27778511650980114,
+        0.19134171618254492,
+        -0.09754516100806417,
+    ],
+];
+
+// idct_block_reference computes every sample with the double precision sum of ITU T.81 A.3.3 over all 64
+// coefficients, adding the products in a fixed order. It is by far the slowest transform, but IEEE 754 makes its
+// output the same on every machine, for conformance tests and decodes that must be reproducible.
+pub fn idct_block_reference(coefficients: &[i32; 64], output: &mut [u8], stride: usize) {
+    for y in 0..8 {
+        for x in 0..8 {
+            let mut sum = 0f64;
+            for v in 0..8 {
+                for u in 0..8 {
+                    let cosines = REFERENCE_COSINES[y][v] * REFERENCE_COSINES[x][u];
+                    sum += cosines * f64::from(coefficients[v * 8 + u]);
+                }
+            }
+            output[y * stride + x] = ((sum + 128.0).clamp(0.0, 255.0) + 0.5) as u8;
+        }
+    }
+}
+
+fn descale(value: i32, bits: i32) -> i32 {
+    (value + (1 << (bits - 1))) >> bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dc_only_test() {
+        let mut coefficients = [0i32; 64];
+        coefficients[0] = 80;
+        let mut output = [0u8; 64];
+
+        idct_block(&coefficients, &mut output, 8, &FixedPointCosines::default());
+
+        assert_eq!(output.to_vec(), vec![138; 64]);
+    }
+
+    #[test]
+    fn zero_block_is_gray_test() {
+        let mut output = [0u8; 64];
+        idct_block(&[0i32; 64], &mut output, 8, &FixedPointCosines::default());
+        assert_eq!(output.to_vec(), vec![128; 64]);
+    }
+
+    #[test]
+    fn stride_test() {
+        let mut coefficients = [0i32; 64];
+        coefficients[0] = -2000;
+        let mut output = [7u8; 80];
+
+        idct_block(
+            &coefficients,
+            &mut output,
+            10,
+            &FixedPointCosines::default(),
+        );
+
+        for y in 0..8 {
+            assert_eq!(&output[y * 10..y * 10 + 8], &[0; 8]);
+            assert_eq!(&output[y * 10 + 8..y * 10 + 10], &[7; 2]);
+        }
+    }
+
+    fn reference_idct(coefficients: &[i32; 64]) -> Vec<i32> {
+        let mut samples = Vec::with_capacity(64);
+        for y in 0..8 {
+            for x in 0..8 {
+                let mut reference = 0f64;
+                for v in 0..8 {
+                    for u in 0..8 {
+                        let cu = if u == 0 { 1.0 / 2f64.sqrt() } else { 1.0 };
+                        let cv = if v == 0 { 1.0 / 2f64.sqrt() } else { 1.0 };
+                        reference += cu * cv / 4.0
+                            * f64::from(coefficients[v * 8 + u])
+                            * ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / 16.0).cos()
+                            * ((2 * y + 1) as f64 * v as f64 * std::f64::consts::PI / 16.0).cos();
+                    }
+                }
+                samples.push((reference + 128.0).round().clamp(0.0, 255.0) as i32);
+            }
+        }
+        samples
+    }
+
+    fn test_coefficients() -> [i32; 64] {
+        let mut coefficients = [0i32; 64];
+        coefficients[1] = 100;
+        coefficients[8] = -50;
+        coefficients[9] = 30;
+        coefficients[63] = 12;
+        coefficients
+    }
+
+    #[test]
+    fn matches_reference_test() {
+        let coefficients = test_coefficients();
+        let mut output = [0u8; 64];
+
+        idct_block(&coefficients, &mut output, 8, &FixedPointCosines::default());
+
+        for (&sample, expected) in output.iter().zip(reference_idct(&coefficients)) {
+            assert!((i32::from(sample) - expected).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn product_test() {
+        // the vector instructions compute the same products as the scalar code, for any coefficients
+        let mut state = 0x2545_f491u32;
+        let mut rows = [[0i16; 8]; 8];
+        for _ in 0..100 {
+            for entry in rows.iter_mut().flatten() {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                *entry = (state >> 16) as i16;
+            }
+            let sums = product(&rows, &TRANSPOSED_COSINES);
+            assert_eq!(sums, scalar_product(&rows, &TRANSPOSED_COSINES));
+            let expected = (0..8).map(|u| i32::from(rows[3][u]) * i32::from(COSINES[5][u]));
+            assert_eq!(sums[3][5], expected.sum::<i32>());
+            assert_eq!(product(&COSINES, &rows), scalar_product(&COSINES, &rows));
+        }
+
+        // coefficients out of the 16 bit range are clamped
+        let mut output = [0u8; 64];
+        idct_block(
+            &[1 << 20; 64],
+            &mut output,
+            8,
+            &FixedPointCosines::default(),
+        );
+        assert_eq!(output[0], 255);
+    }
+
+    #[test]
+    fn float_matches_reference_test() {
+        let coefficients = test_coefficients();
+        let mut output = [0u8; 64];
+
+        idct_block_float(&coefficients, &mut output, 8);
+
+        for (&sample, expected) in output.iter().zip(reference_idct(&coefficients)) {
+            assert!((i32::from(sample) - expected).abs() <= 1);
+        }
+
+        idct_block_float(&[0i32; 64], &mut output, 8);
+        assert_eq!(output.to_vec(), vec![128; 64]);
+    }
+
+    #[test]
+    fn fixed_point_test() {
+        assert_eq!(FixedPointCosines::new(13), FixedPointCosines::default());
+        assert_eq!(FixedPointCosines::new(40), FixedPointCosines::new(15));
+
+        // coefficients of blocks of photos, large at low frequencies and small at high frequencies
+        let mut state = 0x0bad_cafeu32;
+        let mut output = [0u8; 64];
+        // the largest difference from the reference and the number of differing samples for every number of bits
+        let mut errors = [(0i32, 0usize); 16];
+        for _ in 0..2000 {
+            let mut coefficients = [0i32; 64];
+            for (i, coefficient) in coefficients.iter_mut().enumerate() {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let range = 2048 >> (i / 8 + i % 8).min(10);
+                *coefficient = (state >> 8) as i32 % range - range / 2;
+            }
+            let reference = reference_idct(&coefficients);
+            for bits in 8..=15 {
+                idct_block(&coefficients, &mut output, 8, &FixedPointCosines::new(bits));
+                for (&sample, &expected) in output.iter().zip(&reference) {
+                    let error = (i32::from(sample) - expected).abs();
+                    let (largest, differing) = &mut errors[usize::from(bits)];
+                    *largest = (*largest).max(error);
+                    *differing += (error > 0) as usize;
+                }
+            }
+        }
+        let largest: Vec<i32> = errors[8..].iter().map(|&(largest, _)| largest).collect();
+        assert_eq!(largest, [3, 1, 1, 1, 1, 1, 1, 1]);
+        // 8 bits get almost half of the samples wrong, from 11 bits on about one in twenty
+        let differing: Vec<usize> = errors[8..]
+            .iter()
+            .map(|&(_, differing)| differing)
+            .collect();
+        assert!(differing[0] > 128_000 * 2 / 5);
+        assert!(differing
+            .windows(2)
+            .all(|pair| pair[0] * 100 >= pair[1] * 99));
+        assert!(differing[3..].iter().all(|&count| count < 128_000 / 20));
+
+        // the largest coefficients don't overflow with any number of bits
+        for bits in 8..=15 {
+            let cosines = FixedPointCosines::new(bits);
+            idct_block(&[1 << 20; 64], &mut output, 8, &cosines);
+            assert_eq!(output[0], 255);
+            idct_block(&[-1 << 20; 64], &mut output, 8, &cosines);
+            assert_eq!(output[0], 0);
+        }
+    }
+
+    #[test]
+    fn reference_test() {
+        let mut state = 0x1234_5678u32;
+        let mut output = [0u8; 64];
+        for _ in 0..50 {
+            let mut coefficients = [0i32; 64];
+            for coefficient in coefficients.iter_mut() {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                *coefficient = (state >> 16) as i32 % 200;
+            }
+            idct_block_reference(&coefficients, &mut output, 8);
+            let samples: Vec<i32> = output.iter().map(|&sample| i32::from(sample)).collect();
+            assert_eq!(samples, reference_idct(&coefficients));
+        }
+
+        idct_block_reference(&[0i32; 64], &mut output, 8);
+        assert_eq!(output.to_vec(), vec![128; 64]);
+        let mut coefficients = [0i32; 64];
+        coefficients[0] = 80;
+        idct_block_reference(&coefficients, &mut output, 8);
+        assert_eq!(output.to_vec(), vec![138; 64]);
+    }
+}
@@ -0,0 +1,169 @@
+use std::io::Read;
+
+use image::error::{DecodingError, ImageFormatHint};
+use image::{
+    ColorType as ImageColorType, DynamicImage, ImageBuffer, ImageDecoder, ImageError, ImageFormat,
+    ImageResult,
+};
+
+use decoder::{DecodedImage, Decoder, PixelFormat};
+
+// JpegDecoder implements image's ImageDecoder on top of Decoder. The image is decoded when the JpegDecoder is
+// created, since ImageDecoder reports dimensions and color type before reading any pixels. Pixel formats image
+// has no color type for are converted to Rgb8 or Rgba8.
+pub struct JpegDecoder {
+    image: DecodedImage,
+}
+
+impl JpegDecoder {
+    pub fn new<R: Read>(reader: R) -> ImageResult<JpegDecoder> {
+        JpegDecoder::with_decoder(Decoder::new(reader))
+    }
+
+    // with_decoder decodes with an already configured Decoder
+    pub fn with_decoder<R: Read>(decoder: Decoder<R>) -> ImageResult<JpegDecoder> {
+        let image = decoder.decode().map_err(|e| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Exact(ImageFormat::Jpeg),
+                e.to_string(),
+            ))
+        })?;
+        Ok(JpegDecoder {
+            image: to_image_layout(image),
+        })
+    }
+}
+
+impl ImageDecoder for JpegDecoder {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.image.width as u32, self.image.height as u32)
+    }
+
+    fn color_type(&self) -> ImageColorType {
+        match self.image.pixel_format {
+            PixelFormat::Gray8 => ImageColorType::L8,
+            PixelFormat::Rgba8 => ImageColorType::Rgba8,
+            _ => ImageColorType::Rgb8,
+        }
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        assert_eq!(buf.len() as u64, self.total_bytes());
+        buf.copy_from_slice(&self.image.pixels);
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+impl From<DecodedImage> for DynamicImage {
+    fn from(image: DecodedImage) -> DynamicImage {
+        let image = to_image_layout(image);
+        let (width, height) = (image.width as u32, image.height as u32);
+        // pixels always holds width * height pixels of the format, so the buffers can't be too small
+        match image.pixel_format {
+            PixelFormat::Gray8 => DynamicImage::ImageLuma8(
+                ImageBuffer::from_raw(width, height, image.pixels).unwrap(),
+            ),
+            PixelFormat::Rgba8 => DynamicImage::ImageRgba8(
+                ImageBuffer::from_raw(width, height, image.pixels).unwrap(),
+            ),
+            _ => {
+                DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, image.pixels).unwrap())
+            }
+        }
+    }
+}
+
+// to_image_layout converts BGR orders to RGB and expands RGB565 to Rgb8, leaving an image in one of the formats
+// image has a color type for
+fn to_image_layout(mut image: DecodedImage) -> DecodedImage {
+    match image.pixel_format {
+        PixelFormat::Bgr8 | PixelFormat::Bgra8 => {
+            for pixel in image
+                .pixels
+                .chunks_mut(image.pixel_format.bytes_per_pixel())
+            {
+                pixel.swap(0, 2);
+            }
+            image.pixel_format = if image.pixel_format == PixelFormat::Bgr8 {
+                PixelFormat::Rgb8
+            } else {
+                PixelFormat::Rgba8
+            };
+        }
+        PixelFormat::Rgb565 => {
+            let mut pixels = Vec::with_capacity(image.pixels.len() / 2 * 3);
+            for pixel in image.pixels.chunks(2) {
+                let packed = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let (r, g, b) = (packed >> 11, (packed >> 5) & 0x3f, packed & 0x1f);
+                // replicating the high bits fills the low bits, mapping the maximum values to 255
+                pixels.extend_from_slice(&[
+                    (r << 3 | r >> 2) as u8,
+                    (g << 2 | g >> 4) as u8,
+                    (b << 3 | b >> 2) as u8,
+                ]);
+            }
+            image.pixels = pixels;
+            image.pixel_format = PixelFormat::Rgb8;
+        }
+        _ => {}
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::decode_image;
+
+    #[test]
+    fn image_decoder_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let image = DynamicImage::from_decoder(JpegDecoder::new(file).unwrap()).unwrap();
+
+        assert_eq!((image.width(), image.height()), (32, 24));
+        assert_eq!(image.color(), ImageColorType::Rgb8);
+        assert_eq!(image.as_bytes(), &decode_image(file).unwrap().pixels[..]);
+
+        let gray = JpegDecoder::new(&include_bytes!("../tests/images/gray.jpg")[..]).unwrap();
+        assert_eq!(gray.color_type(), ImageColorType::L8);
+
+        assert!(JpegDecoder::new(&file[2..]).is_err());
+    }
+
+    #[test]
+    fn pixel_format_test() {
+        let file = &include_bytes!("../tests/images/gradient_420.jpg")[..];
+        let rgb = decode_image(file).unwrap().pixels;
+
+        let bgr = JpegDecoder::with_decoder(Decoder::new(file).pixel_format(PixelFormat::Bgr8));
+        let image = DynamicImage::from_decoder(bgr.unwrap()).unwrap();
+        assert_eq!(image.as_bytes(), &rgb[..]);
+
+        let bgra = Decoder::new(file).pixel_format(PixelFormat::Bgra8);
+        let image = DynamicImage::from(bgra.decode().unwrap());
+        assert_eq!(image.color(), ImageColorType::Rgba8);
+        assert_eq!(&image.as_bytes()[..4], &[rgb[0], rgb[1], rgb[2], 255]);
+
+        let rgb565 = Decoder::new(file).pixel_format(PixelFormat::Rgb565);
+        let image = DynamicImage::from(rgb565.decode().unwrap());
+        assert_eq!(image.color(), ImageColorType::Rgb8);
+        for (expanded, original) in image.as_bytes().iter().zip(&rgb) {
+            assert!((i16::from(*expanded) - i16::from(*original)).abs() <= 8);
+        }
+    }
+
+    #[test]
+    fn dynamic_image_test() {
+        let decoded = decode_image(include_bytes!("../tests/images/gray.jpg")).unwrap();
+        let pixels = decoded.pixels.clone();
+        let image = DynamicImage::from(decoded);
+
+        assert_eq!(image.color(), ImageColorType::L8);
+        assert_eq!(image.as_bytes(), &pixels[..]);
+    }
+}
@@ -0,0 +1,75 @@
+use errors::{Error, ParserError};
+use parser::{Marker, MarkerIter, ParseOptions, SubsamplingMode};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ImageInfo {
+    pub width: u64,
+    pub height: u64,
+    pub precision: u64,
+    pub components: usize,
+    pub progressive: bool,
+    pub subsampling: SubsamplingMode,
+}
+
+// read_info returns the frame header properties of jpeg_file. Parsing stops at the frame header, so neither the
+// scans nor anything following them are looked at.
+pub fn read_info(jpeg_file: &[u8]) -> Result<ImageInfo, Error> {
+    for spanned in MarkerIter::new(jpeg_file, &ParseOptions::default())? {
+        match spanned?.marker {
+            Marker::SOF(frame) => {
+                return Ok(ImageInfo {
+                    width: frame.width,
+                    height: frame.height,
+                    precision: frame.precision,
+                    components: frame.component_metadata.len(),
+                    // SOF2, SOF6, SOF10 and SOF14 are the progressive coding processes
+                    progressive: frame.marker & 0x03 == 0x02,
+                    subsampling: frame.subsampling_mode(),
+                });
+            }
+            Marker::Image(_) => break,
+            _ => {}
+        }
+    }
+
+    Err(ParserError::MissingFrameHeader.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_info_test() {
+        assert_eq!(
+            read_info(include_bytes!("../tests/images/gradient_420.jpg")).unwrap(),
+            ImageInfo {
+                width: 32,
+                height: 24,
+                precision: 8,
+                components: 3,
+                progressive: false,
+                subsampling: SubsamplingMode::Chroma420,
+            }
+        );
+
+        let progressive = read_info(include_bytes!("../tests/images/progressive_420.jpg")).unwrap();
+        assert!(progressive.progressive);
+
+        let gray = read_info(include_bytes!("../tests/images/gray.jpg")).unwrap();
+        assert_eq!(gray.subsampling, SubsamplingMode::Gray);
+    }
+
+    #[test]
+    fn read_info_stops_at_frame_test() {
+        let file = include_bytes!("../tests/images/gradient_444.jpg");
+        let position = file
+            .windows(2)
+            .position(|window| window == b"\xff\xda")
+            .unwrap();
+
+        // the scan is missing entirely, but the header is complete
+        assert_eq!(read_info(&file[..position]).unwrap().width, 32);
+        assert!(read_info(&[0xFF, 0xD8, 0xFF, 0xD9]).is_err());
+    }
+}
@@ -1,13 +1,121 @@
-#[macro_use]
+#![cfg_attr(not(feature = "std"), no_std)]
+#![allow(clippy::upper_case_acronyms)]
+
 extern crate nom;
 
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+
+#[cfg(feature = "serde")]
 #[macro_use]
-extern crate failure;
+extern crate serde;
 
-extern crate bitvec;
+#[cfg(feature = "image")]
+extern crate image;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "wasm-bindgen")]
+extern crate wasm_bindgen;
+
+// prelude provides the std types used throughout the crate when only alloc is available
+#[cfg(not(feature = "std"))]
+mod prelude {
+    pub use alloc::borrow::ToOwned;
+    pub use alloc::collections::BTreeSet;
+    pub use alloc::vec::Vec;
+}
+
+#[macro_use]
+mod macros;
 
+mod bitreader;
+mod color;
+mod decoder;
+mod edit;
+mod encoder;
 mod errors;
+mod exif;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod huffman;
+mod icc;
+mod idct;
+#[cfg(feature = "image")]
+mod image_decoder;
+mod info;
+mod lossless;
+mod mjpeg;
+mod mpf;
+#[cfg(feature = "std")]
+mod multipart;
 mod parser;
+mod rows;
+#[cfg(feature = "simd")]
+mod simd;
+mod stats;
+#[cfg(feature = "std")]
+mod stream;
+mod strip;
+mod summary;
+mod thumbnail;
+mod tiff;
+mod transcode;
+mod transform;
+mod upsample;
+mod validate;
+#[cfg(feature = "wasm-bindgen")]
+pub mod wasm;
+mod writer;
 
-pub use parser::decode;
+#[cfg(feature = "std")]
+pub use decoder::Decoder;
+pub use decoder::{
+    decode_batch, decode_batch_with_options, decode_blocks, decode_image,
+    decode_image_with_options, decode_image_with_scratch, decode_image_with_stats, decode_planes,
+    read_coefficients, salvage_image, ColorType, ComponentCoefficients, ComponentPlane, DamageMap,
+    DecodeOptions, DecodeProgress, DecodedCoefficients, DecodedImage, DecodedPlanes,
+    DecoderScratch, FixedPoint, IdctMethod, Limits, PixelFormat, SalvagedImage, StreamBlockDecoder,
+    Truncation,
+};
+pub use edit::{JpegFile, Segment};
+pub use encoder::{
+    encode_cmyk_image, encode_coefficients, encode_image, encode_image_12bit,
+    encode_image_with_options, encode_image_with_target_size, encode_ycbcr_planes,
+    ChromaSubsampling, CmykTransform, Density, DensityUnit, EncodeOptions, Optimization, Plane,
+    Scan, ThumbnailOptions, ThumbnailSegment, YCbCrPlanes,
+};
+pub use errors::{EncodeError, Error, ErrorContext, ParserError, Stage};
+pub use exif::{read_orientation, Orientation};
+pub use huffman::{DHTType, HuffmanCode, HuffmanDecoder, HuffmanTable, TableClass, TableSet};
+pub use icc::read_icc_profile;
+#[cfg(feature = "std")]
+pub use icc::IccTransform;
+#[cfg(feature = "image")]
+pub use image_decoder::JpegDecoder;
+pub use info::{read_info, ImageInfo};
+pub use lossless::{encode_lossless_image, LosslessOptions, Predictor};
+pub use mjpeg::{mjpeg_frames, MjpegDecoder};
+pub use mpf::{read_mpf, MultiPictureImage, MultiPictureIndex, MultiPictureType};
+#[cfg(feature = "std")]
+pub use multipart::{multipart_boundary, MultipartStream};
+pub use parser::{
+    decode, decode_spanned, decode_with_options, parse_scan_header, parse_segment,
+    ColorComponentMetaData, ImageStream, ImageStreamOwned, Marker, MarkerIter, MarkerOwned,
+    ParseOptions, QuantizationTable, QuantizationTableOwned, ScanComponentMetaData, SomeMarker,
+    SomeMarkerOwned, Span, SpannedMarker, StartOfFrameMetaData, StartOfStreamMetaData,
+    StreamParser, SubsamplingMode,
+};
+pub use stats::DecodeStats;
+pub use strip::{strip, MetadataKinds};
+pub use summary::MarkerSummary;
+pub use thumbnail::{read_thumbnail, RgbThumbnail, Thumbnail};
+pub use transcode::{transcode, TranscodeOptions};
+pub use transform::{modify_coefficients, optimize_jpeg, transform_jpeg, Crop, TransformOptions};
+pub use upsample::Upsampling;
+pub use validate::{validate, Diagnostic, Problem};
+pub use writer::encode_markers;
@@ -0,0 +1,364 @@
+// Encoding of the lossless process of ITU T.81 Annex H, which codes the difference of every sample to a prediction
+// from its neighbours instead of transformed blocks.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use encoder::{
+    check_dimensions, magnitude_category, write_segment, BitWriter, CodeTable, EntropyCoder,
+    SymbolCounter,
+};
+use errors::{EncodeError, Error};
+use huffman::{DHTType, HuffmanTable};
+
+// TABLE_CLASSES are the DC tables coding the differences of the first and the other components
+const TABLE_CLASSES: [DHTType; 2] = [DHTType::LuminanceDC, DHTType::ChrominanceDC];
+
+// Predictor selects how a sample is predicted from the reconstructed samples a to its left, b above and c above
+// left of it, in the order of the predictor selection values 1 to 7 of ITU T.81 table H.1
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Predictor {
+    // Left predicts a
+    #[default]
+    Left,
+    // Above predicts b
+    Above,
+    // AboveLeft predicts c
+    AboveLeft,
+    // Gradient predicts a + b - c
+    Gradient,
+    // LeftGradient predicts a + (b - c) / 2
+    LeftGradient,
+    // AboveGradient predicts b + (a - c) / 2
+    AboveGradient,
+    // Average predicts (a + b) / 2
+    Average,
+}
+
+impl Predictor {
+    // selection is the value of the Ss field of the scan header selecting the predictor
+    fn selection(self) -> u8 {
+        self as u8 + 1
+    }
+
+    fn predict(self, a: i32, b: i32, c: i32) -> i32 {
+        match self {
+            Predictor::Left => a,
+            Predictor::Above => b,
+            Predictor::AboveLeft => c,
+            Predictor::Gradient => a + b - c,
+            Predictor::LeftGradient => a + ((b - c) >> 1),
+            Predictor::AboveGradient => b + ((a - c) >> 1),
+            Predictor::Average => (a + b) >> 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LosslessOptions {
+    // precision is the number of bits per sample, from 2 to 16. It defaults to 8.
+    pub precision: u8,
+    pub predictor: Predictor,
+}
+
+impl Default for LosslessOptions {
+    fn default() -> LosslessOptions {
+        LosslessOptions {
+            precision: 8,
+            predictor: Predictor::default(),
+        }
+    }
+}
+
+// encode_lossless_image encodes width x height pixels of interleaved samples with 1 to 4 components into a
+// lossless JPEG file, as used by DICOM. The samples are stored as they are, without color conversion, and decode
+// back bit exact. The Huffman tables are built from the differences of the image.
+pub fn encode_lossless_image(
+    samples: &[u16],
+    width: u64,
+    height: u64,
+    components: usize,
+    options: &LosslessOptions,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = check_dimensions(width, height)?;
+    if components == 0 || components > 4 {
+        return Err(EncodeError::UnsupportedComponentCount { count: components }.into());
+    }
+    let precision = options.precision;
+    if !(2..=16).contains(&precision) {
+        return Err(EncodeError::UnsupportedPrecision { precision }.into());
+    }
+    let expected = width * height * components;
+    if samples.len() != expected {
+        return Err(EncodeError::InvalidBufferSize {
+            expected,
+            actual: samples.len(),
+        }
+        .into());
+    }
+    if let Some(&value) = samples
+        .iter()
+        .find(|&&value| u32::from(value) >> precision != 0)
+    {
+        return Err(EncodeError::SampleOutOfRange { value, precision }.into());
+    }
+
+    let image = Image {
+        samples,
+        width,
+        height,
+        components,
+    };
+    let mut counter = SymbolCounter {
+        frequencies: vec![[0; 256]; TABLE_CLASSES.len()],
+    };
+    encode_differences(&mut counter, &image, options);
+    let tables: Vec<_> = TABLE_CLASSES
+        .iter()
+        .zip(&counter.frequencies)
+        .map(|(&class, frequencies)| HuffmanTable::optimal(class, frequencies))
+        .collect();
+
+    let mut output = vec![0xff, 0xd8];
+    let mut sof = vec![precision];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.push(components as u8);
+    for component in 0..components {
+        // lossless frames have no quantization tables, their selectors are zero
+        sof.extend_from_slice(&[component as u8 + 1, 0x11, 0]);
+    }
+    write_segment(&mut output, 0xc3, &sof);
+
+    let mut dht = Vec::new();
+    for table in tables.iter().take(components.min(TABLE_CLASSES.len())) {
+        let (class, id) = table.class.selector();
+        dht.push(class << 4 | id);
+        dht.extend_from_slice(&table.counts);
+        dht.extend_from_slice(&table.values);
+    }
+    write_segment(&mut output, 0xc4, &dht);
+
+    let mut sos = vec![components as u8];
+    for component in 0..components {
+        sos.extend_from_slice(&[component as u8 + 1, (component.min(1) as u8) << 4]);
+    }
+    // the predictor takes the place of the spectral selection, neither the end of band nor a point transform apply
+    sos.extend_from_slice(&[options.predictor.selection(), 0, 0]);
+    write_segment(&mut output, 0xda, &sos);
+
+    let codes: Vec<CodeTable> = tables.iter().map(HuffmanTable::make_code_table).collect();
+    let mut writer = BitWriter::new(&mut output, &codes);
+    encode_differences(&mut writer, &image, options);
+    writer.flush();
+
+    output.extend_from_slice(&[0xff, 0xd9]);
+    Ok(output)
+}
+
+struct Image<'a> {
+    samples: &'a [u16],
+    width: usize,
+    height: usize,
+    components: usize,
+}
+
+// encode_differences codes the differences of all samples to their predictions as in ITU T.81 H.1.2. The first
+// line is predicted from the left and the first column from above, the first sample from half the sample range.
+fn encode_differences<W: EntropyCoder>(coder: &mut W, image: &Image, options: &LosslessOptions) {
+    let sample = |x: usize, y: usize, component: usize| {
+        i32::from(image.samples[(y * image.width + x) * image.components + component])
+    };
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            for component in 0..image.components {
+                let prediction = match (x, y) {
+                    (0, 0) => 1 << (options.precision - 1),
+                    (_, 0) => sample(x - 1, y, component),
+                    (0, _) => sample(x, y - 1, component),
+                    _ => options.predictor.predict(
+                        sample(x - 1, y, component),
+                        sample(x, y - 1, component),
+                        sample(x - 1, y - 1, component),
+                    ),
+                };
+                // differences are taken modulo 2^16, 32768 is coded by its size category 16 alone
+                let difference = i32::from((sample(x, y, component) - prediction) as i16);
+                let table = component.min(1);
+                if difference == i32::from(i16::MIN) {
+                    coder.write_symbol(table, 16);
+                    continue;
+                }
+                let size = magnitude_category(difference);
+                coder.write_symbol(table, size);
+                coder.write_value(difference, size);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitreader::BitReader;
+    use huffman::{decode_symbol, HuffmanDecoder};
+    use parser::{self, Marker};
+
+    // decode_lossless decodes the single scan of a lossless file, returning its precision, predictor selection
+    // and samples
+    fn decode_lossless(file: &[u8]) -> (u64, u64, Vec<u16>) {
+        let markers = parser::decode(file).unwrap();
+        let mut frame = None;
+        let mut tables = Vec::new();
+        let mut scan = None;
+        for marker in &markers {
+            match *marker {
+                Marker::SOF(ref metadata) => frame = Some(metadata.clone()),
+                Marker::DHT(ref dht) => tables.extend(dht.iter().cloned()),
+                Marker::Image(ref image) => scan = Some(image),
+                _ => {}
+            }
+        }
+        let (frame, scan) = (frame.unwrap(), scan.unwrap());
+        assert_eq!(frame.marker, 0xc3);
+
+        let maps: Vec<_> = scan
+            .metadata
+            .component_metadata
+            .iter()
+            .map(|component| {
+                let class = DHTType::from_selector(0, component.dc_table as u8).unwrap();
+                let table = tables.iter().find(|table| table.class == class).unwrap();
+                HuffmanDecoder::new(table)
+            })
+            .collect();
+        let mut reader = BitReader::new(scan.data);
+
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let components = frame.component_metadata.len();
+        let predictor = scan.metadata.spectral_start;
+        let mut samples = vec![0u16; width * height * components];
+        for y in 0..height {
+            for x in 0..width {
+                for (component, map) in maps.iter().enumerate() {
+                    let at = |x: usize, y: usize| {
+                        i32::from(samples[(y * width + x) * components + component])
+                    };
+                    let prediction = match (x, y) {
+                        (0, 0) => 1 << (frame.precision - 1),
+                        (_, 0) => at(x - 1, y),
+                        (0, _) => at(x, y - 1),
+                        _ => {
+                            let (a, b, c) = (at(x - 1, y), at(x, y - 1), at(x - 1, y - 1));
+                            match predictor {
+                                1 => a,
+                                2 => b,
+                                3 => c,
+                                4 => a + b - c,
+                                5 => a + ((b - c) >> 1),
+                                6 => b + ((a - c) >> 1),
+                                _ => (a + b) >> 1,
+                            }
+                        }
+                    };
+                    let size = decode_symbol(map, &mut reader).unwrap();
+                    let difference = match size {
+                        16 => 32768,
+                        _ => reader.receive_extend(size).unwrap(),
+                    };
+                    samples[(y * width + x) * components + component] =
+                        (prediction + difference) as u16;
+                }
+            }
+        }
+        (frame.precision, predictor, samples)
+    }
+
+    #[test]
+    fn encode_lossless_test() {
+        let predictors = [
+            Predictor::Left,
+            Predictor::Above,
+            Predictor::AboveLeft,
+            Predictor::Gradient,
+            Predictor::LeftGradient,
+            Predictor::AboveGradient,
+            Predictor::Average,
+        ];
+        let (width, height) = (19usize, 13usize);
+        for &(precision, components) in &[(8u8, 3usize), (12, 1), (16, 1), (16, 4), (2, 2)] {
+            // a gradient with noise over the whole range, including jumps between the extremes
+            let mut state = 3u32;
+            let samples: Vec<u16> = (0..width * height * components)
+                .map(|i| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    let value = match i % 7 {
+                        0 => 0,
+                        1 => u32::MAX,
+                        _ => (i as u32 * 97) + (state >> 24),
+                    };
+                    (value & ((1 << precision) - 1)) as u16
+                })
+                .collect();
+            for (index, &predictor) in predictors.iter().enumerate() {
+                let options = LosslessOptions {
+                    precision,
+                    predictor,
+                };
+                let file = encode_lossless_image(
+                    &samples,
+                    width as u64,
+                    height as u64,
+                    components,
+                    &options,
+                )
+                .unwrap();
+                let decoded = decode_lossless(&file);
+                assert_eq!(
+                    decoded,
+                    (u64::from(precision), index as u64 + 1, samples.clone())
+                );
+            }
+        }
+
+        // differences of 32768 have no extra bits
+        let options = LosslessOptions {
+            precision: 16,
+            ..LosslessOptions::default()
+        };
+        let file = encode_lossless_image(&[0, 32768, 0], 3, 1, 1, &options).unwrap();
+        assert_eq!(decode_lossless(&file).2, [0, 32768, 0]);
+
+        // a smooth image compresses
+        let smooth: Vec<u16> = (0..64 * 64).map(|i| (i % 64 + i / 64) as u16 * 2).collect();
+        let options = LosslessOptions::default();
+        let file = encode_lossless_image(&smooth, 64, 64, 1, &options).unwrap();
+        assert!(file.len() < smooth.len() / 2);
+        assert_eq!(decode_lossless(&file).2, smooth);
+    }
+
+    #[test]
+    fn encode_lossless_error_test() {
+        let options = LosslessOptions::default();
+        assert!(match encode_lossless_image(&[256, 0], 2, 1, 1, &options) {
+            Err(Error::Encoder(EncodeError::SampleOutOfRange { value, precision })) => {
+                (value, precision) == (256, 8)
+            }
+            _ => false,
+        });
+        assert!(match encode_lossless_image(&[0; 10], 2, 1, 5, &options) {
+            Err(Error::Encoder(EncodeError::UnsupportedComponentCount { count })) => count == 5,
+            _ => false,
+        });
+        let options = LosslessOptions {
+            precision: 17,
+            ..options
+        };
+        assert!(match encode_lossless_image(&[0; 2], 2, 1, 1, &options) {
+            Err(Error::Encoder(EncodeError::UnsupportedPrecision { precision })) => precision == 17,
+            _ => false,
+        });
+    }
+}
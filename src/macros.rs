@@ -0,0 +1,28 @@
+// Instrumentation macros forwarding to tracing when the tracing feature is enabled. Without it they expand to
+// nothing, so instrumented code costs nothing and the arguments must not have side effects.
+
+// trace_event! emits an event at the given level, e.g. trace_event!(debug, offset, "parsed segment")
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($level:ident, $($args:tt)*) => {
+        ::tracing::$level!($($args)*)
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($level:ident, $($args:tt)*) => {};
+}
+
+// trace_span! enters a span at the given level, which is left when the returned guard is dropped
+#[cfg(feature = "tracing")]
+macro_rules! trace_span {
+    ($level:ident, $($args:tt)*) => {
+        ::tracing::span!(::tracing::Level::$level, $($args)*).entered()
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_span {
+    ($level:ident, $($args:tt)*) => {
+        ()
+    };
+}
@@ -0,0 +1,303 @@
+// Motion JPEG, as webcams and cameras record it: AVI files with a JPEG file in every video chunk or streams of
+// JPEG files written back to back, like those of HTTP multipart streams with their headers removed.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use decoder::{decode_image_with_tables, DecodeOptions, DecodedImage, DecoderScratch, TableState};
+use errors::{Error, ParserError};
+use huffman::{DHTType, HuffmanDecoder, HuffmanTable};
+
+// MAX_LIST_DEPTH is how deep AVI lists are searched for frames: the RIFF chunks, their movi list and its rec
+// lists
+const MAX_LIST_DEPTH: usize = 3;
+
+// mjpeg_frames returns the JPEG files of an AVI file or of a stream of concatenated files in order. AVI files are
+// recognized by their RIFF header, their frames are the compressed video chunks of the movi lists, including those
+// of OpenDML extension chunks, leaving out the empty chunks of dropped frames. In other input every file runs from
+// its SOI marker to its EOI marker, bytes between files are skipped and a file cut off by the end of the input is
+// returned as far as it goes. Files are found by walking their segments, so the files embedded in EXIF thumbnails
+// aren't mistaken for frames.
+pub fn mjpeg_frames(data: &[u8]) -> Result<Vec<&[u8]>, ParserError> {
+    let mut frames = Vec::new();
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"AVI " {
+        avi_frames(data, 0, 0, &mut frames)?;
+    } else {
+        let mut offset = 0;
+        while let Some(start) = find_start_of_image(data, offset) {
+            let end = start + file_length(&data[start..]);
+            frames.push(&data[start..end]);
+            offset = end;
+        }
+    }
+    Ok(frames)
+}
+
+// avi_frames collects the video frames of the chunks in data, which starts at offset of the file, descending
+// into the lists that hold them
+fn avi_frames<'a>(
+    data: &'a [u8],
+    offset: usize,
+    depth: usize,
+    frames: &mut Vec<&'a [u8]>,
+) -> Result<(), ParserError> {
+    let mut position = 0;
+    while position + 8 <= data.len() {
+        let id = &data[position..position + 4];
+        let size = u32::from_le_bytes([
+            data[position + 4],
+            data[position + 5],
+            data[position + 6],
+            data[position + 7],
+        ]) as usize;
+        let start = position + 8;
+        let body = data
+            .get(start..start.saturating_add(size))
+            .ok_or(ParserError::OutOfBounds {
+                offset: offset + start,
+                length: size,
+            })?;
+        let list = match id {
+            b"RIFF" | b"LIST" if body.len() >= 4 => Some(&body[..4]),
+            _ => None,
+        };
+        match list {
+            Some(b"AVI ") | Some(b"AVIX") | Some(b"movi") | Some(b"rec ")
+                if depth < MAX_LIST_DEPTH =>
+            {
+                avi_frames(&body[4..], offset + start + 4, depth + 1, frames)?
+            }
+            Some(_) => {}
+            // video chunks are named by their stream number followed by dc for compressed or db for
+            // uncompressed frames, MJPEG writers use both
+            None if (&id[2..] == b"dc" || &id[2..] == b"db") && body.starts_with(&[0xff, 0xd8]) => {
+                frames.push(body)
+            }
+            None => {}
+        }
+        // chunks are padded to an even length
+        position = start + size + (size & 1);
+    }
+    Ok(())
+}
+
+fn find_start_of_image(data: &[u8], offset: usize) -> Option<usize> {
+    data.get(offset..)?
+        .windows(2)
+        .position(|bytes| bytes == [0xff, 0xd8])
+        .map(|position| offset + position)
+}
+
+// file_length returns the length of the JPEG file at the start of data up to and including its EOI marker, or the
+// length of data if it ends first. Segments are skipped by their length, entropy coded data byte by byte.
+fn file_length(data: &[u8]) -> usize {
+    let mut position = 2;
+    while position + 1 < data.len() {
+        if data[position] != 0xff || data[position + 1] == 0xff {
+            position += 1;
+            continue;
+        }
+        match data[position + 1] {
+            0xd9 => return position + 2,
+            // stuffed zero bytes and restart markers have no length
+            0x00 | 0x01 | 0xd0..=0xd7 => position += 2,
+            _ if position + 4 <= data.len() => {
+                position +=
+                    2 + usize::from(u16::from_be_bytes([data[position + 2], data[position + 3]]))
+            }
+            _ => break,
+        }
+    }
+    data.len()
+}
+
+// MjpegDecoder decodes the frames of a Motion JPEG stream in order. Tables carry over from frame to frame, so a
+// frame may leave out the quantization or Huffman tables of the frames before it, and Huffman tables no frame
+// defined are the Annex K tables AVI MJPEG implies. With the pixels handed back by recycle, the buffers of a frame
+// are reused for the next one.
+#[derive(Debug)]
+pub struct MjpegDecoder {
+    options: DecodeOptions,
+    scratch: DecoderScratch,
+    tables: TableState,
+}
+
+impl MjpegDecoder {
+    pub fn new(options: DecodeOptions) -> MjpegDecoder {
+        let mut tables = TableState::default();
+        for &class in &[
+            DHTType::LuminanceDC,
+            DHTType::LuminanceAC,
+            DHTType::ChrominanceDC,
+            DHTType::ChrominanceAC,
+        ] {
+            tables
+                .huffman_tables
+                .insert(class, HuffmanDecoder::new(&HuffmanTable::standard(class)));
+        }
+        MjpegDecoder {
+            options,
+            scratch: DecoderScratch::default(),
+            tables,
+        }
+    }
+
+    // decode_frame decodes the next frame of the stream, one of those mjpeg_frames returns
+    pub fn decode_frame(&mut self, frame: &[u8]) -> Result<DecodedImage, Error> {
+        decode_image_with_tables(frame, &self.options, &mut self.scratch, &mut self.tables)
+    }
+
+    // recycle keeps the pixel buffer of a decoded frame for the next one
+    pub fn recycle(&mut self, image: DecodedImage) {
+        self.scratch.recycle(image);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use decoder::{decode_image, ColorType};
+    use encoder::{encode_image_with_options, EncodeOptions, ThumbnailOptions};
+    use parser::{decode, Marker};
+    use writer::encode_markers;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(id: &[u8], kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(id, &[kind, &chunks.concat()].concat())
+    }
+
+    // frames returns three frames of a moving gradient as complete files and as they are stored in the stream:
+    // the first one complete, the second one without tables and the third one without Huffman tables but with
+    // other quantization tables
+    pub fn frames() -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let complete: Vec<Vec<u8>> = [(0, 50), (1, 50), (2, 90)]
+            .iter()
+            .map(|&(shift, quality)| {
+                let pixels: Vec<u8> = (0..24 * 16 * 3)
+                    .map(|i| ((i / 3 % 24 + shift) * 10) as u8)
+                    .collect();
+                let options = EncodeOptions {
+                    quality,
+                    ..EncodeOptions::default()
+                };
+                encode_image_with_options(&pixels, 24, 16, ColorType::Rgb, &options).unwrap()
+            })
+            .collect();
+        let without = |file: &[u8], keep: &dyn Fn(&Marker) -> bool| {
+            let markers: Vec<Marker> = decode(file).unwrap().into_iter().filter(keep).collect();
+            encode_markers(&markers).unwrap()
+        };
+        let stored = vec![
+            complete[0].clone(),
+            without(&complete[1], &|marker| {
+                !matches!(*marker, Marker::DQT(_) | Marker::DHT(_))
+            }),
+            without(&complete[2], &|marker| !matches!(*marker, Marker::DHT(_))),
+        ];
+        (complete, stored)
+    }
+
+    fn check_frames(found: &[&[u8]], frames: &[Vec<u8>]) {
+        assert_eq!(found.len(), frames.len());
+        for (found, frame) in found.iter().zip(frames) {
+            assert_eq!(found, frame);
+        }
+    }
+
+    #[test]
+    fn avi_test() {
+        let (_, frames) = frames();
+        let header = list(b"LIST", b"hdrl", &[chunk(b"avih", &[0; 56])]);
+        let movie = list(
+            b"LIST",
+            b"movi",
+            &[
+                chunk(b"00dc", &frames[0]),
+                chunk(b"01wb", &[1, 2, 3]),
+                // a dropped frame
+                chunk(b"00dc", &[]),
+                list(b"LIST", b"rec ", &[chunk(b"00db", &frames[1])]),
+            ],
+        );
+        let extension = list(
+            b"RIFF",
+            b"AVIX",
+            &[list(b"LIST", b"movi", &[chunk(b"00dc", &frames[2])])],
+        );
+        let index = chunk(b"idx1", &[0; 32]);
+        let avi = [list(b"RIFF", b"AVI ", &[header, movie, index]), extension].concat();
+        check_frames(&mjpeg_frames(&avi).unwrap(), &frames);
+
+        let truncated = &avi[..avi.len() - 100];
+        assert!(matches!(
+            mjpeg_frames(truncated),
+            Err(ParserError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn stream_test() {
+        let options = EncodeOptions {
+            thumbnail: Some(ThumbnailOptions::default()),
+            ..EncodeOptions::default()
+        };
+        let with_thumbnail =
+            encode_image_with_options(&[80; 32 * 32 * 3], 32, 32, ColorType::Rgb, &options)
+                .unwrap();
+        let (_, mut frames) = frames();
+        frames.insert(1, with_thumbnail);
+        let stream = [
+            &b"--boundary\r\n\r\n"[..],
+            &frames[0],
+            &frames[1],
+            b"\r\n--boundary\r\n\r\n",
+            &frames[2],
+            &frames[3],
+        ]
+        .concat();
+        check_frames(&mjpeg_frames(&stream).unwrap(), &frames);
+
+        let found = mjpeg_frames(&stream[..stream.len() - 10]).unwrap();
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[3], &frames[3][..frames[3].len() - 10]);
+        assert!(mjpeg_frames(b"no frames").unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_frame_test() {
+        let (complete, stored) = frames();
+        let mut decoder = MjpegDecoder::new(DecodeOptions::default());
+        let mut pixels = None;
+        for (complete, stored) in complete.iter().zip(&stored) {
+            let image = decoder.decode_frame(stored).unwrap();
+            assert_eq!(image, decode_image(complete).unwrap());
+
+            // the frames have the same size, so every decode after the first one reuses the recycled buffers
+            let address = image.pixels.as_ptr();
+            assert_eq!(*pixels.get_or_insert(address), address);
+            decoder.recycle(image);
+        }
+        assert!(decode_image(&stored[1]).is_err());
+
+        // frames without Huffman tables use the standard ones from the start
+        let mut decoder = MjpegDecoder::new(DecodeOptions::default());
+        assert_eq!(
+            decoder.decode_frame(&stored[2]).unwrap(),
+            decode_image(&complete[2]).unwrap()
+        );
+        assert!(decoder.decode_frame(&stored[1]).is_ok());
+        assert!(MjpegDecoder::new(DecodeOptions::default())
+            .decode_frame(&stored[1])
+            .is_err());
+    }
+}
@@ -0,0 +1,202 @@
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use errors::{Error, ParserError};
+use parser::{decode, header_segments, Marker};
+use tiff::Tiff;
+
+const MP_ENTRY_TAG: u16 = 0xb002;
+const MP_ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MultiPictureType {
+    BaselinePrimary,
+    LargeThumbnailVga,
+    LargeThumbnailFullHd,
+    Panorama,
+    Disparity,
+    MultiAngle,
+    Undefined,
+    Other(u32),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MultiPictureImage {
+    pub attribute: u32,
+    // offset is the absolute position of the image's SOI marker in the file
+    pub offset: usize,
+    pub length: usize,
+    pub dependent_images: (u16, u16),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MultiPictureIndex {
+    pub images: Vec<MultiPictureImage>,
+}
+
+impl MultiPictureImage {
+    pub fn image_type(&self) -> MultiPictureType {
+        match self.attribute & 0x00ff_ffff {
+            0x03_0000 => MultiPictureType::BaselinePrimary,
+            0x01_0001 => MultiPictureType::LargeThumbnailVga,
+            0x01_0002 => MultiPictureType::LargeThumbnailFullHd,
+            0x02_0001 => MultiPictureType::Panorama,
+            0x02_0002 => MultiPictureType::Disparity,
+            0x02_0003 => MultiPictureType::MultiAngle,
+            0x00_0000 => MultiPictureType::Undefined,
+            other => MultiPictureType::Other(other),
+        }
+    }
+
+    pub fn data<'a>(&self, jpeg_file: &'a [u8]) -> Result<&'a [u8], Error> {
+        self.offset
+            .checked_add(self.length)
+            .and_then(|end| jpeg_file.get(self.offset..end))
+            .ok_or_else(|| {
+                ParserError::OutOfBounds {
+                    offset: self.offset,
+                    length: self.length,
+                }
+                .into()
+            })
+    }
+}
+
+impl MultiPictureIndex {
+    // decode_image parses the image with the given index the same way `decode` parses the primary image
+    pub fn decode_image<'a>(
+        &self,
+        jpeg_file: &'a [u8],
+        index: usize,
+    ) -> Result<Vec<Marker<'a>>, Error> {
+        let image = self
+            .images
+            .get(index)
+            .ok_or(ParserError::MissingImage { index })?;
+
+        decode(image.data(jpeg_file)?)
+    }
+}
+
+// read_mpf returns the multi picture index stored in the APP2 MPF segment of the primary image, if there is one
+pub fn read_mpf(jpeg_file: &[u8]) -> Result<Option<MultiPictureIndex>, Error> {
+    let (tiff_offset, tiff_data) = match find_mpf_segment(jpeg_file)? {
+        Some(segment) => segment,
+        None => return Ok(None),
+    };
+
+    let tiff = Tiff::new(tiff_data)?;
+    let (entries, _) = tiff.read_ifd(tiff.first_ifd_offset()?)?;
+
+    let mp_entry = match entries.iter().find(|entry| entry.tag == MP_ENTRY_TAG) {
+        Some(entry) => entry,
+        None => return Ok(Some(MultiPictureIndex { images: vec![] })),
+    };
+
+    let count = mp_entry.count as usize / MP_ENTRY_SIZE;
+    let mut images = Vec::with_capacity(count);
+
+    for i in 0..count {
+        let entry = mp_entry.value_offset as usize + i * MP_ENTRY_SIZE;
+        let offset = tiff.u32_at(entry + 8)? as usize;
+
+        images.push(MultiPictureImage {
+            attribute: tiff.u32_at(entry)?,
+            // the primary image is stored with offset 0, all others relative to the MPF TIFF header
+            offset: if offset == 0 { 0 } else { tiff_offset + offset },
+            length: tiff.u32_at(entry + 4)? as usize,
+            dependent_images: (tiff.u16_at(entry + 12)?, tiff.u16_at(entry + 14)?),
+        });
+    }
+
+    Ok(Some(MultiPictureIndex { images }))
+}
+
+// find_mpf_segment returns the absolute offset and content of the MPF TIFF structure
+fn find_mpf_segment(jpeg_file: &[u8]) -> Result<Option<(usize, &[u8])>, ParserError> {
+    Ok(header_segments(jpeg_file)?
+        .into_iter()
+        .find(|segment| segment.tag == 0xe2 && segment.data.starts_with(b"MPF\x00"))
+        .map(|segment| (segment.offset + 8, &segment.data[4..])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use parser::SomeMarker;
+
+    fn make_mpf_file() -> Vec<u8> {
+        let second_image = vec![0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x03, 0x42, 0xFF, 0xD9];
+
+        let mut tiff = vec![
+            0x4D, 0x4D, 0x00, 0x2A, 0x00, 0x00, 0x00, 0x08, // header
+            0x00, 0x02, // two entries
+            0xB0, 0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, // images
+            0xB0, 0x02, 0x00, 0x07, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x26, // MPEntry
+            0x00, 0x00, 0x00, 0x00, // no next IFD
+        ];
+        let primary_length = 2 + 4 + 4 + tiff.len() + 32 + 7;
+        let second_offset = primary_length - (2 + 4 + 4);
+
+        tiff.extend(&[0x20, 0x03, 0x00, 0x00]);
+        tiff.extend(&(primary_length as u32).to_be_bytes());
+        tiff.extend(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        tiff.extend(&[0x00, 0x01, 0x00, 0x01]);
+        tiff.extend(&(second_image.len() as u32).to_be_bytes());
+        tiff.extend(&(second_offset as u32).to_be_bytes());
+        tiff.extend(&[0x00, 0x00, 0x00, 0x00]);
+
+        let mut file = vec![0xFF, 0xD8, 0xFF, 0xE2];
+        file.extend(&((tiff.len() + 6) as u16).to_be_bytes());
+        file.extend(b"MPF\x00");
+        file.extend(tiff);
+        file.extend(&[0xFF, 0xFE, 0x00, 0x03, 0x41, 0xFF, 0xD9]);
+        assert_eq!(file.len(), primary_length);
+
+        file.extend(second_image);
+        file
+    }
+
+    #[test]
+    fn read_mpf_test() {
+        let file = make_mpf_file();
+        let index = read_mpf(&file).unwrap().unwrap();
+
+        assert_eq!(index.images.len(), 2);
+        assert_eq!(
+            index.images[0].image_type(),
+            MultiPictureType::BaselinePrimary
+        );
+        assert_eq!(index.images[0].offset, 0);
+        assert_eq!(index.images[0].length, file.len() - 9);
+        assert_eq!(
+            index.images[1].image_type(),
+            MultiPictureType::LargeThumbnailVga
+        );
+        assert_eq!(index.images[1].offset, file.len() - 9);
+        assert_eq!(index.images[1].length, 9);
+    }
+
+    #[test]
+    fn decode_image_test() {
+        let file = make_mpf_file();
+        let index = read_mpf(&file).unwrap().unwrap();
+
+        assert_eq!(
+            index.decode_image(&file, 1).unwrap(),
+            vec![Marker::Other(SomeMarker {
+                tag: 0xfe,
+                length: 1,
+                data: &[0x42],
+            })]
+        );
+        assert!(index.decode_image(&file, 2).is_err());
+    }
+
+    #[test]
+    fn no_mpf_test() {
+        let file = vec![0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x03, 0x41, 0xFF, 0xD9];
+        assert_eq!(read_mpf(&file).unwrap(), None);
+    }
+}
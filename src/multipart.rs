@@ -0,0 +1,274 @@
+// Reading of multipart/x-mixed-replace HTTP bodies, the MJPEG streams network cameras serve with a JPEG file in
+// every part.
+
+use std::io::Read;
+
+use decoder::{DecodeOptions, DecodedImage};
+use errors::{Error, ParserError};
+use mjpeg::MjpegDecoder;
+use stream::StreamReader;
+
+// MAX_HEADER_LINE is the length of the longest header line of a part
+const MAX_HEADER_LINE: usize = 8192;
+
+// multipart_boundary returns the boundary parameter of a multipart Content-Type header value like
+// "multipart/x-mixed-replace; boundary=frame", without the quotes of quoted boundaries
+pub fn multipart_boundary(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim();
+        Some(
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value),
+        )
+    })
+}
+
+// MultipartStream reads the parts of a multipart/x-mixed-replace body as they arrive and iterates over them as
+// decoded frames, with the tables of every frame carrying over to the next one and the standard Huffman tables
+// for frames without any, like MjpegDecoder. A part starts after a line with its delimiter, two dashes followed
+// by the boundary, and a block of headers. Its data is Content-Length bytes long or, without that header, ends
+// in front of the line break before the next delimiter. Delimiters are found wherever they are, as some cameras
+// add dashes to the boundary of their header or leave out line breaks. Iteration ends with the closing delimiter,
+// the end of the stream or the first error reading the stream, while frames failing to decode only return their
+// error.
+pub struct MultipartStream<R: Read> {
+    stream: StreamReader<R>,
+    delimiter: Vec<u8>,
+    decoder: MjpegDecoder,
+    done: bool,
+}
+
+impl<R: Read> MultipartStream<R> {
+    // new reads the parts separated by boundary, which multipart_boundary takes from the Content-Type header
+    pub fn new(reader: R, boundary: &str, options: DecodeOptions) -> MultipartStream<R> {
+        let boundary = boundary.trim_start_matches('-');
+        MultipartStream {
+            stream: StreamReader::new(reader),
+            delimiter: [b"--", boundary.as_bytes()].concat(),
+            decoder: MjpegDecoder::new(options),
+            done: false,
+        }
+    }
+
+    // next_part returns the data of the next part without decoding it
+    pub fn next_part(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        if self.done {
+            return None;
+        }
+        let part = self.read_part();
+        if !matches!(part, Ok(Some(_))) {
+            self.done = true;
+        }
+        part.transpose()
+    }
+
+    // recycle keeps the pixel buffer of a decoded frame for the next one
+    pub fn recycle(&mut self, image: DecodedImage) {
+        self.decoder.recycle(image);
+    }
+
+    fn read_part(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        // skip the preamble, or the line break after the data of the part before
+        loop {
+            if !self.stream.fill(self.delimiter.len())? {
+                return Ok(None);
+            }
+            if self.delimiter_at(0) {
+                break;
+            }
+            self.stream.consume(1);
+        }
+        self.stream.consume(self.delimiter.len());
+        if self.stream.fill(2)? && self.stream.peek_range(0, 2) == b"--" {
+            return Ok(None);
+        }
+        self.read_line()?;
+
+        let mut length = None;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            let line = String::from_utf8_lossy(&line);
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    let value = value.trim().parse::<usize>();
+                    length = Some(value.map_err(|_| ParserError::InvalidMultipartHeader)?);
+                }
+            }
+        }
+
+        if let Some(length) = length {
+            return match self.stream.take(length)? {
+                Some(data) => Ok(Some(data)),
+                None => Err(ParserError::UnexpectedEof.into()),
+            };
+        }
+        let mut end = 0;
+        while self.stream.fill(end + self.delimiter.len())? && !self.delimiter_at(end) {
+            end += 1;
+        }
+        // if the stream ended without another delimiter, the part runs up to its end
+        let end = end.min(self.stream.buffered());
+        let mut data = self.stream.take(end)?.unwrap_or_default();
+        if data.ends_with(b"\n") {
+            data.pop();
+            if data.ends_with(b"\r") {
+                data.pop();
+            }
+        }
+        Ok(Some(data))
+    }
+
+    fn delimiter_at(&self, index: usize) -> bool {
+        self.delimiter
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| self.stream.peek(index + i) == Some(byte))
+    }
+
+    // read_line consumes a line and returns it without its line break, which may be CRLF or just LF
+    fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        let mut length = 0;
+        loop {
+            if length == MAX_HEADER_LINE {
+                return Err(ParserError::InvalidMultipartHeader.into());
+            }
+            if !self.stream.fill(length + 1)? {
+                return Err(ParserError::UnexpectedEof.into());
+            }
+            if self.stream.peek(length) == Some(b'\n') {
+                break;
+            }
+            length += 1;
+        }
+        let mut line = self.stream.take(length + 1)?.unwrap_or_default();
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        Ok(line)
+    }
+}
+
+impl<R: Read> Iterator for MultipartStream<R> {
+    type Item = Result<DecodedImage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let part = self.next_part()?;
+        Some(part.and_then(|data| self.decoder.decode_frame(&data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    use decoder::decode_image;
+    use mjpeg::tests::frames;
+
+    // Chunks returns the data in reads of at most 5 bytes, like a network stream
+    struct Chunks<'a>(&'a [u8]);
+
+    impl<'a> Read for Chunks<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let length = self.0.len().min(buf.len()).min(5);
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn boundary_test() {
+        assert_eq!(
+            multipart_boundary("multipart/x-mixed-replace; boundary=frame"),
+            Some("frame")
+        );
+        assert_eq!(
+            multipart_boundary("multipart/x-mixed-replace;charset=x;Boundary=\"a b\""),
+            Some("a b")
+        );
+        assert_eq!(multipart_boundary("image/jpeg"), None);
+        assert_eq!(multipart_boundary("boundary=frame"), None);
+    }
+
+    #[test]
+    fn multipart_stream_test() {
+        let (complete, stored) = frames();
+        let mut body = b"preamble\r\n--frame\r\n".to_vec();
+        let headers = [
+            format!(
+                "Content-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                stored[0].len()
+            ),
+            "content-type: image/jpeg\n\n".to_string(),
+            format!(
+                "CONTENT-LENGTH:{}\r\nX-Timestamp: 1\r\n\r\n",
+                stored[2].len()
+            ),
+        ];
+        for (headers, frame) in headers.iter().zip(&stored) {
+            body.extend_from_slice(headers.as_bytes());
+            body.extend_from_slice(frame);
+            body.extend_from_slice(b"\r\n--frame\r\n");
+        }
+        body.truncate(body.len() - 2);
+        body.extend_from_slice(b"--\r\nepilogue");
+
+        // the header of some cameras names the boundary with the dashes of the delimiter
+        for boundary in &["frame", "--frame"] {
+            let mut stream =
+                MultipartStream::new(Chunks(&body), boundary, DecodeOptions::default());
+            for frame in &complete {
+                let image = stream.next().unwrap().unwrap();
+                assert_eq!(image, decode_image(frame).unwrap());
+                stream.recycle(image);
+            }
+            assert!(stream.next().is_none());
+        }
+
+        // without a closing delimiter the last part ends with the stream
+        let end = body.len() - b"\r\n--frame--\r\nepilogue".len();
+        let mut stream =
+            MultipartStream::new(Chunks(&body[..end]), "frame", DecodeOptions::default());
+        assert_eq!(
+            stream.nth(2).unwrap().unwrap(),
+            decode_image(&complete[2]).unwrap()
+        );
+        assert!(stream.next().is_none());
+
+        let invalid = b"--frame\r\nContent-Length: many\r\n\r\n\xff\xd8\r\n--frame\r\n";
+        let mut stream = MultipartStream::new(Chunks(invalid), "frame", DecodeOptions::default());
+        assert!(matches!(
+            stream.next_part(),
+            Some(Err(Error::Parser(ParserError::InvalidMultipartHeader)))
+        ));
+        assert!(stream.next_part().is_none());
+
+        let truncated = [
+            &b"--frame\r\nContent-Length: 100\r\n\r\n"[..],
+            &stored[0][..50],
+        ]
+        .concat();
+        let mut stream =
+            MultipartStream::new(Chunks(&truncated), "frame", DecodeOptions::default());
+        assert!(matches!(
+            stream.next_part(),
+            Some(Err(Error::Parser(ParserError::UnexpectedEof)))
+        ));
+
+        let mut stream =
+            MultipartStream::new(Chunks(b"no parts"), "frame", DecodeOptions::default());
+        assert!(stream.next_part().is_none());
+    }
+}
@@ -1,19 +1,32 @@
-use nom::{be_u16, be_u8, Needed};
+#[cfg(not(feature = "std"))]
+use prelude::*;
+#[cfg(feature = "std")]
+use std::collections::BTreeSet;
 
-use failure::Error;
+use nom::branch::alt;
+use nom::bytes::streaming::{tag, take};
+use nom::combinator::{complete, eof, map, map_opt, map_parser, verify};
+use nom::multi::{count, many1};
+use nom::number::streaming::{be_u16, be_u8};
+use nom::sequence::terminated;
+use nom::{IResult, Needed};
 
-use errors::ParserError;
+use errors::{Error, ParserError, Stage};
 use huffman::{DHTType, HuffmanTable};
 
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum Marker<'a> {
     Other(SomeMarker<'a>),
     DHT(Vec<HuffmanTable>),
-    DQT(QuantizationTable<'a>),
+    DQT(Vec<QuantizationTable<'a>>),
+    DRI(u16),
+    SOF(StartOfFrameMetaData),
     Image(ImageStream<'a>),
 }
 
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct SomeMarker<'a> {
     pub tag: u8,
     pub length: u16,
@@ -21,156 +34,1072 @@ pub struct SomeMarker<'a> {
 }
 
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct ImageStream<'a> {
     pub metadata: StartOfStreamMetaData,
     pub data: &'a [u8],
 }
 
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct QuantizationTable<'a> {
     pub id: u64,
+    // precision is 0 for 8 bit and 1 for 16 bit table entries
+    pub precision: u64,
+    // data contains the 64 table entries in zigzag order
     pub data: &'a [u8],
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct StartOfStreamMetaData {
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct StartOfFrameMetaData {
+    // marker is the SOFn marker byte, which encodes the coding process (baseline, progressive, lossless, ...)
+    pub marker: u8,
     pub precision: u64,
     pub height: u64,
     pub width: u64,
     pub component_metadata: Vec<ColorComponentMetaData>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct StartOfStreamMetaData {
+    pub component_metadata: Vec<ScanComponentMetaData>,
+    pub spectral_start: u64,
+    pub spectral_end: u64,
+    pub approximation_high: u64,
+    pub approximation_low: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ScanComponentMetaData {
+    pub id: u64,
+    pub dc_table: u64,
+    pub ac_table: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct ColorComponentMetaData {
     pub id: u64,
     pub sampling_resolution: u64,
     pub quantization_table: u64,
 }
 
-named!(
-    start_of_stream<&[u8], Marker>,
-    do_parse!(
-        tag!(b"\xff\xda")
-            >> _length: be_u16
-            >> metadata: start_of_stream_metadata
-            >> data: take_until!(&b"\xff\xd9"[..])
-            >> (Marker::Image(ImageStream{ metadata, data }))
-    )
-);
-
-named!(start_of_stream_metadata<&[u8], StartOfStreamMetaData>,
-    do_parse!(
-        precision: be_u8
-        >> height: be_u16
-        >> width: be_u16
-        >> components: be_u8
-        >> component_metadata: count!(color_component_metadata, components.into())
-        >> (StartOfStreamMetaData{
+// SubsamplingMode describes the chroma resolution of a frame relative to its luma component
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum SubsamplingMode {
+    Chroma444,
+    Chroma422,
+    Chroma420,
+    Chroma411,
+    Gray,
+    Other,
+}
+
+impl StartOfFrameMetaData {
+    // subsampling_mode compares the sampling factors of the chroma components to the first component. Frames
+    // other than grayscale or three component YCbCr are reported as Other.
+    pub fn subsampling_mode(&self) -> SubsamplingMode {
+        let components = &self.component_metadata;
+        if components.len() == 1 {
+            return SubsamplingMode::Gray;
+        }
+        if components.len() != 3
+            || components[1].sampling_resolution != components[2].sampling_resolution
+        {
+            return SubsamplingMode::Other;
+        }
+
+        let (luma, chroma) = (&components[0], &components[1]);
+        let ratio = |luma: u64, chroma: u64| {
+            if chroma != 0 && luma.is_multiple_of(chroma) {
+                Some(luma / chroma)
+            } else {
+                None
+            }
+        };
+
+        match (
+            ratio(luma.horizontal_sampling(), chroma.horizontal_sampling()),
+            ratio(luma.vertical_sampling(), chroma.vertical_sampling()),
+        ) {
+            (Some(1), Some(1)) => SubsamplingMode::Chroma444,
+            (Some(2), Some(1)) => SubsamplingMode::Chroma422,
+            (Some(2), Some(2)) => SubsamplingMode::Chroma420,
+            (Some(4), Some(1)) => SubsamplingMode::Chroma411,
+            _ => SubsamplingMode::Other,
+        }
+    }
+}
+
+impl ColorComponentMetaData {
+    pub fn horizontal_sampling(&self) -> u64 {
+        (self.sampling_resolution >> 4) & 0x0f
+    }
+
+    pub fn vertical_sampling(&self) -> u64 {
+        self.sampling_resolution & 0x0f
+    }
+}
+
+// MarkerOwned is the counterpart of Marker which owns its data instead of borrowing from the parsed input
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum MarkerOwned {
+    Other(SomeMarkerOwned),
+    DHT(Vec<HuffmanTable>),
+    DQT(Vec<QuantizationTableOwned>),
+    DRI(u16),
+    SOF(StartOfFrameMetaData),
+    Image(ImageStreamOwned),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SomeMarkerOwned {
+    pub tag: u8,
+    pub length: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ImageStreamOwned {
+    pub metadata: StartOfStreamMetaData,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct QuantizationTableOwned {
+    pub id: u64,
+    pub precision: u64,
+    pub data: Vec<u8>,
+}
+
+impl<'a> Marker<'a> {
+    pub fn to_owned(&self) -> MarkerOwned {
+        match *self {
+            Marker::Other(ref marker) => MarkerOwned::Other(marker.to_owned()),
+            Marker::DHT(ref tables) => MarkerOwned::DHT(tables.clone()),
+            Marker::DQT(ref tables) => {
+                MarkerOwned::DQT(tables.iter().map(QuantizationTable::to_owned).collect())
+            }
+            Marker::DRI(interval) => MarkerOwned::DRI(interval),
+            Marker::SOF(ref metadata) => MarkerOwned::SOF(metadata.clone()),
+            Marker::Image(ref image) => MarkerOwned::Image(image.to_owned()),
+        }
+    }
+
+    // tag returns the byte following 0xff that introduces the segment of the marker
+    pub fn tag(&self) -> u8 {
+        match *self {
+            Marker::Other(ref marker) => marker.tag,
+            Marker::DHT(_) => 0xc4,
+            Marker::DQT(_) => 0xdb,
+            Marker::DRI(_) => 0xdd,
+            Marker::SOF(ref frame) => frame.marker,
+            Marker::Image(_) => 0xda,
+        }
+    }
+}
+
+impl MarkerOwned {
+    // as_marker borrows the data of the marker, for functions taking parsed markers
+    pub fn as_marker(&self) -> Marker<'_> {
+        match *self {
+            MarkerOwned::Other(ref marker) => Marker::Other(SomeMarker {
+                tag: marker.tag,
+                length: marker.length,
+                data: &marker.data,
+            }),
+            MarkerOwned::DHT(ref tables) => Marker::DHT(tables.clone()),
+            MarkerOwned::DQT(ref tables) => Marker::DQT(
+                tables
+                    .iter()
+                    .map(|table| QuantizationTable {
+                        id: table.id,
+                        precision: table.precision,
+                        data: &table.data,
+                    })
+                    .collect(),
+            ),
+            MarkerOwned::DRI(interval) => Marker::DRI(interval),
+            MarkerOwned::SOF(ref metadata) => Marker::SOF(metadata.clone()),
+            MarkerOwned::Image(ref image) => Marker::Image(ImageStream {
+                metadata: image.metadata.clone(),
+                data: &image.data,
+            }),
+        }
+    }
+}
+
+impl<'a> SomeMarker<'a> {
+    pub fn to_owned(&self) -> SomeMarkerOwned {
+        SomeMarkerOwned {
+            tag: self.tag,
+            length: self.length,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+impl<'a> ImageStream<'a> {
+    pub fn to_owned(&self) -> ImageStreamOwned {
+        ImageStreamOwned {
+            metadata: self.metadata.clone(),
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+impl<'a> QuantizationTable<'a> {
+    pub fn to_owned(&self) -> QuantizationTableOwned {
+        QuantizationTableOwned {
+            id: self.id,
+            precision: self.precision,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+// entropy_coded_segment consumes scan data up to (but not including) the next marker that terminates it.
+// Stuffed 0xFF00 bytes and RSTn markers are part of the entropy coded data, every other marker ends the scan.
+fn entropy_coded_segment(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let mut cursor = 0usize;
+
+    while cursor + 1 < input.len() {
+        if input[cursor] != 0xff {
+            cursor += 1;
+            continue;
+        }
+
+        match input[cursor + 1] {
+            0x00 | 0xd0..=0xd7 => cursor += 2,
+            _ => {
+                let marker = cursor + fill_bytes(&input[cursor..]);
+                if marker + 1 >= input.len() {
+                    break;
+                }
+                return Ok((&input[marker..], &input[..cursor]));
+            }
+        }
+    }
+
+    Err(nom::Err::Incomplete(Needed::Unknown))
+}
+
+// fill_bytes returns the number of 0xFF fill bytes at the start of input in front of the 0xFF of a marker, which
+// ITU T.81 B.1.1.2 allows before any marker
+pub(crate) fn fill_bytes(input: &[u8]) -> usize {
+    input
+        .windows(2)
+        .take_while(|pair| pair == b"\xff\xff")
+        .count()
+}
+
+// nibbles splits a byte into its high and low four bits, which many segments pack two fields into
+fn nibbles(input: &[u8]) -> IResult<&[u8], (u8, u8)> {
+    map(be_u8, |byte| (byte >> 4, byte & 0x0f))(input)
+}
+
+fn start_of_stream_metadata(input: &[u8]) -> IResult<&[u8], StartOfStreamMetaData> {
+    let (input, components) = be_u8(input)?;
+    let (input, component_metadata) = count(scan_component_metadata, components.into())(input)?;
+    let (input, spectral_start) = be_u8(input)?;
+    let (input, spectral_end) = be_u8(input)?;
+    let (input, approximation) = nibbles(input)?;
+    Ok((
+        input,
+        StartOfStreamMetaData {
+            component_metadata,
+            spectral_start: spectral_start.into(),
+            spectral_end: spectral_end.into(),
+            approximation_high: approximation.0.into(),
+            approximation_low: approximation.1.into(),
+        },
+    ))
+}
+
+fn scan_component_metadata(input: &[u8]) -> IResult<&[u8], ScanComponentMetaData> {
+    let (input, id) = be_u8(input)?;
+    let (input, tables) = nibbles(input)?;
+    Ok((
+        input,
+        ScanComponentMetaData {
+            id: id.into(),
+            dc_table: tables.0.into(),
+            ac_table: tables.1.into(),
+        },
+    ))
+}
+
+fn start_of_frame(input: &[u8]) -> IResult<&[u8], Marker<'_>> {
+    let (input, _) = tag(b"\xff")(input)?;
+    let (input, marker) = verify(be_u8, |&marker| is_start_of_frame(marker))(input)?;
+    let (input, _length) = be_u16(input)?;
+    let (input, precision) = be_u8(input)?;
+    let (input, height) = be_u16(input)?;
+    let (input, width) = be_u16(input)?;
+    let (input, components) = be_u8(input)?;
+    let (input, component_metadata) = count(color_component_metadata, components.into())(input)?;
+    Ok((
+        input,
+        Marker::SOF(StartOfFrameMetaData {
+            marker,
             precision: precision.into(),
             height: height.into(),
             width: width.into(),
-            component_metadata})
-    )
-);
-
-named!(color_component_metadata<&[u8], ColorComponentMetaData>,
-    do_parse!(
-        id: be_u8
-        >> sampling_resolution: be_u8
-        >> quantization_table: be_u8
-        >> (ColorComponentMetaData{
+            component_metadata,
+        }),
+    ))
+}
+
+// 0xc4 (DHT), 0xc8 (JPG) and 0xcc (DAC) share the SOFn range without being frame headers
+fn is_start_of_frame(marker: u8) -> bool {
+    match marker {
+        0xc0..=0xcf => marker != 0xc4 && marker != 0xc8 && marker != 0xcc,
+        _ => false,
+    }
+}
+
+fn color_component_metadata(input: &[u8]) -> IResult<&[u8], ColorComponentMetaData> {
+    let (input, id) = be_u8(input)?;
+    let (input, sampling_resolution) = be_u8(input)?;
+    let (input, quantization_table) = be_u8(input)?;
+    Ok((
+        input,
+        ColorComponentMetaData {
             id: id.into(),
             sampling_resolution: sampling_resolution.into(),
-            quantization_table: quantization_table.into()})
-    )
-);
-
-named!(huffman_tables<&[u8], Marker>,
-        dbg_dmp!(do_parse!(
-        tag!(b"\xff\xc4")
-        >> take!(2)
-        >> tables: dbg_dmp!(many1!(huffman_table))
-        >> (Marker::DHT(tables))
-        )));
-
-named!(huffman_table<&[u8], HuffmanTable>,
-        complete!(do_parse!(
-           id_class: dbg_dmp!(bits!(pair!(take_bits!(u8, 4), take_bits!(u8, 4))))
-        >> symbols_length: dbg_dmp!(count_fixed!(u8, be_u8, 16))
-        >> s1: dbg_dmp!(count!(be_u8, symbols_length[0].into()))
-        >> s2: dbg_dmp!(count!(be_u8, symbols_length[1].into()))
-        >> s3: dbg_dmp!(count!(be_u8, symbols_length[2].into()))
-        >> s4: dbg_dmp!(count!(be_u8, symbols_length[3].into()))
-        >> s5: dbg_dmp!(count!(be_u8, symbols_length[4].into()))
-        >> s6: dbg_dmp!(count!(be_u8, symbols_length[5].into()))
-        >> s7: dbg_dmp!(count!(be_u8, symbols_length[6].into()))
-        >> s8: dbg_dmp!(count!(be_u8, symbols_length[7].into()))
-        >> s9: dbg_dmp!(count!(be_u8, symbols_length[8].into()))
-        >> s10: dbg_dmp!(count!(be_u8, symbols_length[9].into()))
-        >> s11: dbg_dmp!(count!(be_u8, symbols_length[10].into()))
-        >> s12: dbg_dmp!(count!(be_u8, symbols_length[11].into()))
-        >> s13: dbg_dmp!(count!(be_u8, symbols_length[12].into()))
-        >> s14: dbg_dmp!(count!(be_u8, symbols_length[13].into()))
-        >> s15: dbg_dmp!(count!(be_u8, symbols_length[14].into()))
-        >> s16: dbg_dmp!(count!(be_u8, symbols_length[15].into()))
-        >> (HuffmanTable{
-            class: match id_class {
-                (0,0) => DHTType::LuminanceDC,
-                (0,1) => DHTType::LuminanceAC,
-                (1,0) => DHTType::ChrominanceDC,
-                (1,1) => DHTType::ChrominanceAC,
-                _ => return Err(nom::Err::Incomplete(Needed::Size(5)))
-            },
-            symbols: [s1, s2, s3, s4, s5, s6, s7, s8, s9, s10, s11, s12, s13, s14, s15, s16],
-        })
+            quantization_table: quantization_table.into(),
+        },
     ))
-);
-
-named!(quantization_table<&[u8], Marker>,
-    do_parse!(
-        tag!(b"\xff\xdb")
-        >> _length: be_u16
-        >> id: be_u8
-        >> data: take!(64)
-        >> (Marker::DQT(QuantizationTable{id: id.into(), data}))
-    )
-);
-
-named!(some_marker<&[u8], Marker>,
-    do_parse!(
-        tag!(b"\xff")
-        >> tag: be_u8
-        >> length: be_u16
-        >> data: take!(length - 2)
-        >> (Marker::Other(SomeMarker{tag, length: length - 2, data}))
-    )
-);
-
-named!(jpeg<&[u8], (Vec<Marker>, &[u8])>, preceded!(soi, jfif));
-named!(soi, tag!(b"\xff\xd8"));
-
-named!(jfif<&[u8], (Vec<Marker>, &[u8])>,
-      many_till!(alt_complete!(
-            start_of_stream
-          | huffman_tables
-          | quantization_table
-          | some_marker)
-          , tag!(b"\xff\xd9")));
-
-pub fn decode(jpeg_file: &[u8]) -> Result<Vec<Marker>, Error> {
-    jpeg(jpeg_file)
-        .map(|parsed_correctly| (parsed_correctly.1).0)
-        .map_err(|e| {
-            ParserError {
-                reason: format!("{:?}", e),
-            }
-            .into()
+}
+
+// The table parsers fail unless the tables of a segment use up all of its data. Segments with other content are
+// kept as unknown markers instead of dropping what the table parsers didn't understand.
+fn huffman_tables(input: &[u8]) -> IResult<&[u8], Marker<'_>> {
+    let (input, _) = tag(b"\xff\xc4")(input)?;
+    let (input, length) = be_u16(input)?;
+    let (input, tables) = map_parser(
+        take(length.saturating_sub(2)),
+        terminated(many1(complete(huffman_table)), eof),
+    )(input)?;
+    Ok((input, Marker::DHT(tables)))
+}
+
+fn huffman_table(input: &[u8]) -> IResult<&[u8], HuffmanTable> {
+    let (input, class) = map_opt(nibbles, |selector: (u8, u8)| {
+        DHTType::from_selector(selector.0, selector.1)
+    })(input)?;
+    let (input, counts) = map(take(16usize), |counts: &[u8]| {
+        let mut fixed = [0u8; 16];
+        fixed.copy_from_slice(counts);
+        fixed
+    })(input)?;
+    let (input, values) =
+        count(be_u8, counts.iter().map(|&count| usize::from(count)).sum())(input)?;
+    Ok((
+        input,
+        HuffmanTable {
+            class,
+            counts,
+            values,
+        },
+    ))
+}
+
+fn quantization_tables(input: &[u8]) -> IResult<&[u8], Marker<'_>> {
+    let (input, _) = tag(b"\xff\xdb")(input)?;
+    let (input, length) = be_u16(input)?;
+    let (input, tables) = map_parser(
+        take(length.saturating_sub(2)),
+        terminated(many1(complete(quantization_table)), eof),
+    )(input)?;
+    Ok((input, Marker::DQT(tables)))
+}
+
+fn quantization_table(input: &[u8]) -> IResult<&[u8], QuantizationTable<'_>> {
+    let (input, precision_id) = verify(nibbles, |precision_id| precision_id.0 <= 1)(input)?;
+    let (input, data) = take(if precision_id.0 == 0 { 64usize } else { 128 })(input)?;
+    Ok((
+        input,
+        QuantizationTable {
+            id: precision_id.1.into(),
+            precision: precision_id.0.into(),
+            data,
+        },
+    ))
+}
+
+fn restart_interval(input: &[u8]) -> IResult<&[u8], Marker<'_>> {
+    let (input, _) = tag(b"\xff\xdd")(input)?;
+    let (input, _length) = be_u16(input)?;
+    let (input, interval) = be_u16(input)?;
+    Ok((input, Marker::DRI(interval)))
+}
+
+// some_marker doesn't accept SOS, scans always need to be parsed by start_of_stream
+fn some_marker(input: &[u8]) -> IResult<&[u8], Marker<'_>> {
+    let (input, _) = tag(b"\xff")(input)?;
+    let (input, tag) = verify(be_u8, |&tag| tag != 0xda)(input)?;
+    let (input, length) = verify(be_u16, |&length| length >= 2)(input)?;
+    let (input, data) = take(length - 2)(input)?;
+    Ok((
+        input,
+        Marker::Other(SomeMarker {
+            tag,
+            length: length - 2,
+            data,
+        }),
+    ))
+}
+
+fn soi(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    tag(b"\xff\xd8")(input)
+}
+
+fn eoi(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    tag(b"\xff\xd9")(input)
+}
+
+// segment parses the marker segments other than SOS
+fn segment(input: &[u8]) -> IResult<&[u8], Marker<'_>> {
+    alt((
+        complete(start_of_frame),
+        complete(huffman_tables),
+        complete(quantization_tables),
+        complete(restart_interval),
+        complete(some_marker),
+    ))(input)
+}
+
+fn scan_header(input: &[u8]) -> IResult<&[u8], StartOfStreamMetaData> {
+    let (input, _) = tag(b"\xff\xda")(input)?;
+    let (input, _length) = be_u16(input)?;
+    start_of_stream_metadata(input)
+}
+
+// parse_scan_header parses an SOS segment without the entropy coded data following it
+pub fn parse_scan_header(input: &[u8]) -> Result<StartOfStreamMetaData, ParserError> {
+    parse_scan_header_at(input, 0)
+}
+
+// parse_scan_header_at parses the SOS segment at offset in the file like parse_scan_header, the header has to
+// take exactly the bytes its length field announces
+pub(crate) fn parse_scan_header_at(
+    input: &[u8],
+    offset: usize,
+) -> Result<StartOfStreamMetaData, ParserError> {
+    let header = announced_segment(input, offset)?;
+    match scan_header(header) {
+        Ok((&[], metadata)) => Ok(metadata),
+        Ok(_) | Err(nom::Err::Incomplete(_)) => Err(ParserError::InvalidMarkerLength { offset }),
+        Err(e) => Err(ParserError::from_nom(header, e)),
+    }
+}
+
+// parse_segment parses a single complete marker segment other than SOS
+pub fn parse_segment(input: &[u8]) -> Result<Marker<'_>, ParserError> {
+    parse_segment_at(input, 0)
+}
+
+// parse_segment_at parses the segment at offset in the file like parse_segment. Frame headers and restart
+// intervals have to take exactly the bytes their length field announces, other segments hold their tables or
+// are kept as unknown markers.
+pub(crate) fn parse_segment_at(input: &[u8], offset: usize) -> Result<Marker<'_>, ParserError> {
+    if input.starts_with(b"\xff\xda") {
+        return Err(ParserError::UnexpectedScan);
+    }
+    let input = announced_segment(input, offset)?;
+
+    let marker = match segment(input) {
+        Ok((rest, _)) if !rest.is_empty() => {
+            return Err(ParserError::InvalidMarkerLength { offset });
+        }
+        Ok((_, Marker::Other(ref marker)))
+            if is_start_of_frame(marker.tag) || marker.tag == 0xdd =>
+        {
+            return Err(ParserError::InvalidMarkerLength { offset });
+        }
+        Ok((_, marker)) => marker,
+        Err(e) => return Err(ParserError::from_nom(input, e)),
+    };
+    check_segment(&marker)?;
+    Ok(marker)
+}
+
+// check_length rejects segments at the start of input whose length field is too short to include itself, the
+// segment being at offset in the file
+pub(crate) fn check_length(input: &[u8], offset: usize) -> Result<(), ParserError> {
+    match *input {
+        [0xff, _, high, low, ..] if u16::from_be_bytes([high, low]) < 2 => {
+            Err(ParserError::InvalidMarkerLength { offset })
+        }
+        _ => Ok(()),
+    }
+}
+
+// announced_segment returns the bytes of the segment at the start of input up to the end its length field
+// announces, the segment being at offset in the file
+fn announced_segment(input: &[u8], offset: usize) -> Result<&[u8], ParserError> {
+    check_length(input, offset)?;
+    match *input {
+        [0xff, _, high, low, ..] => {
+            let length = 2 + usize::from(u16::from_be_bytes([high, low]));
+            input
+                .get(..length)
+                .ok_or_else(|| ParserError::truncated(input))
+        }
+        [0xff, ..] | [] => Err(ParserError::UnexpectedEof),
+        [_, ..] => Err(ParserError::InvalidSegment {
+            marker: input.get(1).cloned().unwrap_or(0),
+        }),
+    }
+}
+
+// parse_marker parses the segment at the start of input, which is at offset in the file, along with the entropy
+// coded data following an SOS header, and returns the input following it. The slice and stream decoders both
+// parse segments through it. Entropy coded data running up to the end of the input is accepted as a truncated
+// scan with allow_truncated_scan.
+pub(crate) fn parse_marker(
+    input: &[u8],
+    offset: usize,
+    allow_truncated_scan: bool,
+) -> Result<(&[u8], Marker<'_>), ParserError> {
+    let segment = announced_segment(input, offset)?;
+    let data = &input[segment.len()..];
+    if segment[1] != 0xda {
+        return Ok((data, parse_segment_at(segment, offset)?));
+    }
+
+    let metadata = parse_scan_header_at(segment, offset)?;
+    match entropy_coded_segment(data) {
+        Ok((rest, data)) => Ok((rest, Marker::Image(ImageStream { metadata, data }))),
+        Err(_) if allow_truncated_scan => Ok((&[], Marker::Image(ImageStream { metadata, data }))),
+        Err(_) => Err(ParserError::UnexpectedEof),
+    }
+}
+
+// check_segment checks the content of a parsed segment beyond its syntax, so tables that can't be used fail when
+// they are parsed instead of decoding garbage later
+fn check_segment(marker: &Marker) -> Result<(), ParserError> {
+    match *marker {
+        Marker::DHT(ref tables) => tables.iter().try_for_each(HuffmanTable::check),
+        _ => Ok(()),
+    }
+}
+
+// HeaderSegment is a marker segment in front of the first scan, offset is the absolute position of its marker
+#[derive(Debug, Eq, PartialEq)]
+pub struct HeaderSegment<'a> {
+    pub offset: usize,
+    pub tag: u8,
+    pub data: &'a [u8],
+}
+
+// header_segments walks the segments between SOI and the first scan without parsing their content, which is
+// enough for metadata lookups that don't need the image itself
+pub fn header_segments(jpeg_file: &[u8]) -> Result<Vec<HeaderSegment<'_>>, ParserError> {
+    if !jpeg_file.starts_with(b"\xff\xd8") {
+        return Err(ParserError::MissingStartOfImage);
+    }
+
+    let mut segments = Vec::new();
+    let mut cursor = 2usize;
+
+    while cursor + 4 <= jpeg_file.len() {
+        cursor += fill_bytes(&jpeg_file[cursor..]);
+        if cursor + 4 > jpeg_file.len() {
+            break;
+        }
+        if jpeg_file[cursor] != 0xff {
+            return Err(ParserError::InvalidMarker {
+                found: jpeg_file[cursor],
+            });
+        }
+
+        let tag = jpeg_file[cursor + 1];
+        if tag == 0xda || tag == 0xd9 {
+            break;
+        }
+
+        check_length(&jpeg_file[cursor..], cursor)?;
+        let length = u16::from_be_bytes([jpeg_file[cursor + 2], jpeg_file[cursor + 3]]) as usize;
+        let data = jpeg_file
+            .get(cursor + 4..cursor + 2 + length)
+            .ok_or(ParserError::InvalidMarkerLength { offset: cursor })?;
+
+        segments.push(HeaderSegment {
+            offset: cursor,
+            tag,
+            data,
+        });
+        cursor += 2 + length;
+    }
+
+    Ok(segments)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    // skip_leading_garbage scans forward for the first SOI instead of requiring it at the start of the input
+    pub skip_leading_garbage: bool,
+    // ignore_trailing_data stops at the first byte sequence that isn't a valid segment and returns the markers
+    // parsed so far instead of failing. Data following EOI is always ignored.
+    pub ignore_trailing_data: bool,
+    // default_huffman_tables inserts the standard Annex K tables in front of scans that reference Huffman tables
+    // which were never defined, as is common for MJPEG frames
+    pub default_huffman_tables: bool,
+    // allow_truncated_scan accepts a final scan that is cut off by the end of the input instead of failing
+    pub allow_truncated_scan: bool,
+    // resynchronize skips bytes that don't form a segment, like the rest of a scan cut short by a corrupt 0xFF
+    // byte, up to the next marker that starts a valid segment or ends the image
+    pub resynchronize: bool,
+}
+
+// Span is the location of a segment in the parsed file, from its marker up to the last byte of its data
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Span {
+    pub offset: usize,
+    pub length: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SpannedMarker<'a> {
+    pub span: Span,
+    pub marker: Marker<'a>,
+}
+
+impl Span {
+    pub fn end(&self) -> usize {
+        self.offset + self.length
+    }
+
+    // slice returns the bytes of the segment from the buffer the span was parsed from
+    pub fn slice<'a>(&self, jpeg_file: &'a [u8]) -> &'a [u8] {
+        &jpeg_file[self.offset..self.end()]
+    }
+}
+
+pub fn decode(jpeg_file: &[u8]) -> Result<Vec<Marker<'_>>, Error> {
+    decode_with_options(jpeg_file, &ParseOptions::default())
+}
+
+pub fn decode_with_options<'a>(
+    jpeg_file: &'a [u8],
+    options: &ParseOptions,
+) -> Result<Vec<Marker<'a>>, Error> {
+    decode_spanned(jpeg_file, options)
+        .map(|markers| markers.into_iter().map(|spanned| spanned.marker).collect())
+}
+
+// decode_spanned works like decode_with_options, but also returns the location of every marker in jpeg_file.
+// Huffman tables inserted by default_huffman_tables have an empty span in front of the scan using them.
+pub fn decode_spanned<'a>(
+    jpeg_file: &'a [u8],
+    options: &ParseOptions,
+) -> Result<Vec<SpannedMarker<'a>>, Error> {
+    let mut iter = MarkerIter::new(jpeg_file, options).map_err(|e| e.at(Stage::Parse, None, 0))?;
+    let mut markers = Vec::new();
+    while let Some(result) = iter.next() {
+        markers.push(result.map_err(|e| iter.locate(e))?);
+    }
+    Ok(markers)
+}
+
+// MarkerIter yields the markers of jpeg_file one at a time, parsing each segment only when it is requested.
+// Callers that stop before the first scan never look at the entropy coded data.
+pub struct MarkerIter<'a> {
+    input: &'a [u8],
+    offset: usize,
+    options: ParseOptions,
+    defined_huffman_tables: BTreeSet<DHTType>,
+    pending: Option<SpannedMarker<'a>>,
+    finished: bool,
+}
+
+impl<'a> MarkerIter<'a> {
+    // new checks for the start of image marker, the remaining segments are parsed by next
+    pub fn new(jpeg_file: &'a [u8], options: &ParseOptions) -> Result<MarkerIter<'a>, ParserError> {
+        let input = if options.skip_leading_garbage {
+            find_soi(jpeg_file)?
+        } else {
+            jpeg_file
+        };
+
+        let (rest, _) = soi(input).map_err(|_| ParserError::MissingStartOfImage)?;
+
+        Ok(MarkerIter {
+            input: rest,
+            offset: jpeg_file.len() - rest.len(),
+            options: *options,
+            defined_huffman_tables: BTreeSet::new(),
+            pending: None,
+            finished: false,
         })
+    }
+
+    // locate adds the offset and marker of the segment that failed to parse to error
+    pub(crate) fn locate(&self, error: ParserError) -> Error {
+        let marker = match self.input {
+            [0xff, tag, ..] => Some(*tag),
+            _ => None,
+        };
+        error.at(Stage::Parse, marker, self.offset)
+    }
+
+    fn next_segment(&mut self) -> Option<Result<SpannedMarker<'a>, ParserError>> {
+        loop {
+            let fill = fill_bytes(self.input);
+            self.offset += fill;
+            self.input = &self.input[fill..];
+            if self.finished || eoi(self.input).is_ok() {
+                self.finished = true;
+                return None;
+            }
+
+            let error =
+                match parse_marker(self.input, self.offset, self.options.allow_truncated_scan) {
+                    Ok((rest, marker))
+                        if !self.options.resynchronize || plausible_marker(self.input[1]) =>
+                    {
+                        let length = self.input.len() - rest.len();
+                        let span = Span {
+                            offset: self.offset,
+                            length,
+                        };
+                        if rest.is_empty() {
+                            // only scans accepted as truncated run up to the end of the input
+                            self.finished = true;
+                            trace_event!(debug, offset = span.offset, "accepted truncated scan");
+                        }
+                        trace_event!(
+                            trace,
+                            offset = span.offset,
+                            length,
+                            tag = self.input[1],
+                            "parsed segment"
+                        );
+                        self.offset += length;
+                        self.input = rest;
+                        return Some(Ok(SpannedMarker { span, marker }));
+                    }
+                    Ok(_) => ParserError::InvalidSegment {
+                        marker: self.input[1],
+                    },
+                    Err(error) => error,
+                };
+
+            if self.options.resynchronize {
+                if let Some(skipped) = next_plausible_segment(self.input) {
+                    trace_event!(
+                        debug,
+                        offset = self.offset,
+                        skipped,
+                        "skipped to the next plausible segment"
+                    );
+                    self.offset += skipped;
+                    self.input = &self.input[skipped..];
+                    continue;
+                }
+            }
+
+            self.finished = true;
+            trace_event!(debug, offset = self.offset, error = ?error, "failed to parse segment");
+            if self.options.ignore_trailing_data {
+                return None;
+            }
+            return Some(Err(error));
+        }
+    }
+
+    // missing_huffman_tables records the tables defined so far and returns the standard tables for a scan
+    // referencing tables that were never defined
+    fn missing_huffman_tables(&mut self, spanned: &SpannedMarker) -> Option<SpannedMarker<'a>> {
+        match spanned.marker {
+            Marker::DHT(ref tables) => {
+                self.defined_huffman_tables
+                    .extend(tables.iter().map(|table| table.class));
+                None
+            }
+            Marker::Image(ref image) => {
+                let missing: Vec<HuffmanTable> = referenced_huffman_tables(&image.metadata)
+                    .into_iter()
+                    .filter(|class| !self.defined_huffman_tables.contains(class))
+                    .map(HuffmanTable::standard)
+                    .collect();
+
+                if missing.is_empty() {
+                    return None;
+                }
+
+                self.defined_huffman_tables
+                    .extend(missing.iter().map(|table| table.class));
+                Some(SpannedMarker {
+                    span: Span {
+                        offset: spanned.span.offset,
+                        length: 0,
+                    },
+                    marker: Marker::DHT(missing),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for MarkerIter<'a> {
+    type Item = Result<SpannedMarker<'a>, ParserError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(spanned) = self.pending.take() {
+            return Some(Ok(spanned));
+        }
+
+        let spanned = match self.next_segment()? {
+            Ok(spanned) => spanned,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if self.options.default_huffman_tables {
+            if let Some(tables) = self.missing_huffman_tables(&spanned) {
+                self.pending = Some(spanned);
+                return Some(Ok(tables));
+            }
+        }
+
+        Some(Ok(spanned))
+    }
+}
+
+// plausible_marker tells the markers found in JPEG files from byte values a corrupt scan produces
+fn plausible_marker(tag: u8) -> bool {
+    matches!(tag, 0xc0..=0xcf | 0xd9..=0xdf | 0xe0..=0xef | 0xfe)
+}
+
+// next_plausible_segment returns the offset of the first marker after the start of input that begins a valid
+// segment or is EOI
+fn next_plausible_segment(input: &[u8]) -> Option<usize> {
+    (1..input.len().saturating_sub(1)).find(|&i| {
+        let rest = &input[i..];
+        rest[0] == 0xff
+            && plausible_marker(rest[1])
+            && (eoi(rest).is_ok() || parse_marker(rest, 0, false).is_ok())
+    })
+}
+
+// StreamParser parses a file fed in arbitrarily sized chunks. Incomplete segments are kept until the chunks
+// completing them arrive, so every marker is returned exactly once as soon as it is complete.
+#[derive(Debug, Default)]
+pub struct StreamParser {
+    buffer: Vec<u8>,
+    started: bool,
+    finished: bool,
+    // searched is the number of bytes known not to contain the end of the scan at the start of buffer
+    searched: usize,
+    // offset is the position of the start of buffer in the file
+    offset: usize,
+}
+
+impl StreamParser {
+    pub fn new() -> StreamParser {
+        StreamParser::default()
+    }
+
+    // feed appends chunk to the parsed data and returns all markers it completed. Data following EOI is ignored.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<MarkerOwned>, ParserError> {
+        let mut markers = Vec::new();
+        if self.finished {
+            return Ok(markers);
+        }
+        self.buffer.extend_from_slice(chunk);
+
+        if !self.started {
+            if self.buffer.len() < 2 {
+                return Ok(markers);
+            }
+            soi(&self.buffer).map_err(|_| ParserError::MissingStartOfImage)?;
+            self.buffer.drain(..2);
+            self.offset = 2;
+            self.started = true;
+        }
+
+        while let Some(length) = self.complete_segment()? {
+            if eoi(&self.buffer).is_ok() {
+                self.finished = true;
+                self.buffer.clear();
+                break;
+            }
+
+            let consumed = {
+                let (rest, marker) = parse_marker(&self.buffer[..length], self.offset, false)?;
+                trace_event!(
+                    trace,
+                    length,
+                    tag = self.buffer[1],
+                    "parsed streamed segment"
+                );
+                markers.push(marker.to_owned());
+                length - rest.len()
+            };
+            self.buffer.drain(..consumed);
+            self.offset += consumed;
+        }
+
+        Ok(markers)
+    }
+
+    // needed returns the number of bytes feed needs at least to complete the next segment. Once the length field
+    // of a segment arrived, that is exactly the rest of it. The end of a scan is only known once the marker
+    // following it arrives, so needed returns None for scans and after EOI.
+    pub fn needed(&self) -> Option<usize> {
+        if self.finished {
+            return None;
+        }
+        let start = if self.started { 0 } else { 2 };
+        match self.buffer.len() {
+            length if length < start + 4 => Some(start + 4 - length),
+            _ => match ParserError::truncated(&self.buffer[start..]) {
+                ParserError::TruncatedSegment { missing, .. } => Some(missing),
+                _ => None,
+            },
+        }
+    }
+
+    // is_finished is true once EOI has been parsed
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // complete_segment returns the number of bytes the parser needs to see the next segment completely. Scans
+    // include the marker following them, which terminates the entropy coded data.
+    fn complete_segment(&mut self) -> Result<Option<usize>, ParserError> {
+        let fill = fill_bytes(&self.buffer);
+        self.buffer.drain(..fill);
+        self.offset += fill;
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+        if self.buffer[0] != 0xff {
+            return Err(ParserError::InvalidMarker {
+                found: self.buffer[0],
+            });
+        }
+        if self.buffer[1] == 0xd9 {
+            return Ok(Some(2));
+        }
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+        check_length(&self.buffer, self.offset)?;
+
+        let length = 2 + usize::from(u16::from_be_bytes([self.buffer[2], self.buffer[3]]));
+        if self.buffer.len() < length {
+            return Ok(None);
+        }
+        if self.buffer[1] != 0xda {
+            return Ok(Some(length));
+        }
+
+        let mut cursor = length.max(self.searched);
+        while cursor + 1 < self.buffer.len() {
+            if self.buffer[cursor] != 0xff {
+                cursor += 1;
+                continue;
+            }
+            match self.buffer[cursor + 1] {
+                0x00 | 0xd0..=0xd7 => cursor += 2,
+                // fill bytes in front of the marker ending the scan
+                0xff => cursor += 1,
+                _ => {
+                    self.searched = 0;
+                    return Ok(Some(cursor + 2));
+                }
+            }
+        }
+
+        self.searched = cursor;
+        Ok(None)
+    }
+}
+
+// referenced_huffman_tables lists the huffman tables a scan needs for decoding
+pub fn referenced_huffman_tables(metadata: &StartOfStreamMetaData) -> Vec<DHTType> {
+    let mut classes = Vec::new();
+
+    for component in &metadata.component_metadata {
+        let mut selectors = Vec::new();
+        // DC-only progressive scans don't use AC tables and AC scans don't use DC tables
+        if metadata.spectral_start == 0 {
+            selectors.push((0, component.dc_table as u8));
+        }
+        if metadata.spectral_end > 0 {
+            selectors.push((1, component.ac_table as u8));
+        }
+
+        for (class, id) in selectors {
+            if let Some(class) = DHTType::from_selector(class, id) {
+                if !classes.contains(&class) {
+                    classes.push(class);
+                }
+            }
+        }
+    }
+
+    classes
+}
+
+fn find_soi(input: &[u8]) -> Result<&[u8], ParserError> {
+    input
+        .windows(3)
+        .position(|window| window == b"\xff\xd8\xff")
+        .map(|position| &input[position..])
+        .ok_or(ParserError::MissingStartOfImage)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use errors::ErrorContext;
+    #[test]
+    fn invalid_huffman_table_test() {
+        // a code of every length and a second one of 16 bits, which consists of one bits only, are rejected when
+        // they are parsed
+        let mut segment = vec![0xFF, 0xC4, 0x00, 0x24, 0x11];
+        segment.extend_from_slice(&[1; 15]);
+        segment.push(2);
+        segment.extend(0..17);
+        let error = ParserError::AllOnesHuffmanCode {
+            class: DHTType::ChrominanceAC,
+        };
+        assert_eq!(parse_segment(&segment), Err(error.clone()));
+
+        let mut file = b"\xff\xd8".to_vec();
+        file.extend_from_slice(&segment);
+        let decoded = decode(&file).unwrap_err();
+        assert!(matches!(
+            decoded.parser_error(),
+            Some(ParserError::AllOnesHuffmanCode { .. })
+        ));
+        assert_eq!(
+            decoded.context(),
+            Some(ErrorContext {
+                stage: Stage::Parse,
+                marker: Some(0xc4),
+                offset: 2,
+            })
+        );
+        let mut parser = StreamParser::new();
+        assert_eq!(parser.feed(&file), Err(error));
+    }
+
     #[test]
     fn huffman_test() {
         let huffman_table_sample = vec![
@@ -185,25 +1114,11 @@ mod tests {
                 vec![].as_slice(),
                 Marker::DHT(vec![HuffmanTable {
                     class: DHTType::LuminanceDC,
-                    symbols: [
-                        vec![],
-                        vec![],
-                        vec![0x04, 0x05, 0x03, 0x02, 0x06, 0x01, 0x00],
-                        vec![0x07],
-                        vec![0x08],
-                        vec![0x09],
-                        vec![0x0A],
-                        vec![0x0B],
-                        vec![],
-                        vec![],
-                        vec![],
-                        vec![],
-                        vec![],
-                        vec![],
-                        vec![],
-                        vec![],
+                    counts: [0, 0, 7, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+                    values: vec![
+                        0x04, 0x05, 0x03, 0x02, 0x06, 0x01, 0x00, 0x07, 0x08, 0x09, 0x0A, 0x0B
                     ],
-                },])
+                }])
             ))
         )
     }
@@ -224,8 +1139,9 @@ mod tests {
         assert_eq!(
             decode(&minimal_jpeg[..]).unwrap(),
             vec![
-                Marker::DQT(QuantizationTable {
+                Marker::DQT(vec![QuantizationTable {
                     id: 0,
+                    precision: 0,
                     data: &[
                         0x03, 0x02, 0x02, 0x02, 0x02, 0x02, 0x03, 0x02, 0x02, 0x02, 0x03, 0x03,
                         0x03, 0x03, 0x04, 0x06, 0x04, 0x04, 0x04, 0x04, 0x04, 0x08, 0x06, 0x06,
@@ -234,11 +1150,17 @@ mod tests {
                         0x0F, 0x10, 0x10, 0x11, 0x10, 0x0A, 0x0C, 0x12, 0x13, 0x12, 0x10, 0x13,
                         0x0F, 0x10, 0x10, 0x10
                     ],
-                }),
-                Marker::Other(SomeMarker {
-                    tag: 0xc9,
-                    length: 0x9,
-                    data: &[0x08, 0x00, 0x01, 0x00, 0x01, 0x01, 0x01, 0x11, 0x00],
+                }]),
+                Marker::SOF(StartOfFrameMetaData {
+                    marker: 0xc9,
+                    precision: 8,
+                    height: 1,
+                    width: 1,
+                    component_metadata: vec![ColorComponentMetaData {
+                        id: 1,
+                        sampling_resolution: 0x11,
+                        quantization_table: 0,
+                    }],
                 }),
                 Marker::Other(SomeMarker {
                     tag: 0xcc,
@@ -247,14 +1169,658 @@ mod tests {
                 }),
                 Marker::Image(ImageStream {
                     metadata: StartOfStreamMetaData {
-                        precision: 1,
-                        height: 0x100,
-                        width: 0x3F,
-                        component_metadata: vec![]
+                        component_metadata: vec![ScanComponentMetaData {
+                            id: 1,
+                            dc_table: 0,
+                            ac_table: 0,
+                        }],
+                        spectral_start: 0,
+                        spectral_end: 0x3F,
+                        approximation_high: 0,
+                        approximation_low: 0,
                     },
                     data: &[0xD2, 0xCF, 0x20],
                 })
             ]
         );
     }
+
+    #[test]
+    fn entropy_coded_segment_test() {
+        let scan = vec![0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD9];
+        assert_eq!(
+            entropy_coded_segment(&scan),
+            Ok((&[0xFF, 0xD9][..], &[0x12, 0xFF, 0x00, 0x34][..]))
+        );
+    }
+
+    #[test]
+    fn entropy_coded_segment_restart_markers_test() {
+        let scan = vec![0x12, 0xFF, 0xD0, 0x34, 0xFF, 0xD7, 0x56, 0xFF, 0xC4];
+        assert_eq!(
+            entropy_coded_segment(&scan),
+            Ok((
+                &[0xFF, 0xC4][..],
+                &[0x12, 0xFF, 0xD0, 0x34, 0xFF, 0xD7, 0x56][..]
+            ))
+        );
+    }
+
+    #[test]
+    fn entropy_coded_segment_incomplete_test() {
+        let scan = vec![0x12, 0xFF, 0x00, 0x34];
+        assert_eq!(
+            entropy_coded_segment(&scan),
+            Err(nom::Err::Incomplete(Needed::Unknown))
+        );
+    }
+
+    #[test]
+    fn multi_scan_test() {
+        let jpeg_data = vec![
+            0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, 0xD2, 0xFF,
+            0xFE, 0x00, 0x03, 0x41, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00,
+            0x20, 0xFF, 0x00, 0xFF, 0xD9,
+        ];
+
+        let metadata = || StartOfStreamMetaData {
+            component_metadata: vec![ScanComponentMetaData {
+                id: 1,
+                dc_table: 0,
+                ac_table: 0,
+            }],
+            spectral_start: 0,
+            spectral_end: 0x3F,
+            approximation_high: 0,
+            approximation_low: 0,
+        };
+
+        assert_eq!(
+            decode(&jpeg_data[..]).unwrap(),
+            vec![
+                Marker::Image(ImageStream {
+                    metadata: metadata(),
+                    data: &[0xD2],
+                }),
+                Marker::Other(SomeMarker {
+                    tag: 0xfe,
+                    length: 0x1,
+                    data: &[0x41],
+                }),
+                Marker::Image(ImageStream {
+                    metadata: metadata(),
+                    data: &[0x20, 0xFF, 0x00],
+                }),
+            ]
+        );
+    }
+
+    fn comment_jpeg() -> Vec<u8> {
+        vec![0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x03, 0x41, 0xFF, 0xD9]
+    }
+
+    fn comment_marker() -> Marker<'static> {
+        Marker::Other(SomeMarker {
+            tag: 0xfe,
+            length: 0x1,
+            data: &[0x41],
+        })
+    }
+
+    #[test]
+    fn leading_garbage_test() {
+        let mut jpeg_data = vec![0x00, 0xFF, 0xD8, 0x12];
+        jpeg_data.extend(comment_jpeg());
+
+        assert!(decode(&jpeg_data[..]).is_err());
+
+        let options = ParseOptions {
+            skip_leading_garbage: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_with_options(&jpeg_data[..], &options).unwrap(),
+            vec![comment_marker()]
+        );
+    }
+
+    #[test]
+    fn missing_soi_test() {
+        let options = ParseOptions {
+            skip_leading_garbage: true,
+            ..Default::default()
+        };
+        assert!(decode_with_options(&[0x00, 0x01, 0x02], &options).is_err());
+    }
+
+    #[test]
+    fn trailing_data_after_eoi_test() {
+        let mut jpeg_data = comment_jpeg();
+        jpeg_data.extend(&[0x00, 0x01, 0x02]);
+
+        assert_eq!(decode(&jpeg_data[..]).unwrap(), vec![comment_marker()]);
+    }
+
+    #[test]
+    fn trailing_garbage_without_eoi_test() {
+        let mut jpeg_data = comment_jpeg();
+        jpeg_data.truncate(7);
+        jpeg_data.extend(&[0x00, 0x01, 0x02]);
+
+        assert!(decode(&jpeg_data[..]).is_err());
+
+        let options = ParseOptions {
+            ignore_trailing_data: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_with_options(&jpeg_data[..], &options).unwrap(),
+            vec![comment_marker()]
+        );
+    }
+
+    #[test]
+    fn resynchronize_test() {
+        // garbage and a segment with a marker no file uses in front of the comment
+        let mut jpeg_data = vec![0xFF, 0xD8, 0x12, 0xFF, 0x34, 0xFF, 0x45, 0x00, 0x02];
+        jpeg_data.extend(&comment_jpeg()[2..]);
+
+        assert!(decode(&jpeg_data[..]).is_err());
+
+        let options = ParseOptions {
+            resynchronize: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_with_options(&jpeg_data[..], &options).unwrap(),
+            vec![comment_marker()]
+        );
+        let spanned = decode_spanned(&jpeg_data[..], &options).unwrap();
+        assert_eq!(spanned[0].span.offset, 9);
+
+        // without a valid segment following the garbage, trailing data rules apply
+        let garbage = [0xFF, 0xD8, 0x12, 0xFF, 0x34];
+        assert!(decode_with_options(&garbage[..], &options).is_err());
+        let options = ParseOptions {
+            ignore_trailing_data: true,
+            ..options
+        };
+        assert_eq!(decode_with_options(&garbage[..], &options).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn default_huffman_tables_test() {
+        let jpeg_data = vec![
+            0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x0A, 0x02, 0x01, 0x00, 0x02, 0x11, 0x00, 0x3F, 0x00,
+            0xD2, 0xFF, 0xD9,
+        ];
+
+        assert_eq!(decode(&jpeg_data[..]).unwrap().len(), 1);
+
+        let options = ParseOptions {
+            default_huffman_tables: true,
+            ..Default::default()
+        };
+        let markers = decode_with_options(&jpeg_data[..], &options).unwrap();
+
+        assert_eq!(markers.len(), 2);
+        assert_eq!(
+            markers[0],
+            Marker::DHT(vec![
+                HuffmanTable::standard(DHTType::LuminanceDC),
+                HuffmanTable::standard(DHTType::LuminanceAC),
+                HuffmanTable::standard(DHTType::ChrominanceDC),
+                HuffmanTable::standard(DHTType::ChrominanceAC),
+            ])
+        );
+    }
+
+    #[test]
+    fn default_huffman_tables_only_when_missing_test() {
+        let jpeg_data = vec![
+            0xFF, 0xD8, 0xFF, 0xC4, 0x00, 0x1F, 0x00, 0x00, 0x00, 0x07, 0x01, 0x01, 0x01, 0x01,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x05, 0x03, 0x02, 0x06,
+            0x01, 0x00, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00,
+            0x00, 0x3F, 0x00, 0xD2, 0xFF, 0xD9,
+        ];
+
+        let options = ParseOptions {
+            default_huffman_tables: true,
+            ..Default::default()
+        };
+        let markers = decode_with_options(&jpeg_data[..], &options).unwrap();
+
+        assert_eq!(markers.len(), 3);
+        assert_eq!(
+            markers[1],
+            Marker::DHT(vec![HuffmanTable::standard(DHTType::LuminanceAC)])
+        );
+    }
+
+    #[test]
+    fn header_segments_test() {
+        let jpeg_data = vec![
+            0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x03, 0x41, 0xFF, 0xE1, 0x00, 0x02, 0xFF, 0xDA, 0x00,
+            0x08,
+        ];
+
+        assert_eq!(
+            header_segments(&jpeg_data).unwrap(),
+            vec![
+                HeaderSegment {
+                    offset: 2,
+                    tag: 0xfe,
+                    data: &[0x41],
+                },
+                HeaderSegment {
+                    offset: 7,
+                    tag: 0xe1,
+                    data: &[],
+                },
+            ]
+        );
+
+        assert!(header_segments(&jpeg_data[..6]).is_err());
+        assert!(header_segments(&jpeg_data[2..]).is_err());
+        // a length below 2 doesn't even cover the length field
+        let mut short = jpeg_data.clone();
+        short[10] = 0x01;
+        assert_eq!(
+            header_segments(&short),
+            Err(ParserError::InvalidMarkerLength { offset: 7 })
+        );
+    }
+
+    #[test]
+    fn quantization_tables_test() {
+        let mut segment = vec![0xFF, 0xDB, 0x00, 0xC4, 0x00];
+        segment.extend(vec![0x01; 64]);
+        segment.push(0x11);
+        segment.extend(vec![0x00; 128]);
+
+        assert_eq!(
+            quantization_tables(&segment),
+            Ok((
+                &[][..],
+                Marker::DQT(vec![
+                    QuantizationTable {
+                        id: 0,
+                        precision: 0,
+                        data: &[0x01; 64],
+                    },
+                    QuantizationTable {
+                        id: 1,
+                        precision: 1,
+                        data: &[0x00; 128],
+                    },
+                ])
+            ))
+        );
+    }
+
+    #[test]
+    fn unparsed_table_data_test() {
+        // a DHT segment with a table for destination 4 after a baseline table, and a DQT segment with precision 2
+        let mut dht = vec![0xFF, 0xC4, 0x00, 0x26, 0x00, 0x01];
+        dht.extend(vec![0x00; 15]);
+        dht.extend(&[0x07, 0x04, 0x01]);
+        dht.extend(vec![0x00; 15]);
+        dht.push(0x07);
+        let mut dqt = vec![0xFF, 0xDB, 0x00, 0x83, 0x20];
+        dqt.extend(vec![0x01; 128]);
+
+        // both are kept as unknown markers instead of losing the tables the parsers don't understand
+        for segment in &[dht, dqt] {
+            assert_eq!(
+                parse_segment(segment),
+                Ok(Marker::Other(SomeMarker {
+                    tag: segment[1],
+                    length: segment.len() as u16 - 4,
+                    data: &segment[4..],
+                }))
+            );
+        }
+    }
+
+    #[test]
+    fn restart_interval_test() {
+        assert_eq!(
+            restart_interval(&[0xFF, 0xDD, 0x00, 0x04, 0x01, 0x02]),
+            Ok((&[][..], Marker::DRI(0x102)))
+        );
+    }
+
+    #[test]
+    fn truncated_scan_test() {
+        let jpeg_data = vec![
+            0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, 0xD2, 0xFF,
+            0x00, 0x12,
+        ];
+
+        assert!(decode(&jpeg_data[..]).is_err());
+
+        let options = ParseOptions {
+            allow_truncated_scan: true,
+            ..Default::default()
+        };
+        match decode_with_options(&jpeg_data[..], &options).unwrap()[..] {
+            [Marker::Image(ref image)] => assert_eq!(image.data, &[0xD2, 0xFF, 0x00, 0x12]),
+            ref markers => panic!("unexpected markers {:?}", markers),
+        }
+    }
+
+    #[test]
+    fn decode_spanned_test() {
+        let mut jpeg_data = vec![0x00, 0x00];
+        jpeg_data.extend(comment_jpeg());
+        jpeg_data.truncate(jpeg_data.len() - 2);
+        jpeg_data.extend(&[
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, 0xD2, 0xFF, 0xD9,
+        ]);
+
+        let options = ParseOptions {
+            skip_leading_garbage: true,
+            default_huffman_tables: true,
+            ..Default::default()
+        };
+        let markers = decode_spanned(&jpeg_data, &options).unwrap();
+        let spans: Vec<Span> = markers.iter().map(|spanned| spanned.span).collect();
+
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    offset: 4,
+                    length: 5,
+                },
+                Span {
+                    offset: 9,
+                    length: 0,
+                },
+                Span {
+                    offset: 9,
+                    length: 11,
+                },
+            ]
+        );
+        assert_eq!(markers[0].marker, comment_marker());
+        assert_eq!(spans[0].slice(&jpeg_data), &[0xFF, 0xFE, 0x00, 0x03, 0x41]);
+        assert_eq!(spans[2].end(), jpeg_data.len() - 2);
+    }
+
+    #[test]
+    fn marker_iter_test() {
+        let mut jpeg_data = comment_jpeg();
+        jpeg_data.truncate(jpeg_data.len() - 2);
+        // an unterminated scan would fail to parse if the iterator ever got to it
+        jpeg_data.extend(&[
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, 0xD2,
+        ]);
+
+        let mut markers = MarkerIter::new(&jpeg_data, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            markers.next(),
+            Some(Ok(SpannedMarker {
+                span: Span {
+                    offset: 2,
+                    length: 5,
+                },
+                marker: comment_marker(),
+            }))
+        );
+        assert_eq!(markers.next(), Some(Err(ParserError::UnexpectedEof)));
+        assert_eq!(markers.next(), None);
+
+        assert_eq!(
+            MarkerIter::new(&jpeg_data[2..], &ParseOptions::default()).err(),
+            Some(ParserError::MissingStartOfImage)
+        );
+    }
+
+    #[test]
+    fn marker_iter_eoi_test() {
+        let jpeg_data = comment_jpeg();
+        let markers: Vec<Marker> = MarkerIter::new(&jpeg_data, &ParseOptions::default())
+            .unwrap()
+            .map(|spanned| spanned.unwrap().marker)
+            .collect();
+
+        assert_eq!(markers, vec![comment_marker()]);
+    }
+
+    #[test]
+    fn to_owned_test() {
+        let owned = {
+            let jpeg_data = comment_jpeg();
+            let markers = decode(&jpeg_data).unwrap();
+            markers.iter().map(Marker::to_owned).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            owned,
+            vec![MarkerOwned::Other(SomeMarkerOwned {
+                tag: 0xfe,
+                length: 0x1,
+                data: vec![0x41],
+            })]
+        );
+
+        let table = QuantizationTable {
+            id: 1,
+            precision: 0,
+            data: &[1; 64],
+        };
+        assert_eq!(
+            Marker::DQT(vec![table]).to_owned(),
+            MarkerOwned::DQT(vec![QuantizationTableOwned {
+                id: 1,
+                precision: 0,
+                data: vec![1; 64],
+            }])
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_test() {
+        fn is_serialize<T: ::serde::Serialize>() {}
+
+        is_serialize::<SpannedMarker>();
+        is_serialize::<MarkerOwned>();
+    }
+
+    #[test]
+    fn subsampling_mode_test() {
+        let frame = |sampling: &[u64]| StartOfFrameMetaData {
+            marker: 0xc0,
+            precision: 8,
+            height: 16,
+            width: 16,
+            component_metadata: sampling
+                .iter()
+                .enumerate()
+                .map(|(id, &sampling_resolution)| ColorComponentMetaData {
+                    id: id as u64 + 1,
+                    sampling_resolution,
+                    quantization_table: 0,
+                })
+                .collect(),
+        };
+
+        assert_eq!(frame(&[0x11]).subsampling_mode(), SubsamplingMode::Gray);
+        assert_eq!(
+            frame(&[0x11, 0x11, 0x11]).subsampling_mode(),
+            SubsamplingMode::Chroma444
+        );
+        assert_eq!(
+            frame(&[0x22, 0x22, 0x22]).subsampling_mode(),
+            SubsamplingMode::Chroma444
+        );
+        assert_eq!(
+            frame(&[0x21, 0x11, 0x11]).subsampling_mode(),
+            SubsamplingMode::Chroma422
+        );
+        assert_eq!(
+            frame(&[0x22, 0x11, 0x11]).subsampling_mode(),
+            SubsamplingMode::Chroma420
+        );
+        assert_eq!(
+            frame(&[0x41, 0x11, 0x11]).subsampling_mode(),
+            SubsamplingMode::Chroma411
+        );
+        assert_eq!(
+            frame(&[0x12, 0x11, 0x11]).subsampling_mode(),
+            SubsamplingMode::Other
+        );
+        assert_eq!(
+            frame(&[0x22, 0x11, 0x21]).subsampling_mode(),
+            SubsamplingMode::Other
+        );
+        assert_eq!(
+            frame(&[0x11, 0x11, 0x11, 0x11]).subsampling_mode(),
+            SubsamplingMode::Other
+        );
+    }
+
+    #[test]
+    fn stream_parser_test() {
+        let mut jpeg_data = comment_jpeg();
+        jpeg_data.truncate(jpeg_data.len() - 2);
+        jpeg_data.extend(&[
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, 0xD2, 0xFF, 0x00, 0xFF,
+            0xD0, 0xFF, 0xD9, 0x12,
+        ]);
+        let expected: Vec<MarkerOwned> = decode_with_options(
+            &jpeg_data,
+            &ParseOptions {
+                ignore_trailing_data: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .iter()
+        .map(Marker::to_owned)
+        .collect();
+
+        for chunk_size in 1..jpeg_data.len() + 1 {
+            let mut parser = StreamParser::new();
+            let mut markers = Vec::new();
+            for chunk in jpeg_data.chunks(chunk_size) {
+                markers.extend(parser.feed(chunk).unwrap());
+            }
+
+            assert!(parser.is_finished());
+            assert_eq!(markers, expected);
+        }
+    }
+
+    #[test]
+    fn stream_parser_error_test() {
+        let mut parser = StreamParser::new();
+        assert_eq!(parser.feed(&[0xFF]), Ok(vec![]));
+        assert_eq!(parser.feed(&[0xD9]), Err(ParserError::MissingStartOfImage));
+
+        let mut parser = StreamParser::new();
+        assert_eq!(parser.feed(&[0xFF, 0xD8, 0xFF, 0xFE, 0x00]), Ok(vec![]));
+        assert!(!parser.is_finished());
+        assert_eq!(
+            parser.feed(&[0x03, 0x41, 0x00, 0x00]),
+            Err(ParserError::InvalidMarker { found: 0x00 })
+        );
+    }
+
+    #[test]
+    fn fill_bytes_test() {
+        // fill bytes in front of a comment, and between the scan and EOI
+        let file = [
+            0xFF, 0xD8, 0xFF, 0xFF, 0xFF, 0xFE, 0x00, 0x03, 0x41, 0xFF, 0xDA, 0x00, 0x08, 0x01,
+            0x01, 0x00, 0x00, 0x3F, 0x00, 0xD2, 0xFF, 0xFF, 0xFF, 0xD9,
+        ];
+        let expected = vec![
+            MarkerOwned::Other(SomeMarkerOwned {
+                tag: 0xfe,
+                length: 1,
+                data: vec![0x41],
+            }),
+            MarkerOwned::Image(ImageStreamOwned {
+                metadata: StartOfStreamMetaData {
+                    component_metadata: vec![ScanComponentMetaData {
+                        id: 1,
+                        dc_table: 0,
+                        ac_table: 0,
+                    }],
+                    spectral_start: 0,
+                    spectral_end: 63,
+                    approximation_high: 0,
+                    approximation_low: 0,
+                },
+                data: vec![0xd2],
+            }),
+        ];
+        let markers: Vec<MarkerOwned> = decode(&file)
+            .unwrap()
+            .iter()
+            .map(Marker::to_owned)
+            .collect();
+        assert_eq!(markers, expected);
+
+        for chunk_size in 1..file.len() + 1 {
+            let mut parser = StreamParser::new();
+            let mut markers = Vec::new();
+            for chunk in file.chunks(chunk_size) {
+                markers.extend(parser.feed(chunk).unwrap());
+            }
+            assert!(parser.is_finished());
+            assert_eq!(markers, expected);
+        }
+
+        let segments = header_segments(&file).unwrap();
+        assert_eq!(
+            (segments.len(), segments[0].offset, segments[0].tag),
+            (1, 4, 0xfe)
+        );
+    }
+
+    #[test]
+    fn short_length_test() {
+        // lengths below 2 don't even cover the length field
+        let file = b"\xff\xd8\xff\xe0\x00\x00\xff\xd9";
+        let error = ParserError::InvalidMarkerLength { offset: 2 };
+        assert_eq!(decode(file).unwrap_err().parser_error(), Some(&error));
+        assert_eq!(StreamParser::new().feed(file), Err(error));
+        assert_eq!(
+            parse_segment(b"\xff\xfe\x00\x01"),
+            Err(ParserError::InvalidMarkerLength { offset: 0 })
+        );
+        let resynchronize = ParseOptions {
+            resynchronize: true,
+            ..Default::default()
+        };
+        // resynchronizing skips the segment up to EOI
+        assert!(decode_with_options(file, &resynchronize)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn stream_parser_needed_test() {
+        let mut parser = StreamParser::new();
+        assert_eq!(parser.needed(), Some(6));
+        assert_eq!(parser.feed(&[0xFF, 0xD8, 0xFF]), Ok(vec![]));
+        assert_eq!(parser.needed(), Some(3));
+        // a comment of three bytes
+        assert_eq!(parser.feed(&[0xFE, 0x00, 0x05, 0x41]), Ok(vec![]));
+        assert_eq!(parser.needed(), Some(2));
+        assert_eq!(parser.feed(&[0x42, 0x43]).unwrap().len(), 1);
+        assert_eq!(parser.needed(), Some(4));
+
+        assert_eq!(
+            parser.feed(&[0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, 0xD2]),
+            Ok(vec![])
+        );
+        assert_eq!(parser.needed(), None);
+        assert_eq!(parser.feed(&[0xFF, 0xD9]).unwrap().len(), 1);
+        assert!(parser.is_finished());
+        assert_eq!(parser.needed(), None);
+    }
 }
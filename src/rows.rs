@@ -0,0 +1,33 @@
+// Iteration over the independent rows of samples and blocks the decoder and encoder transform. With the rayon
+// feature the rows are spread over the threads of the current rayon pool, otherwise they are processed in order.
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+// for_each_row calls f with the index and elements of every row of size elements of rows
+pub(crate) fn for_each_row<T, F>(rows: &mut [T], size: usize, f: F)
+where
+    T: Send,
+    F: Fn((usize, &mut [T])) + Send + Sync,
+{
+    for_each_row_init(rows, size, || (), |_, row| f(row));
+}
+
+// for_each_row_init calls f like for_each_row, along with scratch space made by init. Every thread makes its
+// own, without rayon a single one is reused for all rows.
+pub(crate) fn for_each_row_init<T, S, I, F>(rows: &mut [T], size: usize, init: I, f: F)
+where
+    T: Send,
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, (usize, &mut [T])) + Send + Sync,
+{
+    #[cfg(feature = "rayon")]
+    rows.par_chunks_mut(size).enumerate().for_each_init(init, f);
+    #[cfg(not(feature = "rayon"))]
+    {
+        let mut scratch = init();
+        rows.chunks_mut(size)
+            .enumerate()
+            .for_each(|row| f(&mut scratch, row));
+    }
+}
@@ -0,0 +1,598 @@
+// Kernels of the integer IDCT, the chroma upsampling and the color conversion with the vector instructions of x86
+// and ARM processors. The intrinsics of core::arch need unsafe code, which is why this is the opt-in simd feature.
+// Every kernel computes the same results as the scalar code bit for bit, so images decode the same on any
+// processor.
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::aarch64::*;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::*;
+
+use idct::Matrix;
+
+// product returns the matrix product of left and right with the widest instructions the processor supports, or
+// None if there are none. NEON is part of every aarch64 processor.
+#[allow(unreachable_code, unused_variables)]
+pub fn product(left: &Matrix<i16>, right: &Matrix<i16>) -> Option<Matrix<i32>> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        #[cfg(feature = "std")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                // SAFETY: the processor supports AVX2
+                return Some(unsafe { product_avx2(left, right) });
+            }
+        }
+        if has_sse2() {
+            // SAFETY: the processor supports SSE2
+            return Some(unsafe { product_sse2(left, right) });
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // SAFETY: every aarch64 processor supports NEON
+        return Some(unsafe { product_neon(left, right) });
+    }
+    None
+}
+
+// ycbcr_to_rgb converts the leading pixels of the y, cb and cr samples into rgb in groups of eight, returning how
+// many it converted. The rest is left to the scalar code.
+#[allow(unreachable_code, unused_variables)]
+pub fn ycbcr_to_rgb(y: &[u8], cb: &[u8], cr: &[u8], rgb: &mut [[u8; 3]]) -> usize {
+    let count = y.len().min(cb.len()).min(cr.len()).min(rgb.len()) / 8 * 8;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_sse2() {
+            for i in (0..count).step_by(8) {
+                let range = i..i + 8;
+                // SAFETY: the processor supports SSE2
+                let converted =
+                    unsafe { ycbcr_to_rgb_sse2(&y[range.clone()], &cb[range.clone()], &cr[range]) };
+                for (pixel, j) in rgb[i..i + 8].iter_mut().zip(0..8) {
+                    *pixel = [converted[0][j], converted[1][j], converted[2][j]];
+                }
+            }
+            return count;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        for i in (0..count).step_by(8) {
+            let range = i..i + 8;
+            // SAFETY: every aarch64 processor supports NEON
+            unsafe {
+                ycbcr_to_rgb_neon(
+                    &y[range.clone()],
+                    &cb[range.clone()],
+                    &cr[range.clone()],
+                    &mut rgb[range],
+                )
+            };
+        }
+        return count;
+    }
+    0
+}
+
+// duplicate repeats the leading samples of line twice each in output in groups of 16, returning how many samples
+// it repeated
+#[allow(unreachable_code, unused_variables)]
+pub fn duplicate(line: &[u8], output: &mut [u8]) -> usize {
+    let count = line.len().min(output.len() / 2) / 16 * 16;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_sse2() {
+            for i in (0..count).step_by(16) {
+                // SAFETY: the processor supports SSE2
+                unsafe { duplicate_sse2(&line[i..i + 16], &mut output[2 * i..2 * i + 32]) };
+            }
+            return count;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        for i in (0..count).step_by(16) {
+            // SAFETY: every aarch64 processor supports NEON
+            unsafe { duplicate_neon(&line[i..i + 16], &mut output[2 * i..2 * i + 32]) };
+        }
+        return count;
+    }
+    0
+}
+
+// triangle doubles the resolution of line into output, weighting the nearer sample with 3/4 and the farther one
+// with 1/4, for the samples from 1 on whose neighbours are both inside the line, in groups of 16. It returns how
+// many samples it doubled, which fill output from 2 on. The samples at the edges are left to the scalar code.
+#[allow(unreachable_code, unused_variables)]
+pub fn triangle(line: &[u8], output: &mut [u8]) -> usize {
+    let count = line
+        .len()
+        .saturating_sub(2)
+        .min((output.len() / 2).saturating_sub(1))
+        / 16
+        * 16;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_sse2() {
+            for i in (1..count + 1).step_by(16) {
+                // SAFETY: the processor supports SSE2
+                unsafe { triangle_sse2(&line[i - 1..i + 17], &mut output[2 * i..2 * i + 32]) };
+            }
+            return count;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        for i in (1..count + 1).step_by(16) {
+            // SAFETY: every aarch64 processor supports NEON
+            unsafe { triangle_neon(&line[i - 1..i + 17], &mut output[2 * i..2 * i + 32]) };
+        }
+        return count;
+    }
+    0
+}
+
+// blend computes (first * weights.0 + second * weights.1 + 2^shift / 2) >> shift for the leading samples of two
+// lines in groups of 16, returning how many it computed. The weights add up to 2^shift, which is at most 8.
+#[allow(unreachable_code, unused_variables)]
+pub fn blend(
+    first: &[u8],
+    second: &[u8],
+    weights: (u8, u8),
+    shift: u32,
+    output: &mut [u8],
+) -> usize {
+    debug_assert!(
+        shift >= 1 && u32::from(weights.0) + u32::from(weights.1) == 1 << shift && shift <= 3
+    );
+    let count = first.len().min(second.len()).min(output.len()) / 16 * 16;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_sse2() {
+            for i in (0..count).step_by(16) {
+                let range = i..i + 16;
+                // SAFETY: the processor supports SSE2
+                unsafe {
+                    blend_lines_sse2(
+                        &first[range.clone()],
+                        &second[range.clone()],
+                        weights,
+                        shift,
+                        &mut output[range],
+                    )
+                };
+            }
+            return count;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        for i in (0..count).step_by(16) {
+            let range = i..i + 16;
+            // SAFETY: every aarch64 processor supports NEON
+            unsafe {
+                blend_lines_neon(
+                    &first[range.clone()],
+                    &second[range.clone()],
+                    weights,
+                    shift,
+                    &mut output[range],
+                )
+            };
+        }
+        return count;
+    }
+    0
+}
+
+#[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+fn has_sse2() -> bool {
+    is_x86_feature_detected!("sse2")
+}
+
+// without std the processor can't be checked, SSE2 is used if the target enables it
+#[cfg(all(not(feature = "std"), any(target_arch = "x86", target_arch = "x86_64")))]
+fn has_sse2() -> bool {
+    cfg!(target_feature = "sse2")
+}
+
+// pair packs two entries into the 32 bits madd multiplies with two interleaved entries of the other factor
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn pair(first: i16, second: i16) -> i32 {
+    (u32::from(first as u16) | u32::from(second as u16) << 16) as i32
+}
+
+// product_sse2 multiplies every row of left with right as the sum of the rows of right weighted by the entries of
+// the row. madd multiplies eight pairs of entries and adds up the products of every pair, so the rows of right
+// are interleaved two by two and multiplied with two entries of left at a time.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn product_sse2(left: &Matrix<i16>, right: &Matrix<i16>) -> Matrix<i32> {
+    // interleaved holds the rows 2k and 2k + 1 of right interleaved, for the columns 0 to 3 and 4 to 7
+    let mut interleaved = [(_mm_setzero_si128(), _mm_setzero_si128()); 4];
+    for (k, rows) in interleaved.iter_mut().enumerate() {
+        let first = _mm_loadu_si128(right[2 * k].as_ptr() as *const __m128i);
+        let second = _mm_loadu_si128(right[2 * k + 1].as_ptr() as *const __m128i);
+        *rows = (
+            _mm_unpacklo_epi16(first, second),
+            _mm_unpackhi_epi16(first, second),
+        );
+    }
+
+    let mut product = [[0i32; 8]; 8];
+    for (row, output) in left.iter().zip(product.iter_mut()) {
+        let (mut low, mut high) = (_mm_setzero_si128(), _mm_setzero_si128());
+        for (k, &(first, second)) in interleaved.iter().enumerate() {
+            let factors = _mm_set1_epi32(pair(row[2 * k], row[2 * k + 1]));
+            low = _mm_add_epi32(low, _mm_madd_epi16(factors, first));
+            high = _mm_add_epi32(high, _mm_madd_epi16(factors, second));
+        }
+        _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, low);
+        _mm_storeu_si128(output.as_mut_ptr().add(4) as *mut __m128i, high);
+    }
+    product
+}
+
+// product_avx2 works like product_sse2 with all eight columns in one register
+#[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+#[target_feature(enable = "avx2")]
+unsafe fn product_avx2(left: &Matrix<i16>, right: &Matrix<i16>) -> Matrix<i32> {
+    let mut interleaved = [_mm256_setzero_si256(); 4];
+    for (k, rows) in interleaved.iter_mut().enumerate() {
+        let first = _mm_loadu_si128(right[2 * k].as_ptr() as *const __m128i);
+        let second = _mm_loadu_si128(right[2 * k + 1].as_ptr() as *const __m128i);
+        *rows = _mm256_set_m128i(
+            _mm_unpackhi_epi16(first, second),
+            _mm_unpacklo_epi16(first, second),
+        );
+    }
+
+    let mut product = [[0i32; 8]; 8];
+    for (row, output) in left.iter().zip(product.iter_mut()) {
+        let mut sums = _mm256_setzero_si256();
+        for (k, &rows) in interleaved.iter().enumerate() {
+            let factors = _mm256_set1_epi32(pair(row[2 * k], row[2 * k + 1]));
+            sums = _mm256_add_epi32(sums, _mm256_madd_epi16(factors, rows));
+        }
+        _mm256_storeu_si256(output.as_mut_ptr() as *mut __m256i, sums);
+    }
+    product
+}
+
+// product_neon adds up the rows of right weighted by the entries of every row of left, multiplying 16 bit entries
+// into 32 bit sums
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn product_neon(left: &Matrix<i16>, right: &Matrix<i16>) -> Matrix<i32> {
+    let mut rows = [vdupq_n_s16(0); 8];
+    for (row, entries) in rows.iter_mut().zip(right) {
+        *row = vld1q_s16(entries.as_ptr());
+    }
+
+    let mut product = [[0i32; 8]; 8];
+    for (factors, output) in left.iter().zip(product.iter_mut()) {
+        let (mut low, mut high) = (vdupq_n_s32(0), vdupq_n_s32(0));
+        for (&factor, &row) in factors.iter().zip(&rows) {
+            low = vmlal_n_s16(low, vget_low_s16(row), factor);
+            high = vmlal_high_n_s16(high, row, factor);
+        }
+        vst1q_s32(output.as_mut_ptr(), low);
+        vst1q_s32(output.as_mut_ptr().add(4), high);
+    }
+    product
+}
+
+// The color conversion multiplies 16 bit samples with the 16 bit fixed-point constants of color.rs, which don't
+// fit into 16 bits themselves. They are split into a multiple of 2^16, which is added to the sample shifted up,
+// and a remainder that fits. Every madd adds the remainder products of two samples of a pixel, or one of them and
+// the rounding half.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const CR_TO_R_REMAINDER: i16 = 26_345; // 91_881 - 2^16
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const CR_TO_G_REMAINDER: i16 = 18_734; // 2^16 - 46_802
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const CB_TO_B_REMAINDER: i16 = -14_942; // 116_130 - 2^17
+
+// ycbcr_to_rgb_sse2 converts eight pixels into their red, green and blue samples
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn ycbcr_to_rgb_sse2(y: &[u8], cb: &[u8], cr: &[u8]) -> [[u8; 8]; 3] {
+    let center = _mm_set1_epi16(128);
+    let y = load_sse2(y);
+    let cb = _mm_sub_epi16(load_sse2(cb), center);
+    let cr = _mm_sub_epi16(load_sse2(cr), center);
+    // two times 2^14 adds the rounding half
+    let two = _mm_set1_epi16(2);
+    let (zero, half) = (_mm_setzero_si128(), _mm_set1_epi32(1 << 15));
+
+    let r = pack_sse2(
+        _mm_add_epi16(y, cr),
+        cr,
+        two,
+        pair(CR_TO_R_REMAINDER, 1 << 14),
+        zero,
+    );
+    let g = pack_sse2(
+        _mm_sub_epi16(y, cr),
+        cb,
+        cr,
+        pair(-22_554, CR_TO_G_REMAINDER),
+        half,
+    );
+    let y = _mm_add_epi16(y, _mm_add_epi16(cb, cb));
+    let b = pack_sse2(y, cb, two, pair(CB_TO_B_REMAINDER, 1 << 14), zero);
+    [r, g, b]
+}
+
+// load_sse2 loads eight bytes as 16 bit samples
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn load_sse2(samples: &[u8]) -> __m128i {
+    let bytes = _mm_loadl_epi64(samples.as_ptr() as *const __m128i);
+    _mm_unpacklo_epi8(bytes, _mm_setzero_si128())
+}
+
+// pack_sse2 computes (shifted * 2^16 + first * factors.0 + second * factors.1 + rounding) >> 16 for eight
+// pixels and packs the results into bytes with saturation
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn pack_sse2(
+    shifted: __m128i,
+    first: __m128i,
+    second: __m128i,
+    factors: i32,
+    rounding: __m128i,
+) -> [u8; 8] {
+    let (zero, factors) = (_mm_setzero_si128(), _mm_set1_epi32(factors));
+    let low = _mm_add_epi32(
+        _mm_unpacklo_epi16(zero, shifted),
+        _mm_madd_epi16(_mm_unpacklo_epi16(first, second), factors),
+    );
+    let high = _mm_add_epi32(
+        _mm_unpackhi_epi16(zero, shifted),
+        _mm_madd_epi16(_mm_unpackhi_epi16(first, second), factors),
+    );
+    let low = _mm_srai_epi32(_mm_add_epi32(low, rounding), 16);
+    let high = _mm_srai_epi32(_mm_add_epi32(high, rounding), 16);
+    let packed = _mm_packus_epi16(_mm_packs_epi32(low, high), zero);
+    let mut samples = [0u8; 8];
+    _mm_storel_epi64(samples.as_mut_ptr() as *mut __m128i, packed);
+    samples
+}
+
+// ycbcr_to_rgb_neon converts eight pixels with the constants of color.rs in 32 bit arithmetic, storing them
+// interleaved
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn ycbcr_to_rgb_neon(y: &[u8], cb: &[u8], cr: &[u8], rgb: &mut [[u8; 3]]) {
+    let center = vdupq_n_s16(128);
+    let y = vreinterpretq_s16_u16(vmovl_u8(vld1_u8(y.as_ptr())));
+    let cb = vsubq_s16(
+        vreinterpretq_s16_u16(vmovl_u8(vld1_u8(cb.as_ptr()))),
+        center,
+    );
+    let cr = vsubq_s16(
+        vreinterpretq_s16_u16(vmovl_u8(vld1_u8(cr.as_ptr()))),
+        center,
+    );
+    let pixels = uint8x8x3_t(
+        pack_neon(y, cb, cr, 0, 91_881),
+        pack_neon(y, cb, cr, -22_554, -46_802),
+        pack_neon(y, cb, cr, 116_130, 0),
+    );
+    vst3_u8(rgb.as_mut_ptr() as *mut u8, pixels);
+}
+
+// pack_neon computes (y * 2^16 + cb * cb_factor + cr * cr_factor + HALF) >> 16 for eight pixels and packs the
+// results into bytes with saturation
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn pack_neon(
+    y: int16x8_t,
+    cb: int16x8_t,
+    cr: int16x8_t,
+    cb_factor: i32,
+    cr_factor: i32,
+) -> uint8x8_t {
+    let half = vdupq_n_s32(1 << 15);
+    let low = vaddq_s32(vshlq_n_s32(vmovl_s16(vget_low_s16(y)), 16), half);
+    let low = vmlaq_n_s32(low, vmovl_s16(vget_low_s16(cb)), cb_factor);
+    let low = vshrq_n_s32(vmlaq_n_s32(low, vmovl_s16(vget_low_s16(cr)), cr_factor), 16);
+    let high = vaddq_s32(vshlq_n_s32(vmovl_s16(vget_high_s16(y)), 16), half);
+    let high = vmlaq_n_s32(high, vmovl_s16(vget_high_s16(cb)), cb_factor);
+    let high = vshrq_n_s32(
+        vmlaq_n_s32(high, vmovl_s16(vget_high_s16(cr)), cr_factor),
+        16,
+    );
+    vqmovun_s16(vcombine_s16(vqmovn_s32(low), vqmovn_s32(high)))
+}
+
+// duplicate_sse2 interleaves 16 samples with themselves
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn duplicate_sse2(line: &[u8], output: &mut [u8]) {
+    let samples = _mm_loadu_si128(line.as_ptr() as *const __m128i);
+    let output = output.as_mut_ptr() as *mut __m128i;
+    _mm_storeu_si128(output, _mm_unpacklo_epi8(samples, samples));
+    _mm_storeu_si128(output.add(1), _mm_unpackhi_epi8(samples, samples));
+}
+
+// triangle_sse2 doubles the 16 samples in the middle of 18, interleaving the outputs that weight their left
+// neighbours with the ones that weight their right neighbours
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn triangle_sse2(line: &[u8], output: &mut [u8]) {
+    let previous = _mm_loadu_si128(line.as_ptr() as *const __m128i);
+    let samples = _mm_loadu_si128(line.as_ptr().add(1) as *const __m128i);
+    let next = _mm_loadu_si128(line.as_ptr().add(2) as *const __m128i);
+    let (weights, shift) = ((_mm_set1_epi16(3), _mm_set1_epi16(1)), _mm_cvtsi32_si128(2));
+    let left = blend_sse2(samples, previous, weights, shift);
+    let right = blend_sse2(samples, next, weights, shift);
+    let output = output.as_mut_ptr() as *mut __m128i;
+    _mm_storeu_si128(output, _mm_unpacklo_epi8(left, right));
+    _mm_storeu_si128(output.add(1), _mm_unpackhi_epi8(left, right));
+}
+
+// blend_lines_sse2 blends 16 samples of two lines
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn blend_lines_sse2(
+    first: &[u8],
+    second: &[u8],
+    weights: (u8, u8),
+    shift: u32,
+    output: &mut [u8],
+) {
+    let first = _mm_loadu_si128(first.as_ptr() as *const __m128i);
+    let second = _mm_loadu_si128(second.as_ptr() as *const __m128i);
+    let weights = (
+        _mm_set1_epi16(i16::from(weights.0)),
+        _mm_set1_epi16(i16::from(weights.1)),
+    );
+    let blended = blend_sse2(first, second, weights, _mm_cvtsi32_si128(shift as i32));
+    _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, blended);
+}
+
+// blend_sse2 computes the weighted sums of 16 pairs of bytes in 16 bit lanes, rounds them and shifts them down by
+// the count in the low 64 bits of shift. The sums of weights up to 8 fit the lanes and the results the bytes.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn blend_sse2(
+    first: __m128i,
+    second: __m128i,
+    weights: (__m128i, __m128i),
+    shift: __m128i,
+) -> __m128i {
+    let zero = _mm_setzero_si128();
+    let rounding = _mm_srli_epi16(_mm_sll_epi16(_mm_set1_epi16(1), shift), 1);
+    let low = _mm_add_epi16(
+        _mm_mullo_epi16(_mm_unpacklo_epi8(first, zero), weights.0),
+        _mm_mullo_epi16(_mm_unpacklo_epi8(second, zero), weights.1),
+    );
+    let high = _mm_add_epi16(
+        _mm_mullo_epi16(_mm_unpackhi_epi8(first, zero), weights.0),
+        _mm_mullo_epi16(_mm_unpackhi_epi8(second, zero), weights.1),
+    );
+    let low = _mm_srl_epi16(_mm_add_epi16(low, rounding), shift);
+    let high = _mm_srl_epi16(_mm_add_epi16(high, rounding), shift);
+    _mm_packus_epi16(low, high)
+}
+
+// duplicate_neon stores 16 samples interleaved with themselves
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn duplicate_neon(line: &[u8], output: &mut [u8]) {
+    let samples = vld1q_u8(line.as_ptr());
+    vst2q_u8(output.as_mut_ptr(), uint8x16x2_t(samples, samples));
+}
+
+// triangle_neon doubles the 16 samples in the middle of 18 like triangle_sse2
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn triangle_neon(line: &[u8], output: &mut [u8]) {
+    let previous = vld1q_u8(line.as_ptr());
+    let samples = vld1q_u8(line.as_ptr().add(1));
+    let next = vld1q_u8(line.as_ptr().add(2));
+    let (weights, shift) = ((3, 1), 2);
+    let left = blend_neon(samples, previous, weights, shift);
+    let right = blend_neon(samples, next, weights, shift);
+    vst2q_u8(output.as_mut_ptr(), uint8x16x2_t(left, right));
+}
+
+// blend_lines_neon blends 16 samples of two lines
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn blend_lines_neon(
+    first: &[u8],
+    second: &[u8],
+    weights: (u8, u8),
+    shift: u32,
+    output: &mut [u8],
+) {
+    let blended = blend_neon(
+        vld1q_u8(first.as_ptr()),
+        vld1q_u8(second.as_ptr()),
+        weights,
+        shift,
+    );
+    vst1q_u8(output.as_mut_ptr(), blended);
+}
+
+// blend_neon computes the weighted sums of 16 pairs of bytes in 16 bit lanes and narrows them with a rounding
+// shift
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn blend_neon(
+    first: uint8x16_t,
+    second: uint8x16_t,
+    weights: (u8, u8),
+    shift: u32,
+) -> uint8x16_t {
+    let (first_weight, second_weight) = (vdup_n_u8(weights.0), vdup_n_u8(weights.1));
+    // a rounding shift to the left by a negative count shifts to the right
+    let shift = vdupq_n_s16(-(shift as i16));
+    let low = vmlal_u8(
+        vmull_u8(vget_low_u8(first), first_weight),
+        vget_low_u8(second),
+        second_weight,
+    );
+    let high = vmlal_u8(
+        vmull_u8(vget_high_u8(first), first_weight),
+        vget_high_u8(second),
+        second_weight,
+    );
+    vcombine_u8(
+        vmovn_u16(vrshlq_u16(low, shift)),
+        vmovn_u16(vrshlq_u16(high, shift)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simd_product_test() {
+        // entries up to the 16 bit limits, with the sum of every pair of products still in 32 bits
+        let mut left = [[0i16; 8]; 8];
+        let mut right = [[0i16; 8]; 8];
+        let mut state = 0x1234_5678u32;
+        for (i, (a, b)) in left
+            .iter_mut()
+            .flatten()
+            .zip(right.iter_mut().flatten())
+            .enumerate()
+        {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *a = if i % 9 == 0 {
+                i16::MIN
+            } else {
+                (state >> 16) as i16
+            };
+            *b = (state >> 20) as i16 - 2048;
+        }
+        let mut expected = [[0i32; 8]; 8];
+        for (i, row) in expected.iter_mut().enumerate() {
+            for (j, sum) in row.iter_mut().enumerate() {
+                *sum = (0..8)
+                    .map(|k| i32::from(left[i][k]) * i32::from(right[k][j]))
+                    .sum();
+            }
+        }
+        assert_eq!(product(&left, &right), Some(expected));
+
+        // product only uses the widest instructions, the others are checked one by one
+        #[cfg(all(feature = "std", any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            if is_x86_feature_detected!("sse2") {
+                assert_eq!(unsafe { product_sse2(&left, &right) }, expected);
+            }
+            if is_x86_feature_detected!("avx2") {
+                assert_eq!(unsafe { product_avx2(&left, &right) }, expected);
+            }
+        }
+    }
+}
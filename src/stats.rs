@@ -0,0 +1,91 @@
+// Counters and timings collected while decoding, for debugging encoder output and tuning the decoder.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use huffman::DHTType;
+
+// DecodeStats describes the work decoding an image took. The durations need a clock and stay zero without std.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DecodeStats {
+    // scans is the number of decoded scans
+    pub scans: u64,
+    // restart_markers is the number of restart markers the entropy decoder resynchronized at
+    pub restart_markers: u64,
+    // entropy_coded_bits is the number of bits consumed by Huffman decoding, including the extra bits
+    pub entropy_coded_bits: u64,
+    // huffman_symbols holds the number of decoded symbols per table, in the order the tables were first used
+    pub huffman_symbols: Vec<(DHTType, u64)>,
+    // entropy_decoding is the time spent decoding scans into coefficients
+    pub entropy_decoding: Duration,
+    // reconstruction is the time spent dequantizing and transforming coefficients into sample planes
+    pub reconstruction: Duration,
+    // conversion is the time spent upsampling, converting colors and orienting the output pixels
+    pub conversion: Duration,
+    // allocated_bytes is the capacity of the coefficient, sample, entropy coded data and pixel buffers at the end
+    // of the decode, the memory a decode takes besides marker segments and tables
+    pub allocated_bytes: u64,
+}
+
+impl DecodeStats {
+    pub(crate) fn add_symbols(&mut self, class: DHTType, count: u64) {
+        match self
+            .huffman_symbols
+            .iter_mut()
+            .find(|entry| entry.0 == class)
+        {
+            Some(entry) => entry.1 += count,
+            None => self.huffman_symbols.push((class, count)),
+        }
+    }
+}
+
+// Stopwatch measures the duration of a decoding stage
+pub(crate) struct Stopwatch {
+    #[cfg(feature = "std")]
+    start: Instant,
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Stopwatch {
+        Stopwatch {
+            #[cfg(feature = "std")]
+            start: Instant::now(),
+        }
+    }
+
+    // add_to adds the time since the start to total
+    pub(crate) fn add_to(&self, total: &mut Duration) {
+        #[cfg(feature = "std")]
+        {
+            *total += self.start.elapsed();
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            let _ = total;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_symbols_test() {
+        let mut stats = DecodeStats::default();
+        stats.add_symbols(DHTType::LuminanceDC, 4);
+        stats.add_symbols(DHTType::LuminanceAC, 10);
+        stats.add_symbols(DHTType::LuminanceDC, 0);
+        stats.add_symbols(DHTType::LuminanceDC, 2);
+
+        assert_eq!(
+            stats.huffman_symbols,
+            vec![(DHTType::LuminanceDC, 6), (DHTType::LuminanceAC, 10)]
+        );
+    }
+}
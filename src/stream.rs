@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+const CHUNK_SIZE: usize = 8192;
+
+// StreamReader buffers the not yet consumed bytes of a reader in a ring buffer, so callers can look ahead
+// without holding on to everything they have already consumed
+pub struct StreamReader<R: Read> {
+    reader: R,
+    buffer: VecDeque<u8>,
+    eof: bool,
+    // position counts the bytes consumed so far
+    position: usize,
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(reader: R) -> StreamReader<R> {
+        StreamReader {
+            reader,
+            buffer: VecDeque::with_capacity(CHUNK_SIZE),
+            eof: false,
+            position: 0,
+        }
+    }
+
+    // fill reads from the underlying reader until at least length bytes are buffered, returning false if the
+    // stream ends before that
+    pub fn fill(&mut self, length: usize) -> io::Result<bool> {
+        let mut chunk = [0u8; CHUNK_SIZE];
+
+        while self.buffer.len() < length && !self.eof {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(read) => self.buffer.extend(&chunk[..read]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(self.buffer.len() >= length)
+    }
+
+    // buffered returns the number of bytes read ahead that haven't been consumed
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn peek(&self, index: usize) -> Option<u8> {
+        self.buffer.get(index).cloned()
+    }
+
+    // peek_range copies length buffered bytes starting at index without consuming them
+    pub fn peek_range(&self, index: usize, length: usize) -> Vec<u8> {
+        self.buffer.range(index..index + length).cloned().collect()
+    }
+
+    pub fn consume(&mut self, length: usize) {
+        let length = length.min(self.buffer.len());
+        self.buffer.drain(..length);
+        self.position += length;
+    }
+
+    // position returns the offset in the stream of the next byte that wasn't consumed
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // take removes the next length bytes, returning None if the stream ends before that
+    pub fn take(&mut self, length: usize) -> io::Result<Option<Vec<u8>>> {
+        if !self.fill(length)? {
+            return Ok(None);
+        }
+        self.position += length;
+        Ok(Some(self.buffer.drain(..length).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // OneByte returns at most one byte per read call, like a slow network stream
+    struct OneByte<'a>(&'a [u8]);
+
+    impl<'a> Read for OneByte<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn stream_reader_test() {
+        let mut stream = StreamReader::new(OneByte(&[1, 2, 3, 4, 5]));
+
+        assert!(stream.fill(2).unwrap());
+        assert_eq!(
+            (stream.peek(0), stream.peek(1), stream.peek(2)),
+            (Some(1), Some(2), None)
+        );
+
+        stream.consume(1);
+        assert!(stream.fill(3).unwrap());
+        assert_eq!(stream.peek_range(1, 2), vec![3, 4]);
+        assert_eq!(stream.buffered(), 3);
+        assert_eq!(stream.take(3).unwrap(), Some(vec![2, 3, 4]));
+        assert_eq!(stream.take(2).unwrap(), None);
+        assert_eq!(stream.position(), 4);
+        assert_eq!(stream.take(1).unwrap(), Some(vec![5]));
+        assert_eq!(stream.position(), 5);
+        assert!(!stream.fill(1).unwrap());
+        assert_eq!(stream.peek(0), None);
+    }
+}
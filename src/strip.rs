@@ -0,0 +1,229 @@
+// Removal of metadata segments from JPEG files, copying the compressed image data as it is.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use encoder::{write_segment, EXIF_HEADER, XMP_HEADER};
+use errors::Error;
+use icc::ICC_MARKER;
+use parser::{header_segments, HeaderSegment};
+use thumbnail::{JPEG_INTERCHANGE_FORMAT_LENGTH_TAG, JPEG_INTERCHANGE_FORMAT_TAG};
+use tiff::Tiff;
+
+const XMP_EXTENSION_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\x00";
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\x00";
+
+// MetadataKinds selects the metadata strip removes, the default selects nothing
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MetadataKinds {
+    // exif removes the EXIF segment, including its thumbnail
+    pub exif: bool,
+    // xmp removes the XMP segment and the segments of extended XMP
+    pub xmp: bool,
+    pub icc_profile: bool,
+    pub comments: bool,
+    // iptc removes the Photoshop APP13 segment, which holds the IPTC data
+    pub iptc: bool,
+    // thumbnails removes the thumbnails of the JFIF, JFXX and EXIF segments, keeping the other EXIF data
+    pub thumbnails: bool,
+}
+
+impl MetadataKinds {
+    // all selects every kind of metadata
+    pub fn all() -> MetadataKinds {
+        MetadataKinds {
+            exif: true,
+            xmp: true,
+            icc_profile: true,
+            comments: true,
+            iptc: true,
+            thumbnails: true,
+        }
+    }
+}
+
+// strip removes the selected metadata from the segments in front of the first scan of jpeg_file. The scans and
+// everything following them are copied byte by byte, so the image stays exactly the same. EXIF data that can't
+// be parsed keeps its thumbnail.
+pub fn strip(jpeg_file: &[u8], kinds: &MetadataKinds) -> Result<Vec<u8>, Error> {
+    let segments = header_segments(jpeg_file)?;
+    let scans = segments
+        .last()
+        .map_or(2, |segment| segment.offset + 4 + segment.data.len());
+
+    let mut output = Vec::with_capacity(jpeg_file.len());
+    output.extend_from_slice(&jpeg_file[..2]);
+    for segment in &segments {
+        let HeaderSegment { tag, data, .. } = *segment;
+        let is_exif = tag == 0xe1 && data.starts_with(EXIF_HEADER);
+        let removed = match tag {
+            0xe0 => kinds.thumbnails && data.starts_with(b"JFXX\x00"),
+            0xe1 if is_exif => kinds.exif,
+            0xe1 => {
+                kinds.xmp
+                    && (data.starts_with(XMP_HEADER) || data.starts_with(XMP_EXTENSION_HEADER))
+            }
+            0xe2 => kinds.icc_profile && data.starts_with(ICC_MARKER),
+            0xed => kinds.iptc && data.starts_with(PHOTOSHOP_HEADER),
+            0xfe => kinds.comments,
+            _ => false,
+        };
+        if removed {
+            continue;
+        }
+
+        let stripped = match tag {
+            0xe0 if kinds.thumbnails && data.starts_with(b"JFIF\x00") && data.len() > 14 => {
+                // the thumbnail dimensions follow the densities
+                let mut jfif = data[..14].to_vec();
+                jfif[12..14].copy_from_slice(&[0, 0]);
+                Some(jfif)
+            }
+            _ if is_exif && kinds.thumbnails => exif_without_thumbnail(&data[EXIF_HEADER.len()..])
+                .map(|tiff| [EXIF_HEADER, &tiff].concat()),
+            _ => None,
+        };
+        match stripped {
+            Some(data) => write_segment(&mut output, tag, &data),
+            None => output
+                .extend_from_slice(&jpeg_file[segment.offset..segment.offset + 4 + data.len()]),
+        }
+    }
+    output.extend_from_slice(&jpeg_file[scans..]);
+    Ok(output)
+}
+
+// exif_without_thumbnail unlinks IFD1, which describes the thumbnail, from IFD0. The thumbnail is cut off if it
+// ends the data and overwritten with zeros otherwise. Data without IFD1 or that can't be parsed returns None.
+fn exif_without_thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let tiff = Tiff::new(data).ok()?;
+    let ifd0 = tiff.first_ifd_offset().ok()?;
+    let (ifd0_entries, ifd1) = tiff.read_ifd(ifd0).ok()?;
+    if ifd1 == 0 {
+        return None;
+    }
+    let (entries, _) = tiff.read_ifd(ifd1).ok()?;
+    let value = |tag| {
+        entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.value_offset as usize)
+    };
+    let thumbnail = match (
+        value(JPEG_INTERCHANGE_FORMAT_TAG),
+        value(JPEG_INTERCHANGE_FORMAT_LENGTH_TAG),
+    ) {
+        (Some(offset), Some(length)) => tiff
+            .bytes(offset, length)
+            .ok()
+            .map(|_| offset..offset + length),
+        _ => None,
+    };
+
+    let mut stripped = data.to_vec();
+    let next_ifd = ifd0 + 2 + tiff.u16_at(ifd0).ok()? as usize * 12;
+    stripped[next_ifd..next_ifd + 4].copy_from_slice(&[0; 4]);
+    if let Some(thumbnail) = thumbnail {
+        // IFD1 is cut off along with the thumbnail when they end the data and nothing of IFD0 follows them
+        let ifd1_at_end = ifd1 > next_ifd
+            && ifd1 < thumbnail.start
+            && ifd0_entries
+                .iter()
+                .all(|entry| (entry.value_offset as usize) < ifd1);
+        let start = if ifd1_at_end { ifd1 } else { thumbnail.start };
+        if thumbnail.end == stripped.len() && start >= next_ifd + 4 {
+            stripped.truncate(start);
+        } else {
+            for byte in &mut stripped[thumbnail] {
+                *byte = 0;
+            }
+        }
+    }
+    Some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::{decode_image, ColorType};
+    use encoder::{encode_image_with_options, EncodeOptions, ThumbnailOptions, ThumbnailSegment};
+    use icc::read_icc_profile;
+    use thumbnail::read_thumbnail;
+    use transcode::copy_metadata;
+
+    fn file(thumbnail: ThumbnailSegment) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..64 * 48 * 3).map(|i| (i % 253) as u8).collect();
+        let options = EncodeOptions {
+            exif: Some(b"II\x2a\x00\x08\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec()),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            icc_profile: Some(vec![3; 40]),
+            comments: vec![b"private".to_vec()],
+            thumbnail: Some(ThumbnailOptions {
+                segment: thumbnail,
+                max_size: 16,
+            }),
+            ..EncodeOptions::default()
+        };
+        let mut file =
+            encode_image_with_options(&pixels, 64, 48, ColorType::Rgb, &options).unwrap();
+        // an APP13 segment with IPTC data after the JFIF header
+        let mut app13 = Vec::new();
+        write_segment(
+            &mut app13,
+            0xed,
+            b"Photoshop 3.0\x008BIM\x04\x04\x00\x00\x00\x00\x00\x00",
+        );
+        let jfif = 4 + usize::from(u16::from_be_bytes([file[4], file[5]]));
+        file.splice(jfif..jfif, app13);
+        file
+    }
+
+    fn scans(file: &[u8]) -> &[u8] {
+        let last = header_segments(file).unwrap().pop().unwrap();
+        &file[last.offset + 4 + last.data.len()..]
+    }
+
+    #[test]
+    fn strip_test() {
+        for &segment in &[ThumbnailSegment::Exif, ThumbnailSegment::Jfxx] {
+            let file = file(segment);
+            assert_eq!(strip(&file, &MetadataKinds::default()).unwrap(), file);
+            assert!(read_thumbnail(&file).is_some());
+
+            let stripped = strip(&file, &MetadataKinds::all()).unwrap();
+            assert_eq!(scans(&stripped), scans(&file));
+            assert_eq!(
+                decode_image(&stripped).unwrap(),
+                decode_image(&file).unwrap()
+            );
+            let mut options = EncodeOptions::default();
+            copy_metadata(&stripped, &mut options).unwrap();
+            assert_eq!(options.exif, None);
+            assert_eq!(options.xmp, None);
+            assert_eq!(options.icc_profile, None);
+            assert!(options.comments.is_empty());
+            assert!(read_thumbnail(&stripped).is_none());
+            assert!(!stripped.windows(13).any(|bytes| bytes == b"Photoshop 3.0"));
+
+            let thumbnails = MetadataKinds {
+                thumbnails: true,
+                ..MetadataKinds::default()
+            };
+            let stripped = strip(&file, &thumbnails).unwrap();
+            assert!(read_thumbnail(&stripped).is_none());
+            let mut options = EncodeOptions::default();
+            copy_metadata(&stripped, &mut options).unwrap();
+            // the EXIF data is back to its IFD0 without the thumbnail appended by the encoder
+            assert_eq!(
+                options.exif.as_deref(),
+                Some(&b"II\x2a\x00\x08\x00\x00\x00\x00\x00\x00\x00\x00\x00"[..])
+            );
+            assert_eq!(read_icc_profile(&stripped), Some(vec![3; 40]));
+            assert_eq!(options.comments, vec![b"private".to_vec()]);
+            assert!(stripped.len() < file.len());
+        }
+
+        assert!(strip(b"\x00\x01", &MetadataKinds::all()).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+// Human readable summaries of parsed markers, for debugging tools and logs.
+
+use core::fmt;
+use core::str;
+
+use decoder::ZIGZAG;
+use huffman::HuffmanTable;
+use parser::{Marker, QuantizationTable, StartOfFrameMetaData, StartOfStreamMetaData};
+
+// MAX_SHOWN_BYTES is how much of comments and unknown segments a summary shows
+const MAX_SHOWN_BYTES: usize = 48;
+
+// MarkerSummary displays a marker as a first line naming it and indented lines with its tables or components
+pub struct MarkerSummary<'m, 'a: 'm> {
+    marker: &'m Marker<'a>,
+}
+
+impl<'a> Marker<'a> {
+    // summarize describes the marker for people instead of dumping its fields like Debug does. Quantization
+    // tables are shown as 8x8 grids in natural order, Huffman tables as histograms of their code lengths and
+    // comments and unknown segments only with their first bytes. The alternate format {:#} lists the code of
+    // every Huffman symbol below its histogram.
+    pub fn summarize(&self) -> MarkerSummary<'_, 'a> {
+        MarkerSummary { marker: self }
+    }
+}
+
+impl<'m, 'a> fmt::Display for MarkerSummary<'m, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.marker {
+            Marker::Other(ref marker) => {
+                write_marker_name(f, marker.tag)?;
+                match marker.tag {
+                    0xe0..=0xef => {
+                        if let Some(identifier) = identifier(marker.data) {
+                            write!(f, ": {}", identifier)?;
+                        }
+                        write!(f, ", {} bytes", marker.data.len())
+                    }
+                    0xfe => {
+                        f.write_str(": ")?;
+                        write_text(f, marker.data)?;
+                        write!(f, ", {} bytes", marker.data.len())
+                    }
+                    _ => {
+                        write!(f, ", {} bytes:", marker.data.len())?;
+                        for byte in marker.data.iter().take(MAX_SHOWN_BYTES / 3) {
+                            write!(f, " {:02x}", byte)?;
+                        }
+                        if marker.data.len() > MAX_SHOWN_BYTES / 3 {
+                            f.write_str(" ...")?;
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            Marker::DHT(ref tables) => {
+                f.write_str("DHT")?;
+                tables
+                    .iter()
+                    .try_for_each(|table| write_huffman_table(f, table))
+            }
+            Marker::DQT(ref tables) => {
+                f.write_str("DQT")?;
+                tables
+                    .iter()
+                    .try_for_each(|table| write_quantization_table(f, table))
+            }
+            Marker::DRI(interval) => write!(f, "DRI: restart interval of {} MCUs", interval),
+            Marker::SOF(ref frame) => {
+                write_marker_name(f, frame.marker)?;
+                f.write_str(": ")?;
+                write_frame(f, frame)
+            }
+            Marker::Image(ref image) => {
+                f.write_str("SOS: ")?;
+                write_scan(f, &image.metadata)?;
+                write!(f, "\n  {} bytes of entropy coded data", image.data.len())
+            }
+        }
+    }
+}
+
+fn write_frame(f: &mut fmt::Formatter, frame: &StartOfFrameMetaData) -> fmt::Result {
+    write!(
+        f,
+        "{}, {} bit, {}x{} pixels, {} components",
+        process_name(frame.marker),
+        frame.precision,
+        frame.width,
+        frame.height,
+        frame.component_metadata.len()
+    )?;
+    for component in &frame.component_metadata {
+        write!(
+            f,
+            "\n  component {}: {}x{} sampling, quantization table {}",
+            component.id,
+            component.horizontal_sampling(),
+            component.vertical_sampling(),
+            component.quantization_table
+        )?;
+    }
+    Ok(())
+}
+
+fn write_scan(f: &mut fmt::Formatter, scan: &StartOfStreamMetaData) -> fmt::Result {
+    write!(
+        f,
+        "coefficients {} to {}, successive approximation {} to {}",
+        scan.spectral_start, scan.spectral_end, scan.approximation_high, scan.approximation_low
+    )?;
+    for component in &scan.component_metadata {
+        write!(
+            f,
+            "\n  component {}: DC table {}, AC table {}",
+            component.id, component.dc_table, component.ac_table
+        )?;
+    }
+    Ok(())
+}
+
+// write_huffman_table shows the number of codes of every length below the lengths, followed by the codes of
+// the symbols in the alternate format
+fn write_huffman_table(f: &mut fmt::Formatter, table: &HuffmanTable) -> fmt::Result {
+    let (class, id) = table.class.selector();
+    let kind = if class == 0 { "DC" } else { "AC" };
+    let total: usize = table.counts.iter().map(|&count| usize::from(count)).sum();
+    write!(f, "\n  {} table {}, {} codes:\n    length", kind, id, total)?;
+    for length in 1..=table.counts.len() {
+        write!(f, " {:3}", length)?;
+    }
+    f.write_str("\n    codes ")?;
+    for count in &table.counts {
+        write!(f, " {:3}", count)?;
+    }
+    if f.alternate() {
+        for code in table.codes() {
+            let length = usize::from(code.length);
+            write!(
+                f,
+                "\n    symbol 0x{:02x}: {:02$b}",
+                code.symbol, code.code, length
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// write_quantization_table shows the table as an 8x8 grid in natural order, as the DCT sees it
+fn write_quantization_table(f: &mut fmt::Formatter, table: &QuantizationTable) -> fmt::Result {
+    let bits = if table.precision == 0 { 8 } else { 16 };
+    write!(f, "\n  table {}, {} bit entries:", table.id, bits)?;
+    let mut natural = [0u16; 64];
+    for (k, &position) in ZIGZAG.iter().enumerate() {
+        natural[position] = if table.precision == 0 {
+            u16::from(table.data[k])
+        } else {
+            u16::from_be_bytes([table.data[2 * k], table.data[2 * k + 1]])
+        };
+    }
+    for row in natural.chunks(8) {
+        f.write_str("\n   ")?;
+        for entry in row {
+            write!(f, "{:5}", entry)?;
+        }
+    }
+    Ok(())
+}
+
+// write_text writes the start of data as a quoted string, escaping bytes that aren't printable ASCII
+fn write_text(f: &mut fmt::Formatter, data: &[u8]) -> fmt::Result {
+    f.write_str("\"")?;
+    for &byte in data.iter().take(MAX_SHOWN_BYTES) {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+            0x20..=0x7e => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\x{:02x}", byte)?,
+        }
+    }
+    f.write_str("\"")?;
+    if data.len() > MAX_SHOWN_BYTES {
+        f.write_str("...")?;
+    }
+    Ok(())
+}
+
+// identifier returns the NUL terminated signature APPn segments start with, like JFIF or Exif
+fn identifier(data: &[u8]) -> Option<&str> {
+    let end = data.iter().take(64).position(|&byte| byte == 0)?;
+    let identifier = &data[..end];
+    if identifier.is_empty() || !identifier.iter().all(|byte| (0x20..0x7f).contains(byte)) {
+        return None;
+    }
+    str::from_utf8(identifier).ok()
+}
+
+pub(crate) fn write_marker_name(f: &mut fmt::Formatter, tag: u8) -> fmt::Result {
+    match tag {
+        0xc4 => f.write_str("DHT"),
+        0xc8 => f.write_str("JPG"),
+        0xcc => f.write_str("DAC"),
+        0xc0..=0xcf => write!(f, "SOF{}", tag - 0xc0),
+        0xd0..=0xd7 => write!(f, "RST{}", tag - 0xd0),
+        0xd8 => f.write_str("SOI"),
+        0xd9 => f.write_str("EOI"),
+        0xda => f.write_str("SOS"),
+        0xdb => f.write_str("DQT"),
+        0xdc => f.write_str("DNL"),
+        0xdd => f.write_str("DRI"),
+        0xe0..=0xef => write!(f, "APP{}", tag - 0xe0),
+        0xfe => f.write_str("COM"),
+        _ => write!(f, "marker {:#04x}", tag),
+    }
+}
+
+fn process_name(marker: u8) -> &'static str {
+    match marker {
+        0xc0 => "baseline",
+        0xc1 => "extended sequential",
+        0xc2 => "progressive",
+        0xc3 => "lossless",
+        0xc5 => "differential sequential",
+        0xc6 => "differential progressive",
+        0xc7 => "differential lossless",
+        0xc9 => "arithmetic sequential",
+        0xca => "arithmetic progressive",
+        0xcb => "arithmetic lossless",
+        0xcd => "arithmetic differential sequential",
+        0xce => "arithmetic differential progressive",
+        0xcf => "arithmetic differential lossless",
+        _ => "unknown process",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::ColorType;
+    use encoder::{encode_image_with_options, EncodeOptions};
+    use parser::{decode, SomeMarker};
+
+    #[test]
+    fn summarize_test() {
+        let options = EncodeOptions {
+            comments: vec![[&b"a \"long\" comment\x01"[..], &[b'x'; 60]].concat()],
+            restart_interval: 2,
+            ..EncodeOptions::default()
+        };
+        let file =
+            encode_image_with_options(&[100; 16 * 8 * 3], 16, 8, ColorType::Rgb, &options).unwrap();
+        let summaries: Vec<String> = decode(&file)
+            .unwrap()
+            .iter()
+            .map(|marker| marker.summarize().to_string())
+            .collect();
+
+        assert_eq!(summaries[0], "APP0: JFIF, 14 bytes");
+        assert_eq!(
+            summaries[1],
+            format!(
+                "COM: \"a \\\"long\\\" comment\\x01{}\"..., 77 bytes",
+                "x".repeat(31)
+            )
+        );
+        let dqt = summaries.iter().find(|s| s.starts_with("DQT")).unwrap();
+        // the standard luminance table at quality 75 starts with 8 6 5 in its first row
+        assert!(dqt.starts_with(
+            "DQT\n  table 0, 8 bit entries:\n       8    6    5    8   12   20   26   31\n"
+        ));
+        assert_eq!(dqt.lines().count(), 19);
+        let dht = summaries.iter().find(|s| s.starts_with("DHT")).unwrap();
+        assert!(dht.starts_with(concat!(
+            "DHT\n  DC table 0, 12 codes:\n",
+            "    length   1   2   3   4   5   6   7   8   9  10  11  12  13  14  15  16\n",
+            "    codes    0   1   5   1   1   1   1   1   1   0   0   0   0   0   0   0\n",
+        )));
+        let markers = decode(&file).unwrap();
+        let tables = markers
+            .iter()
+            .find(|m| matches!(m, Marker::DHT(_)))
+            .unwrap();
+        let codes = format!("{:#}", tables.summarize());
+        // the codes of a table follow its histogram
+        let histogram: Vec<&str> = dht.lines().take(4).collect();
+        assert!(codes.starts_with(&histogram.join("\n")));
+        assert!(codes.contains("\n    symbol 0x00: 00\n    symbol 0x01: 010\n"));
+        assert!(codes.contains("\n    symbol 0x0b: 111111110\n"));
+        assert!(summaries.contains(&"DRI: restart interval of 2 MCUs".to_string()));
+        assert!(summaries.contains(
+            &concat!(
+                "SOF0: baseline, 8 bit, 16x8 pixels, 3 components\n",
+                "  component 1: 2x2 sampling, quantization table 0\n",
+                "  component 2: 1x1 sampling, quantization table 1\n",
+                "  component 3: 1x1 sampling, quantization table 1",
+            )
+            .to_string()
+        ));
+        let sos = summaries.last().unwrap();
+        assert!(sos.starts_with(concat!(
+            "SOS: coefficients 0 to 63, successive approximation 0 to 0\n",
+            "  component 1: DC table 0, AC table 0\n",
+        )));
+        assert!(sos.ends_with(" bytes of entropy coded data"));
+
+        let unknown = Marker::Other(SomeMarker {
+            tag: 0xf0,
+            length: 20,
+            data: &[0xab; 18],
+        });
+        assert_eq!(
+            unknown.summarize().to_string(),
+            format!("marker 0xf0, 18 bytes:{} ...", " ab".repeat(16))
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use parser::{header_segments, HeaderSegment};
+#[cfg(not(feature = "std"))]
+use prelude::*;
+use tiff::Tiff;
+
+pub(crate) const JPEG_INTERCHANGE_FORMAT_TAG: u16 = 0x0201;
+pub(crate) const JPEG_INTERCHANGE_FORMAT_LENGTH_TAG: u16 = 0x0202;
+
+const JFXX_JPEG: u8 = 0x10;
+const JFXX_PALETTE: u8 = 0x11;
+const JFXX_RGB: u8 = 0x13;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Thumbnail<'a> {
+    // Jpeg is a complete embedded JPEG file which can be fed into `decode` again
+    Jpeg(&'a [u8]),
+    // Rgb is an uncompressed thumbnail, palette based JFXX thumbnails are expanded to RGB
+    Rgb(RgbThumbnail),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct RgbThumbnail {
+    pub width: u64,
+    pub height: u64,
+    // pixels contains width * height interleaved RGB triplets
+    pub pixels: Vec<u8>,
+}
+
+// read_thumbnail looks for an embedded thumbnail in JFIF, JFXX and EXIF segments, in that order. Only the
+// segments in front of the first scan are inspected, the main image isn't decoded.
+pub fn read_thumbnail(jpeg_file: &[u8]) -> Option<Thumbnail<'_>> {
+    let segments = header_segments(jpeg_file).ok()?;
+
+    segments
+        .iter()
+        .filter_map(jfif_thumbnail)
+        .chain(segments.iter().filter_map(jfxx_thumbnail))
+        .chain(segments.iter().filter_map(exif_thumbnail))
+        .next()
+}
+
+fn jfif_thumbnail<'a>(segment: &HeaderSegment<'a>) -> Option<Thumbnail<'a>> {
+    if segment.tag != 0xe0 || !segment.data.starts_with(b"JFIF\x00") {
+        return None;
+    }
+
+    // identifier (5), version (2), units (1), density (4)
+    let header = segment.data.get(12..14)?;
+    rgb_thumbnail(header[0], header[1], &segment.data[14..])
+}
+
+fn jfxx_thumbnail<'a>(segment: &HeaderSegment<'a>) -> Option<Thumbnail<'a>> {
+    if segment.tag != 0xe0 || !segment.data.starts_with(b"JFXX\x00") {
+        return None;
+    }
+
+    let extension = *segment.data.get(5)?;
+    let data = &segment.data[6..];
+    match extension {
+        JFXX_JPEG => Some(Thumbnail::Jpeg(data)),
+        JFXX_PALETTE => palette_thumbnail(*data.first()?, *data.get(1)?, &data[2..]),
+        JFXX_RGB => rgb_thumbnail(*data.first()?, *data.get(1)?, &data[2..]),
+        _ => None,
+    }
+}
+
+fn exif_thumbnail<'a>(segment: &HeaderSegment<'a>) -> Option<Thumbnail<'a>> {
+    if segment.tag != 0xe1 || !segment.data.starts_with(b"Exif\x00\x00") {
+        return None;
+    }
+
+    let tiff = Tiff::new(&segment.data[6..]).ok()?;
+    let (_, ifd1) = tiff.read_ifd(tiff.first_ifd_offset().ok()?).ok()?;
+    if ifd1 == 0 {
+        return None;
+    }
+
+    let (entries, _) = tiff.read_ifd(ifd1).ok()?;
+    let value = |tag| {
+        entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.value_offset as usize)
+    };
+
+    let offset = value(JPEG_INTERCHANGE_FORMAT_TAG)?;
+    let length = value(JPEG_INTERCHANGE_FORMAT_LENGTH_TAG)?;
+    tiff.bytes(offset, length).ok().map(Thumbnail::Jpeg)
+}
+
+fn rgb_thumbnail<'a>(width: u8, height: u8, data: &[u8]) -> Option<Thumbnail<'a>> {
+    let size = width as usize * height as usize * 3;
+    if size == 0 {
+        return None;
+    }
+
+    Some(Thumbnail::Rgb(RgbThumbnail {
+        width: width.into(),
+        height: height.into(),
+        pixels: data.get(..size)?.to_vec(),
+    }))
+}
+
+// palette_thumbnail expands a thumbnail of one byte indices into a 256 entry RGB palette in front of them
+fn palette_thumbnail<'a>(width: u8, height: u8, data: &[u8]) -> Option<Thumbnail<'a>> {
+    let size = width as usize * height as usize;
+    if size == 0 || data.len() < 768 + size {
+        return None;
+    }
+
+    let (palette, indices) = data.split_at(768);
+    let pixels = indices[..size]
+        .iter()
+        .flat_map(|&index| {
+            palette[index as usize * 3..index as usize * 3 + 3]
+                .iter()
+                .cloned()
+        })
+        .collect();
+
+    Some(Thumbnail::Rgb(RgbThumbnail {
+        width: width.into(),
+        height: height.into(),
+        pixels,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_jpeg(segment_tag: u8, segment: &[u8]) -> Vec<u8> {
+        let mut file = vec![0xFF, 0xD8, 0xFF, segment_tag];
+        file.extend(&((segment.len() + 2) as u16).to_be_bytes());
+        file.extend(segment);
+        file.extend(&[0xFF, 0xD9]);
+        file
+    }
+
+    #[test]
+    fn jfif_thumbnail_test() {
+        let mut segment = b"JFIF\x00\x01\x02\x00\x00\x01\x00\x01\x02\x01".to_vec();
+        segment.extend(&[0x10, 0x20, 0x30, 0x40, 0x50, 0x60]);
+
+        assert_eq!(
+            read_thumbnail(&make_jpeg(0xe0, &segment)),
+            Some(Thumbnail::Rgb(RgbThumbnail {
+                width: 2,
+                height: 1,
+                pixels: vec![0x10, 0x20, 0x30, 0x40, 0x50, 0x60],
+            }))
+        );
+    }
+
+    #[test]
+    fn jfif_without_thumbnail_test() {
+        let segment = b"JFIF\x00\x01\x02\x00\x00\x01\x00\x01\x00\x00".to_vec();
+        assert_eq!(read_thumbnail(&make_jpeg(0xe0, &segment)), None);
+    }
+
+    #[test]
+    fn jfxx_jpeg_thumbnail_test() {
+        let mut segment = b"JFXX\x00\x10".to_vec();
+        segment.extend(&[0xFF, 0xD8, 0xFF, 0xD9]);
+
+        assert_eq!(
+            read_thumbnail(&make_jpeg(0xe0, &segment)),
+            Some(Thumbnail::Jpeg(&[0xFF, 0xD8, 0xFF, 0xD9]))
+        );
+    }
+
+    #[test]
+    fn jfxx_palette_thumbnail_test() {
+        let mut segment = b"JFXX\x00\x11\x02\x01".to_vec();
+        let mut palette = vec![0; 768];
+        palette[3..6].copy_from_slice(&[0x10, 0x20, 0x30]);
+        palette[765..].copy_from_slice(&[0x40, 0x50, 0x60]);
+        segment.extend(palette);
+        segment.extend(&[0xFF, 0x01]);
+
+        assert_eq!(
+            read_thumbnail(&make_jpeg(0xe0, &segment)),
+            Some(Thumbnail::Rgb(RgbThumbnail {
+                width: 2,
+                height: 1,
+                pixels: vec![0x40, 0x50, 0x60, 0x10, 0x20, 0x30],
+            }))
+        );
+
+        segment.pop();
+        assert_eq!(read_thumbnail(&make_jpeg(0xe0, &segment)), None);
+    }
+
+    #[test]
+    fn jfxx_rgb_thumbnail_test() {
+        let mut segment = b"JFXX\x00\x13\x01\x02".to_vec();
+        segment.extend(&[0x10, 0x20, 0x30, 0x40, 0x50, 0x60]);
+
+        assert_eq!(
+            read_thumbnail(&make_jpeg(0xe0, &segment)),
+            Some(Thumbnail::Rgb(RgbThumbnail {
+                width: 1,
+                height: 2,
+                pixels: vec![0x10, 0x20, 0x30, 0x40, 0x50, 0x60],
+            }))
+        );
+    }
+
+    #[test]
+    fn exif_thumbnail_test() {
+        let mut segment = b"Exif\x00\x00".to_vec();
+        segment.extend(&[
+            0x49, 0x49, 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, // header
+            0x00, 0x00, 0x0E, 0x00, 0x00, 0x00, // empty IFD0 pointing to IFD1
+            0x02, 0x00, // two entries
+            0x01, 0x02, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x2C, 0x00, 0x00, 0x00, // offset
+            0x02, 0x02, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // length
+            0x00, 0x00, 0x00, 0x00, // no next IFD
+            0xFF, 0xD8, 0xFF, 0xD9,
+        ]);
+
+        assert_eq!(
+            read_thumbnail(&make_jpeg(0xe1, &segment)),
+            Some(Thumbnail::Jpeg(&[0xFF, 0xD8, 0xFF, 0xD9]))
+        );
+    }
+
+    #[test]
+    fn no_thumbnail_test() {
+        assert_eq!(read_thumbnail(&make_jpeg(0xfe, b"comment")), None);
+        assert_eq!(read_thumbnail(&[0x00, 0x01]), None);
+    }
+}
@@ -0,0 +1,130 @@
+use errors::ParserError;
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IfdEntry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    // value_offset holds the value itself if it fits into four bytes, otherwise the offset of the value
+    pub value_offset: u32,
+}
+
+// Tiff reads the TIFF structure used by EXIF and MPF segments. All offsets are relative to the start of the
+// TIFF header, i.e. the byte order mark.
+#[derive(Debug)]
+pub struct Tiff<'a> {
+    pub data: &'a [u8],
+    pub byte_order: ByteOrder,
+}
+
+impl<'a> Tiff<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Tiff<'a>, ParserError> {
+        let byte_order = match data.get(0..4) {
+            Some(b"II\x2a\x00") => ByteOrder::LittleEndian,
+            Some(b"MM\x00\x2a") => ByteOrder::BigEndian,
+            _ => return Err(ParserError::InvalidTiffHeader),
+        };
+
+        Ok(Tiff { data, byte_order })
+    }
+
+    pub fn first_ifd_offset(&self) -> Result<usize, ParserError> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    // read_ifd returns the entries of the IFD at offset and the offset of the next IFD (0 if there is none)
+    pub fn read_ifd(&self, offset: usize) -> Result<(Vec<IfdEntry>, usize), ParserError> {
+        let count = self.u16_at(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+
+        for i in 0..count {
+            let entry = offset + 2 + i * 12;
+            entries.push(IfdEntry {
+                tag: self.u16_at(entry)?,
+                field_type: self.u16_at(entry + 2)?,
+                count: self.u32_at(entry + 4)?,
+                value_offset: self.u32_at(entry + 8)?,
+            });
+        }
+
+        let next = self.u32_at(offset + 2 + count * 12)? as usize;
+        Ok((entries, next))
+    }
+
+    pub fn bytes(&self, offset: usize, length: usize) -> Result<&'a [u8], ParserError> {
+        offset
+            .checked_add(length)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or(ParserError::OutOfBounds { offset, length })
+    }
+
+    pub fn u16_at(&self, offset: usize) -> Result<u16, ParserError> {
+        let bytes = self.bytes(offset, 2)?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(match self.byte_order {
+            ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+        })
+    }
+
+    pub fn u32_at(&self, offset: usize) -> Result<u32, ParserError> {
+        let bytes = self.bytes(offset, 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(match self.byte_order {
+            ByteOrder::LittleEndian => u32::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u32::from_be_bytes(bytes),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_ifd_test() {
+        let little_endian = vec![
+            0x49, 0x49, 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x12, 0x01, 0x03, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let tiff = Tiff::new(&little_endian).unwrap();
+
+        assert_eq!(tiff.byte_order, ByteOrder::LittleEndian);
+        assert_eq!(tiff.first_ifd_offset(), Ok(8));
+        assert_eq!(
+            tiff.read_ifd(8),
+            Ok((
+                vec![IfdEntry {
+                    tag: 0x0112,
+                    field_type: 3,
+                    count: 1,
+                    value_offset: 6,
+                }],
+                0
+            ))
+        );
+    }
+
+    #[test]
+    fn big_endian_test() {
+        let big_endian = vec![0x4D, 0x4D, 0x00, 0x2A, 0x00, 0x00, 0x00, 0x08];
+        let tiff = Tiff::new(&big_endian).unwrap();
+
+        assert_eq!(tiff.byte_order, ByteOrder::BigEndian);
+        assert_eq!(tiff.first_ifd_offset(), Ok(8));
+        assert!(tiff.read_ifd(8).is_err());
+    }
+
+    #[test]
+    fn invalid_header_test() {
+        assert!(Tiff::new(b"XX\x2a\x00").is_err());
+    }
+}
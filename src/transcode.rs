@@ -0,0 +1,210 @@
+// Re-encoding of JPEG files with new settings, carrying their metadata over.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use decoder::{decode_image_with_options, DecodeOptions, Limits};
+use encoder::{
+    encode_image_with_options, ChromaSubsampling, Density, DensityUnit, EncodeOptions, EXIF_HEADER,
+    XMP_HEADER,
+};
+use errors::Error;
+use icc::read_icc_profile;
+use parser::{header_segments, HeaderSegment};
+
+// TranscodeOptions selects the encoding settings of transcode, see EncodeOptions for their meaning
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TranscodeOptions {
+    pub quality: u8,
+    pub subsampling: ChromaSubsampling,
+    pub progressive: bool,
+    pub optimize_huffman: bool,
+    // copy_metadata carries the EXIF, XMP, ICC profile and comment segments and the JFIF density of the input
+    // over. Without it the EXIF orientation is applied to the pixels, as it would be lost otherwise.
+    pub copy_metadata: bool,
+    // limits rejects inputs exceeding them before decoding
+    pub limits: Limits,
+}
+
+impl Default for TranscodeOptions {
+    fn default() -> TranscodeOptions {
+        TranscodeOptions {
+            quality: 75,
+            subsampling: ChromaSubsampling::default(),
+            progressive: false,
+            optimize_huffman: true,
+            copy_metadata: true,
+            limits: Limits::default(),
+        }
+    }
+}
+
+// transcode decodes input and encodes its pixels again with the given settings, typically to shrink a file.
+// Gray files stay gray, all others are encoded as RGB. Thumbnails inside the EXIF data are copied as they are.
+pub fn transcode(input: &[u8], options: &TranscodeOptions) -> Result<Vec<u8>, Error> {
+    let mut encode_options = EncodeOptions {
+        quality: options.quality,
+        subsampling: options.subsampling,
+        progressive: options.progressive,
+        optimize_huffman: options.optimize_huffman,
+        ..EncodeOptions::default()
+    };
+    if options.copy_metadata {
+        copy_metadata(input, &mut encode_options)?;
+    }
+
+    // pixels stay as stored when the orientation is copied along with the EXIF data
+    let decode_options = DecodeOptions {
+        limits: options.limits,
+        apply_orientation: encode_options.exif.is_none(),
+        ..DecodeOptions::default()
+    };
+    let image = decode_image_with_options(input, &decode_options)?;
+    encode_image_with_options(
+        &image.pixels,
+        image.width,
+        image.height,
+        image.color_type,
+        &encode_options,
+    )
+}
+
+// copy_metadata sets the metadata options of options to the metadata of jpeg_file
+pub(crate) fn copy_metadata(jpeg_file: &[u8], options: &mut EncodeOptions) -> Result<(), Error> {
+    for HeaderSegment { tag, data, .. } in header_segments(jpeg_file)? {
+        match tag {
+            0xe0 if options.density == Density::default() => {
+                if let Some(density) = jfif_density(data) {
+                    options.density = density;
+                }
+            }
+            0xe1 if data.starts_with(EXIF_HEADER) && options.exif.is_none() => {
+                options.exif = Some(data[EXIF_HEADER.len()..].to_vec());
+            }
+            0xe1 if data.starts_with(XMP_HEADER) && options.xmp.is_none() => {
+                options.xmp = Some(data[XMP_HEADER.len()..].to_vec());
+            }
+            0xfe => options.comments.push(data.to_vec()),
+            _ => {}
+        }
+    }
+    options.icc_profile = read_icc_profile(jpeg_file);
+    Ok(())
+}
+
+// jfif_density returns the density of a JFIF APP0 segment's content, None for other segments and zero densities
+fn jfif_density(data: &[u8]) -> Option<Density> {
+    if !data.starts_with(b"JFIF\x00") || data.len() < 12 {
+        return None;
+    }
+    let unit = match data[7] {
+        0 => DensityUnit::AspectRatio,
+        1 => DensityUnit::DotsPerInch,
+        2 => DensityUnit::DotsPerCentimeter,
+        _ => return None,
+    };
+    let x = u16::from_be_bytes([data[8], data[9]]);
+    let y = u16::from_be_bytes([data[10], data[11]]);
+    if x == 0 || y == 0 {
+        return None;
+    }
+    Some(Density { unit, x, y })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::{decode_image, ColorType};
+    use exif::{read_orientation, Orientation};
+
+    #[test]
+    fn transcode_test() {
+        let (width, height) = (48u64, 32u64);
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                vec![(x * 5) as u8, (y * 7) as u8, ((x * y) % 256) as u8]
+            })
+            .collect();
+        // EXIF with orientation 6 in a big endian IFD0
+        let exif = [
+            &b"MM\x00\x2a\x00\x00\x00\x08\x00\x01"[..],
+            b"\x01\x12\x00\x03\x00\x00\x00\x01\x00\x06\x00\x00",
+            b"\x00\x00\x00\x00",
+        ]
+        .concat();
+        let input_options = EncodeOptions {
+            quality: 95,
+            subsampling: ChromaSubsampling::Chroma444,
+            density: Density {
+                unit: DensityUnit::DotsPerInch,
+                x: 300,
+                y: 300,
+            },
+            exif: Some(exif.clone()),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            icc_profile: Some(vec![7; 100]),
+            comments: vec![b"first".to_vec(), b"second".to_vec()],
+            ..EncodeOptions::default()
+        };
+        let input =
+            encode_image_with_options(&pixels, width, height, ColorType::Rgb, &input_options)
+                .unwrap();
+
+        let options = TranscodeOptions {
+            quality: 50,
+            subsampling: ChromaSubsampling::Chroma420,
+            progressive: true,
+            ..TranscodeOptions::default()
+        };
+        let output = transcode(&input, &options).unwrap();
+        assert!(output.len() < input.len());
+        let mut copied = EncodeOptions::default();
+        copy_metadata(&output, &mut copied).unwrap();
+        assert_eq!(copied.density, input_options.density);
+        assert_eq!(copied.exif, Some(exif));
+        assert_eq!(copied.xmp, input_options.xmp);
+        assert_eq!(copied.icc_profile, input_options.icc_profile);
+        assert_eq!(copied.comments, input_options.comments);
+        // the pixels keep their stored orientation
+        assert_eq!(read_orientation(&output), Some(Orientation::Rotate90));
+        let stored = DecodeOptions {
+            apply_orientation: false,
+            ..DecodeOptions::default()
+        };
+        let image = decode_image_with_options(&output, &stored).unwrap();
+        assert_eq!((image.width, image.height), (48, 32));
+
+        // without metadata the orientation is applied
+        let options = TranscodeOptions {
+            copy_metadata: false,
+            ..TranscodeOptions::default()
+        };
+        let output = transcode(&input, &options).unwrap();
+        let mut copied = EncodeOptions::default();
+        copy_metadata(&output, &mut copied).unwrap();
+        assert_eq!(copied.exif, None);
+        assert_eq!(copied.icc_profile, None);
+        assert!(copied.comments.is_empty());
+        let image = decode_image(&output).unwrap();
+        assert_eq!((image.width, image.height), (32, 48));
+        assert_eq!(image.color_type, ColorType::Rgb);
+
+        let gray =
+            encode_image_with_options(&[128; 64], 8, 8, ColorType::Gray, &EncodeOptions::default())
+                .unwrap();
+        let output = transcode(&gray, &TranscodeOptions::default()).unwrap();
+        assert_eq!(decode_image(&output).unwrap().color_type, ColorType::Gray);
+
+        let limits = TranscodeOptions {
+            limits: Limits {
+                max_width: Some(16),
+                ..Limits::default()
+            },
+            ..TranscodeOptions::default()
+        };
+        assert!(transcode(&input, &limits).is_err());
+        assert!(transcode(b"\xff\xd8\xff", &TranscodeOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,900 @@
+// Lossless rotation, mirroring and cropping of JPEG files in the DCT domain, like jpegtran.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use decoder::{read_coefficients, ComponentCoefficients, DecodeOptions, DecodedCoefficients};
+use encoder::{encode_coefficients, EncodeOptions, Scan};
+use errors::{EncodeError, Error};
+use exif::Orientation;
+use info::read_info;
+use transcode::copy_metadata;
+
+// Crop is a rectangle of pixels, x and y are the offsets of its top left corner
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Crop {
+    pub x: u64,
+    pub y: u64,
+    pub width: u64,
+    pub height: u64,
+}
+
+// TransformOptions selects what transform_jpeg does to a file
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TransformOptions {
+    // orientation is the transformation applied to the image, the one displaying files with this EXIF orientation
+    // upright
+    pub orientation: Orientation,
+    // crop keeps the given rectangle of the transformed image. Its top left corner is moved up and left to the
+    // nearest MCU boundary, keeping the bottom right corner, and parts outside of the image are left out. The
+    // dimensions of the output tell the rectangle that was kept.
+    pub crop: Option<Crop>,
+    // copy_metadata carries the metadata of the input over like transcode does. The EXIF orientation and
+    // thumbnail are copied as they are.
+    pub copy_metadata: bool,
+    // progressive selects the process of the output, None keeps the one of the input. Converting between the
+    // processes only codes the coefficients differently, like jpegtran -progressive, so the pixels stay the same.
+    pub progressive: Option<bool>,
+    // optimize_scans searches progressive scan scripts for the one coding the coefficients into the fewest bytes,
+    // like jpegrescan, trying how the DC coefficients are grouped, where the AC bands split and how many bits
+    // successive approximation holds back. Each choice is tried in turn with the best ones before it, which takes
+    // about 20 encodes. It only applies to progressive output.
+    pub optimize_scans: bool,
+}
+
+impl Default for TransformOptions {
+    fn default() -> TransformOptions {
+        TransformOptions {
+            orientation: Orientation::Normal,
+            crop: None,
+            copy_metadata: true,
+            progressive: None,
+            optimize_scans: false,
+        }
+    }
+}
+
+// transform_jpeg rotates and mirrors the DCT blocks of jpeg_file without decoding them to pixels, so the image
+// loses no quality. Mirroring would move the partial MCUs at the right or bottom edge to the opposite side, so
+// like jpegtran -trim they are dropped. The output uses optimized Huffman tables and is progressive if jpeg_file
+// is, unless options select the process.
+pub fn transform_jpeg(jpeg_file: &[u8], options: &TransformOptions) -> Result<Vec<u8>, Error> {
+    let info = read_info(jpeg_file)?;
+    let coefficients = read_coefficients(jpeg_file, &DecodeOptions::default())?;
+    let mut transformed = transform_coefficients(&coefficients, options.orientation)?;
+    if let Some(crop) = options.crop {
+        transformed = crop_coefficients(&transformed, crop)?;
+    }
+
+    let mut encode_options = EncodeOptions {
+        progressive: options.progressive.unwrap_or(info.progressive),
+        optimize_huffman: true,
+        ..EncodeOptions::default()
+    };
+    if options.copy_metadata {
+        copy_metadata(jpeg_file, &mut encode_options)?;
+    }
+    if options.optimize_scans && encode_options.progressive {
+        return encode_smallest(&transformed, &encode_options);
+    }
+    encode_coefficients(&transformed, &encode_options)
+}
+
+// optimize_jpeg codes the coefficients of jpeg_file again with Huffman tables built from them, like jpegtran
+// -optimize. Files written with the standard tables typically shrink by a few percent without any change to
+// their pixels. The process and metadata of jpeg_file are kept.
+pub fn optimize_jpeg(jpeg_file: &[u8]) -> Result<Vec<u8>, Error> {
+    transform_jpeg(jpeg_file, &TransformOptions::default())
+}
+
+// modify_coefficients reads the quantized DCT coefficients of jpeg_file, lets modify change them and codes them
+// again with the quantization tables of the components, like transform_jpeg with its default options. Only the
+// coefficients modify changes are different in the output, which keeps the process and metadata of jpeg_file.
+// Progressive files leave out the AC coefficients of the blocks past the right and bottom edges of the image that
+// only complete MCUs, so changing them has no effect.
+pub fn modify_coefficients<F>(jpeg_file: &[u8], modify: F) -> Result<Vec<u8>, Error>
+where
+    F: FnOnce(&mut [ComponentCoefficients]),
+{
+    let info = read_info(jpeg_file)?;
+    let mut coefficients = read_coefficients(jpeg_file, &DecodeOptions::default())?;
+    modify(&mut coefficients.components);
+
+    let mut options = EncodeOptions {
+        progressive: info.progressive,
+        optimize_huffman: true,
+        ..EncodeOptions::default()
+    };
+    copy_metadata(jpeg_file, &mut options)?;
+    encode_coefficients(&coefficients, &options)
+}
+
+// DcGrouping is how the DC coefficients of color images are split over scans
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum DcGrouping {
+    Interleaved,
+    LumaSeparate,
+    Separate,
+}
+
+// SPLITS are the last coefficients of the first AC band optimize_scans tries, 63 codes all of them in one band
+const SPLITS: [usize; 6] = [2, 5, 8, 12, 18, 63];
+
+// ScanChoices are the parameters of a progressive scan script. split and approximation hold the values for luma
+// and chroma, approximation being the number of low bits coded in refinement scans.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct ScanChoices {
+    dc_grouping: DcGrouping,
+    dc_approximation: u8,
+    split: [usize; 2],
+    approximation: [u8; 2],
+}
+
+impl Default for ScanChoices {
+    // default returns the choices of libjpeg's standard script
+    fn default() -> ScanChoices {
+        ScanChoices {
+            dc_grouping: DcGrouping::Interleaved,
+            dc_approximation: 1,
+            split: [5, 63],
+            approximation: [2, 1],
+        }
+    }
+}
+
+impl ScanChoices {
+    // script returns the scans for count components: the DC coefficients first, then the AC bands of every
+    // component without their low bits and finally the refinement scans, highest bits first
+    fn script(&self, count: usize) -> Vec<Scan> {
+        let scan = |components: Vec<usize>, (start, end), high, low| Scan {
+            components,
+            spectral_start: start,
+            spectral_end: end,
+            approximation_high: high,
+            approximation_low: low,
+        };
+        let dc_groups: Vec<Vec<usize>> = match (count, self.dc_grouping) {
+            (1, _) | (_, DcGrouping::Interleaved) => vec![(0..count).collect()],
+            (_, DcGrouping::LumaSeparate) => vec![vec![0], (1..count).collect()],
+            (_, DcGrouping::Separate) => (0..count).map(|c| vec![c]).collect(),
+        };
+        let kind = |component: usize| usize::from(component > 0);
+
+        let mut script = Vec::new();
+        for group in &dc_groups {
+            script.push(scan(group.clone(), (0, 0), 0, self.dc_approximation));
+        }
+        for component in 0..count {
+            let (split, low) = (
+                self.split[kind(component)],
+                self.approximation[kind(component)],
+            );
+            script.push(scan(vec![component], (1, split), 0, low));
+            if split < 63 {
+                script.push(scan(vec![component], (split + 1, 63), 0, low));
+            }
+        }
+        let bits = self
+            .approximation
+            .iter()
+            .fold(self.dc_approximation, |bits, &low| bits.max(low));
+        for bit in (0..bits).rev() {
+            if self.dc_approximation > bit {
+                for group in &dc_groups {
+                    script.push(scan(group.clone(), (0, 0), bit + 1, bit));
+                }
+            }
+            for component in 0..count {
+                if self.approximation[kind(component)] > bit {
+                    script.push(scan(vec![component], (1, 63), bit + 1, bit));
+                }
+            }
+        }
+        script
+    }
+}
+
+// encode_smallest encodes coefficients with the progressive scan scripts optimize_scans tries and returns the
+// smallest file. Starting with libjpeg's script, every choice is set to the value giving the smallest file with
+// the choices made before it.
+fn encode_smallest(
+    coefficients: &DecodedCoefficients,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, Error> {
+    let count = coefficients.components.len();
+    let encode = |choices: &ScanChoices| {
+        let options = EncodeOptions {
+            scan_script: Some(choices.script(count)),
+            ..options.clone()
+        };
+        encode_coefficients(coefficients, &options)
+    };
+
+    let mut best = ScanChoices::default();
+    let mut smallest = encode(&best)?;
+    let kinds = if count > 1 { 2 } else { 1 };
+    for step in 0..2 + 2 * kinds {
+        let current = best;
+        let variants: Vec<ScanChoices> = match step {
+            0 if count > 1 => [
+                DcGrouping::Interleaved,
+                DcGrouping::LumaSeparate,
+                DcGrouping::Separate,
+            ]
+            .iter()
+            .map(|&dc_grouping| ScanChoices {
+                dc_grouping,
+                ..current
+            })
+            .collect(),
+            0 => Vec::new(),
+            1 => (0..3)
+                .map(|dc_approximation| ScanChoices {
+                    dc_approximation,
+                    ..current
+                })
+                .collect(),
+            _ => {
+                let kind = (step - 2) / 2;
+                if step % 2 == 0 {
+                    (0..4)
+                        .map(|approximation| {
+                            let mut variant = current;
+                            variant.approximation[kind] = approximation;
+                            variant
+                        })
+                        .collect()
+                } else {
+                    SPLITS
+                        .iter()
+                        .map(|&split| {
+                            let mut variant = current;
+                            variant.split[kind] = split;
+                            variant
+                        })
+                        .collect()
+                }
+            }
+        };
+        for variant in variants.iter().filter(|&&variant| variant != current) {
+            let file = encode(variant)?;
+            if file.len() < smallest.len() {
+                smallest = file;
+                best = *variant;
+            }
+        }
+    }
+    Ok(smallest)
+}
+
+// transform_coefficients applies orientation to the blocks of every component. Transformations are split into
+// mirroring the source horizontally and vertically, followed by transposing it.
+fn transform_coefficients(
+    coefficients: &DecodedCoefficients,
+    orientation: Orientation,
+) -> Result<DecodedCoefficients, EncodeError> {
+    let (mirror_x, mirror_y, transpose) = match orientation {
+        Orientation::Normal => (false, false, false),
+        Orientation::MirrorHorizontal => (true, false, false),
+        Orientation::Rotate180 => (true, true, false),
+        Orientation::MirrorVertical => (false, true, false),
+        Orientation::Transpose => (false, false, true),
+        Orientation::Rotate90 => (false, true, true),
+        Orientation::Transverse => (true, true, true),
+        Orientation::Rotate270 => (true, false, true),
+    };
+
+    let components = &coefficients.components;
+    let (mcu_width, mcu_height) = mcu_size(coefficients);
+    let mut width = coefficients.width;
+    let mut height = coefficients.height;
+    if mirror_x {
+        width -= width % mcu_width;
+    }
+    if mirror_y {
+        height -= height % mcu_height;
+    }
+    if width == 0 || height == 0 {
+        return Err(EncodeError::InvalidDimensions {
+            width: coefficients.width,
+            height: coefficients.height,
+        });
+    }
+    let mcus_per_line = width.div_ceil(mcu_width) as usize;
+    let mcus_per_column = height.div_ceil(mcu_height) as usize;
+
+    let mut transformed = Vec::with_capacity(components.len());
+    for component in components {
+        // the source blocks that are kept, whole MCUs along mirrored directions
+        let (h, v) = (component.horizontal_sampling, component.vertical_sampling);
+        let (blocks_per_line, blocks_per_column) = (mcus_per_line * h, mcus_per_column * v);
+        if component.blocks_per_line < blocks_per_line
+            || component.blocks_per_column < blocks_per_column
+        {
+            return Err(EncodeError::InvalidBufferSize {
+                expected: blocks_per_line * blocks_per_column,
+                actual: component.blocks.len(),
+            });
+        }
+
+        let (output_line, output_column) = if transpose {
+            (blocks_per_column, blocks_per_line)
+        } else {
+            (blocks_per_line, blocks_per_column)
+        };
+        let mut blocks = Vec::with_capacity(output_line * output_column);
+        for y in 0..output_column {
+            for x in 0..output_line {
+                let (mut source_x, mut source_y) = if transpose { (y, x) } else { (x, y) };
+                if mirror_x {
+                    source_x = blocks_per_line - 1 - source_x;
+                }
+                if mirror_y {
+                    source_y = blocks_per_column - 1 - source_y;
+                }
+                let block = component.block(source_x, source_y);
+                blocks.push(transform_block(block, (mirror_x, mirror_y, transpose)));
+            }
+        }
+
+        let (horizontal_sampling, vertical_sampling, quantization_table) = if transpose {
+            (v, h, transposed(&component.quantization_table))
+        } else {
+            (h, v, component.quantization_table)
+        };
+        transformed.push(ComponentCoefficients {
+            id: component.id,
+            horizontal_sampling,
+            vertical_sampling,
+            blocks_per_line: output_line,
+            blocks_per_column: output_column,
+            blocks,
+            quantization_table,
+        });
+    }
+
+    let (width, height) = if transpose {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    Ok(DecodedCoefficients {
+        width,
+        height,
+        components: transformed,
+        truncation: coefficients.truncation,
+    })
+}
+
+// crop_coefficients keeps the blocks of the MCUs covering crop, starting at the MCU boundary at or before its
+// top left corner
+fn crop_coefficients(
+    coefficients: &DecodedCoefficients,
+    crop: Crop,
+) -> Result<DecodedCoefficients, EncodeError> {
+    if crop.width == 0
+        || crop.height == 0
+        || crop.x >= coefficients.width
+        || crop.y >= coefficients.height
+    {
+        return Err(EncodeError::InvalidCrop {
+            x: crop.x,
+            y: crop.y,
+            width: crop.width,
+            height: crop.height,
+        });
+    }
+    let (mcu_width, mcu_height) = mcu_size(coefficients);
+    let (x, y) = (crop.x - crop.x % mcu_width, crop.y - crop.y % mcu_height);
+    let width = crop.x.saturating_add(crop.width).min(coefficients.width) - x;
+    let height = crop.y.saturating_add(crop.height).min(coefficients.height) - y;
+    let (mcu_x, mcu_y) = ((x / mcu_width) as usize, (y / mcu_height) as usize);
+    let mcus_per_line = width.div_ceil(mcu_width) as usize;
+    let mcus_per_column = height.div_ceil(mcu_height) as usize;
+
+    let mut cropped = Vec::with_capacity(coefficients.components.len());
+    for component in &coefficients.components {
+        let (h, v) = (component.horizontal_sampling, component.vertical_sampling);
+        let (first_x, first_y) = (mcu_x * h, mcu_y * v);
+        let (blocks_per_line, blocks_per_column) = (mcus_per_line * h, mcus_per_column * v);
+        if component.blocks_per_line < first_x + blocks_per_line
+            || component.blocks_per_column < first_y + blocks_per_column
+        {
+            return Err(EncodeError::InvalidBufferSize {
+                expected: (first_x + blocks_per_line) * (first_y + blocks_per_column),
+                actual: component.blocks.len(),
+            });
+        }
+
+        let mut blocks = Vec::with_capacity(blocks_per_line * blocks_per_column);
+        for block_y in first_y..first_y + blocks_per_column {
+            let line = block_y * component.blocks_per_line;
+            blocks.extend_from_slice(
+                &component.blocks[line + first_x..line + first_x + blocks_per_line],
+            );
+        }
+        cropped.push(ComponentCoefficients {
+            id: component.id,
+            horizontal_sampling: h,
+            vertical_sampling: v,
+            blocks_per_line,
+            blocks_per_column,
+            blocks,
+            quantization_table: component.quantization_table,
+        });
+    }
+
+    Ok(DecodedCoefficients {
+        width,
+        height,
+        components: cropped,
+        truncation: coefficients.truncation,
+    })
+}
+
+// mcu_size returns the width and height of the MCUs of interleaved scans in pixels
+fn mcu_size(coefficients: &DecodedCoefficients) -> (u64, u64) {
+    let components = &coefficients.components;
+    let max_horizontal_sampling = components.iter().map(|c| c.horizontal_sampling).max();
+    let max_vertical_sampling = components.iter().map(|c| c.vertical_sampling).max();
+    (
+        8 * max_horizontal_sampling.unwrap_or(1) as u64,
+        8 * max_vertical_sampling.unwrap_or(1) as u64,
+    )
+}
+
+// transform_block mirrors and transposes a block in natural order. Mirroring horizontally negates the
+// coefficients of odd horizontal frequencies, mirroring vertically those of odd vertical frequencies.
+fn transform_block(
+    block: &[i16; 64],
+    (mirror_x, mirror_y, transpose): (bool, bool, bool),
+) -> [i16; 64] {
+    let mut mirrored = *block;
+    for (index, coefficient) in mirrored.iter_mut().enumerate() {
+        let (u, v) = (index % 8, index / 8);
+        let negate = (mirror_x && u % 2 == 1) != (mirror_y && v % 2 == 1);
+        if negate {
+            *coefficient = -*coefficient;
+        }
+    }
+    if transpose {
+        transposed(&mirrored)
+    } else {
+        mirrored
+    }
+}
+
+fn transposed<T: Copy>(block: &[T; 64]) -> [T; 64] {
+    let mut output = *block;
+    for (index, value) in output.iter_mut().enumerate() {
+        *value = block[index % 8 * 8 + index / 8];
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::{decode_image_with_options, ColorType};
+    use encoder::{encode_image_with_options, ChromaSubsampling};
+
+    const ORIENTATIONS: [Orientation; 8] = [
+        Orientation::Normal,
+        Orientation::MirrorHorizontal,
+        Orientation::Rotate180,
+        Orientation::MirrorVertical,
+        Orientation::Transpose,
+        Orientation::Rotate90,
+        Orientation::Transverse,
+        Orientation::Rotate270,
+    ];
+
+    fn encode(
+        width: u64,
+        height: u64,
+        subsampling: ChromaSubsampling,
+        options: EncodeOptions,
+    ) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                vec![(x * 5 + y) as u8, (y * 6) as u8, ((x * y) % 200) as u8]
+            })
+            .collect();
+        let options = EncodeOptions {
+            quality: 90,
+            subsampling,
+            ..options
+        };
+        encode_image_with_options(&pixels, width, height, ColorType::Rgb, &options).unwrap()
+    }
+
+    // stored decodes the pixels as stored, ignoring the EXIF orientation
+    fn stored() -> DecodeOptions {
+        DecodeOptions {
+            apply_orientation: false,
+            ..DecodeOptions::default()
+        }
+    }
+
+    #[test]
+    fn crop_test() {
+        let crop = |file: &[u8], orientation, crop| {
+            let options = TransformOptions {
+                orientation,
+                crop: Some(crop),
+                ..TransformOptions::default()
+            };
+            transform_jpeg(file, &options)
+        };
+        let region = |x, y, width, height| Crop {
+            x,
+            y,
+            width,
+            height,
+        };
+
+        // blocks of 4:4:4 files decode on their own, so the pixels of the cropped area stay the same
+        let file = encode(
+            45,
+            30,
+            ChromaSubsampling::Chroma444,
+            EncodeOptions::default(),
+        );
+        let image = decode_image_with_options(&file, &stored()).unwrap();
+        for &(x, y, width, height) in &[
+            (8, 16, 24, 8),
+            (0, 0, 45, 30),
+            (16, 8, 29, 22),
+            (40, 24, 100, 100),
+        ] {
+            let cropped = crop(&file, Orientation::Normal, region(x, y, width, height)).unwrap();
+            let output = decode_image_with_options(&cropped, &stored()).unwrap();
+            let (width, height) = (width.min(45 - x), height.min(30 - y));
+            assert_eq!((output.width, output.height), (width, height));
+            for line in 0..height {
+                let start = (((y + line) * 45 + x) * 3) as usize;
+                let expected = &image.pixels[start..start + width as usize * 3];
+                let start = (line * width * 3) as usize;
+                assert_eq!(&output.pixels[start..start + width as usize * 3], expected);
+            }
+        }
+        let full = crop(&file, Orientation::Normal, region(0, 0, 45, 30)).unwrap();
+        assert_eq!(
+            read_coefficients(&full, &DecodeOptions::default()).unwrap(),
+            read_coefficients(&file, &DecodeOptions::default()).unwrap()
+        );
+
+        // corners inside 4:2:0 MCUs snap to the MCU boundary, cropping applies to the transformed image
+        let file = encode(
+            37,
+            19,
+            ChromaSubsampling::Chroma420,
+            EncodeOptions::default(),
+        );
+        let info = |file: Vec<u8>| {
+            let info = read_info(&file).unwrap();
+            (info.width, info.height)
+        };
+        assert_eq!(
+            info(crop(&file, Orientation::Normal, region(20, 3, 10, 10)).unwrap()),
+            (14, 13)
+        );
+        assert_eq!(
+            info(crop(&file, Orientation::Normal, region(16, 16, 40, 40)).unwrap()),
+            (21, 3)
+        );
+        assert_eq!(
+            info(crop(&file, Orientation::Rotate90, region(0, 17, 16, 20)).unwrap()),
+            (16, 21)
+        );
+
+        for &invalid in &[
+            region(37, 0, 1, 1),
+            region(0, 19, 1, 1),
+            region(0, 0, 0, 5),
+            region(3, 3, 5, 0),
+        ] {
+            assert_eq!(
+                crop(&file, Orientation::Normal, invalid)
+                    .unwrap_err()
+                    .to_string(),
+                format!(
+                    "Encoder Error: crop of {}x{} pixels at {},{} is outside of the image",
+                    invalid.width, invalid.height, invalid.x, invalid.y
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn optimize_jpeg_test() {
+        for &progressive in &[false, true] {
+            let options = EncodeOptions {
+                progressive,
+                optimize_huffman: false,
+                comments: vec![b"optimized".to_vec()],
+                ..EncodeOptions::default()
+            };
+            let file = encode(64, 48, ChromaSubsampling::Chroma420, options);
+            let optimized = optimize_jpeg(&file).unwrap();
+            assert!(optimized.len() < file.len());
+            assert_eq!(read_info(&optimized).unwrap().progressive, progressive);
+            assert_eq!(
+                read_coefficients(&optimized, &DecodeOptions::default()).unwrap(),
+                read_coefficients(&file, &DecodeOptions::default()).unwrap()
+            );
+            let mut copied = EncodeOptions::default();
+            copy_metadata(&optimized, &mut copied).unwrap();
+            assert_eq!(copied.comments, vec![b"optimized".to_vec()]);
+        }
+        assert!(optimize_jpeg(b"\xff\xd8\xff\xd9").is_err());
+    }
+
+    #[test]
+    fn modify_coefficients_test() {
+        let options = EncodeOptions {
+            progressive: true,
+            comments: vec![b"marked".to_vec()],
+            ..EncodeOptions::default()
+        };
+        let file = encode(40, 24, ChromaSubsampling::Chroma420, options);
+        let original = read_coefficients(&file, &DecodeOptions::default()).unwrap();
+
+        // hide a bit in the lowest bit of the first AC coefficient of every luma block
+        let marked = modify_coefficients(&file, |components| {
+            for (index, block) in components[0].blocks.iter_mut().enumerate() {
+                block[1] = (block[1] & !1) | (index % 2) as i16;
+            }
+        })
+        .unwrap();
+        let info = read_info(&marked).unwrap();
+        assert!(info.progressive);
+        let mut copied = EncodeOptions::default();
+        copy_metadata(&marked, &mut copied).unwrap();
+        assert_eq!(copied.comments, vec![b"marked".to_vec()]);
+
+        let modified = read_coefficients(&marked, &DecodeOptions::default()).unwrap();
+        assert_eq!(modified.components[1..], original.components[1..]);
+        // progressive files don't code the AC coefficients of the blocks completing the MCUs past the edges
+        let luma = &modified.components[0];
+        for (index, (block, original)) in luma
+            .blocks
+            .iter()
+            .zip(&original.components[0].blocks)
+            .enumerate()
+        {
+            if index % luma.blocks_per_line >= 5 || index / luma.blocks_per_line >= 3 {
+                continue;
+            }
+            assert_eq!(block[1] & 1, (index % 2) as i16);
+            assert_eq!(block[1] & !1, original[1] & !1);
+            assert_eq!(block[2..], original[2..]);
+        }
+
+        let unchanged = modify_coefficients(&file, |_| {}).unwrap();
+        assert_eq!(
+            read_coefficients(&unchanged, &DecodeOptions::default()).unwrap(),
+            original
+        );
+        for &(k, value) in &[(0, -1025), (5, 1024), (63, -1024)] {
+            assert!(matches!(
+                modify_coefficients(&file, |components| components[2].blocks[3][k] = value),
+                Err(Error::Encoder(EncodeError::CoefficientOutOfRange { component: 3, value: v })) if v == value
+            ));
+        }
+        assert!(modify_coefficients(&file, |components| {
+            components[0].blocks[0][0] = -1024;
+            components[0].blocks[0][63] = 1023;
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn optimize_scans_test() {
+        for &subsampling in &[ChromaSubsampling::Chroma420, ChromaSubsampling::Gray] {
+            let file = encode(64, 48, subsampling, EncodeOptions::default());
+            let progressive = TransformOptions {
+                progressive: Some(true),
+                ..TransformOptions::default()
+            };
+            let standard = transform_jpeg(&file, &progressive).unwrap();
+            let options = TransformOptions {
+                optimize_scans: true,
+                ..progressive
+            };
+            let optimized = transform_jpeg(&file, &options).unwrap();
+            assert!(optimized.len() <= standard.len());
+            assert!(read_info(&optimized).unwrap().progressive);
+            assert_eq!(
+                read_coefficients(&optimized, &DecodeOptions::default()).unwrap(),
+                read_coefficients(&file, &DecodeOptions::default()).unwrap()
+            );
+
+            // sequential output ignores optimize_scans
+            let sequential = TransformOptions {
+                progressive: Some(false),
+                ..options
+            };
+            assert_eq!(
+                transform_jpeg(&file, &sequential).unwrap(),
+                optimize_jpeg(&file).unwrap()
+            );
+        }
+
+        // the default choices are libjpeg's script, other choices are valid scripts too
+        assert_eq!(ScanChoices::default().script(3).len(), 10);
+        let coefficients = read_coefficients(
+            &encode(
+                32,
+                32,
+                ChromaSubsampling::Chroma420,
+                EncodeOptions::default(),
+            ),
+            &DecodeOptions::default(),
+        )
+        .unwrap();
+        for &dc_grouping in &[DcGrouping::LumaSeparate, DcGrouping::Separate] {
+            let choices = ScanChoices {
+                dc_grouping,
+                dc_approximation: 2,
+                split: [63, 2],
+                approximation: [0, 3],
+            };
+            let options = EncodeOptions {
+                progressive: true,
+                scan_script: Some(choices.script(3)),
+                ..EncodeOptions::default()
+            };
+            let file = encode_coefficients(&coefficients, &options).unwrap();
+            assert_eq!(
+                read_coefficients(&file, &DecodeOptions::default()).unwrap(),
+                coefficients
+            );
+        }
+    }
+
+    #[test]
+    fn transform_jpeg_test() {
+        for &subsampling in &[
+            ChromaSubsampling::Gray,
+            ChromaSubsampling::Chroma444,
+            ChromaSubsampling::Chroma422,
+        ] {
+            let file = encode(48, 32, subsampling, EncodeOptions::default());
+            let image = decode_image_with_options(&file, &stored()).unwrap();
+            let channels = if subsampling == ChromaSubsampling::Gray {
+                1
+            } else {
+                3
+            };
+            for &orientation in &ORIENTATIONS {
+                let options = TransformOptions {
+                    orientation,
+                    ..TransformOptions::default()
+                };
+                let transformed = transform_jpeg(&file, &options).unwrap();
+                let output = decode_image_with_options(&transformed, &stored()).unwrap();
+                let (expected, width, height) = orientation.apply(&image.pixels, 48, 32, channels);
+                assert_eq!((output.width, output.height), (width as u64, height as u64));
+                // the IDCT rounds between its row and column passes, so transposed blocks and the color
+                // conversion of their rounding differences may be a few levels off
+                let difference = output
+                    .pixels
+                    .iter()
+                    .zip(&expected)
+                    .map(|(&a, &b)| (i32::from(a) - i32::from(b)).abs())
+                    .max();
+                assert!(
+                    difference <= Some(3),
+                    "{:?} {:?}: {:?}",
+                    subsampling,
+                    orientation,
+                    difference
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transform_coefficients_test() {
+        for &progressive in &[false, true] {
+            let options = EncodeOptions {
+                progressive,
+                comments: vec![b"rotated".to_vec()],
+                ..EncodeOptions::default()
+            };
+            let file = encode(48, 32, ChromaSubsampling::Chroma420, options);
+            let original = read_coefficients(&file, &DecodeOptions::default()).unwrap();
+
+            // four quarter turns restore the coefficients
+            let rotate = TransformOptions {
+                orientation: Orientation::Rotate90,
+                ..TransformOptions::default()
+            };
+            let mut rotated = file.clone();
+            for _ in 0..4 {
+                rotated = transform_jpeg(&rotated, &rotate).unwrap();
+                assert_eq!(read_info(&rotated).unwrap().progressive, progressive);
+            }
+            assert_eq!(
+                read_coefficients(&rotated, &DecodeOptions::default()).unwrap(),
+                original
+            );
+
+            let mut copied = EncodeOptions::default();
+            copy_metadata(&rotated, &mut copied).unwrap();
+            assert_eq!(copied.comments, vec![b"rotated".to_vec()]);
+        }
+
+        // converting between the processes keeps the pixels. Progressive AC scans hold just one component and
+        // leave out the blocks padding the last MCUs, so only files of whole MCUs keep every coefficient.
+        for &(width, height) in &[(48, 32), (37, 19)] {
+            let file = encode(
+                width,
+                height,
+                ChromaSubsampling::Chroma420,
+                EncodeOptions::default(),
+            );
+            let original = read_coefficients(&file, &DecodeOptions::default()).unwrap();
+            let convert = |file: &[u8], progressive| {
+                let options = TransformOptions {
+                    progressive: Some(progressive),
+                    ..TransformOptions::default()
+                };
+                let converted = transform_jpeg(file, &options).unwrap();
+                assert_eq!(read_info(&converted).unwrap().progressive, progressive);
+                let decoded = decode_image_with_options(&converted, &stored()).unwrap();
+                assert_eq!(decoded, decode_image_with_options(file, &stored()).unwrap());
+                if width % 16 == 0 && height % 16 == 0 {
+                    let coefficients = read_coefficients(&converted, &DecodeOptions::default());
+                    assert_eq!(coefficients.unwrap(), original);
+                }
+                converted
+            };
+            let progressive = convert(&file, true);
+            convert(&progressive, false);
+        }
+
+        // 4:2:0 MCUs are 16 x 16 pixels, the partial ones are dropped where they would move
+        let file = encode(
+            37,
+            19,
+            ChromaSubsampling::Chroma420,
+            EncodeOptions::default(),
+        );
+        let dimensions = |orientation| {
+            let options = TransformOptions {
+                orientation,
+                ..TransformOptions::default()
+            };
+            let info = read_info(&transform_jpeg(&file, &options).unwrap()).unwrap();
+            (info.width, info.height)
+        };
+        assert_eq!(dimensions(Orientation::Normal), (37, 19));
+        assert_eq!(dimensions(Orientation::MirrorHorizontal), (32, 19));
+        assert_eq!(dimensions(Orientation::Rotate180), (32, 16));
+        assert_eq!(dimensions(Orientation::Transpose), (19, 37));
+        assert_eq!(dimensions(Orientation::Rotate90), (16, 37));
+        assert_eq!(dimensions(Orientation::Rotate270), (19, 32));
+
+        let small = encode(
+            12,
+            40,
+            ChromaSubsampling::Chroma420,
+            EncodeOptions::default(),
+        );
+        let mirror = TransformOptions {
+            orientation: Orientation::MirrorHorizontal,
+            ..TransformOptions::default()
+        };
+        assert!(matches!(
+            transform_jpeg(&small, &mirror),
+            Err(Error::Encoder(EncodeError::InvalidDimensions {
+                width: 12,
+                height: 40
+            }))
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+// Chroma upsampling of component planes to the full image resolution.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+use rows::for_each_row;
+#[cfg(feature = "simd")]
+use simd;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Upsampling {
+    // Box repeats every sample for all pixels it covers
+    #[default]
+    Box,
+    // Triangle interpolates linearly between the centers of neighbouring samples, like libjpeg's fancy upsampling
+    Triangle,
+}
+
+// SampledPlane is a component plane with stride bytes per line, of which the first width samples of the first
+// height lines are inside the image
+pub struct SampledPlane<'a> {
+    pub samples: &'a [u8],
+    pub stride: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// upsample scales plane by horizontal and vertical (sampling factor, maximum sampling factor) ratios to width x
+// height samples in output. Triangle filtering keeps the horizontally scaled lines in lines. Both buffers are
+// cleared first, so their allocations are reused.
+pub fn upsample(
+    method: Upsampling,
+    plane: &SampledPlane,
+    horizontal: (usize, usize),
+    vertical: (usize, usize),
+    (width, height): (usize, usize),
+    output: &mut Vec<u8>,
+    lines: &mut Vec<u8>,
+) {
+    output.clear();
+    output.resize(width * height, 0);
+    if width == 0 {
+        return;
+    }
+    match method {
+        Upsampling::Box => {
+            let repeat_line = |(y, row): (usize, &mut [u8])| {
+                let line = y * vertical.0 / vertical.1 * plane.stride;
+                repeat(&plane.samples[line..], horizontal, row);
+            };
+            for_each_row(output, width, repeat_line);
+        }
+        Upsampling::Triangle => {
+            lines.clear();
+            lines.resize(width * plane.height, 0);
+            let interpolate_source = |(y, row): (usize, &mut [u8])| {
+                let line = &plane.samples[y * plane.stride..y * plane.stride + plane.width];
+                interpolate_line(line, horizontal, row);
+            };
+            for_each_row(lines, width, interpolate_source);
+
+            let lines = &lines[..];
+            let blend_lines = |(y, row): (usize, &mut [u8])| {
+                let (first, second, fraction, denominator) = source(y, plane.height, vertical);
+                let first = &lines[first * width..(first + 1) * width];
+                let second = &lines[second * width..(second + 1) * width];
+                blend(
+                    first,
+                    second,
+                    (denominator - fraction, fraction),
+                    denominator,
+                    row,
+                );
+            };
+            for_each_row(output, width, blend_lines);
+        }
+    }
+}
+
+// repeat fills output with the samples of line that cover its positions
+fn repeat(line: &[u8], ratio: (usize, usize), output: &mut [u8]) {
+    if ratio.0 == ratio.1 {
+        output.copy_from_slice(&line[..output.len()]);
+        return;
+    }
+    #[cfg(feature = "simd")]
+    let repeated = if 2 * ratio.0 == ratio.1 {
+        2 * simd::duplicate(line, output)
+    } else {
+        0
+    };
+    #[cfg(not(feature = "simd"))]
+    let repeated = 0;
+    for (x, sample) in output.iter_mut().enumerate().skip(repeated) {
+        *sample = line[x * ratio.0 / ratio.1];
+    }
+}
+
+// interpolate_line fills output with the samples of line interpolated horizontally
+fn interpolate_line(line: &[u8], ratio: (usize, usize), output: &mut [u8]) {
+    if ratio.0 == ratio.1 {
+        output.copy_from_slice(&line[..output.len()]);
+        return;
+    }
+    #[cfg(feature = "simd")]
+    let interpolated = if 2 * ratio.0 == ratio.1 {
+        2..2 + 2 * simd::triangle(line, output)
+    } else {
+        0..0
+    };
+    #[cfg(not(feature = "simd"))]
+    let interpolated = 0..0;
+    for x in (0..output.len()).filter(|x| !interpolated.contains(x)) {
+        output[x] = interpolate(line, x, ratio);
+    }
+}
+
+// blend computes the rounded weighted average of two lines
+fn blend(
+    first: &[u8],
+    second: &[u8],
+    weights: (usize, usize),
+    denominator: usize,
+    output: &mut [u8],
+) {
+    if weights.1 == 0 {
+        output.copy_from_slice(first);
+        return;
+    }
+    #[cfg(feature = "simd")]
+    let blended = if denominator.is_power_of_two() && denominator <= 8 {
+        let shift = denominator.trailing_zeros();
+        simd::blend(
+            first,
+            second,
+            (weights.0 as u8, weights.1 as u8),
+            shift,
+            output,
+        )
+    } else {
+        0
+    };
+    #[cfg(not(feature = "simd"))]
+    let blended = 0;
+    for ((sample, &first), &second) in output.iter_mut().zip(first).zip(second).skip(blended) {
+        let sum =
+            usize::from(first) * weights.0 + usize::from(second) * weights.1 + denominator / 2;
+        *sample = (sum / denominator) as u8;
+    }
+}
+
+// source maps output position to the source position (position + 0.5) * sampling / max_sampling - 0.5 between
+// two of the given number of samples, returning their indices, with positions outside of the samples replaced by
+// the first and last one, and the fraction of the denominator the position lies past the first one
+fn source(position: usize, samples: usize, ratio: (usize, usize)) -> (usize, usize, usize, usize) {
+    let (sampling, max_sampling) = (ratio.0 as isize, ratio.1 as isize);
+    let numerator = (2 * position as isize + 1) * sampling - max_sampling;
+    let denominator = 2 * max_sampling;
+
+    let index = numerator.div_euclid(denominator);
+    let last = samples as isize - 1;
+    (
+        index.clamp(0, last) as usize,
+        (index + 1).clamp(0, last) as usize,
+        numerator.rem_euclid(denominator) as usize,
+        denominator as usize,
+    )
+}
+
+// interpolate computes output sample position of a line
+fn interpolate(line: &[u8], position: usize, ratio: (usize, usize)) -> u8 {
+    let (first, second, fraction, denominator) = source(position, line.len(), ratio);
+    let value = usize::from(line[first]) * (denominator - fraction)
+        + usize::from(line[second]) * fraction
+        + denominator / 2;
+    (value / denominator) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plane(samples: &[u8], stride: usize, width: usize, height: usize) -> SampledPlane<'_> {
+        SampledPlane {
+            samples,
+            stride,
+            width,
+            height,
+        }
+    }
+
+    fn upsampled(
+        method: Upsampling,
+        plane: &SampledPlane,
+        horizontal: (usize, usize),
+        vertical: (usize, usize),
+        width: usize,
+        height: usize,
+    ) -> Vec<u8> {
+        let mut output = vec![7; 100];
+        upsample(
+            method,
+            plane,
+            horizontal,
+            vertical,
+            (width, height),
+            &mut output,
+            &mut vec![9; 100],
+        );
+        output
+    }
+
+    #[test]
+    fn box_test() {
+        let samples = [10, 20, 0, 30, 40, 0];
+        let output = upsampled(
+            Upsampling::Box,
+            &plane(&samples, 3, 2, 2),
+            (1, 2),
+            (1, 2),
+            3,
+            3,
+        );
+
+        assert_eq!(output, vec![10, 10, 20, 10, 10, 20, 30, 30, 40]);
+    }
+
+    #[test]
+    fn triangle_test() {
+        let samples = [0, 100, 200];
+        let output = upsampled(
+            Upsampling::Triangle,
+            &plane(&samples, 4, 3, 1),
+            (1, 2),
+            (1, 1),
+            6,
+            1,
+        );
+
+        // libjpeg's h2v1 fancy upsampling weights the nearer sample with 3/4 and the farther one with 1/4
+        assert_eq!(output, vec![0, 25, 75, 125, 175, 200]);
+    }
+
+    #[test]
+    fn triangle_vertical_test() {
+        let samples = [0, 40, 80, 120];
+        let output = upsampled(
+            Upsampling::Triangle,
+            &plane(&samples, 2, 2, 2),
+            (1, 1),
+            (1, 2),
+            2,
+            4,
+        );
+
+        assert_eq!(output, vec![0, 40, 20, 60, 60, 100, 80, 120]);
+    }
+
+    #[test]
+    fn random_test() {
+        // lines long enough for the vector kernels, which must compute the same samples as the scalar formulas
+        let (stride, plane_width, plane_height) = (80, 75, 5);
+        let mut state = 0x2468_ace0u32;
+        let samples: Vec<u8> = (0..stride * plane_height)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 24) as u8
+            })
+            .collect();
+        let plane = plane(&samples, stride, plane_width, plane_height);
+        let ratios = [(1, 2), (1, 4), (2, 4), (1, 3), (1, 1)];
+        for &horizontal in &ratios {
+            for &vertical in &ratios {
+                let width = plane_width * horizontal.1 / horizontal.0 - 1;
+                let height = plane_height * vertical.1 / vertical.0;
+
+                let output =
+                    upsampled(Upsampling::Box, &plane, horizontal, vertical, width, height);
+                for (i, &sample) in output.iter().enumerate() {
+                    let (x, y) = (i % width, i / width);
+                    let line = y * vertical.0 / vertical.1 * stride;
+                    assert_eq!(sample, samples[line + x * horizontal.0 / horizontal.1]);
+                }
+
+                let output = upsampled(
+                    Upsampling::Triangle,
+                    &plane,
+                    horizontal,
+                    vertical,
+                    width,
+                    height,
+                );
+                for (i, &sample) in output.iter().enumerate() {
+                    let (x, y) = (i % width, i / width);
+                    let (first, second, fraction, denominator) = source(y, plane_height, vertical);
+                    let value = |line: usize| {
+                        usize::from(interpolate(
+                            &samples[line * stride..][..plane_width],
+                            x,
+                            horizontal,
+                        ))
+                    };
+                    let sum = value(first) * (denominator - fraction)
+                        + value(second) * fraction
+                        + denominator / 2;
+                    assert_eq!(
+                        usize::from(sample),
+                        sum / denominator,
+                        "{:?} {:?} {}",
+                        horizontal,
+                        vertical,
+                        i
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn full_resolution_test() {
+        let samples = [1, 2, 3, 4, 5, 6];
+        for &method in &[Upsampling::Box, Upsampling::Triangle] {
+            let output = upsampled(method, &plane(&samples, 3, 3, 2), (2, 2), (1, 1), 3, 2);
+            assert_eq!(output, samples.to_vec());
+        }
+    }
+}
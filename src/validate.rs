@@ -0,0 +1,967 @@
+// Structural conformance checks of JPEG files against ITU T.81, for QA of encoder output. The parser stops at the
+// first problem and tolerates whatever it can still decode, validate keeps going and reports every problem.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use core::fmt;
+
+// Diagnostic is a problem found by validate, offset is the position of the marker of the segment it concerns
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub offset: usize,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Problem {
+    MissingStartOfImage,
+    // MissingEndOfImage is reported at the end of files without EOI marker
+    MissingEndOfImage,
+    // TrailingData is reported for bytes following the EOI marker
+    TrailingData {
+        length: usize,
+    },
+    // InvalidMarker is reported if a segment doesn't start with 0xFF, validation stops there
+    InvalidMarker {
+        found: u8,
+    },
+    // UnexpectedMarker is reported for reserved markers and markers that only belong into other places, like
+    // restart markers outside of scans
+    UnexpectedMarker {
+        marker: u8,
+    },
+    // TruncatedSegment is reported for segments running past the end of the file
+    TruncatedSegment {
+        marker: u8,
+    },
+    // InvalidLength is reported for segments whose length doesn't match their content, expected is the length
+    // the content needs
+    InvalidLength {
+        marker: u8,
+        length: usize,
+        expected: usize,
+    },
+    // MisplacedSegment is reported for frame headers after the first one, scans in front of the frame header and
+    // DNL segments anywhere but right after the first scan
+    MisplacedSegment {
+        marker: u8,
+    },
+    MissingFrameHeader,
+    MissingScan,
+    InvalidPrecision {
+        precision: u8,
+    },
+    // InvalidDimensions is reported for frames without columns, or without lines and a DNL segment
+    InvalidDimensions {
+        width: u16,
+        height: u16,
+    },
+    InvalidComponentCount {
+        count: usize,
+    },
+    DuplicateComponent {
+        id: u8,
+    },
+    // InvalidSampling is reported for sampling factors outside of 1 to 4
+    InvalidSampling {
+        id: u8,
+    },
+    // TooManyBlocks is reported for interleaved scans with more than 10 blocks per MCU
+    TooManyBlocks {
+        blocks: usize,
+    },
+    // InvalidTableDestination is reported for table ids the segment with the given marker can't hold
+    InvalidTableDestination {
+        marker: u8,
+        id: u8,
+    },
+    // InvalidQuantizationTable is reported for tables with zero entries, a precision other than 8 or 16 bits,
+    // or 16 bit entries in baseline files
+    InvalidQuantizationTable {
+        id: u8,
+    },
+    // InvalidHuffmanTable is reported for tables with more codes than fit their lengths or more than 256 symbols
+    InvalidHuffmanTable {
+        class: u8,
+        id: u8,
+    },
+    UndefinedQuantizationTable {
+        component: u8,
+        id: u8,
+    },
+    UndefinedHuffmanTable {
+        class: u8,
+        id: u8,
+    },
+    // UnknownScanComponent is reported for scan components the frame doesn't have
+    UnknownScanComponent {
+        id: u8,
+    },
+    // ScanComponentOrder is reported for scan components that aren't in the order of the frame header
+    ScanComponentOrder {
+        id: u8,
+    },
+    InvalidSpectralSelection {
+        start: u8,
+        end: u8,
+    },
+    InvalidApproximation {
+        high: u8,
+        low: u8,
+    },
+    // RestartMarkerOrder is reported for restart markers out of their modulo 8 sequence, offset is their position
+    RestartMarkerOrder {
+        expected: u8,
+        found: u8,
+    },
+    // RestartMarkerCount is reported for scans with a different number of restart markers than their MCUs and the
+    // restart interval call for
+    RestartMarkerCount {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::MissingStartOfImage => write!(f, "no start of image marker"),
+            Problem::MissingEndOfImage => write!(f, "no end of image marker"),
+            Problem::TrailingData { length } => {
+                write!(f, "{} bytes after the end of image marker", length)
+            }
+            Problem::InvalidMarker { found } => write!(f, "expected marker, found {:#x}", found),
+            Problem::UnexpectedMarker { marker } => write!(f, "unexpected marker {:#x}", marker),
+            Problem::TruncatedSegment { marker } => {
+                write!(f, "segment with marker {:#x} is truncated", marker)
+            }
+            Problem::InvalidLength {
+                marker,
+                length,
+                expected,
+            } => write!(
+                f,
+                "segment with marker {:#x} has length {}, its content needs {}",
+                marker, length, expected
+            ),
+            Problem::MisplacedSegment { marker } => {
+                write!(f, "segment with marker {:#x} out of order", marker)
+            }
+            Problem::MissingFrameHeader => write!(f, "no frame header"),
+            Problem::MissingScan => write!(f, "no scan"),
+            Problem::InvalidPrecision { precision } => {
+                write!(f, "invalid sample precision {}", precision)
+            }
+            Problem::InvalidDimensions { width, height } => {
+                write!(f, "invalid frame dimensions {}x{}", width, height)
+            }
+            Problem::InvalidComponentCount { count } => {
+                write!(f, "invalid number of components: {}", count)
+            }
+            Problem::DuplicateComponent { id } => write!(f, "component {} appears twice", id),
+            Problem::InvalidSampling { id } => {
+                write!(f, "invalid sampling factors of component {}", id)
+            }
+            Problem::TooManyBlocks { blocks } => {
+                write!(f, "{} blocks per MCU exceed the limit of 10", blocks)
+            }
+            Problem::InvalidTableDestination { marker, id } => write!(
+                f,
+                "invalid table {} in segment with marker {:#x}",
+                id, marker
+            ),
+            Problem::InvalidQuantizationTable { id } => {
+                write!(f, "invalid quantization table {}", id)
+            }
+            Problem::InvalidHuffmanTable { class, id } => {
+                write!(f, "invalid huffman table {} of class {}", id, class)
+            }
+            Problem::UndefinedQuantizationTable { component, id } => write!(
+                f,
+                "quantization table {} of component {} is undefined",
+                id, component
+            ),
+            Problem::UndefinedHuffmanTable { class, id } => {
+                write!(f, "huffman table {} of class {} is undefined", id, class)
+            }
+            Problem::UnknownScanComponent { id } => {
+                write!(f, "scan component {} isn't part of the frame", id)
+            }
+            Problem::ScanComponentOrder { id } => {
+                write!(f, "scan component {} out of frame order", id)
+            }
+            Problem::InvalidSpectralSelection { start, end } => {
+                write!(f, "invalid spectral selection {} to {}", start, end)
+            }
+            Problem::InvalidApproximation { high, low } => {
+                write!(f, "invalid successive approximation {} to {}", high, low)
+            }
+            Problem::RestartMarkerOrder { expected, found } => {
+                write!(f, "expected restart marker {}, found {}", expected, found)
+            }
+            Problem::RestartMarkerCount { expected, found } => {
+                write!(f, "expected {} restart markers, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.problem)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Process {
+    Baseline,
+    Sequential,
+    Progressive,
+    Lossless,
+}
+
+struct FrameComponent {
+    id: u8,
+    horizontal_sampling: usize,
+    vertical_sampling: usize,
+    quantization_table: u8,
+}
+
+struct Frame {
+    process: Process,
+    arithmetic: bool,
+    precision: u8,
+    width: usize,
+    height: usize,
+    components: Vec<FrameComponent>,
+}
+
+#[derive(Default)]
+struct Validator {
+    diagnostics: Vec<Diagnostic>,
+    frame: Option<Frame>,
+    // quantization_tables holds the precision of every defined table
+    quantization_tables: [Option<u8>; 4],
+    // huffman_tables records the defined DC and AC tables
+    huffman_tables: [[bool; 4]; 2],
+    restart_interval: usize,
+    scans: usize,
+    // lines is the number of lines a DNL segment defined
+    lines: Option<u16>,
+}
+
+// validate checks the structure of jpeg_file: the order of its markers, segment lengths, frame and scan
+// parameters, tables being defined before the scans using them and the sequence of restart markers. It returns
+// every problem found in the order of the file, an empty list for conformant files. The entropy coded data itself
+// isn't decoded.
+pub fn validate(jpeg_file: &[u8]) -> Vec<Diagnostic> {
+    let mut validator = Validator::default();
+    if !jpeg_file.starts_with(b"\xff\xd8") {
+        validator.report(0, Problem::MissingStartOfImage);
+        return validator.diagnostics;
+    }
+
+    let mut cursor = 2;
+    let mut after_first_scan = false;
+    loop {
+        if cursor + 2 > jpeg_file.len() {
+            validator.report(jpeg_file.len(), Problem::MissingEndOfImage);
+            break;
+        }
+        if jpeg_file[cursor] != 0xff {
+            let found = jpeg_file[cursor];
+            validator.report(cursor, Problem::InvalidMarker { found });
+            break;
+        }
+
+        let marker = jpeg_file[cursor + 1];
+        match marker {
+            // fill bytes may precede any marker
+            0xff => {
+                cursor += 1;
+                continue;
+            }
+            0xd9 => {
+                if cursor + 2 < jpeg_file.len() {
+                    let length = jpeg_file.len() - cursor - 2;
+                    validator.report(cursor + 2, Problem::TrailingData { length });
+                }
+                break;
+            }
+            0x00..=0xbf | 0xd0..=0xd8 => {
+                validator.report(cursor, Problem::UnexpectedMarker { marker });
+                cursor += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        if cursor + 4 > jpeg_file.len() {
+            validator.report(cursor, Problem::TruncatedSegment { marker });
+            break;
+        }
+        let length = usize::from(u16::from_be_bytes([
+            jpeg_file[cursor + 2],
+            jpeg_file[cursor + 3],
+        ]));
+        if length < 2 {
+            let problem = Problem::InvalidLength {
+                marker,
+                length,
+                expected: 2,
+            };
+            validator.report(cursor, problem);
+            break;
+        }
+        let data = match jpeg_file.get(cursor + 4..cursor + 2 + length) {
+            Some(data) => data,
+            None => {
+                validator.report(cursor, Problem::TruncatedSegment { marker });
+                break;
+            }
+        };
+
+        if marker == 0xdc && !after_first_scan {
+            validator.report(cursor, Problem::MisplacedSegment { marker });
+        }
+        after_first_scan = false;
+        validator.segment(cursor, marker, data);
+        let offset = cursor;
+        cursor += 2 + length;
+        if marker == 0xda {
+            let (end, restarts) = validator.restart_markers(jpeg_file, cursor);
+            validator.check_restart_count(offset, data, restarts);
+            after_first_scan = validator.scans == 1;
+            cursor = end;
+        }
+    }
+
+    validator.finish(jpeg_file.len());
+    validator.diagnostics
+}
+
+impl Validator {
+    fn report(&mut self, offset: usize, problem: Problem) {
+        self.diagnostics.push(Diagnostic { offset, problem });
+    }
+
+    // check_length reports a segment whose content needs expected bytes of data
+    fn check_length(&mut self, offset: usize, marker: u8, data: &[u8], expected: usize) -> bool {
+        if data.len() == expected {
+            return true;
+        }
+        let problem = Problem::InvalidLength {
+            marker,
+            length: data.len() + 2,
+            expected: expected + 2,
+        };
+        self.report(offset, problem);
+        false
+    }
+
+    fn segment(&mut self, offset: usize, marker: u8, data: &[u8]) {
+        match marker {
+            0xc4 => self.huffman_tables(offset, data),
+            0xdb => self.quantization_tables(offset, data),
+            0xdd if self.check_length(offset, marker, data, 2) => {
+                self.restart_interval = usize::from(u16::from_be_bytes([data[0], data[1]]));
+            }
+            0xdc if self.check_length(offset, marker, data, 2) => {
+                self.lines = Some(u16::from_be_bytes([data[0], data[1]]));
+            }
+            0xda => self.scan(offset, data),
+            0xc8 | 0xf0..=0xfd => self.report(offset, Problem::UnexpectedMarker { marker }),
+            0xc0..=0xcf if marker != 0xcc => self.frame(offset, marker, data),
+            // DAC, DHP, EXP, APPn and COM segments and DRI or DNL segments of the wrong length
+            _ => {}
+        }
+    }
+
+    // truncated_table reports a DHT or DQT segment whose table at position needs size bytes more than the segment
+    // holds
+    fn truncated_table(
+        &mut self,
+        offset: usize,
+        marker: u8,
+        data: &[u8],
+        position: usize,
+        size: usize,
+    ) {
+        let problem = Problem::InvalidLength {
+            marker,
+            length: data.len() + 2,
+            expected: position + size + 2,
+        };
+        self.report(offset, problem);
+    }
+
+    fn quantization_tables(&mut self, offset: usize, data: &[u8]) {
+        let baseline = self
+            .frame
+            .as_ref()
+            .is_some_and(|frame| frame.process == Process::Baseline);
+        let mut position = 0;
+        while position < data.len() {
+            let (precision, id) = (data[position] >> 4, data[position] & 0x0f);
+            let size = 1 + (64 << precision.min(1));
+            let entries = match data.get(position + 1..position + size) {
+                Some(entries) => entries,
+                None => return self.truncated_table(offset, 0xdb, data, position, size),
+            };
+            if id > 3 {
+                self.report(
+                    offset,
+                    Problem::InvalidTableDestination { marker: 0xdb, id },
+                );
+            } else {
+                let zero = if precision == 0 {
+                    entries.contains(&0)
+                } else {
+                    entries.chunks(2).any(|entry| entry == [0, 0])
+                };
+                if precision > 1 || zero || (precision == 1 && baseline) {
+                    self.report(offset, Problem::InvalidQuantizationTable { id });
+                }
+                self.quantization_tables[usize::from(id)] = Some(precision);
+            }
+            position += size;
+        }
+    }
+
+    fn huffman_tables(&mut self, offset: usize, data: &[u8]) {
+        let mut position = 0;
+        while position < data.len() {
+            let (class, id) = (data[position] >> 4, data[position] & 0x0f);
+            let counts = match data.get(position + 1..position + 17) {
+                Some(counts) => counts,
+                None => return self.truncated_table(offset, 0xc4, data, position, 17),
+            };
+            let symbols: usize = counts.iter().map(|&count| usize::from(count)).sum();
+            let size = 17 + symbols;
+            if data.len() < position + size {
+                return self.truncated_table(offset, 0xc4, data, position, size);
+            }
+
+            if class > 1 || id > 3 {
+                let problem = Problem::InvalidTableDestination { marker: 0xc4, id };
+                self.report(offset, problem);
+            } else {
+                // every length doubles the codes left, only the all ones code of 16 bits stays unused
+                let mut space = 2usize;
+                let mut fits = symbols <= 256;
+                for (len, &count) in counts.iter().enumerate() {
+                    let count = usize::from(count);
+                    fits &= count < space || count == space && (len < 15 || count == 0);
+                    space = space.saturating_sub(count) * 2;
+                }
+                if !fits {
+                    self.report(offset, Problem::InvalidHuffmanTable { class, id });
+                }
+                self.huffman_tables[usize::from(class)][usize::from(id)] = true;
+            }
+            position += size;
+        }
+    }
+
+    fn frame(&mut self, offset: usize, marker: u8, data: &[u8]) {
+        if self.frame.is_some() || self.scans > 0 {
+            self.report(offset, Problem::MisplacedSegment { marker });
+            return;
+        }
+        let count = data.get(5).map_or(0, |&count| usize::from(count));
+        if !self.check_length(offset, marker, data, 6 + 3 * count) {
+            return;
+        }
+
+        let process = match marker & 0x03 {
+            0 if marker == 0xc0 => Process::Baseline,
+            0 | 1 => Process::Sequential,
+            2 => Process::Progressive,
+            _ => Process::Lossless,
+        };
+        let precision = data[0];
+        let valid_precision = match process {
+            Process::Baseline => precision == 8,
+            Process::Sequential | Process::Progressive => precision == 8 || precision == 12,
+            Process::Lossless => (2..=16).contains(&precision),
+        };
+        if !valid_precision {
+            self.report(offset, Problem::InvalidPrecision { precision });
+        }
+        let height = u16::from_be_bytes([data[1], data[2]]);
+        let width = u16::from_be_bytes([data[3], data[4]]);
+        if width == 0 {
+            self.report(offset, Problem::InvalidDimensions { width, height });
+        }
+        if count == 0 || (process == Process::Progressive && count > 4) {
+            self.report(offset, Problem::InvalidComponentCount { count });
+        }
+
+        let mut components: Vec<FrameComponent> = Vec::with_capacity(count);
+        for component in data[6..].chunks(3) {
+            let (id, sampling, table) = (component[0], component[1], component[2]);
+            if components.iter().any(|c| c.id == id) {
+                self.report(offset, Problem::DuplicateComponent { id });
+            }
+            let (horizontal, vertical) = (usize::from(sampling >> 4), usize::from(sampling & 0x0f));
+            if !(1..=4).contains(&horizontal) || !(1..=4).contains(&vertical) {
+                self.report(offset, Problem::InvalidSampling { id });
+            }
+            if table > 3 {
+                let problem = Problem::InvalidTableDestination { marker, id: table };
+                self.report(offset, problem);
+            }
+            components.push(FrameComponent {
+                id,
+                horizontal_sampling: horizontal.clamp(1, 4),
+                vertical_sampling: vertical.clamp(1, 4),
+                quantization_table: table & 0x03,
+            });
+        }
+
+        self.frame = Some(Frame {
+            process,
+            arithmetic: marker >= 0xc8,
+            precision,
+            width: usize::from(width),
+            height: usize::from(height),
+            components,
+        });
+    }
+
+    fn scan(&mut self, offset: usize, data: &[u8]) {
+        self.scans += 1;
+        let frame = match self.frame.take() {
+            Some(frame) => frame,
+            None => {
+                self.report(offset, Problem::MisplacedSegment { marker: 0xda });
+                return;
+            }
+        };
+        self.check_scan(offset, &frame, data);
+        self.frame = Some(frame);
+    }
+
+    fn check_scan(&mut self, offset: usize, frame: &Frame, data: &[u8]) {
+        let count = data.first().map_or(0, |&count| usize::from(count));
+        if !self.check_length(offset, 0xda, data, 4 + 2 * count) {
+            return;
+        }
+        if count == 0 || count > 4 {
+            self.report(offset, Problem::InvalidComponentCount { count });
+        }
+        let parameters = &data[1 + 2 * count..];
+        let (start, end) = (parameters[0], parameters[1]);
+        let (high, low) = (parameters[2] >> 4, parameters[2] & 0x0f);
+
+        let valid_selection = match frame.process {
+            Process::Baseline | Process::Sequential => start == 0 && end == 63,
+            Process::Progressive => start <= end && end <= 63 && (start == 0) == (end == 0),
+            Process::Lossless => (1..=7).contains(&start) && end == 0,
+        };
+        if !valid_selection || (frame.process == Process::Progressive && start > 0 && count > 1) {
+            self.report(offset, Problem::InvalidSpectralSelection { start, end });
+        }
+        let valid_approximation = match frame.process {
+            Process::Baseline | Process::Sequential => high == 0 && low == 0,
+            Process::Progressive => low <= 13 && (high == 0 || high == low + 1),
+            Process::Lossless => high == 0 && low < frame.precision,
+        };
+        if !valid_approximation {
+            self.report(offset, Problem::InvalidApproximation { high, low });
+        }
+
+        // progressive DC refinement scans code bits without Huffman tables, lossless scans only use DC tables
+        let uses_dc_table = frame.process == Process::Lossless
+            || (start == 0 && (frame.process != Process::Progressive || high == 0));
+        let uses_ac_table = end > 0;
+        let mut blocks = 0;
+        let mut previous = None;
+        for (index, component) in data[1..1 + 2 * count].chunks(2).enumerate() {
+            let id = component[0];
+            if data[1..1 + 2 * index].chunks(2).any(|c| c[0] == id) {
+                self.report(offset, Problem::DuplicateComponent { id });
+                continue;
+            }
+            let position = match frame.components.iter().position(|c| c.id == id) {
+                Some(position) => position,
+                None => {
+                    self.report(offset, Problem::UnknownScanComponent { id });
+                    continue;
+                }
+            };
+            if previous.is_some_and(|previous| position < previous) {
+                self.report(offset, Problem::ScanComponentOrder { id });
+            }
+            previous = Some(position);
+            let frame_component = &frame.components[position];
+            blocks += frame_component.horizontal_sampling * frame_component.vertical_sampling;
+
+            let table = frame_component.quantization_table;
+            match self.quantization_tables[usize::from(table)] {
+                None if frame.process != Process::Lossless => {
+                    let problem = Problem::UndefinedQuantizationTable {
+                        component: id,
+                        id: table,
+                    };
+                    self.report(offset, problem);
+                }
+                _ => {}
+            }
+            if frame.arithmetic {
+                continue;
+            }
+            let tables = [
+                (uses_dc_table, component[1] >> 4),
+                (uses_ac_table, component[1] & 0x0f),
+            ];
+            for (class, &(used, table)) in tables.iter().enumerate() {
+                let class = class as u8;
+                if !used {
+                    continue;
+                }
+                if table > 3 || (frame.process == Process::Baseline && table > 1) {
+                    let problem = Problem::InvalidTableDestination {
+                        marker: 0xda,
+                        id: table,
+                    };
+                    self.report(offset, problem);
+                } else if !self.huffman_tables[usize::from(class)][usize::from(table)] {
+                    self.report(offset, Problem::UndefinedHuffmanTable { class, id: table });
+                }
+            }
+        }
+        if count > 1 && blocks > 10 {
+            self.report(offset, Problem::TooManyBlocks { blocks });
+        }
+    }
+
+    // restart_markers checks the order of the restart markers in the entropy coded data starting at cursor. It
+    // returns the position of the marker ending the data and the number of restart markers.
+    fn restart_markers(&mut self, jpeg_file: &[u8], mut cursor: usize) -> (usize, usize) {
+        let mut count = 0;
+        while cursor + 1 < jpeg_file.len() {
+            if jpeg_file[cursor] != 0xff {
+                cursor += 1;
+                continue;
+            }
+            match jpeg_file[cursor + 1] {
+                0x00 => cursor += 2,
+                marker @ 0xd0..=0xd7 => {
+                    let (expected, found) = ((count % 8) as u8, marker - 0xd0);
+                    if found != expected {
+                        self.report(cursor, Problem::RestartMarkerOrder { expected, found });
+                    }
+                    count += 1;
+                    cursor += 2;
+                }
+                _ => return (cursor, count),
+            }
+        }
+        (jpeg_file.len(), count)
+    }
+
+    // check_restart_count compares the restart markers of a scan with the number its MCUs need
+    fn check_restart_count(&mut self, offset: usize, data: &[u8], found: usize) {
+        let mcus = match self.frame {
+            Some(ref frame) => scan_mcus(frame, data, self.lines),
+            None => None,
+        };
+        let expected = match mcus {
+            Some(mcus) if self.restart_interval > 0 => (mcus.max(1) - 1) / self.restart_interval,
+            Some(_) => 0,
+            None => return,
+        };
+        if found != expected {
+            self.report(offset, Problem::RestartMarkerCount { expected, found });
+        }
+    }
+
+    fn finish(&mut self, length: usize) {
+        match self.frame {
+            None => self.report(length, Problem::MissingFrameHeader),
+            Some(ref frame) if frame.height == 0 && self.lines.is_none_or(|lines| lines == 0) => {
+                let width = frame.width as u16;
+                self.report(length, Problem::InvalidDimensions { width, height: 0 })
+            }
+            _ => {}
+        }
+        if self.scans == 0 {
+            self.report(length, Problem::MissingScan);
+        }
+    }
+}
+
+// scan_mcus returns the number of MCUs of the scan with the given header, None if the frame has no known height or
+// the header is invalid. Scans of a single component cover only its blocks inside the image.
+fn scan_mcus(frame: &Frame, data: &[u8], lines: Option<u16>) -> Option<usize> {
+    let height = if frame.height > 0 {
+        frame.height
+    } else {
+        usize::from(lines.filter(|&lines| lines > 0)?)
+    };
+    let block = if frame.process == Process::Lossless {
+        1
+    } else {
+        8
+    };
+    let max_horizontal = frame
+        .components
+        .iter()
+        .map(|c| c.horizontal_sampling)
+        .max()?;
+    let max_vertical = frame.components.iter().map(|c| c.vertical_sampling).max()?;
+    let count = usize::from(*data.first()?);
+    if count == 1 {
+        let id = *data.get(1)?;
+        let component = frame.components.iter().find(|c| c.id == id)?;
+        let width = (frame.width * component.horizontal_sampling).div_ceil(max_horizontal);
+        let height = (height * component.vertical_sampling).div_ceil(max_vertical);
+        Some(width.div_ceil(block) * height.div_ceil(block))
+    } else {
+        Some(frame.width.div_ceil(block * max_horizontal) * height.div_ceil(block * max_vertical))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::ColorType;
+    use encoder::{
+        encode_image_12bit, encode_image_with_options, ChromaSubsampling, EncodeOptions,
+    };
+    use lossless::{encode_lossless_image, LosslessOptions};
+
+    fn segment(file: &mut Vec<u8>, marker: u8, data: &[u8]) -> usize {
+        let offset = file.len();
+        file.extend_from_slice(&[0xff, marker]);
+        file.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        file.extend_from_slice(data);
+        offset
+    }
+
+    #[test]
+    fn conformant_files_test() {
+        let mut files: Vec<Vec<u8>> = vec![
+            include_bytes!("../tests/images/gradient_420.jpg").to_vec(),
+            include_bytes!("../tests/images/gradient_422.jpg").to_vec(),
+            include_bytes!("../tests/images/gradient_444.jpg").to_vec(),
+            include_bytes!("../tests/images/gray.jpg").to_vec(),
+            include_bytes!("../tests/images/progressive_420.jpg").to_vec(),
+            include_bytes!("../tests/images/restart_420.jpg").to_vec(),
+        ];
+        let pixels: Vec<u8> = (0..37 * 19 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        for &subsampling in &[ChromaSubsampling::Chroma444, ChromaSubsampling::Gray] {
+            for &(progressive, optimize_huffman, restart_interval) in &[
+                (false, false, 0),
+                (true, false, 3),
+                (false, true, 1),
+                (true, true, 0),
+            ] {
+                let options = EncodeOptions {
+                    subsampling,
+                    progressive,
+                    optimize_huffman,
+                    restart_interval,
+                    ..EncodeOptions::default()
+                };
+                files.push(
+                    encode_image_with_options(&pixels, 37, 19, ColorType::Rgb, &options).unwrap(),
+                );
+            }
+        }
+        let samples: Vec<u16> = pixels
+            .iter()
+            .map(|&sample| u16::from(sample) * 16)
+            .collect();
+        let options = EncodeOptions::default();
+        files.push(encode_image_12bit(&samples, 37, 19, ColorType::Rgb, &options).unwrap());
+        let options = LosslessOptions::default();
+        files.push(
+            encode_lossless_image(
+                &samples[..37 * 19],
+                37,
+                19,
+                1,
+                &LosslessOptions {
+                    precision: 12,
+                    ..options
+                },
+            )
+            .unwrap(),
+        );
+
+        for file in &files {
+            assert_eq!(validate(file), vec![]);
+        }
+    }
+
+    #[test]
+    fn validate_test() {
+        let mut file = vec![0xff, 0xd8];
+        let mut table = vec![0x00];
+        table.extend(vec![1; 63]);
+        table.push(0);
+        let dqt = segment(&mut file, 0xdb, &table);
+        // three codes of one bit don't fit
+        let mut table = vec![0x00, 3];
+        table.extend(vec![0; 15]);
+        table.extend(&[0, 1, 2]);
+        let dht = segment(&mut file, 0xc4, &table);
+        let dri = segment(&mut file, 0xdd, &[0, 1, 0]);
+        let restart = file.len();
+        file.extend_from_slice(&[0xff, 0xd0]);
+        let sof = segment(
+            &mut file,
+            0xc0,
+            &[12, 0, 16, 0, 16, 2, 1, 0x22, 0, 2, 0x51, 1],
+        );
+        let sos = segment(&mut file, 0xda, &[2, 2, 0x00, 1, 0x00, 0, 63, 0x10]);
+        file.push(0x12);
+        let wrong_restart = file.len();
+        file.extend_from_slice(&[0xff, 0xd1, 0x34, 0xff, 0xd9, 0xab, 0xcd]);
+
+        let diagnostic = |offset, problem| Diagnostic { offset, problem };
+        assert_eq!(
+            validate(&file),
+            vec![
+                diagnostic(dqt, Problem::InvalidQuantizationTable { id: 0 }),
+                diagnostic(dht, Problem::InvalidHuffmanTable { class: 0, id: 0 }),
+                diagnostic(
+                    dri,
+                    Problem::InvalidLength {
+                        marker: 0xdd,
+                        length: 5,
+                        expected: 4,
+                    }
+                ),
+                diagnostic(restart, Problem::UnexpectedMarker { marker: 0xd0 }),
+                diagnostic(sof, Problem::InvalidPrecision { precision: 12 }),
+                diagnostic(sof, Problem::InvalidSampling { id: 2 }),
+                diagnostic(sos, Problem::InvalidApproximation { high: 1, low: 0 }),
+                diagnostic(
+                    sos,
+                    Problem::UndefinedQuantizationTable {
+                        component: 2,
+                        id: 1
+                    }
+                ),
+                diagnostic(sos, Problem::UndefinedHuffmanTable { class: 1, id: 0 }),
+                diagnostic(sos, Problem::ScanComponentOrder { id: 1 }),
+                diagnostic(sos, Problem::UndefinedHuffmanTable { class: 1, id: 0 }),
+                diagnostic(
+                    wrong_restart,
+                    Problem::RestartMarkerOrder {
+                        expected: 0,
+                        found: 1,
+                    }
+                ),
+                diagnostic(
+                    sos,
+                    Problem::RestartMarkerCount {
+                        expected: 0,
+                        found: 1,
+                    }
+                ),
+                diagnostic(file.len() - 2, Problem::TrailingData { length: 2 }),
+            ]
+        );
+        assert_eq!(
+            validate(&file)[4].to_string(),
+            format!("offset {}: invalid sample precision 12", sof)
+        );
+
+        // a progressive frame of five components, two with the same id, and a second frame header
+        let mut file = vec![0xff, 0xd8];
+        let mut tables = vec![0x00];
+        tables.extend(vec![1; 64]);
+        tables.push(0x04);
+        tables.extend(vec![1; 64]);
+        let dqt = segment(&mut file, 0xdb, &tables);
+        let mut table = vec![0x10, 1];
+        table.extend(vec![0; 16]);
+        segment(&mut file, 0xc4, &table);
+        let sof = segment(
+            &mut file,
+            0xc2,
+            &[
+                8, 0, 16, 0, 0, 5, 1, 0x22, 0, 1, 0x11, 0, 2, 0x22, 0, 3, 0x22, 0, 4, 0x11, 0,
+            ],
+        );
+        let second_sof = segment(&mut file, 0xc0, &[8, 0, 16, 0, 16, 1, 1, 0x11, 0]);
+        // an AC band of several components, one of them unknown, with more than 10 blocks per MCU
+        let sos = segment(
+            &mut file,
+            0xda,
+            &[4, 1, 0x00, 9, 0x00, 2, 0x00, 3, 0x00, 1, 5, 0x00],
+        );
+        file.push(0x00);
+        segment(&mut file, 0xfe, &[]);
+        let garbage = file.len();
+        file.extend_from_slice(&[0x12, 0xff, 0xd9]);
+
+        assert_eq!(
+            validate(&file),
+            vec![
+                diagnostic(
+                    dqt,
+                    Problem::InvalidTableDestination {
+                        marker: 0xdb,
+                        id: 4,
+                    }
+                ),
+                diagnostic(
+                    sof,
+                    Problem::InvalidDimensions {
+                        width: 0,
+                        height: 16,
+                    }
+                ),
+                diagnostic(sof, Problem::InvalidComponentCount { count: 5 }),
+                diagnostic(sof, Problem::DuplicateComponent { id: 1 }),
+                diagnostic(second_sof, Problem::MisplacedSegment { marker: 0xc0 }),
+                diagnostic(sos, Problem::InvalidSpectralSelection { start: 1, end: 5 }),
+                diagnostic(sos, Problem::UnknownScanComponent { id: 9 }),
+                diagnostic(sos, Problem::TooManyBlocks { blocks: 12 }),
+                diagnostic(garbage, Problem::InvalidMarker { found: 0x12 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_file_test() {
+        assert_eq!(
+            validate(b"\xff\xd9"),
+            vec![Diagnostic {
+                offset: 0,
+                problem: Problem::MissingStartOfImage,
+            }]
+        );
+        let problems: Vec<Problem> = validate(b"\xff\xd8\xff\xd9")
+            .into_iter()
+            .map(|diagnostic| diagnostic.problem)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![Problem::MissingFrameHeader, Problem::MissingScan]
+        );
+
+        let file = include_bytes!("../tests/images/gray.jpg");
+        assert_eq!(
+            validate(&file[..file.len() - 2]),
+            vec![Diagnostic {
+                offset: file.len() - 2,
+                problem: Problem::MissingEndOfImage,
+            }]
+        );
+        // a segment length running past the end of the file
+        let diagnostics = validate(&file[..40]);
+        assert!(matches!(
+            diagnostics[0].problem,
+            Problem::TruncatedSegment { .. }
+        ));
+    }
+}
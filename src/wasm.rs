@@ -0,0 +1,95 @@
+// JavaScript bindings, build them with wasm-pack or
+//
+//     cargo build --release --target wasm32-unknown-unknown --features wasm-bindgen
+//
+// followed by wasm-bindgen.
+
+use wasm_bindgen::prelude::*;
+
+use decoder::{decode_image_with_options, DecodeOptions, PixelFormat};
+use info::read_info;
+
+// ImageData mirrors the browser's ImageData, data holds width * height * 4 bytes of RGBA pixels and can be passed
+// to the ImageData constructor as a Uint8ClampedArray
+#[wasm_bindgen]
+pub struct ImageData {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl ImageData {
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+}
+
+// decode_to_rgba decodes bytes to opaque RGBA pixels, throwing the decoder's error message on failure
+#[wasm_bindgen(js_name = decodeToRgba)]
+pub fn decode_to_rgba(bytes: &[u8]) -> Result<ImageData, JsValue> {
+    let options = DecodeOptions {
+        pixel_format: Some(PixelFormat::Rgba8),
+        ..Default::default()
+    };
+
+    decode_image_with_options(bytes, &options)
+        .map(|image| ImageData {
+            width: image.width as u32,
+            height: image.height as u32,
+            data: image.pixels,
+        })
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+// dimensions returns [width, height] of bytes without decoding the scans
+#[wasm_bindgen]
+pub fn dimensions(bytes: &[u8]) -> Result<Vec<u32>, JsValue> {
+    read_info(bytes)
+        .map(|info| vec![info.width as u32, info.height as u32])
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::decode_image;
+
+    // the error paths call into JavaScript and can only run on wasm32, so only the conversions are tested here
+
+    #[test]
+    fn decode_to_rgba_test() {
+        let file = include_bytes!("../tests/images/gradient_420.jpg");
+        let rgb = decode_image(file).unwrap();
+        let rgba = decode_to_rgba(file).ok().unwrap();
+
+        assert_eq!((rgba.width(), rgba.height()), (32, 24));
+        assert_eq!(rgba.data().len(), 32 * 24 * 4);
+        assert_eq!(
+            &rgba.data()[..4],
+            &[rgb.pixels[0], rgb.pixels[1], rgb.pixels[2], 255]
+        );
+        assert_eq!(dimensions(file).ok().unwrap(), vec![32, 24]);
+    }
+
+    #[test]
+    fn gray_to_rgba_test() {
+        let file = include_bytes!("../tests/images/gray.jpg");
+        let first = decode_image(file).unwrap().pixels[0];
+        let rgba = decode_to_rgba(file).ok().unwrap();
+
+        assert_eq!(&rgba.data[..4], &[first, first, first, 255]);
+    }
+}
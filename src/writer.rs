@@ -0,0 +1,378 @@
+// Serialization of parsed markers back into JPEG files, the inverse of parser::decode.
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use encoder::{write_segment, MAX_SEGMENT_DATA};
+use errors::{EncodeError, Error};
+use parser::{Marker, QuantizationTable, StartOfFrameMetaData, StartOfStreamMetaData};
+
+// encode_markers writes markers between SOI and EOI markers, recomputing the length of every segment. Scans are
+// written as their header followed by their entropy coded data as parsed, restart markers included. Markers are
+// written in the given order without checking that they make up a decodable file, so editing the metadata of a
+// parsed file leaves its image data untouched.
+//
+// Writing the markers decode returns reproduces a conformant file byte for byte: encode_markers(&decode(file)?)
+// equals file if its segment lengths match their content and nothing follows EOI. Lossless editing relies on this,
+// the only bytes that change are those of the edited markers.
+pub fn encode_markers(markers: &[Marker]) -> Result<Vec<u8>, Error> {
+    let mut output = vec![0xff, 0xd8];
+    for marker in markers {
+        write_marker(&mut output, marker)?;
+    }
+    output.extend_from_slice(&[0xff, 0xd9]);
+    Ok(output)
+}
+
+fn write_marker(output: &mut Vec<u8>, marker: &Marker) -> Result<(), EncodeError> {
+    let (tag, data) = match *marker {
+        Marker::Other(ref marker) => (marker.tag, marker.data.to_vec()),
+        Marker::DHT(ref tables) => {
+            let mut data = Vec::new();
+            for table in tables {
+                let (class, id) = table.class.selector();
+                data.push(class << 4 | id);
+                // the counts of the codes have to add up to the symbols following them
+                let count: usize = table.counts.iter().map(|&count| usize::from(count)).sum();
+                if count != table.values.len() {
+                    return Err(EncodeError::InvalidSegment { marker: 0xc4 });
+                }
+                data.extend_from_slice(&table.counts);
+                data.extend_from_slice(&table.values);
+            }
+            (0xc4, data)
+        }
+        Marker::DQT(ref tables) => (0xdb, quantization_tables(tables)?),
+        Marker::DRI(interval) => (0xdd, interval.to_be_bytes().to_vec()),
+        Marker::SOF(ref frame) => (frame.marker, frame_header(frame)?),
+        Marker::Image(ref image) => {
+            let header = scan_header(&image.metadata)?;
+            check_length(0xda, &header)?;
+            write_segment(output, 0xda, &header);
+            output.extend_from_slice(image.data);
+            return Ok(());
+        }
+    };
+    check_length(tag, &data)?;
+    write_segment(output, tag, &data);
+    Ok(())
+}
+
+fn quantization_tables(tables: &[QuantizationTable]) -> Result<Vec<u8>, EncodeError> {
+    let mut data = Vec::with_capacity(129 * tables.len());
+    for table in tables {
+        let precision = field(table.precision, 1, 0xdb)?;
+        let id = field(table.id, 0x0f, 0xdb)?;
+        if table.data.len() != 64 << precision {
+            return Err(EncodeError::InvalidSegment { marker: 0xdb });
+        }
+        data.push(precision << 4 | id);
+        data.extend_from_slice(table.data);
+    }
+    Ok(data)
+}
+
+fn frame_header(frame: &StartOfFrameMetaData) -> Result<Vec<u8>, EncodeError> {
+    let marker = frame.marker;
+    let components = &frame.component_metadata;
+    let mut data = Vec::with_capacity(6 + 3 * components.len());
+    data.push(field(frame.precision, 0xff, marker)?);
+    data.extend_from_slice(&(field_u16(frame.height, marker)?).to_be_bytes());
+    data.extend_from_slice(&(field_u16(frame.width, marker)?).to_be_bytes());
+    data.push(field(components.len() as u64, 0xff, marker)?);
+    for component in components {
+        data.push(field(component.id, 0xff, marker)?);
+        data.push(field(component.sampling_resolution, 0xff, marker)?);
+        data.push(field(component.quantization_table, 0xff, marker)?);
+    }
+    Ok(data)
+}
+
+fn scan_header(scan: &StartOfStreamMetaData) -> Result<Vec<u8>, EncodeError> {
+    let components = &scan.component_metadata;
+    let mut data = Vec::with_capacity(4 + 2 * components.len());
+    data.push(field(components.len() as u64, 0xff, 0xda)?);
+    for component in components {
+        data.push(field(component.id, 0xff, 0xda)?);
+        data.push(
+            field(component.dc_table, 0x0f, 0xda)? << 4 | field(component.ac_table, 0x0f, 0xda)?,
+        );
+    }
+    data.push(field(scan.spectral_start, 0xff, 0xda)?);
+    data.push(field(scan.spectral_end, 0xff, 0xda)?);
+    data.push(
+        field(scan.approximation_high, 0x0f, 0xda)? << 4
+            | field(scan.approximation_low, 0x0f, 0xda)?,
+    );
+    Ok(data)
+}
+
+// field returns value as a byte if it is at most max, the largest value the field of the segment allows
+fn field(value: u64, max: u8, marker: u8) -> Result<u8, EncodeError> {
+    if value > u64::from(max) {
+        return Err(EncodeError::InvalidSegment { marker });
+    }
+    Ok(value as u8)
+}
+
+fn field_u16(value: u64, marker: u8) -> Result<u16, EncodeError> {
+    if value > 0xffff {
+        return Err(EncodeError::InvalidSegment { marker });
+    }
+    Ok(value as u16)
+}
+
+fn check_length(marker: u8, data: &[u8]) -> Result<(), EncodeError> {
+    if data.len() > MAX_SEGMENT_DATA {
+        return Err(EncodeError::MetadataTooLarge {
+            marker,
+            length: data.len(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use decoder::{decode_image, ColorType};
+    use encoder::{encode_image_with_options, ChromaSubsampling, EncodeOptions};
+    use huffman::{DHTType, HuffmanTable};
+    use parser::{decode, MarkerOwned, SomeMarker};
+
+    fn pattern(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height * 3)
+            .map(|i| ((i * 7) % 251) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn encode_markers_test() {
+        for &progressive in &[false, true] {
+            let options = EncodeOptions {
+                progressive,
+                restart_interval: 2,
+                comments: vec![b"comment".to_vec()],
+                ..EncodeOptions::default()
+            };
+            let file =
+                encode_image_with_options(&pattern(37, 19), 37, 19, ColorType::Rgb, &options)
+                    .unwrap();
+            let markers = decode(&file).unwrap();
+            assert_eq!(encode_markers(&markers).unwrap(), file);
+
+            // owned markers edited without touching the scans
+            let mut owned: Vec<MarkerOwned> = markers.iter().map(Marker::to_owned).collect();
+            owned.retain(|marker| match *marker {
+                MarkerOwned::Other(ref marker) => marker.tag != 0xfe,
+                _ => true,
+            });
+            let markers: Vec<Marker> = owned.iter().map(MarkerOwned::as_marker).collect();
+            let edited = encode_markers(&markers).unwrap();
+            assert_eq!(edited.len(), file.len() - 11);
+            assert_eq!(decode_image(&edited).unwrap(), decode_image(&file).unwrap());
+        }
+    }
+
+    // Random is a linear congruential generator, so every run checks the same files
+    struct Random(u32);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (self.0 >> 8) as usize % n
+        }
+
+        fn bytes(&mut self, length: usize) -> Vec<u8> {
+            (0..length).map(|_| self.below(256) as u8).collect()
+        }
+
+        // data returns random bytes of a random length below max
+        fn data(&mut self, max: usize) -> Vec<u8> {
+            let length = self.below(max);
+            self.bytes(length)
+        }
+    }
+
+    fn segment(file: &mut Vec<u8>, tag: u8, data: &[u8]) {
+        file.extend_from_slice(&[0xff, tag]);
+        file.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        file.extend_from_slice(data);
+    }
+
+    // random_tables writes a DHT or DQT segment holding several tables
+    fn random_tables(random: &mut Random, file: &mut Vec<u8>) {
+        let mut data = Vec::new();
+        if random.below(2) == 0 {
+            for _ in 0..1 + random.below(4) {
+                // ids 2 and 3 only exist in extended files, the parser keeps such segments as unknown markers
+                data.push((random.below(2) << 4 | random.below(4)) as u8);
+                // the counts leave room in the code space for every length, like a valid prefix code does
+                let (mut counts, mut space) = ([0u8; 16], 2usize);
+                for count in counts.iter_mut() {
+                    *count = random.below(space.min(4)) as u8;
+                    space = (space - usize::from(*count)) * 2;
+                }
+                data.extend_from_slice(&counts);
+                let symbols = counts.iter().map(|&count| usize::from(count)).sum();
+                data.extend(random.bytes(symbols));
+            }
+            segment(file, 0xc4, &data);
+        } else {
+            for _ in 0..1 + random.below(4) {
+                let precision = random.below(2);
+                data.push((precision << 4 | random.below(4)) as u8);
+                data.extend(random.bytes(64 << precision));
+            }
+            segment(file, 0xdb, &data);
+        }
+    }
+
+    // random_file writes a file of segments with random content in the order of ITU T.81 B.2: tables and metadata,
+    // a frame header and scans of stuffed random data with restart markers, with more tables between the scans
+    fn random_file(random: &mut Random) -> Vec<u8> {
+        let mut file = vec![0xff, 0xd8];
+        for _ in 0..random.below(6) {
+            match random.below(4) {
+                0 => segment(&mut file, 0xe0 + random.below(16) as u8, &random.data(40)),
+                1 => segment(&mut file, 0xfe, &random.data(40)),
+                2 => segment(
+                    &mut file,
+                    0xdd,
+                    &(random.below(0x10000) as u16).to_be_bytes(),
+                ),
+                _ => random_tables(random, &mut file),
+            }
+        }
+
+        let markers = [
+            0xc0, 0xc1, 0xc2, 0xc3, 0xc5, 0xc6, 0xc7, 0xc9, 0xca, 0xcb, 0xcd, 0xce, 0xcf,
+        ];
+        let components = 1 + random.below(4);
+        let mut frame = vec![[8, 12, 16][random.below(3)]];
+        frame.extend_from_slice(&(1 + random.below(0xffff) as u16).to_be_bytes());
+        frame.extend_from_slice(&(1 + random.below(0xffff) as u16).to_be_bytes());
+        frame.push(components as u8);
+        for id in 0..components {
+            let sampling = (1 + random.below(4)) << 4 | (1 + random.below(4));
+            frame.extend_from_slice(&[id as u8 + 1, sampling as u8, random.below(4) as u8]);
+        }
+        segment(&mut file, markers[random.below(markers.len())], &frame);
+
+        for scan in 0..1 + random.below(4) {
+            if scan > 0 && random.below(2) == 0 {
+                random_tables(random, &mut file);
+            }
+            let count = 1 + random.below(components);
+            let mut header = vec![count as u8];
+            for id in 0..count {
+                header.extend_from_slice(&[
+                    id as u8 + 1,
+                    (random.below(4) << 4 | random.below(4)) as u8,
+                ]);
+            }
+            header.extend_from_slice(&[
+                random.below(64) as u8,
+                random.below(64) as u8,
+                random.below(256) as u8,
+            ]);
+            segment(&mut file, 0xda, &header);
+
+            let mut restart = 0;
+            for _ in 0..random.below(200) {
+                match random.below(64) {
+                    0 => {
+                        file.extend_from_slice(&[0xff, 0xd0 + restart]);
+                        restart = (restart + 1) % 8;
+                    }
+                    1..=4 => file.extend_from_slice(&[0xff, 0x00]),
+                    _ => file.push(random.below(255) as u8),
+                }
+            }
+        }
+        if random.below(4) == 0 {
+            // a DNL segment after the first scan sets the number of lines
+            segment(
+                &mut file,
+                0xdc,
+                &(1 + random.below(0xffff) as u16).to_be_bytes(),
+            );
+        }
+        file.extend_from_slice(&[0xff, 0xd9]);
+        file
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut random = Random(1);
+        for _ in 0..1000 {
+            let file = random_file(&mut random);
+            let markers = decode(&file).unwrap();
+            assert_eq!(encode_markers(&markers).unwrap(), file);
+        }
+
+        // files of the encoder with random settings and metadata
+        for _ in 0..50 {
+            let (width, height) = (1 + random.below(40), 1 + random.below(40));
+            let subsampling = [
+                ChromaSubsampling::Chroma444,
+                ChromaSubsampling::Chroma422,
+                ChromaSubsampling::Chroma420,
+                ChromaSubsampling::Gray,
+            ][random.below(4)];
+            let options = EncodeOptions {
+                quality: 1 + random.below(100) as u8,
+                subsampling,
+                progressive: random.below(2) == 0,
+                optimize_huffman: random.below(2) == 0,
+                restart_interval: random.below(4) as u16,
+                xmp: Some(random.data(100)),
+                icc_profile: Some(random.data(100)),
+                comments: vec![random.data(100)],
+                ..EncodeOptions::default()
+            };
+            let pixels = random.bytes(width * height * 3);
+            let file = encode_image_with_options(
+                &pixels,
+                width as u64,
+                height as u64,
+                ColorType::Rgb,
+                &options,
+            )
+            .unwrap();
+            assert_eq!(encode_markers(&decode(&file).unwrap()).unwrap(), file);
+        }
+    }
+
+    #[test]
+    fn encode_markers_error_test() {
+        let table = QuantizationTable {
+            id: 0,
+            precision: 1,
+            data: &[1; 64],
+        };
+        assert_eq!(
+            encode_markers(&[Marker::DQT(vec![table])])
+                .unwrap_err()
+                .to_string(),
+            "Encoder Error: invalid fields in segment with marker 0xdb"
+        );
+
+        let mut table = HuffmanTable::standard(DHTType::LuminanceAC);
+        table.values.pop();
+        assert!(encode_markers(&[Marker::DHT(vec![table])]).is_err());
+
+        let data = vec![0; MAX_SEGMENT_DATA + 1];
+        let marker = Marker::Other(SomeMarker {
+            tag: 0xfe,
+            length: 0,
+            data: &data,
+        });
+        let error = encode_markers(&[marker]).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Encoder(EncodeError::MetadataTooLarge { marker: 0xfe, length }) if length == data.len()
+        ));
+    }
+}
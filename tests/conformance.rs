@@ -0,0 +1,264 @@
+// Conformance harness for the compliance test streams of ITU T.83. The streams aren't distributed with the crate,
+// point RSJPEG_CONFORMANCE_DIR at a directory holding them and run
+//
+//     RSJPEG_CONFORMANCE_DIR=path cargo test --features conformance --test conformance -- --nocapture
+//
+// Every .jpg file is compared with the reference data next to it, either the decoded image as <name>.pgm or
+// <name>.ppm, or every component at its own resolution as <name>.<index>.pgm with index counting from 0. As T.83
+// requires, samples may differ from the reference by at most one. Streams are decoded with the reference IDCT,
+// so the results don't depend on the machine running them. The harness prints which streams of every
+// coding process pass and fails if a stream of a process the decoder supports doesn't.
+#![cfg(feature = "conformance")]
+
+extern crate rsjpeg;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rsjpeg::{
+    decode_image_with_options, decode_planes, ColorType, DecodeOptions, Error, IdctMethod,
+    ParserError,
+};
+
+#[derive(Debug, Default)]
+struct Results {
+    passed: Vec<String>,
+    failed: Vec<String>,
+    unsupported: Vec<String>,
+}
+
+enum Outcome {
+    Passed,
+    Failed(String),
+    Unsupported(String),
+}
+
+#[test]
+fn conformance_test() {
+    let directory = match env::var_os("RSJPEG_CONFORMANCE_DIR") {
+        Some(directory) => PathBuf::from(directory),
+        None => {
+            println!("RSJPEG_CONFORMANCE_DIR is not set, skipping the conformance streams");
+            return;
+        }
+    };
+    let mut streams: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|e| panic!("{}: {}", directory.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("jpg"))
+        })
+        .collect();
+    streams.sort();
+    assert!(
+        !streams.is_empty(),
+        "no .jpg files in {}",
+        directory.display()
+    );
+
+    let mut results: BTreeMap<String, Results> = BTreeMap::new();
+    for path in &streams {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let file = fs::read(path).unwrap();
+        let result = results.entry(process(&file)).or_default();
+        match check(path, &file) {
+            Outcome::Passed => result.passed.push(name),
+            Outcome::Failed(reason) => result.failed.push(format!("{} ({})", name, reason)),
+            Outcome::Unsupported(reason) => {
+                result.unsupported.push(format!("{} ({})", name, reason))
+            }
+        }
+    }
+
+    let mut failed = 0;
+    for (process, result) in &results {
+        println!(
+            "{}: {} passed, {} failed, {} unsupported",
+            process,
+            result.passed.len(),
+            result.failed.len(),
+            result.unsupported.len()
+        );
+        for name in &result.failed {
+            println!("  failed {}", name);
+        }
+        for name in &result.unsupported {
+            println!("  unsupported {}", name);
+        }
+        failed += result.failed.len();
+    }
+    assert_eq!(failed, 0, "{} conformance streams failed", failed);
+}
+
+// check decodes the stream at path and compares it with its reference data
+fn check(path: &Path, file: &[u8]) -> Outcome {
+    let options = DecodeOptions {
+        apply_orientation: false,
+        idct: IdctMethod::Reference,
+        ..DecodeOptions::default()
+    };
+
+    let mut planes = Vec::new();
+    while let Ok(reference) = fs::read(path.with_extension(format!("{}.pgm", planes.len()))) {
+        planes.push(reference);
+    }
+    if !planes.is_empty() {
+        let decoded = match decode_planes(file, &options) {
+            Ok(decoded) => decoded,
+            Err(e) => return failure(e),
+        };
+        if decoded.components.len() != planes.len() {
+            return Outcome::Failed(format!(
+                "{} components instead of {}",
+                decoded.components.len(),
+                planes.len()
+            ));
+        }
+        for (index, (component, reference)) in decoded.components.iter().zip(&planes).enumerate() {
+            let samples: Vec<u8> = component
+                .samples
+                .chunks(component.stride)
+                .take(component.height as usize)
+                .flat_map(|line| line[..component.width as usize].to_vec())
+                .collect();
+            let decoded = (component.width, component.height, 1, &samples[..]);
+            if let Err(reason) = compare(decoded, reference) {
+                return Outcome::Failed(format!("component {}: {}", index, reason));
+            }
+        }
+        return Outcome::Passed;
+    }
+
+    let (reference, color_type, channels) =
+        if let Ok(reference) = fs::read(path.with_extension("pgm")) {
+            (reference, ColorType::Gray, 1)
+        } else if let Ok(reference) = fs::read(path.with_extension("ppm")) {
+            (reference, ColorType::Rgb, 3)
+        } else {
+            return Outcome::Failed("no reference data".to_string());
+        };
+    let options = DecodeOptions {
+        color_type: Some(color_type),
+        ..options
+    };
+    match decode_image_with_options(file, &options) {
+        Ok(image) => match compare(
+            (image.width, image.height, channels, &image.pixels),
+            &reference,
+        ) {
+            Ok(()) => Outcome::Passed,
+            Err(reason) => Outcome::Failed(reason),
+        },
+        Err(e) => failure(e),
+    }
+}
+
+// failure tells errors for coding processes the decoder doesn't implement from broken decodes
+fn failure(error: Error) -> Outcome {
+    match error.parser_error() {
+        Some(ParserError::UnsupportedSofProcess { .. })
+        | Some(ParserError::UnsupportedPrecision { .. })
+        | Some(ParserError::UnsupportedComponentCount { .. }) => {
+            Outcome::Unsupported(error.to_string())
+        }
+        _ => Outcome::Failed(error.to_string()),
+    }
+}
+
+// compare checks decoded samples of the given width, height and channels against a binary PGM or PPM file
+fn compare(decoded: (u64, u64, usize, &[u8]), reference: &[u8]) -> Result<(), String> {
+    let (width, height, channels, samples) = decoded;
+    let (reference_width, reference_height, reference_channels, reference) = read_pnm(reference)?;
+    if (width, height, channels) != (reference_width, reference_height, reference_channels) {
+        return Err(format!(
+            "decoded {}x{} with {} channels, the reference is {}x{} with {}",
+            width, height, channels, reference_width, reference_height, reference_channels
+        ));
+    }
+    let differences = samples
+        .iter()
+        .zip(reference)
+        .filter(|&(&sample, &expected)| (i16::from(sample) - i16::from(expected)).abs() > 1)
+        .count();
+    if differences > 0 {
+        return Err(format!("{} samples differ by more than one", differences));
+    }
+    Ok(())
+}
+
+// read_pnm parses a binary PGM or PPM file with a maximum value of at most 255
+fn read_pnm(file: &[u8]) -> Result<(u64, u64, usize, &[u8]), String> {
+    let channels = match file.get(..2) {
+        Some(b"P5") => 1,
+        Some(b"P6") => 3,
+        _ => return Err("reference isn't a binary PGM or PPM file".to_string()),
+    };
+    // the magic number is followed by width, height and maximum value, separated by whitespace and comments
+    let mut fields = Vec::with_capacity(3);
+    let mut cursor = 2;
+    while fields.len() < 3 {
+        while cursor < file.len() && file[cursor].is_ascii_whitespace() {
+            cursor += 1;
+        }
+        if file.get(cursor) == Some(&b'#') {
+            while cursor < file.len() && file[cursor] != b'\n' {
+                cursor += 1;
+            }
+            continue;
+        }
+        let start = cursor;
+        while cursor < file.len() && file[cursor].is_ascii_digit() {
+            cursor += 1;
+        }
+        let field = std::str::from_utf8(&file[start..cursor]).unwrap();
+        fields.push(
+            field
+                .parse::<u64>()
+                .map_err(|_| "invalid reference header".to_string())?,
+        );
+    }
+    let (width, height, maximum) = (fields[0], fields[1], fields[2]);
+    if maximum > 255 {
+        return Err(format!("reference has a maximum value of {}", maximum));
+    }
+    // a single whitespace byte separates the header from the samples
+    let samples = &file[(cursor + 1).min(file.len())..];
+    if samples.len() as u64 != width * height * channels as u64 {
+        return Err("reference is truncated".to_string());
+    }
+    Ok((width, height, channels, samples))
+}
+
+// process names the coding process of the frame in file and its sample precision
+fn process(file: &[u8]) -> String {
+    let mut cursor = 2;
+    while cursor + 4 <= file.len() && file[cursor] == 0xff {
+        let tag = file[cursor + 1];
+        let length = usize::from(u16::from_be_bytes([file[cursor + 2], file[cursor + 3]]));
+        let name = match tag {
+            0xc0 => "baseline",
+            0xc1 => "extended sequential",
+            0xc2 => "progressive",
+            0xc3 => "lossless",
+            0xc5..=0xc7 => "hierarchical",
+            0xc9 => "arithmetic extended sequential",
+            0xca => "arithmetic progressive",
+            0xcb => "arithmetic lossless",
+            0xcd..=0xcf => "arithmetic hierarchical",
+            0xda | 0xd9 => break,
+            _ => {
+                cursor += 2 + length;
+                continue;
+            }
+        };
+        return match file.get(cursor + 4) {
+            Some(precision) => format!("{}, {} bit", name, precision),
+            None => name.to_string(),
+        };
+    }
+    "unknown process".to_string()
+}